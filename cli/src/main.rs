@@ -1,38 +1,334 @@
+use std::env;
+use std::fs;
 use std::io::{self, prelude::*, BufReader, Write};
 use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use srv::utils::tokenizer::tokenize;
+
+/// Maximum number of commands kept in the history.
+const HISTORY_MAX_LEN: usize = 1000;
 
 fn main() {
     // connect
     // Struct used to start requests to the server.
     // Check TcpStream Connection to the server
-    let mut stream = TcpStream::connect("127.0.0.1:7878").unwrap();
+    let mut stream = match TcpStream::connect("127.0.0.1:7878") {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("failed to connect: {e}");
+            process::exit(1);
+        }
+    };
+
+    // one reader for the whole connection, it may buffer ahead.
+    let mut reader = match stream.try_clone() {
+        Ok(stream) => BufReader::new(stream),
+        Err(e) => {
+            eprintln!("failed to connect: {e}");
+            process::exit(1);
+        }
+    };
+
+    let history_path = history_path();
+    let mut history = History::load(history_path.as_ref());
+
+    if let Err(e) = repl(&mut stream, &mut reader, &mut history) {
+        eprintln!("error: {e}");
+    }
+
+    if let Some(path) = &history_path {
+        if let Err(e) = history.save(path) {
+            eprintln!("failed to save history to {}: {e}", path.display());
+        }
+    }
+}
+
+/// Read commands from stdin and print their replies until `quit`, `exit`
+/// or the end of stdin. Lines are edited in place, earlier commands are
+/// recalled with the arrow keys.
+fn repl<R: BufRead>(
+    stream: &mut TcpStream,
+    reader: &mut R,
+    history: &mut History,
+) -> io::Result<()> {
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    for line in history.entries.iter() {
+        editor.add_history_entry(line).map_err(io::Error::other)?;
+    }
 
     loop {
-        let mut cmd = String::new();
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl-C drops the line being edited.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                // Ctrl-D, leave the server cleanly.
+                println!();
+                return stream.write_all(b"exit\n");
+            }
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        let line = match history.expand(line.trim()) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("(error) {e}");
+                continue;
+            }
+        };
+        let tokens = match tokenize(&line) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("(error) {e}");
+                continue;
+            }
+        };
+        let Some(name) = tokens.first() else {
+            continue;
+        };
+        if history.push(&line) {
+            editor.add_history_entry(&line).map_err(io::Error::other)?;
+        }
+
+        match name.as_str() {
+            "quit" | "exit" => return stream.write_all(b"exit\n"),
+            "history" => {
+                for (i, line) in history.entries.iter().enumerate() {
+                    println!("{:>4}  {line}", i + 1);
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        stream.write_all(encode_command(&tokens).as_bytes())?;
+        match read_reply(reader)? {
+            Some(lines) => lines.iter().for_each(|line| println!("{line}")),
+            None => {
+                println!("(error) connection closed by server");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Join `tokens` into a command line the server splits back into the
+/// same tokens, quoting the ones which need it.
+fn encode_command(tokens: &[String]) -> String {
+    let mut line = tokens
+        .iter()
+        .map(|token| quote(token))
+        .collect::<Vec<_>>()
+        .join(" ");
+    line.push('\n');
+    line
+}
+
+/// Quote `token` if it's empty, contains whitespace or starts with a quote.
+fn quote(token: &str) -> String {
+    if !token.is_empty() && !token.starts_with('"') && !token.contains(char::is_whitespace) {
+        return token.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(token.len() + 2);
+    quoted.push('"');
+    for c in token.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Read a reply, multi-line replies start with `*<count>`, values
+/// starting with `*` have one more.
+///
+/// Return `None` if the server closed the connection before replying.
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<String>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+
+    if let Some(value) = line.strip_prefix("**") {
+        return Ok(Some(vec![format!("*{value}")]));
+    }
+    let Some(count) = line.strip_prefix('*').and_then(|n| n.parse::<usize>().ok()) else {
+        return Ok(Some(vec![line]));
+    };
 
-        let _size = io::stdout().write("> ".as_bytes()).unwrap();
-        io::stdout().flush().unwrap();
+    let mut lines = Vec::with_capacity(count);
+    for _ in 0..count {
+        match read_line(reader)? {
+            Some(line) => lines.push(line),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+    Ok(Some(lines))
+}
 
-        io::stdin()
-            .read_line(&mut cmd)
-            .expect("failed to read command");
+/// Read a reply line without the trailing newline, `None` on EOF.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut buf: Vec<u8> = Vec::new();
 
-        // Write the message so that the receiver can access it.
-        let _size = stream
-            .write(cmd.as_bytes())
-            .expect("failed to write command");
+    if reader.read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
 
-        // Add Buffering so that the receiver can read the message from the stream.
-        let mut reader = BufReader::new(&stream);
-        let mut buf: Vec<u8> = Vec::new();
+    let buf = String::from_utf8_lossy(&buf);
+    Ok(Some(buf.strip_suffix('\n').unwrap_or(&buf).to_owned()))
+}
 
-        if reader.read_until(b'\n', &mut buf).unwrap() == 0 {
-            break;
+/// Return the history file, `BITCASK_HISTORY` or `~/.bitcask_history`.
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("BITCASK_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".bitcask_history"))
+}
+
+/// Previously entered commands, oldest first.
+#[derive(Debug, Default)]
+struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    /// Load the history from `path`, a missing or unreadable file gives
+    /// an empty history.
+    fn load(path: Option<&PathBuf>) -> Self {
+        let mut history = Self::default();
+        if let Some(content) = path.and_then(|path| fs::read_to_string(path).ok()) {
+            content.lines().for_each(|line| {
+                history.push(line);
+            });
+        }
+        history
+    }
+
+    fn save(&self, path: &PathBuf) -> io::Result<()> {
+        let mut content = self.entries.join("\n");
+        content.push('\n');
+        fs::write(path, content)
+    }
+
+    /// Add `line` unless it repeats the last command or holds a
+    /// password, return `true` if it's added.
+    fn push(&mut self, line: &str) -> bool {
+        if line.is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return false;
+        }
+        // the history file is plain text, `auth` lines are never saved.
+        if tokenize(line).is_ok_and(|tokens| tokens.first().is_some_and(|name| name == "auth")) {
+            return false;
+        }
+        self.entries.push(line.to_owned());
+        if self.entries.len() > HISTORY_MAX_LEN {
+            self.entries.remove(0);
         }
+        true
+    }
+
+    /// Replace `!!` by the last command and `!<n>` by the n-th one.
+    fn expand(&self, line: &str) -> Result<String, String> {
+        let Some(reference) = line.strip_prefix('!') else {
+            return Ok(line.to_owned());
+        };
+
+        let entry = if reference == "!" {
+            self.entries.last()
+        } else {
+            let n: usize = reference
+                .parse()
+                .map_err(|_| format!("invalid history reference: {line}"))?;
+            n.checked_sub(1).and_then(|i| self.entries.get(i))
+        };
+        entry
+            .cloned()
+            .ok_or_else(|| format!("{line}: event not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_reply() {
+        let mut reader = b"OK\n*2\na\nb\n*0\nlast".as_slice();
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["OK".into()]));
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Some(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec![]));
+        // the final reply may miss its newline.
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["last".into()]));
+        assert_eq!(read_reply(&mut reader).unwrap(), None);
+
+        // a truncated multi-line reply is an error, not a panic.
+        let mut reader = b"*3\na\n".as_slice();
+        assert_eq!(
+            read_reply(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // not a count, printed as is.
+        let mut reader = b"*x\n".as_slice();
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["*x".into()]));
+
+        // values starting with `*` are sent with one more.
+        let mut reader = b"**2\n***\nnext\n".as_slice();
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["*2".into()]));
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["**".into()]));
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(vec!["next".into()]));
+    }
+
+    #[test]
+    fn test_encode_command() {
+        for line in [
+            "get foo",
+            "set key \"two words\"",
+            "set key \"\"",
+            r#"set key "say \"hi\" \\ ok""#,
+            r#"set key "\"quoted\"""#,
+            "set key a\"b",
+        ] {
+            let tokens = tokenize(line).unwrap();
+            let encoded = encode_command(&tokens);
+            assert!(encoded.ends_with('\n'));
+            assert_eq!(tokenize(&encoded).unwrap(), tokens, "line: {line:?}");
+        }
+        assert_eq!(quote("foo"), "foo");
+        assert_eq!(quote("a b"), "\"a b\"");
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        assert!(history.expand("!!").is_err());
+
+        history.push("set a 1");
+        history.push("set a 1");
+        history.push("get a");
+        assert_eq!(history.entries, vec!["set a 1", "get a"]);
 
-        let buf = String::from_utf8_lossy(&buf);
-        let buf = buf.replace("\\n", "\n");
+        assert_eq!(history.expand("!!").unwrap(), "get a");
+        assert_eq!(history.expand("!1").unwrap(), "set a 1");
+        assert!(history.expand("!0").is_err());
+        assert!(history.expand("!3").is_err());
+        assert!(history.expand("!x").is_err());
+        assert_eq!(history.expand("get b").unwrap(), "get b");
 
-        println!("{}", buf.strip_suffix("\n").unwrap());
+        // passwords are kept out of the history.
+        assert!(!history.push("auth secret"));
+        assert!(!history.push("\"auth\" secret"));
+        assert!(history.push("get auth"));
+        assert_eq!(history.entries, vec!["set a 1", "get a", "get auth"]);
     }
 }
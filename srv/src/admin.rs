@@ -0,0 +1,180 @@
+//! Admin commands.
+//!
+//! Commands of the admin tool, they write to any `Write` so that
+//! output is streamed and can be tested without spawning the binary.
+
+use std::io::Write;
+
+use crate::store::error::Result;
+use crate::store::storage::Storage;
+use crate::utils::encoding::{base64, hex};
+
+/// Number of keys `list` holds in memory at once.
+const LIST_PAGE_SIZE: usize = 1024;
+
+/// Write all keys in lexicographic order, one per line, hex encoded
+/// if `hex_keys`.
+pub fn list<S, W>(db: &S, out: &mut W, hex_keys: bool) -> Result<()>
+where
+    S: Storage,
+    W: Write,
+{
+    let mut after: Option<Vec<u8>> = None;
+    loop {
+        let keys = db.keys_with_prefix(b"", after.as_deref(), LIST_PAGE_SIZE)?;
+        for key in keys.iter() {
+            if hex_keys {
+                out.write_all(hex(key).as_bytes())?;
+            } else {
+                out.write_all(key)?;
+            }
+            out.write_all(b"\n")?;
+        }
+        if keys.len() < LIST_PAGE_SIZE {
+            return Ok(());
+        }
+        after = keys.into_iter().next_back();
+    }
+}
+
+/// Write all key value pairs as JSON lines, keys and values base64 encoded.
+pub fn dump_jsonl<S, W>(db: &mut S, out: &mut W) -> Result<()>
+where
+    S: Storage,
+    W: Write,
+{
+    db.for_each(&mut |key, value| {
+        writeln!(
+            out,
+            r#"{{"key":"{}","value":"{}"}}"#,
+            base64(key),
+            base64(value)
+        )?;
+        Ok(false)
+    })
+}
+
+/// Write value of the key, nothing if not found.
+/// Return `false` if the key is not found.
+pub fn get<S, W>(db: &mut S, out: &mut W, key: &[u8]) -> Result<bool>
+where
+    S: Storage,
+    W: Write,
+{
+    match db.get(key)? {
+        None => Ok(false),
+        Some(value) => {
+            out.write_all(&value)?;
+            out.write_all(b"\n")?;
+            Ok(true)
+        }
+    }
+}
+
+/// Write store statistics, one `name:value` per line.
+pub fn stats<S, W>(db: &S, out: &mut W) -> Result<()>
+where
+    S: Storage,
+    W: Write,
+{
+    let stats = db.stats();
+    let last_sync_time = stats
+        .last_sync_time
+        .map_or_else(|| "-1".to_owned(), |t| t.to_string());
+
+    writeln!(out, "keys:{}", stats.keys)?;
+    writeln!(out, "segment_count:{}", stats.segment_count)?;
+    writeln!(out, "last_sync_time:{}", last_sync_time)?;
+    writeln!(out, "pending_bytes:{}", stats.pending_bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::error::StoreError;
+    use crate::store::OpenOptions;
+
+    #[test]
+    fn test_admin_commands() {
+        let dir = tempdir::TempDir::new("admin-test.db").unwrap();
+
+        {
+            let mut db = OpenOptions::new().open(dir.path()).unwrap();
+            db.set(b"foo", b"bar").unwrap();
+            db.set([0xffu8], b"hello").unwrap();
+
+            // a writer holds the lock.
+            assert!(matches!(
+                OpenOptions::new().read_only(true).open(dir.path()),
+                Err(StoreError::AlreadyLocked { .. })
+            ));
+            let mut db = OpenOptions::new()
+                .read_only(true)
+                .ignore_lock(true)
+                .open(dir.path())
+                .unwrap();
+            assert!(matches!(db.set(b"a", b"b"), Err(StoreError::ReadOnly)));
+        }
+
+        let mut db = OpenOptions::new().read_only(true).open(dir.path()).unwrap();
+
+        let mut out = Vec::new();
+        list(&db, &mut out, true).unwrap();
+        let mut lines: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, vec!["666f6f", "ff"]);
+
+        let mut out = Vec::new();
+        dump_jsonl(&mut db, &mut out).unwrap();
+        let mut lines: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"key":"/w==","value":"aGVsbG8="}"#,
+                r#"{"key":"Zm9v","value":"YmFy"}"#,
+            ]
+        );
+
+        let mut out = Vec::new();
+        assert!(get(&mut db, &mut out, b"foo").unwrap());
+        assert!(!get(&mut db, &mut out, b"missing").unwrap());
+        assert_eq!(out, b"bar\n");
+
+        let mut out = Vec::new();
+        stats(&db, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("keys:2\n"));
+
+        // nothing is written by a read-only store.
+        assert!(matches!(db.compact(), Err(StoreError::ReadOnly)));
+    }
+
+    #[test]
+    fn test_list_pages() {
+        let dir = tempdir::TempDir::new("admin-test.db").unwrap();
+        let mut db = OpenOptions::new().open(dir.path()).unwrap();
+        let count = LIST_PAGE_SIZE * 2 + 1;
+        for i in 0..count {
+            db.set(format!("{i:05}"), b"").unwrap();
+        }
+
+        let mut out = Vec::new();
+        list(&db, &mut out, false).unwrap();
+        let lines: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), count);
+        assert!(lines.windows(2).all(|w| w[0] < w[1]));
+    }
+}
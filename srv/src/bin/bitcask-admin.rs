@@ -0,0 +1,71 @@
+//! bitcask-admin, inspect a database directory read-only.
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+use srv::admin;
+use srv::store::error::Result;
+use srv::store::OpenOptions;
+
+const USAGE: &str = "usage: bitcask-admin [--force-read-only] <db-path> <command>
+
+commands:
+  list [--hex]          list keys, one per line, optionally hex encoded
+  dump --format jsonl   dump key value pairs as base64 encoded JSON lines
+  get <key>             print value of the key
+  stats                 print store statistics";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn run(args: &[String]) -> Result<bool> {
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let force_read_only = match args.iter().position(|&a| a == "--force-read-only") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    let (path, cmds) = match args.split_first() {
+        Some((path, cmds)) if !cmds.is_empty() => (path, cmds),
+        _ => usage(),
+    };
+
+    let mut db = OpenOptions::new()
+        .read_only(true)
+        .ignore_lock(force_read_only)
+        .open(path)?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let found = match cmds {
+        ["list"] => admin::list(&db, &mut out, false).map(|_| true)?,
+        ["list", "--hex"] => admin::list(&db, &mut out, true).map(|_| true)?,
+        ["dump", "--format", "jsonl"] => admin::dump_jsonl(&mut db, &mut out).map(|_| true)?,
+        ["get", key] => admin::get(&mut db, &mut out, key.as_bytes())?,
+        ["stats"] => admin::stats(&db, &mut out).map(|_| true)?,
+        _ => usage(),
+    };
+
+    out.flush()?;
+    Ok(found)
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
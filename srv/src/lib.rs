@@ -0,0 +1,5 @@
+//! Bitcask storage library.
+
+pub mod admin;
+pub mod store;
+pub mod utils;
@@ -1,67 +1,936 @@
 //! main
-use std::io::{prelude::*, BufReader, Write};
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, prelude::*, BufReader, BufWriter, Write};
 use std::net::TcpStream;
+use std::ops::RangeInclusive;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
-use store::storage::Storage;
-use store::BitCask;
+use log::{error, info, warn};
+use srv::store::error::{Result, StoreError};
+use srv::store::keydir::KeydirKind;
+use srv::store::storage::{EntryMetadata, RecoveryProgress, Storage};
+use srv::store::{BitCask, OpenOptions};
+use srv::utils::chunked::{ChunkedReader, ChunkedWriter};
+use srv::utils::server::Server;
+use srv::utils::threadpool::ThreadPool;
+use srv::utils::time::now_secs;
+use srv::utils::tokenizer::tokenize;
 
-mod store;
-mod utils;
+/// Server command.
+struct Command {
+    name: &'static str,
 
-use crate::store::{error::Result, OpenOptions};
-use crate::utils::server::Server;
-use crate::utils::threadpool::ThreadPool;
+    /// usage of arguments, shown in help.
+    args: &'static str,
 
-fn help(stream: &mut TcpStream) -> Result<()> {
-    stream.write_all("help -- show help\\n".as_bytes())?;
-    stream.write_all("get  -- get key value, by: <key>\\n".as_bytes())?;
-    stream.write_all("set  -- set key value, by: <key> <value>\\n".as_bytes())?;
-    stream.write_all("ls   -- list keys\\n".as_bytes())?;
-    stream.write_all("rm   -- remove key value, by: <key>\\n".as_bytes())?;
-    stream.write_all("exit -- exit command\\n".as_bytes())?;
+    /// number of arguments.
+    arity: RangeInclusive<usize>,
+
+    /// the command modifies the store, denied to read-only connections.
+    write: bool,
+
+    summary: &'static str,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "show help",
+    },
+    Command {
+        name: "commands",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "list commands and their number of arguments",
+    },
+    Command {
+        name: "auth",
+        args: "<password>",
+        arity: 1..=1,
+        write: false,
+        summary: "authenticate the connection",
+    },
+    Command {
+        name: "get",
+        args: "<key>",
+        arity: 1..=1,
+        write: false,
+        summary: "get key value",
+    },
+    Command {
+        name: "exists",
+        args: "<key>",
+        arity: 1..=1,
+        write: false,
+        summary: "1 if key exists, 0 otherwise",
+    },
+    Command {
+        name: "set",
+        args: "<key> <value>",
+        arity: 2..=2,
+        write: true,
+        summary: "set key value",
+    },
+    Command {
+        name: "setex",
+        args: "<key> <seconds> <value>",
+        arity: 3..=3,
+        write: true,
+        summary: "set key value with ttl",
+    },
+    Command {
+        name: "expire",
+        args: "<key> <seconds>",
+        arity: 2..=2,
+        write: true,
+        summary: "set key expiry",
+    },
+    Command {
+        name: "ttl",
+        args: "<key>",
+        arity: 1..=1,
+        write: false,
+        summary: "get key ttl in seconds (-1 no expiry, -2 missing)",
+    },
+    Command {
+        name: "persist",
+        args: "<key>",
+        arity: 1..=1,
+        write: true,
+        summary: "remove key expiry",
+    },
+    Command {
+        name: "incr",
+        args: "<key>",
+        arity: 1..=1,
+        write: true,
+        summary: "increment integer value by one",
+    },
+    Command {
+        name: "incrby",
+        args: "<key> <delta>",
+        arity: 2..=2,
+        write: true,
+        summary: "increment integer value",
+    },
+    Command {
+        name: "decr",
+        args: "<key>",
+        arity: 1..=1,
+        write: true,
+        summary: "decrement integer value by one",
+    },
+    Command {
+        name: "append",
+        args: "<key> <value>",
+        arity: 2..=2,
+        write: true,
+        summary: "append to value",
+    },
+    Command {
+        name: "getchunked",
+        args: "<key>",
+        arity: 1..=1,
+        write: false,
+        summary: "get key value in chunks, $-1 if missing",
+    },
+    Command {
+        name: "setchunked",
+        args: "<key> <size>",
+        arity: 2..=2,
+        write: true,
+        summary: "set key value sent in chunks after the command",
+    },
+    Command {
+        name: "ls",
+        args: "[-l] [<prefix>] [--limit <limit>] [--after <after>]",
+        arity: 0..=6,
+        write: false,
+        summary: "list keys, a page of at most limit keys after the given key, -l with sizes and timestamps",
+    },
+    Command {
+        name: "rm",
+        args: "<key>",
+        arity: 1..=1,
+        write: true,
+        summary: "remove key value, 1 if it existed, 0 otherwise",
+    },
+    Command {
+        name: "purge",
+        args: "",
+        arity: 0..=0,
+        write: true,
+        summary: "remove expired keys, reply how many",
+    },
+    Command {
+        name: "sync",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "flush pending writes to disk, report durability state",
+    },
+    Command {
+        name: "info",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "show operation counters, one name:value per line",
+    },
+    Command {
+        name: "stat",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "show key count and disk usage, one name:value per line",
+    },
+    Command {
+        name: "segments",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "show data files, one per line",
+    },
+    Command {
+        name: "config",
+        args: "resetstat",
+        arity: 1..=1,
+        write: false,
+        summary: "reset operation counters",
+    },
+    Command {
+        name: "slowlog",
+        args: "[get [<count>] | len | reset]",
+        arity: 0..=2,
+        write: false,
+        summary: "show or reset commands slower than the slow log threshold",
+    },
+    Command {
+        name: "merge",
+        args: "",
+        arity: 0..=0,
+        write: true,
+        summary: "compact data files in the background",
+    },
+    Command {
+        name: "select",
+        args: "<name>",
+        arity: 1..=1,
+        write: false,
+        summary: "switch the connection to another database",
+    },
+    Command {
+        name: "databases",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "list databases, one name per line",
+    },
+    Command {
+        name: "ping",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "reply +PONG, without touching the store",
+    },
+    Command {
+        name: "ready",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "show whether the store is loaded, with loading progress or key count",
+    },
+    Command {
+        name: "exit",
+        args: "",
+        arity: 0..=0,
+        write: false,
+        summary: "exit command",
+    },
+];
+
+/// Role of a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    #[default]
+    ReadWrite,
+
+    /// commands modifying the store are denied.
+    ReadOnly,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "readwrite" => Ok(Role::ReadWrite),
+            "readonly" => Ok(Role::ReadOnly),
+            _ => Err(format!("invalid role `{s}`")),
+        }
+    }
+}
+
+/// Name of the database connections start with, unless configured.
+const DEFAULT_DATABASE: &str = "default";
+
+/// Default time a connection may stay silent before it's closed.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Default number of commands kept in the slow log.
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+
+/// Arguments longer than it are truncated in the slow log.
+const SLOWLOG_MAX_ARG_LEN: usize = 64;
+
+/// Server configuration, read from `BITCASK_*` environment variables.
+#[derive(Debug, Clone)]
+struct Config {
+    /// password connections must `auth` with before other commands,
+    /// no authentication if `None`.
+    requirepass: Option<String>,
+
+    /// role of connections.
+    default_role: Role,
+
+    /// commands taking at least this long are logged in full and kept
+    /// in the slow log, `None` disables the slow log.
+    slowlog_threshold: Option<Duration>,
+
+    /// number of commands kept in the slow log.
+    slowlog_max_len: usize,
+
+    /// replace keys in the access log and the slow log.
+    redact_keys: bool,
+
+    /// in-memory index of the store.
+    keydir: KeydirKind,
+
+    /// names and data directories of databases, connections start with
+    /// the first one.
+    databases: Vec<(String, String)>,
+
+    /// connections sending nothing for this long are closed, `None`
+    /// keeps them open.
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            requirepass: None,
+            default_role: Role::default(),
+            slowlog_threshold: None,
+            slowlog_max_len: DEFAULT_SLOWLOG_MAX_LEN,
+            redact_keys: false,
+            keydir: KeydirKind::default(),
+            databases: vec![(DEFAULT_DATABASE.to_owned(), "database".to_owned())],
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+impl Config {
+    fn from_env() -> std::result::Result<Self, String> {
+        let requirepass = env::var("BITCASK_REQUIREPASS")
+            .ok()
+            .filter(|pass| !pass.is_empty());
+        let default_role = match env::var("BITCASK_DEFAULT_ROLE") {
+            Ok(role) => role.parse()?,
+            Err(_) => Role::default(),
+        };
+        let slowlog_threshold = match env::var("BITCASK_SLOWLOG_THRESHOLD_US") {
+            Ok(micros) => {
+                Some(Duration::from_micros(micros.parse().map_err(|_| {
+                    format!("invalid slow log threshold `{micros}`")
+                })?))
+            }
+            Err(_) => None,
+        };
+        let slowlog_max_len = match env::var("BITCASK_SLOWLOG_MAX_LEN") {
+            Ok(len) => len
+                .parse()
+                .map_err(|_| format!("invalid slow log length `{len}`"))?,
+            Err(_) => DEFAULT_SLOWLOG_MAX_LEN,
+        };
+        let redact_keys = env::var("BITCASK_REDACT_KEYS").is_ok_and(|v| v == "1" || v == "true");
+        let keydir = match env::var("BITCASK_KEYDIR") {
+            Ok(kind) => kind.parse()?,
+            Err(_) => KeydirKind::default(),
+        };
+        let databases = match env::var("BITCASK_DATABASES") {
+            Ok(databases) => parse_databases(&databases)?,
+            Err(_) => Config::default().databases,
+        };
+        // zero disables the timeout.
+        let idle_timeout = match env::var("BITCASK_IDLE_TIMEOUT_SECS") {
+            Ok(secs) => Some(Duration::from_secs(
+                secs.parse()
+                    .map_err(|_| format!("invalid idle timeout `{secs}`"))?,
+            ))
+            .filter(|timeout| !timeout.is_zero()),
+            Err(_) => Some(DEFAULT_IDLE_TIMEOUT),
+        };
+
+        Ok(Self {
+            requirepass,
+            default_role,
+            slowlog_threshold,
+            slowlog_max_len,
+            redact_keys,
+            keydir,
+            databases,
+            idle_timeout,
+        })
+    }
+}
+
+/// Parse comma separated `<name>=<path>` databases.
+fn parse_databases(s: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let mut databases: Vec<(String, String)> = Vec::new();
+    for database in s.split(',') {
+        let (name, path) = database
+            .split_once('=')
+            .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+            .ok_or_else(|| format!("invalid database `{database}`"))?;
+        if databases.iter().any(|(n, _)| n == name) {
+            return Err(format!("duplicate database `{name}`"));
+        }
+        databases.push((name.to_owned(), path.to_owned()));
+    }
+    Ok(databases)
+}
+
+/// Command which took at least the slow log threshold.
+#[derive(Debug, Clone)]
+struct SlowEntry {
+    id: u64,
+
+    /// unix timestamp in seconds the command completed.
+    timestamp: u64,
+
+    duration: Duration,
+
+    /// address of the client.
+    peer: String,
+
+    /// command and its arguments, truncated to `SLOWLOG_MAX_ARG_LEN`.
+    args: Vec<String>,
+}
+
+impl SlowEntry {
+    /// Format as `<id> <timestamp> <duration>us <peer> <command...>`.
+    fn format(&self) -> String {
+        format!(
+            "{} {} {}us {} {}",
+            self.id,
+            self.timestamp,
+            self.duration.as_micros(),
+            self.peer,
+            self.args.join(" ")
+        )
+    }
+}
+
+/// Last slow commands, shared by all connections.
+#[derive(Debug, Default)]
+struct SlowLog {
+    /// newest first.
+    entries: VecDeque<SlowEntry>,
+
+    max_len: usize,
+
+    next_id: u64,
+}
+
+impl SlowLog {
+    fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            ..Self::default()
+        }
+    }
+
+    /// Record a slow command, dropping the oldest one if full.
+    fn push(&mut self, duration: Duration, peer: &str, args: Vec<String>) {
+        if self.max_len == 0 {
+            return;
+        }
+        self.entries.truncate(self.max_len - 1);
+        self.entries.push_front(SlowEntry {
+            id: self.next_id,
+            timestamp: now_secs(),
+            duration,
+            peer: peer.to_owned(),
+            args,
+        });
+        self.next_id += 1;
+    }
+}
+
+fn lookup_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|cmd| cmd.name == name)
+}
+
+/// Return command and arguments fit for logs: the password of `auth`
+/// is hidden, the key too if `redact_keys`, long arguments are cut.
+fn loggable_args(cmds: &[&str], redact_keys: bool) -> Vec<String> {
+    let cmd = lookup_command(cmds[0]);
+    let hidden = |i: usize| match cmd {
+        Some(cmd) if cmd.name == "auth" => i > 0,
+        Some(cmd) => redact_keys && i == 1 && cmd.args.starts_with("<key>"),
+        None => false,
+    };
+
+    cmds.iter()
+        .enumerate()
+        .map(|(i, arg)| match arg {
+            _ if hidden(i) => "<redacted>".to_owned(),
+            arg if arg.len() > SLOWLOG_MAX_ARG_LEN => {
+                let mut end = SLOWLOG_MAX_ARG_LEN;
+                while !arg.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("{}...", &arg[..end])
+            }
+            arg => arg.to_string(),
+        })
+        .collect()
+}
+
+/// Write an access log line for a command which took `duration` and
+/// completed with `status`, and record it in the slow log if slow.
+fn log_command(
+    config: &Config,
+    slowlog: &Mutex<SlowLog>,
+    peer: &str,
+    cmds: &[&str],
+    duration: Duration,
+    status: &str,
+) {
+    let args = loggable_args(cmds, config.redact_keys);
+    let key = match lookup_command(cmds[0]) {
+        Some(cmd) if cmd.args.starts_with("<key>") && args.len() > 1 => args[1].as_str(),
+        _ => "-",
+    };
+    info!(
+        "{} {} {} {}us {}",
+        peer,
+        cmds[0],
+        key,
+        duration.as_micros(),
+        status
+    );
+
+    // keep the slow log itself out of it.
+    let slow = config.slowlog_threshold.is_some_and(|t| duration >= t);
+    if !slow || cmds[0] == "slowlog" {
+        return;
+    }
+    warn!(
+        "slow command from {} took {}us: {}",
+        peer,
+        duration.as_micros(),
+        args.join(" ")
+    );
+    slowlog.lock().unwrap().push(duration, peer, args);
+}
+
+/// Reply the slow log, newest first, its length, or reset it.
+fn slowlog_command(stream: &mut impl Write, slowlog: &Mutex<SlowLog>, args: &[&str]) -> Result<()> {
+    let mut slowlog = slowlog.lock().unwrap();
+    match args {
+        [] | ["get"] => write_lines(stream, slowlog.entries.iter().map(SlowEntry::format)),
+        ["get", count] => {
+            let Ok(count) = count.parse::<usize>() else {
+                stream.write_all("-ERR value is not an integer".as_bytes())?;
+                return Ok(());
+            };
+            write_lines(
+                stream,
+                slowlog.entries.iter().take(count).map(SlowEntry::format),
+            )
+        }
+        ["len"] => {
+            stream.write_all(slowlog.entries.len().to_string().as_bytes())?;
+            Ok(())
+        }
+        ["reset"] => {
+            slowlog.entries.clear();
+            stream.write_all("OK".as_bytes())?;
+            Ok(())
+        }
+        [sub, ..] => {
+            stream.write_all(format!("-ERR unknown slowlog subcommand '{sub}'").as_bytes())?;
+            Ok(())
+        }
+    }
+}
+
+/// Write a multi-line reply, a `*<count>` line followed by the lines.
+/// Single values starting with `*` are sent with one more `*`.
+fn write_lines<I>(stream: &mut impl Write, lines: I) -> Result<()>
+where
+    I: ExactSizeIterator,
+    I::Item: AsRef<[u8]>,
+{
+    stream.write_all(format!("*{}", lines.len()).as_bytes())?;
+    for line in lines {
+        stream.write_all(b"\n")?;
+        stream.write_all(line.as_ref())?;
+    }
     Ok(())
 }
 
-fn process_db_command(stream: &mut TcpStream, handle: &mut BitCask, cmds: &[&str]) -> Result<()> {
+/// Split `ls` arguments into prefix, limit and after key. Limit and
+/// after key are given either as `--limit` and `--after` flags or
+/// positionally after the prefix, `None` if arguments don't parse.
+fn parse_ls_args<'a>(args: &[&'a str]) -> Option<(&'a str, Option<&'a str>, Option<&'a str>)> {
+    let (mut limit, mut after) = (None, None);
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--limit" => limit = Some(*args.next()?),
+            "--after" => after = Some(*args.next()?),
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional[..] {
+        [] => Some(("", limit, after)),
+        [prefix] => Some((prefix, limit, after)),
+        [prefix, l] if limit.is_none() => Some((prefix, Some(l), after)),
+        [prefix, l, a] if limit.is_none() && after.is_none() => Some((prefix, Some(l), Some(a))),
+        _ => None,
+    }
+}
+
+/// Reply key, entry size and timestamp of keys in aligned columns,
+/// read from keydir only. With `limit`, reply a page as `ls` does.
+fn ls_long(
+    stream: &mut impl Write,
+    handle: &BitCask,
+    prefix: &[u8],
+    after: Option<&[u8]>,
+    limit: Option<usize>,
+) -> Result<()> {
+    let Some(limit) = limit else {
+        let entries = match (prefix, after) {
+            (b"", None) => {
+                let mut entries = handle.entries_metadata()?;
+                entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+                entries
+            }
+            _ => handle.entries_metadata_with_prefix(prefix, after, usize::MAX)?,
+        };
+        return write_lines(stream, format_entries(&entries).iter());
+    };
+
+    // one more entry tells whether there is a next page.
+    let mut entries =
+        handle.entries_metadata_with_prefix(prefix, after, limit.saturating_add(1))?;
+    let token = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|e| e.key.clone()).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    let mut lines = format_entries(&entries);
+    lines.insert(0, String::from_utf8_lossy(&token).into_owned());
+    write_lines(stream, lines.iter())
+}
+
+/// Format entries as lines of key, size and timestamp, padded so
+/// that columns align.
+fn format_entries(entries: &[EntryMetadata]) -> Vec<String> {
+    let keys: Vec<_> = entries
+        .iter()
+        .map(|e| String::from_utf8_lossy(&e.key))
+        .collect();
+    let key_width = keys.iter().map(|k| k.chars().count()).max();
+    let size_width = entries.iter().map(|e| e.size.to_string().len()).max();
+    let (key_width, size_width) = (key_width.unwrap_or(0), size_width.unwrap_or(0));
+
+    keys.iter()
+        .zip(entries)
+        .map(|(key, e)| {
+            format!(
+                "{key:<key_width$}  {:>size_width$}  {}",
+                e.size, e.timestamp
+            )
+        })
+        .collect()
+}
+
+fn help(stream: &mut impl Write) -> Result<()> {
+    let width = COMMANDS
+        .iter()
+        .map(|cmd| cmd.name.len() + cmd.args.len() + 1)
+        .max()
+        .unwrap_or_default();
+
+    write_lines(
+        stream,
+        COMMANDS.iter().map(|cmd| {
+            let usage = format!("{} {}", cmd.name, cmd.args);
+            format!("{:<width$} -- {}", usage, cmd.summary)
+        }),
+    )
+}
+
+/// Write one `<name> <min args> <max args>` line per command,
+/// for completion in clients.
+fn commands(stream: &mut impl Write) -> Result<()> {
+    write_lines(
+        stream,
+        COMMANDS
+            .iter()
+            .map(|cmd| format!("{} {} {}", cmd.name, cmd.arity.start(), cmd.arity.end())),
+    )
+}
+
+fn process_db_command(stream: &mut impl Write, handle: &mut BitCask, cmds: &[&str]) -> Result<()> {
     match cmds[0] {
         "set" => {
-            if cmds.len() != 3 {
-                return Ok(());
-            }
             let key = cmds[1].as_bytes().to_vec();
             let value = cmds[2].as_bytes().to_vec();
             handle.set(key, value)?;
         }
-        "get" => {
-            if cmds.len() != 2 {
+        "setex" => {
+            let key = cmds[1].as_bytes().to_vec();
+            let Ok(seconds) = cmds[2].parse::<u64>() else {
+                stream.write_all("-ERR value is not an integer".as_bytes())?;
                 return Ok(());
+            };
+            let value = cmds[3].as_bytes().to_vec();
+            match handle.set_with_expiry(key, value, Duration::from_secs(seconds)) {
+                Ok(()) => {}
+                Err(e @ StoreError::ExpiryOutOfRange) => {
+                    stream.write_all(format!("-ERR {e}").as_bytes())?
+                }
+                Err(e) => return Err(e),
             }
+        }
+        "expire" => {
+            let key = cmds[1].as_bytes().to_vec();
+            let Ok(seconds) = cmds[2].parse::<u64>() else {
+                stream.write_all("-ERR value is not an integer".as_bytes())?;
+                return Ok(());
+            };
+            match handle.expire(&key, Duration::from_secs(seconds)) {
+                Ok(found) => stream.write_all(if found { b"1" } else { b"0" })?,
+                Err(e @ StoreError::ExpiryOutOfRange) => {
+                    stream.write_all(format!("-ERR {e}").as_bytes())?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        "ttl" => {
+            let key = cmds[1].as_bytes().to_vec();
+            let ttl: i64 = if !handle.contains_key(&key) {
+                -2
+            } else {
+                match handle.ttl(&key) {
+                    None => -1,
+                    // round to the nearest second.
+                    Some(d) => ((d.as_millis() + 500) / 1000) as i64,
+                }
+            };
+            stream.write_all(ttl.to_string().as_bytes())?;
+        }
+        "persist" => {
+            let key = cmds[1].as_bytes().to_vec();
+            let found = handle.persist(&key)?;
+            stream.write_all(if found { b"1" } else { b"0" })?;
+        }
+        "incr" | "incrby" | "decr" => {
+            let delta = match cmds[0] {
+                "incr" => 1,
+                "decr" => -1,
+                _ => match cmds[2].parse::<i64>() {
+                    Ok(delta) => delta,
+                    Err(_) => {
+                        stream.write_all("-ERR value is not an integer".as_bytes())?;
+                        return Ok(());
+                    }
+                },
+            };
+            let key = cmds[1].as_bytes().to_vec();
+            match handle.increment(&key, delta) {
+                Ok(value) => stream.write_all(value.to_string().as_bytes())?,
+                Err(e @ (StoreError::NotAnInteger | StoreError::IntegerOverflow)) => {
+                    stream.write_all(format!("-ERR {e}").as_bytes())?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        "append" => {
+            let key = cmds[1].as_bytes().to_vec();
+            match handle.append(&key, cmds[2].as_bytes()) {
+                Ok(len) => stream.write_all(len.to_string().as_bytes())?,
+                Err(e @ StoreError::ValueIsTooLarge) => {
+                    stream.write_all(format!("-ERR {e}").as_bytes())?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        "get" => {
             let key = cmds[1].as_bytes().to_vec();
             match handle.get(&key)? {
                 None => {}
                 Some(v) => {
+                    // one more `*` tells a value from a multi-line reply.
+                    if v.starts_with(b"*") {
+                        stream.write_all(b"*")?;
+                    }
                     stream.write_all(&v)?;
                 }
             };
         }
         "ls" => {
-            let keys = handle.keys()?;
-            for key in keys.iter() {
-                stream.write_all(key)?;
-                stream.write_all("\\n".as_bytes())?;
+            let (long, args) = match &cmds[1..] {
+                ["-l", args @ ..] => (true, args),
+                args => (false, args),
+            };
+            let Some((prefix, limit, after)) = parse_ls_args(args) else {
+                stream.write_all("-ERR syntax error".as_bytes())?;
+                return Ok(());
+            };
+            let after = after.map(str::as_bytes);
+            // an empty page would end listing with keys left.
+            let limit = match limit.map(str::parse::<usize>).transpose() {
+                Ok(Some(0)) => {
+                    stream.write_all("-ERR limit must be positive".as_bytes())?;
+                    return Ok(());
+                }
+                Ok(limit) => limit,
+                Err(_) => {
+                    stream.write_all("-ERR value is not an integer".as_bytes())?;
+                    return Ok(());
+                }
+            };
+
+            if long {
+                return ls_long(stream, handle, prefix.as_bytes(), after, limit);
             }
+
+            let Some(limit) = limit else {
+                let keys = match (prefix, after) {
+                    ("", None) => handle.keys_sorted()?,
+                    _ => handle.keys_with_prefix(prefix.as_bytes(), after, usize::MAX)?,
+                };
+                write_lines(stream, keys.iter())?;
+                return Ok(());
+            };
+
+            // one more key tells whether there is a next page.
+            let mut keys =
+                handle.keys_with_prefix(prefix.as_bytes(), after, limit.saturating_add(1))?;
+            let token = if keys.len() > limit {
+                keys.truncate(limit);
+                keys.last().cloned().unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+
+            // the continuation token goes first, empty on the last page.
+            keys.insert(0, token);
+            write_lines(stream, keys.iter())?;
+        }
+        "exists" => {
+            let found = handle.contains_key(cmds[1].as_bytes());
+            stream.write_all(if found { b"1" } else { b"0" })?;
         }
         "rm" => {
-            if cmds.len() != 2 {
-                return Ok(());
-            }
-            let key = cmds[1].as_bytes().to_vec();
-            handle.delete(&key)?;
+            let found = handle.delete(cmds[1].as_bytes())?;
+            stream.write_all(if found { b"1" } else { b"0" })?;
+        }
+        "purge" => {
+            let purged = handle.purge_expired()?;
+            stream.write_all(purged.to_string().as_bytes())?;
+        }
+        "sync" => {
+            handle.sync()?;
+            write_durability_state(stream, handle)?;
         }
+        "info" => {
+            let report = handle.metrics().report();
+            write_lines(
+                stream,
+                report.iter().map(|(name, value)| format!("{name}:{value}")),
+            )?;
+        }
+        "stat" => {
+            let stats = handle.stats();
+            write_lines(
+                stream,
+                [
+                    ("keys", stats.keys),
+                    ("data_files", stats.segment_count),
+                    ("total_bytes", stats.total_bytes),
+                    ("reclaimable_bytes", stats.reclaimable_bytes),
+                ]
+                .iter()
+                .map(|(name, value)| format!("{name}:{value}")),
+            )?;
+        }
+        "segments" => {
+            let segments = handle.segments()?;
+            write_lines(
+                stream,
+                segments.iter().map(|s| {
+                    format!(
+                        "file_id:{} size_bytes:{} live_keys:{} live_bytes:{} active:{} hint:{} path:{}",
+                        s.file_id,
+                        s.size_bytes,
+                        s.live_keys,
+                        s.live_bytes,
+                        s.is_active as u8,
+                        s.has_hint as u8,
+                        s.path.display()
+                    )
+                }),
+            )?;
+        }
+        "config" => match cmds[1] {
+            "resetstat" => {
+                handle.stats_reset();
+                stream.write_all("OK".as_bytes())?;
+            }
+            sub => {
+                stream.write_all(format!("-ERR unknown config subcommand '{sub}'").as_bytes())?;
+            }
+        },
         "merge" => {
             info!("Command to do compact ...");
-            handle.compact()?;
+            // the reply doesn't wait for the store lock, the report is
+            // logged once compaction is done.
+            let done = handle.spawn_compaction()?;
+            thread::spawn(move || {
+                if let Ok(Ok(report)) = done.recv() {
+                    info!(
+                        "Compaction done: input_bytes:{} output_bytes:{} entries_copied:{} entries_dropped:{} segments_removed:{} segments_created:{} duration:{:?}",
+                        report.input_bytes,
+                        report.output_bytes,
+                        report.entries_copied,
+                        report.entries_dropped,
+                        report.segments_removed,
+                        report.segments_created,
+                        report.duration
+                    );
+                }
+            });
+            stream.write_all("+scheduled".as_bytes())?;
         }
         &_ => todo!(),
     };
@@ -69,47 +938,361 @@ fn process_db_command(stream: &mut TcpStream, handle: &mut BitCask, cmds: &[&str
     Ok(())
 }
 
+/// Reply value of the key in chunks read from the data file,
+/// `$-1` if the key is missing.
+fn get_chunked(stream: &mut impl Write, handle: &mut BitCask, key: &str) -> Result<()> {
+    let mut w = ChunkedWriter::new(&mut *stream);
+    match handle.get_to(key.as_bytes(), &mut w)? {
+        Some(_) => {
+            w.finish()?;
+        }
+        None => {
+            drop(w);
+            stream.write_all(b"$-1\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Store value sent in chunks after the command, it's copied to the
+/// data file as it arrives.
+///
+/// Return `false` if the chunked value is malformed, the connection
+/// can't be used any more.
+fn set_chunked<R: BufRead>(
+    stream: &mut impl Write,
+    reader: &mut R,
+    handle: &mut BitCask,
+    cmds: &[&str],
+) -> Result<bool> {
+    let Ok(size) = cmds[2].parse::<u64>() else {
+        ChunkedReader::new(reader).drain()?;
+        stream.write_all("-ERR value is not an integer".as_bytes())?;
+        return Ok(true);
+    };
+
+    let mut body = ChunkedReader::with_len(reader, size);
+    match handle.set_from_reader(cmds[1].as_bytes(), size, &mut body) {
+        Ok(()) => Ok(true),
+        Err(
+            e @ (StoreError::KeyIsTooLarge
+            | StoreError::ValueIsTooLarge
+            | StoreError::KeyLimitExceeded
+            | StoreError::DiskLimitExceeded),
+        ) => {
+            body.drain()?;
+            stream.write_all(format!("-ERR {e}").as_bytes())?;
+            Ok(true)
+        }
+        Err(StoreError::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            stream.write_all(format!("-ERR invalid chunked value: {e}").as_bytes())?;
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn write_durability_state(stream: &mut impl Write, handle: &BitCask) -> Result<()> {
+    let last_sync_time = handle
+        .last_sync_time()
+        .map_or_else(|| "-1".to_owned(), |t| t.to_string());
+
+    write_lines(
+        stream,
+        [
+            format!("last_sync_time:{}", last_sync_time),
+            format!("pending_bytes:{}", handle.pending_bytes()),
+        ]
+        .iter(),
+    )
+}
+
 fn empty() {}
 
-fn handle_connection(mut stream: TcpStream, mut bitcask: BitCask) -> Result<()> {
+/// Stores opened in the background, connections are served while
+/// their keydirs are built but only commands not touching a store
+/// succeed.
+#[derive(Default)]
+struct Startup {
+    /// databases by name, the first one is the default.
+    databases: OnceLock<Vec<(String, BitCask)>>,
+
+    /// progress of building the keydir.
+    progress: Mutex<RecoveryProgress>,
+}
+
+/// Reply `status:ready` with the key count of all databases once
+/// they're loaded, `status:loading` with the progress of building the
+/// keydir otherwise. Connections not `authenticated` get the status only.
+fn ready(stream: &mut impl Write, startup: &Startup, authenticated: bool) -> Result<()> {
+    let mut lines = match startup.databases.get() {
+        Some(databases) => {
+            let (mut keys, mut data_files) = (0, 0);
+            for (_, handle) in databases {
+                let stats = handle.stats();
+                keys += stats.keys;
+                data_files += stats.segment_count;
+            }
+            vec![
+                "status:ready".to_owned(),
+                format!("keys:{keys}"),
+                format!("data_files:{data_files}"),
+            ]
+        }
+        None => {
+            let progress = startup.progress.lock().unwrap().clone();
+            vec![
+                "status:loading".to_owned(),
+                format!("files_processed:{}", progress.files_processed),
+                format!("files_total:{}", progress.files_total),
+                format!("keys_loaded:{}", progress.keys_loaded),
+            ]
+        }
+    };
+    if !authenticated {
+        lines.truncate(1);
+    }
+    write_lines(stream, lines.iter())
+}
+
+/// Reply side of a connection, a reply is buffered while it's written
+/// and sent at once by `end`, not in many small packets.
+struct Response<W: Write> {
+    out: BufWriter<W>,
+}
+
+impl<W: Write> Response<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out: BufWriter::new(out),
+        }
+    }
+
+    /// Send the reply written so far.
+    fn end(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Write for Response<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    startup: &Startup,
+    config: &Config,
+    slowlog: &Mutex<SlowLog>,
+) -> Result<()> {
+    // reads of the connection time out once idle, the clone below
+    // shares the socket and the timeout.
+    stream.set_read_timeout(config.idle_timeout)?;
+    // replies are sent whole, don't hold them back waiting for acks.
+    stream.set_nodelay(true)?;
+    // one reader for the whole connection, it may buffer ahead.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut authenticated = config.requirepass.is_none();
+    let peer = stream.peer_addr()?.to_string();
+    let mut stream = Response::new(stream);
+    // index of the selected database.
+    let mut selected = 0;
+    let mut bitcask: Option<BitCask> = None;
     loop {
-        let mut buf_reader = BufReader::new(&mut stream);
         let mut cmd = String::new();
 
-        if buf_reader.read_line(&mut cmd)? == 0 {
-            break;
+        match reader.read_line(&mut cmd) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {
+                info!("close idle connection from {peer}");
+                break;
+            }
+            Err(e) => return Err(e.into()),
         }
 
         if cmd.is_empty() {
             stream.write_all("\n".as_bytes())?;
+            stream.end()?;
             continue;
         }
 
-        let cmd = cmd.strip_suffix('\n').unwrap();
-        let cmds: Vec<&str> = cmd.split(' ').collect();
-
-        match cmds[0] {
-            "exit" => {
-                break;
-            }
-            "help" => {
-                help(&mut stream)?;
-            }
-            "set" | "get" | "ls" | "rm" | "merge" => {
-                process_db_command(&mut stream, &mut bitcask, &cmds)?;
+        let tokens = match tokenize(&cmd) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                stream.write_all(format!("-ERR {e}\n").as_bytes())?;
+                stream.end()?;
+                continue;
             }
+        };
+        let cmds: Vec<&str> = tokens.iter().map(String::as_str).collect();
+
+        if bitcask.is_none() {
+            bitcask = startup
+                .databases
+                .get()
+                .map(|databases| databases[selected].1.clone());
+        }
+
+        let start = Instant::now();
+        let mut status = "ok";
+        // chunked replies end with their own newline.
+        let mut newline = true;
+        match cmds.first().copied().unwrap_or_default() {
             "" => empty(),
-            _ => {
-                stream.write_all(cmds.join("-").as_bytes())?;
-            }
+            name => match lookup_command(name) {
+                None => {
+                    status = "unknown";
+                    stream.write_all(cmds.join("-").as_bytes())?;
+                }
+                Some(cmd) if !cmd.arity.contains(&(cmds.len() - 1)) => {
+                    status = "err";
+                    stream.write_all(
+                        format!("-ERR wrong number of arguments for '{}'", name).as_bytes(),
+                    )?;
+                }
+                Some(cmd)
+                    if !authenticated
+                        && !matches!(cmd.name, "auth" | "help" | "exit" | "ping" | "ready") =>
+                {
+                    status = "denied";
+                    if cmd.name == "setchunked" {
+                        ChunkedReader::new(&mut reader).drain()?;
+                    }
+                    stream.write_all("-ERR authentication required".as_bytes())?;
+                }
+                Some(cmd) if cmd.write && config.default_role == Role::ReadOnly => {
+                    status = "denied";
+                    if cmd.name == "setchunked" {
+                        ChunkedReader::new(&mut reader).drain()?;
+                    }
+                    stream.write_all("-ERR readonly".as_bytes())?;
+                }
+                Some(cmd) => match cmd.name {
+                    "exit" => {
+                        break;
+                    }
+                    "auth" => match &config.requirepass {
+                        None => stream.write_all("-ERR no password is set".as_bytes())?,
+                        Some(pass) if pass == cmds[1] => {
+                            authenticated = true;
+                            stream.write_all("OK".as_bytes())?;
+                        }
+                        Some(_) => {
+                            status = "denied";
+                            stream.write_all("-ERR invalid password".as_bytes())?;
+                        }
+                    },
+                    "help" => {
+                        help(&mut stream)?;
+                    }
+                    "commands" => {
+                        commands(&mut stream)?;
+                    }
+                    "slowlog" => {
+                        slowlog_command(&mut stream, slowlog, &cmds[1..])?;
+                    }
+                    "ping" => {
+                        stream.write_all("+PONG".as_bytes())?;
+                    }
+                    "ready" => {
+                        ready(&mut stream, startup, authenticated)?;
+                    }
+                    "select" | "databases" if startup.databases.get().is_none() => {
+                        status = "err";
+                        stream.write_all("-LOADING store is loading".as_bytes())?;
+                    }
+                    "select" => {
+                        let databases = startup.databases.get().unwrap();
+                        match databases.iter().position(|(name, _)| name == cmds[1]) {
+                            Some(i) => {
+                                selected = i;
+                                bitcask = Some(databases[i].1.clone());
+                                stream.write_all("OK".as_bytes())?;
+                            }
+                            None => {
+                                status = "err";
+                                stream.write_all(
+                                    format!("-ERR unknown database '{}'", cmds[1]).as_bytes(),
+                                )?;
+                            }
+                        }
+                    }
+                    "databases" => {
+                        let databases = startup.databases.get().unwrap();
+                        write_lines(&mut stream, databases.iter().map(|(name, _)| name))?;
+                    }
+                    name => match bitcask.as_mut() {
+                        None => {
+                            status = "err";
+                            if name == "setchunked" {
+                                ChunkedReader::new(&mut reader).drain()?;
+                            }
+                            stream.write_all("-LOADING store is loading".as_bytes())?;
+                        }
+                        Some(bitcask) => match name {
+                            "getchunked" => {
+                                get_chunked(&mut stream, bitcask, cmds[1])?;
+                                newline = false;
+                            }
+                            "setchunked" => {
+                                if !set_chunked(&mut stream, &mut reader, bitcask, &cmds)? {
+                                    stream.write_all("\n".as_bytes())?;
+                                    break;
+                                }
+                            }
+                            _ => match process_db_command(&mut stream, bitcask, &cmds) {
+                                Ok(()) => {}
+                                Err(
+                                    e @ (StoreError::KeyLimitExceeded
+                                    | StoreError::DiskLimitExceeded
+                                    | StoreError::OutOfSpace
+                                    | StoreError::CompactionInProgress),
+                                ) => {
+                                    status = "err";
+                                    stream.write_all(format!("-ERR {e}").as_bytes())?;
+                                }
+                                Err(e) => return Err(e),
+                            },
+                        },
+                    },
+                },
+            },
         };
 
-        stream.write_all("\n".as_bytes())?;
+        if newline {
+            stream.write_all("\n".as_bytes())?;
+        }
+        stream.end()?;
+        if !cmds.is_empty() {
+            log_command(config, slowlog, &peer, &cmds, start.elapsed(), status);
+        }
     }
+    // send the reply of a command closing the connection.
+    stream.end()?;
 
     Ok(())
 }
 
+/// Return `true` if `e` is a read timing out, whose kind depends on
+/// the platform.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
 fn main() -> Result<()> {
     // Init log config from env.
     env_logger::init();
@@ -121,11 +1304,41 @@ fn main() -> Result<()> {
 
     let pool = ThreadPool::new(4);
 
-    let path = "database";
-    let bitcask = OpenOptions::new()
-        // .max_log_file_size(100)
-        .open(path)
-        .unwrap();
+    let config = Arc::new(Config::from_env().map_err(io::Error::other)?);
+    if config.requirepass.is_some() {
+        info!("Connections must authenticate");
+    }
+
+    let slowlog = Arc::new(Mutex::new(SlowLog::new(config.slowlog_max_len)));
+
+    // build keydirs in the background, one database after the other,
+    // `ping` and `ready` are served meanwhile.
+    let startup = Arc::new(Startup::default());
+    let loading = startup.clone();
+    let keydir = config.keydir;
+    let paths = config.databases.clone();
+    thread::spawn(move || {
+        let mut databases = Vec::with_capacity(paths.len());
+        for (name, path) in paths {
+            let progress = loading.clone();
+            let opened = OpenOptions::new()
+                // .max_log_file_size(100)
+                .keydir(keydir)
+                .recovery_progress(move |p| *progress.progress.lock().unwrap() = p.clone())
+                .open(&path);
+            match opened {
+                Ok(bitcask) => {
+                    info!("Store {name} at {path} loaded");
+                    databases.push((name, bitcask));
+                }
+                Err(e) => {
+                    error!("Failed to open store {name} at {path}: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+        let _ = loading.databases.set(databases);
+    });
 
     server.running(move |stream: TcpStream| {
         info!(
@@ -133,12 +1346,834 @@ fn main() -> Result<()> {
             stream.peer_addr().unwrap()
         );
 
-        let handle = bitcask.clone();
+        let startup = startup.clone();
+        let config = config.clone();
+        let slowlog = slowlog.clone();
 
         pool.execute(move || {
-            handle_connection(stream, handle).unwrap_or_else(|e| error!("{:?}", e));
+            match handle_connection(stream, &startup, &config, &slowlog) {
+                Ok(()) => {}
+                // e.g. in the middle of a chunked value.
+                Err(StoreError::Io(e)) if is_timeout(&e) => info!("close idle connection"),
+                Err(e) => error!("{:?}", e),
+            }
         });
     })?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use srv::utils::chunked::CHUNK_SIZE;
+
+    use super::*;
+
+    /// Serve a single connection with `handle_connection`, return the client side.
+    fn connect(bitcask: BitCask) -> TcpStream {
+        connect_with_config(bitcask, Config::default())
+    }
+
+    fn connect_with_config(bitcask: BitCask, config: Config) -> TcpStream {
+        let startup = Startup::default();
+        let _ = startup
+            .databases
+            .set(vec![(DEFAULT_DATABASE.to_owned(), bitcask)]);
+        connect_startup(Arc::new(startup), config)
+    }
+
+    fn connect_startup(startup: Arc<Startup>, config: Config) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let slowlog = Mutex::new(SlowLog::new(config.slowlog_max_len));
+            handle_connection(stream, &startup, &config, &slowlog).unwrap();
+        });
+
+        TcpStream::connect(addr).unwrap()
+    }
+
+    /// Send a command and return the reply line without the trailing newline.
+    fn request(stream: &mut TcpStream, cmd: &str) -> String {
+        stream.write_all(format!("{cmd}\n").as_bytes()).unwrap();
+
+        let mut reply = String::new();
+        BufReader::new(&*stream).read_line(&mut reply).unwrap();
+        reply.strip_suffix('\n').unwrap().to_owned()
+    }
+
+    /// Send a command and return lines of the multi-line reply.
+    fn request_lines(stream: &mut TcpStream, cmd: &str) -> Vec<String> {
+        stream.write_all(format!("{cmd}\n").as_bytes()).unwrap();
+
+        // read the whole reply with one reader, it may buffer ahead.
+        let mut reader = BufReader::new(&*stream);
+        let mut read_line = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.strip_suffix('\n').unwrap().to_owned()
+        };
+
+        let count: usize = read_line().strip_prefix('*').unwrap().parse().unwrap();
+        (0..count).map(|_| read_line()).collect()
+    }
+
+    #[test]
+    fn test_ttl_commands() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "ttl missing"), "-2");
+
+        request(&mut stream, "set forever value");
+        assert_eq!(request(&mut stream, "ttl forever"), "-1");
+
+        request(&mut stream, "setex short 1 value");
+        request(&mut stream, "setex unread 1 value");
+        assert_eq!(request(&mut stream, "ttl short"), "1");
+        assert_eq!(request(&mut stream, "get short"), "value");
+
+        assert_eq!(request(&mut stream, "expire forever 100"), "1");
+        assert_eq!(request(&mut stream, "ttl forever"), "100");
+        assert_eq!(request(&mut stream, "persist forever"), "1");
+        assert_eq!(request(&mut stream, "ttl forever"), "-1");
+        assert_eq!(request(&mut stream, "persist forever"), "0");
+
+        // expire times beyond the range of timestamps are refused.
+        assert_eq!(
+            request(&mut stream, &format!("setex huge {} value", u64::MAX)),
+            "-ERR expire time is out of range"
+        );
+        assert_eq!(
+            request(&mut stream, &format!("expire forever {}", u64::MAX / 1000)),
+            "-ERR expire time is out of range"
+        );
+        assert_eq!(request(&mut stream, "ttl huge"), "-2");
+        assert_eq!(request(&mut stream, "ttl forever"), "-1");
+
+        thread::sleep(Duration::from_millis(1100));
+
+        assert_eq!(request(&mut stream, "get short"), "");
+        assert_eq!(request(&mut stream, "ttl short"), "-2");
+        assert_eq!(request(&mut stream, "expire short 10"), "0");
+        assert_eq!(request(&mut stream, "get forever"), "value");
+
+        // `unread` was never read since it expired.
+        assert_eq!(request(&mut stream, "purge"), "1");
+        assert_eq!(request(&mut stream, "purge"), "0");
+        assert_eq!(request(&mut stream, "exists unread"), "0");
+    }
+
+    #[test]
+    fn test_incr_commands() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "incr counter"), "1");
+        assert_eq!(request(&mut stream, "incrby counter 41"), "42");
+        assert_eq!(request(&mut stream, "decr counter"), "41");
+        assert_eq!(
+            request(&mut stream, "incrby counter ten"),
+            "-ERR value is not an integer"
+        );
+
+        request(&mut stream, "set name bitcask");
+        assert_eq!(
+            request(&mut stream, "incr name"),
+            "-ERR value is not an integer"
+        );
+    }
+
+    #[test]
+    fn test_append_command() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "append log foo"), "3");
+        assert_eq!(request(&mut stream, "append log bar"), "6");
+        assert_eq!(request(&mut stream, "get log"), "foobar");
+    }
+
+    #[test]
+    fn test_crlf_and_quoted_values() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "set  foo   bar\r"), "");
+        assert_eq!(request(&mut stream, "get foo\r"), "bar");
+
+        assert_eq!(request(&mut stream, "set greeting \"hello world\"\r"), "");
+        assert_eq!(request(&mut stream, "get greeting"), "hello world");
+
+        assert_eq!(
+            request(&mut stream, "set broken \"value"),
+            "-ERR unbalanced quotes in request"
+        );
+        assert_eq!(request(&mut stream, "get broken"), "");
+
+        // a value isn't taken for a multi-line reply.
+        assert_eq!(request(&mut stream, "set star *2"), "");
+        assert_eq!(request(&mut stream, "get star"), "**2");
+        assert_eq!(request(&mut stream, "get greeting"), "hello world");
+    }
+
+    #[test]
+    fn test_sync_command() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().sync(false).open(dir.path()).unwrap());
+
+        request(&mut stream, "set hello world");
+
+        let reply = request_lines(&mut stream, "sync");
+        let [last_sync_time, pending_bytes] = reply.as_slice() else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        assert!(
+            last_sync_time
+                .strip_prefix("last_sync_time:")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+                > 0
+        );
+        assert_eq!(pending_bytes, "pending_bytes:0");
+    }
+
+    #[test]
+    fn test_command_registry() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        let help = request_lines(&mut stream, "help");
+        assert_eq!(help.len(), COMMANDS.len());
+        assert!(help[0].starts_with("help"));
+
+        let commands = request_lines(&mut stream, "commands");
+        assert_eq!(commands.len(), COMMANDS.len());
+        assert!(commands.contains(&"setex 3 3".to_owned()));
+        assert!(commands.contains(&"ls 0 6".to_owned()));
+
+        for cmd in COMMANDS {
+            let (min, max) = (*cmd.arity.start(), *cmd.arity.end());
+            let mut line = vec![cmd.name];
+            line.extend(std::iter::repeat_n("1", max + 1));
+            assert_eq!(
+                request(&mut stream, &line.join(" ")),
+                format!("-ERR wrong number of arguments for '{}'", cmd.name)
+            );
+
+            // `exit` closes the connection, the rest reply a single line.
+            if min > 0 {
+                line.truncate(min);
+                assert_eq!(
+                    request(&mut stream, &line.join(" ")),
+                    format!("-ERR wrong number of arguments for '{}'", cmd.name)
+                );
+
+                line.push("1");
+                let mut req = line.join(" ");
+                if cmd.name == "setchunked" {
+                    // the value follows the command.
+                    req.push_str("\n$1\n1$0");
+                }
+                let reply = request(&mut stream, &req);
+                assert!(!reply.starts_with("-ERR wrong number"), "{}", cmd.name);
+            }
+        }
+
+        request(&mut stream, "set foo bar");
+        assert_eq!(request_lines(&mut stream, "ls"), vec!["foo"]);
+    }
+
+    #[test]
+    fn test_chunked_values() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(
+            OpenOptions::new()
+                .max_value_size(1 << 20)
+                .open(dir.path())
+                .unwrap(),
+        );
+
+        // larger than a chunk and the buffers of both sides.
+        let value: Vec<u8> = (0..CHUNK_SIZE as u32 * 4 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        stream
+            .write_all(format!("setchunked big {}\n", value.len()).as_bytes())
+            .unwrap();
+        let mut w = ChunkedWriter::new(&stream);
+        w.write_all(&value).unwrap();
+        w.finish().unwrap();
+
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "\n");
+
+        stream.write_all(b"getchunked big\n").unwrap();
+        let mut got = Vec::new();
+        ChunkedReader::with_len(&mut reader, value.len() as u64)
+            .read_to_end(&mut got)
+            .unwrap();
+        assert!(got == value);
+
+        stream.write_all(b"getchunked missing\n").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "$-1\n");
+
+        // the size is checked before the value is read.
+        stream
+            .write_all(b"setchunked huge 2000000\n$3\nabc$0\n")
+            .unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "-ERR value is too large\n");
+
+        // a value not matching its size closes the connection.
+        stream
+            .write_all(b"setchunked short 4\n$3\nabc$0\n")
+            .unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("-ERR invalid chunked value"), "{}", line);
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_ping_and_ready() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+
+        // the store is still loading.
+        let startup = Arc::new(Startup::default());
+        let config = Config {
+            requirepass: Some("secret".to_owned()),
+            ..Config::default()
+        };
+        let mut stream = connect_startup(startup.clone(), config);
+        assert_eq!(request(&mut stream, "ping"), "+PONG");
+        startup.progress.lock().unwrap().files_total = 3;
+        // the progress is only shown once authenticated.
+        assert_eq!(request_lines(&mut stream, "ready"), vec!["status:loading"]);
+        assert_eq!(request(&mut stream, "auth secret"), "OK");
+        assert_eq!(
+            request_lines(&mut stream, "ready"),
+            vec![
+                "status:loading",
+                "files_processed:0",
+                "files_total:3",
+                "keys_loaded:0"
+            ]
+        );
+        assert_eq!(request(&mut stream, "get foo"), "-LOADING store is loading");
+        assert_eq!(
+            request(&mut stream, "setchunked foo 3\n$3\nbar$0"),
+            "-LOADING store is loading"
+        );
+
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+        let _ = startup
+            .databases
+            .set(vec![(DEFAULT_DATABASE.to_owned(), db)]);
+        assert_eq!(request(&mut stream, "set foo bar"), "");
+        assert_eq!(request(&mut stream, "ping"), "+PONG");
+        assert_eq!(
+            request_lines(&mut stream, "ready"),
+            vec!["status:ready", "keys:1", "data_files:1"]
+        );
+    }
+
+    #[test]
+    fn test_select_database() {
+        let dir1 = tempdir::TempDir::new("server-test.db").unwrap();
+        let dir2 = tempdir::TempDir::new("server-test.db").unwrap();
+        let startup = Startup::default();
+        let _ = startup.databases.set(vec![
+            (
+                DEFAULT_DATABASE.to_owned(),
+                OpenOptions::new().open(dir1.path()).unwrap(),
+            ),
+            (
+                "tenant".to_owned(),
+                OpenOptions::new().open(dir2.path()).unwrap(),
+            ),
+        ]);
+        let startup = Arc::new(startup);
+        let mut stream = connect_startup(startup.clone(), Config::default());
+
+        assert_eq!(
+            request_lines(&mut stream, "databases"),
+            vec![DEFAULT_DATABASE, "tenant"]
+        );
+        request(&mut stream, "set foo default-value");
+        assert_eq!(request(&mut stream, "select tenant"), "OK");
+        assert_eq!(request(&mut stream, "get foo"), "");
+        request(&mut stream, "set foo tenant-value");
+        assert_eq!(request(&mut stream, "get foo"), "tenant-value");
+        assert_eq!(
+            request(&mut stream, "select missing"),
+            "-ERR unknown database 'missing'"
+        );
+        assert_eq!(request(&mut stream, "get foo"), "tenant-value");
+
+        // other connections start with the default database.
+        let mut other = connect_startup(startup, Config::default());
+        assert_eq!(request(&mut other, "get foo"), "default-value");
+
+        assert_eq!(request(&mut stream, "select default"), "OK");
+        assert_eq!(request(&mut stream, "get foo"), "default-value");
+    }
+
+    #[test]
+    fn test_parse_databases() {
+        assert_eq!(
+            parse_databases("default=database,tenant=/data/tenant").unwrap(),
+            vec![
+                ("default".to_owned(), "database".to_owned()),
+                ("tenant".to_owned(), "/data/tenant".to_owned())
+            ]
+        );
+        assert!(parse_databases("default").is_err());
+        assert!(parse_databases("=database").is_err());
+        assert!(parse_databases("a=x,a=y").is_err());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let config = Config {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let mut stream = connect_with_config(OpenOptions::new().open(dir.path()).unwrap(), config);
+        assert_eq!(request(&mut stream, "ping"), "+PONG");
+
+        // send nothing, the server hangs up.
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stat_command() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+        let mut stream = connect(db);
+
+        request(&mut stream, "set foo bar");
+        request(&mut stream, "set hello world");
+        request(&mut stream, "set foo baz");
+        request(&mut stream, "set gone value");
+        request(&mut stream, "rm gone");
+
+        let stat: Vec<(String, u64)> = request_lines(&mut stream, "stat")
+            .iter()
+            .map(|line| {
+                let (name, value) = line.split_once(':').unwrap();
+                (name.to_owned(), value.parse().unwrap())
+            })
+            .collect();
+        let value = |name: &str| stat.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(value("keys"), 2);
+        assert_eq!(value("data_files"), 1);
+        assert!(value("total_bytes") > 0);
+        // the first foo, gone and its tombstone are reclaimable.
+        assert!(value("reclaimable_bytes") > 0);
+        assert!(value("reclaimable_bytes") < value("total_bytes"));
+    }
+
+    #[test]
+    fn test_info_command() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+        let mut stream = connect(db.clone());
+
+        let info = |stream: &mut TcpStream| -> Vec<(String, u64)> {
+            request_lines(stream, "info")
+                .iter()
+                .map(|line| {
+                    let (name, value) = line.split_once(':').unwrap();
+                    (name.to_owned(), value.parse().unwrap())
+                })
+                .collect()
+        };
+        let counter =
+            |info: &[(String, u64)], name: &str| info.iter().find(|(n, _)| n == name).unwrap().1;
+
+        request(&mut stream, "set foo bar");
+        request(&mut stream, "set hello world");
+        request(&mut stream, "get foo");
+        request(&mut stream, "get missing");
+        request(&mut stream, "rm hello");
+        assert_eq!(request(&mut stream, "merge"), "+scheduled");
+        while db.is_compacting() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // the compacted data file and the new active one.
+        let segments = request_lines(&mut stream, "segments");
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].contains(" live_keys:1 live_bytes:22 active:0 hint:1 "));
+        assert!(segments[1]
+            .starts_with("file_id:3 size_bytes:0 live_keys:0 live_bytes:0 active:1 hint:0 "));
+
+        let stats = info(&mut stream);
+        assert_eq!(counter(&stats, "sets"), 2);
+        assert_eq!(counter(&stats, "gets"), 2);
+        assert_eq!(counter(&stats, "hits"), 1);
+        assert_eq!(counter(&stats, "misses"), 1);
+        assert_eq!(counter(&stats, "deletes"), 1);
+        assert_eq!(counter(&stats, "compactions"), 1);
+        assert_eq!(counter(&stats, "bytes_read"), 3);
+        assert!(counter(&stats, "bytes_written") > 0);
+        let latencies: u64 = stats
+            .iter()
+            .filter(|(name, _)| name.starts_with("set_latency_"))
+            .map(|(_, value)| value)
+            .sum();
+        assert_eq!(latencies, 2);
+
+        assert_eq!(request(&mut stream, "config resetstat"), "OK");
+        assert!(info(&mut stream).iter().all(|(_, value)| *value == 0));
+        assert_eq!(
+            request(&mut stream, "config foo"),
+            "-ERR unknown config subcommand 'foo'"
+        );
+    }
+
+    #[test]
+    fn test_limit_errors() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().max_keys(1).open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "set foo bar"), "");
+        assert_eq!(
+            request(&mut stream, "set hello world"),
+            "-ERR key limit exceeded"
+        );
+        assert_eq!(
+            request(&mut stream, "setchunked hello 1\n$1\n1$0"),
+            "-ERR key limit exceeded"
+        );
+        assert_eq!(request(&mut stream, "set foo baz"), "");
+        assert_eq!(request(&mut stream, "get foo"), "baz");
+    }
+
+    #[test]
+    fn test_auth_and_roles() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let bitcask = OpenOptions::new().open(dir.path()).unwrap();
+        let config = Config {
+            requirepass: Some("secret".to_owned()),
+            ..Config::default()
+        };
+        let mut stream = connect_with_config(bitcask.clone(), config.clone());
+
+        assert_eq!(
+            request(&mut stream, "set foo bar"),
+            "-ERR authentication required"
+        );
+        assert_eq!(
+            request(&mut stream, "setchunked foo 1\n$1\n1$0"),
+            "-ERR authentication required"
+        );
+        assert_eq!(request(&mut stream, "auth wrong"), "-ERR invalid password");
+        assert_eq!(
+            request(&mut stream, "get foo"),
+            "-ERR authentication required"
+        );
+        assert_eq!(request(&mut stream, "auth secret"), "OK");
+        assert_eq!(request(&mut stream, "set foo bar"), "");
+        assert_eq!(request(&mut stream, "get foo"), "bar");
+
+        // authentication is per connection.
+        let mut stream = connect_with_config(bitcask.clone(), config.clone());
+        assert_eq!(
+            request(&mut stream, "get foo"),
+            "-ERR authentication required"
+        );
+
+        let config = Config {
+            default_role: Role::ReadOnly,
+            ..config
+        };
+        let mut stream = connect_with_config(bitcask, config);
+        assert_eq!(request(&mut stream, "auth secret"), "OK");
+        assert_eq!(request(&mut stream, "get foo"), "bar");
+        for cmd in [
+            "set foo baz",
+            "rm foo",
+            "merge",
+            "setchunked foo 1\n$1\n1$0",
+        ] {
+            assert_eq!(request(&mut stream, cmd), "-ERR readonly");
+        }
+        assert_eq!(request(&mut stream, "get foo"), "bar");
+        assert_eq!("readonly".parse(), Ok(Role::ReadOnly));
+        assert!("admin".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_exists_and_rm() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut stream = connect(OpenOptions::new().open(dir.path()).unwrap());
+
+        assert_eq!(request(&mut stream, "exists foo"), "0");
+        request(&mut stream, "set foo bar");
+        assert_eq!(request(&mut stream, "exists foo"), "1");
+
+        assert_eq!(request(&mut stream, "rm foo"), "1");
+        assert_eq!(request(&mut stream, "exists foo"), "0");
+        assert_eq!(request(&mut stream, "rm foo"), "0");
+    }
+
+    #[test]
+    fn test_ls_pagination() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut bitcask = OpenOptions::new().open(dir.path()).unwrap();
+        for i in 0..1000 {
+            bitcask.set(format!("key-{i:04}"), "value").unwrap();
+        }
+        bitcask.set("other", "value").unwrap();
+        let mut stream = connect(bitcask);
+
+        assert_eq!(request_lines(&mut stream, "ls key-099").len(), 10);
+
+        // without a limit, all keys are listed in order too.
+        let mut all: Vec<_> = (0..1000).map(|i| format!("key-{i:04}")).collect();
+        all.push("other".to_owned());
+        assert_eq!(request_lines(&mut stream, "ls"), all);
+
+        let mut keys = Vec::new();
+        let mut token = String::new();
+        loop {
+            let mut page = request_lines(&mut stream, &format!("ls key- 100 \"{token}\""));
+            token = page.remove(0);
+            assert!(page.len() <= 100);
+            keys.extend(page);
+            if token.is_empty() {
+                break;
+            }
+        }
+
+        let expected: Vec<_> = (0..1000).map(|i| format!("key-{i:04}")).collect();
+        assert_eq!(keys, expected);
+
+        // flags, the last full page has an empty next page.
+        let page = request_lines(&mut stream, "ls --limit 3 --after key-0997");
+        assert_eq!(page, vec!["", "key-0998", "key-0999", "other"]);
+        let page = request_lines(&mut stream, "ls --after key-0999 --limit 2");
+        assert_eq!(page, vec!["", "other"]);
+        let page = request_lines(&mut stream, "ls key- --limit 2");
+        assert_eq!(page, vec!["key-0001", "key-0000", "key-0001"]);
+        assert_eq!(
+            request_lines(&mut stream, "ls --after key-0999"),
+            vec!["other"]
+        );
+        assert_eq!(request(&mut stream, "ls --limit"), "-ERR syntax error");
+
+        // long listing, with the same pagination.
+        let page = request_lines(&mut stream, "ls -l key- --limit 2 --after key-0997");
+        assert_eq!(page[0], "");
+        let columns: Vec<Vec<&str>> = page[1..]
+            .iter()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(columns[0][..2], ["key-0998", "29"]);
+        assert_eq!(columns[1][..2], ["key-0999", "29"]);
+        assert!(columns.iter().all(|c| c[2].parse::<u64>().unwrap() > 0));
+        let page = request_lines(&mut stream, "ls -l");
+        assert_eq!(page.len(), 1001);
+        assert!(page.iter().all(|line| line.len() == page[0].len()));
+        assert_eq!(
+            request(&mut stream, "ls -l --limit x"),
+            "-ERR value is not an integer"
+        );
+        assert_eq!(
+            request(&mut stream, "ls key- 2 --limit 3"),
+            "-ERR syntax error"
+        );
+
+        assert_eq!(
+            request(&mut stream, "ls key- ten"),
+            "-ERR value is not an integer"
+        );
+        for cmd in ["ls key- 0", "ls -l --limit 0"] {
+            assert_eq!(request(&mut stream, cmd), "-ERR limit must be positive");
+        }
+    }
+
+    /// Socket stand-in counting the writes and flushes reaching it.
+    #[derive(Default)]
+    struct MockSocket {
+        data: Vec<u8>,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl Write for &mut MockSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_response_buffering() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let mut bitcask = OpenOptions::new().open(dir.path()).unwrap();
+        for i in 0..100 {
+            bitcask.set(format!("key-{i:04}"), "value").unwrap();
+        }
+
+        for cmds in [&["ls"][..], &["ls", "-l"], &["ls", "key-00"]] {
+            let mut unbuffered = MockSocket::default();
+            process_db_command(&mut &mut unbuffered, &mut bitcask, cmds).unwrap();
+
+            let mut socket = MockSocket::default();
+            let mut response = Response::new(&mut socket);
+            process_db_command(&mut response, &mut bitcask, cmds).unwrap();
+            response.end().unwrap();
+            drop(response);
+
+            // same reply, sent at once instead of a write per line.
+            assert!(unbuffered.writes > 1, "cmds: {cmds:?}");
+            assert_eq!(socket.data, unbuffered.data, "cmds: {cmds:?}");
+            assert_eq!(socket.writes, 1, "cmds: {cmds:?}");
+            assert_eq!(socket.flushes, 1, "cmds: {cmds:?}");
+        }
+
+        // help goes through the same helper.
+        let mut socket = MockSocket::default();
+        let mut response = Response::new(&mut socket);
+        help(&mut response).unwrap();
+        response.end().unwrap();
+        drop(response);
+        assert_eq!((socket.writes, socket.flushes), (1, 1));
+    }
+
+    #[test]
+    fn test_concurrent_merge() {
+        /// Reader of a single byte, blocking until released so that
+        /// the store stays locked meanwhile.
+        struct Blocking {
+            started: Option<mpsc::Sender<()>>,
+            release: mpsc::Receiver<()>,
+        }
+
+        impl Read for Blocking {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if let Some(started) = self.started.take() {
+                    started.send(()).unwrap();
+                    self.release.recv().unwrap();
+                }
+                buf[0] = b'x';
+                Ok(1)
+            }
+        }
+
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+        let mut first = connect(db.clone());
+        let mut second = connect(db.clone());
+        request(&mut first, "set foo bar");
+        request(&mut first, "set foo baz");
+
+        let (started, wait_started) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let writer = {
+            let mut db = db.clone();
+            thread::spawn(move || {
+                let mut r = Blocking {
+                    started: Some(started),
+                    release: released,
+                };
+                db.set_from_reader(b"slow", 1, &mut r).unwrap();
+            })
+        };
+        wait_started.recv().unwrap();
+
+        // merge replies while the store is locked, a second one is rejected.
+        assert_eq!(request(&mut first, "merge"), "+scheduled");
+        assert_eq!(
+            request(&mut second, "merge"),
+            "-ERR compaction is already in progress"
+        );
+
+        release.send(()).unwrap();
+        writer.join().unwrap();
+        while db.is_compacting() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(request(&mut second, "merge"), "+scheduled");
+        while db.is_compacting() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(request(&mut first, "get foo"), "baz");
+        assert_eq!(request(&mut second, "get slow"), "x");
+        assert_eq!(db.metrics().compactions.get(), 2);
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let dir = tempdir::TempDir::new("server-test.db").unwrap();
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+        let config = Config {
+            slowlog_threshold: Some(Duration::ZERO),
+            slowlog_max_len: 2,
+            ..Config::default()
+        };
+        let mut stream = connect_with_config(db.clone(), config.clone());
+
+        request(&mut stream, "set foo bar");
+        request(&mut stream, "get foo");
+        request_lines(&mut stream, "ls");
+        assert_eq!(request(&mut stream, "slowlog len"), "2");
+
+        // newest first, slowlog commands are left out.
+        let entries = request_lines(&mut stream, "slowlog get");
+        assert!(entries[0].ends_with(" ls"), "{}", entries[0]);
+        assert!(entries[1].ends_with(" get foo"), "{}", entries[1]);
+        assert!(entries[1].starts_with("1 "), "{}", entries[1]);
+        assert_eq!(request_lines(&mut stream, "slowlog get 1").len(), 1);
+        assert_eq!(request(&mut stream, "slowlog reset"), "OK");
+        assert_eq!(request(&mut stream, "slowlog len"), "0");
+        assert_eq!(
+            request(&mut stream, "slowlog rewind"),
+            "-ERR unknown slowlog subcommand 'rewind'"
+        );
+
+        let config = Config {
+            redact_keys: true,
+            ..config
+        };
+        let mut stream = connect_with_config(db.clone(), config);
+        request(&mut stream, "get foo");
+        request(&mut stream, &format!("set other {}", "x".repeat(100)));
+        let entries = request_lines(&mut stream, "slowlog get");
+        assert!(entries[0].ends_with(&format!(" set <redacted> {}...", "x".repeat(64))));
+        assert!(entries[1].ends_with(" get <redacted>"), "{}", entries[1]);
+
+        // disabled by default.
+        let mut stream = connect(db);
+        request(&mut stream, "get foo");
+        assert_eq!(request(&mut stream, "slowlog len"), "0");
+    }
+}
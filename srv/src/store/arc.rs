@@ -1,57 +1,384 @@
 //! Arc Store.
 
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{
+    Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    Weak,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use log::info;
+use log::{debug, error, info, warn};
 
-use super::error::Result;
-use super::storage::Storage;
+use super::error::{Result, StoreError};
+use super::fs::{create_dir_all, FileSystem, StdFileSystem};
+use super::keydir::KeydirKind;
+use super::layout::Layout;
+use super::manifest::Manifest;
+use super::metrics::Metrics;
+use super::storage::{
+    CompactionReport, EntryMetadata, RecoveryCallback, RecoveryProgress, RecoveryStats,
+    SegmentInfo, Stats, Storage,
+};
+use super::watch::Event;
 use super::{Store, StoreOptions};
 
+/// Attempts to read a value without holding the store before reading
+/// it under the lock, an attempt fails only if compaction removed the
+/// data file in between.
+const MAX_UNLOCKED_READS: usize = 3;
+
 /// Build custom open options.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct OpenOptions(StoreOptions);
 
 impl OpenOptions {
-    #[allow(dead_code)]
     pub fn new() -> Self {
         Self(StoreOptions::default())
     }
 
-    #[allow(dead_code)]
     pub fn max_log_file_size(mut self, value: u64) -> Self {
         self.0.max_log_file_size = value;
         self
     }
 
-    #[allow(dead_code)]
     pub fn sync(mut self, value: bool) -> Self {
         self.0.sync = value;
         self
     }
 
-    #[allow(dead_code)]
+    /// Sync data every `interval` from a background thread, so that
+    /// writes without `sync` become durable after a bounded delay.
+    /// Zero disables it.
+    pub fn sync_interval(mut self, interval: Duration) -> Self {
+        self.0.sync_interval = interval;
+        self
+    }
+
     pub fn max_value_size(mut self, value: u64) -> Self {
         self.0.max_value_size = value;
         self
     }
 
-    #[allow(dead_code)]
     pub fn max_key_size(mut self, value: u64) -> Self {
         self.0.max_key_size = value;
         self
     }
 
-    #[allow(dead_code)]
+    pub fn keydir_capacity(mut self, value: u64) -> Self {
+        self.0.keydir_capacity = value;
+        self
+    }
+
+    pub fn value_cache_bytes(mut self, value: u64) -> Self {
+        self.0.value_cache_bytes = value;
+        self
+    }
+
+    /// Buffer up to `value` bytes of writes to the active data file in
+    /// memory, so that small writes coalesce. Buffered writes are lost
+    /// if the process crashes before they're flushed, on sync, rotation
+    /// or once the buffer is full. Zero writes through.
+    pub fn write_buffer_size(mut self, value: u64) -> Self {
+        self.0.write_buffer_size = value;
+        self
+    }
+
+    /// Bound number of data files kept open for reading, readers of
+    /// the least recently used data files are closed and reopened on
+    /// demand. Zero means unlimited.
+    pub fn max_open_files(mut self, value: u64) -> Self {
+        self.0.max_open_files = value;
+        self
+    }
+
+    /// Store values larger than `value` bytes in separate blob files,
+    /// so compaction of data files doesn't copy them. Zero disables it.
+    pub fn blob_threshold(mut self, value: u64) -> Self {
+        self.0.blob_threshold = value;
+        self
+    }
+
+    /// Fail adding new keys with `StoreError::KeyLimitExceeded` once the
+    /// store holds `value` keys, overwrites are allowed. Zero means unlimited.
+    pub fn max_keys(mut self, value: u64) -> Self {
+        self.0.max_keys = value;
+        self
+    }
+
+    /// Fail writes with `StoreError::DiskLimitExceeded` which would grow
+    /// data and blob files beyond `value` bytes. Zero means unlimited.
+    ///
+    /// Deletes are always allowed, but their space is reclaimed only by
+    /// compaction, as are overwritten values.
+    pub fn max_disk_bytes(mut self, value: u64) -> Self {
+        self.0.max_disk_bytes = value;
+        self
+    }
+
+    pub fn max_segment_files(mut self, value: u64) -> Self {
+        self.0.max_segment_files = value;
+        self
+    }
+
+    /// Keep tombstones for at least `grace` on compaction, so that
+    /// deletes stay observable on disk for a while.
+    pub fn tombstone_grace(mut self, grace: Duration) -> Self {
+        self.0.tombstone_grace = grace;
+        self
+    }
+
+    /// Sync after each delete whatever `sync` and `sync_interval` are,
+    /// e.g. when erasure must be durable but other writes needn't be.
+    pub fn durable_deletes(mut self, value: bool) -> Self {
+        self.0.durable_deletes = value;
+        self
+    }
+
+    pub fn continue_on_corruption(mut self, value: bool) -> Self {
+        self.0.continue_on_corruption = value;
+        self
+    }
+
+    /// Open the store read-only, all writes return `StoreError::ReadOnly`.
+    pub fn read_only(mut self, value: bool) -> Self {
+        self.0.read_only = value;
+        self
+    }
+
+    /// Detect immutable data files modified by another process, reads
+    /// then fail with `StoreError::ExternalModification`.
+    pub fn detect_external_modification(mut self, value: bool) -> Self {
+        self.0.detect_external_modification = value;
+        self
+    }
+
+    /// Open a read-only store even if a writer holds the lock,
+    /// ignored unless the store is read-only.
+    pub fn ignore_lock(mut self, value: bool) -> Self {
+        self.0.ignore_lock = value;
+        self
+    }
+
+    /// Create the store directory and files with permissions `mode`,
+    /// e.g. `0o600`, on unix. Directories also get execute permission
+    /// where `mode` grants read permission.
+    ///
+    /// Data files are created on the local disk with `mode`, a file
+    /// system set afterwards by `file_system` creates them its own way.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.0.mode = Some(mode);
+        self.0.fs = Arc::new(StdFileSystem::with_mode(mode));
+        self
+    }
+
+    /// Place data, hint and blob files as `layout` says. Files of a store
+    /// written with the default layout are moved into its directories on
+    /// open, unless it names files with other suffixes, then they're
+    /// left to the store they belong to.
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.0.layout = Arc::new(layout);
+        self
+    }
+
+    /// Keep data files on the given file system instead of the local disk.
+    pub fn file_system(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.0.fs = fs;
+        self
+    }
+
+    /// Set callback invoked after each segment is loaded on open.
+    pub fn recovery_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RecoveryProgress) + Send + Sync + 'static,
+    {
+        self.0.recovery_progress = Some(RecoveryCallback(Arc::new(f)));
+        self
+    }
+
+    /// Stop long operations, i.e. rebuilding keydir on open, compaction
+    /// and verifying segments, with `StoreError::Cancelled` once `cancel`
+    /// is set, e.g. on shutdown. The store stays consistent.
+    pub fn cancel_token(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.0.cancel = Some(cancel);
+        self
+    }
+
+    /// Remove hint files without a data file on open, they're ignored
+    /// and reported either way.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.0.repair = repair;
+        self
+    }
+
+    /// Skip files named like data or blob files but without a file id on
+    /// open, e.g. editor backups, instead of failing with
+    /// `StoreError::InvalidFileName`.
+    pub fn ignore_foreign_files(mut self, value: bool) -> Self {
+        self.0.ignore_foreign_files = value;
+        self
+    }
+
+    /// Reserve `max_log_file_size` bytes on disk for each new active data
+    /// file, so that it isn't fragmented as it grows. Rotation still
+    /// happens once its entries reach the size.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.0.preallocate = preallocate;
+        self
+    }
+
+    /// Select the keydir implementation, e.g. `KeydirKind::BTree` for
+    /// cheap ordered listing. The data files are the same whatever the
+    /// keydir, a store can be reopened with another one.
+    pub fn keydir(mut self, kind: KeydirKind) -> Self {
+        self.0.keydir = kind;
+        self
+    }
+
+    /// Remove the active data file on close if nothing was written to
+    /// it, it's kept and reused by the next open by default.
+    pub fn remove_empty_files(mut self, remove: bool) -> Self {
+        self.0.remove_empty_files = remove;
+        self
+    }
+
     pub fn open(&self, path: impl AsRef<std::path::Path>) -> Result<BitCask> {
-        BitCask::open_with_options(path, self.0)
+        BitCask::open_with_options(path, self.0.clone())
+    }
+}
+
+/// Store and flusher of an opened path, both owned by its handles.
+#[derive(Debug)]
+struct Registered {
+    store: Weak<RwLock<Store>>,
+    flusher: Option<Weak<Flusher>>,
+}
+
+/// Process-local registry of opened stores, keyed by canonicalized path.
+fn registry() -> &'static Mutex<HashMap<PathBuf, Registered>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<PathBuf, Registered>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Lock `store` for reading. A panic while it was locked, e.g. a bug in
+/// the store, poisons the lock, later requests go on with the store as
+/// the panic left it instead of panicking too.
+fn read_lock(store: &RwLock<Store>) -> RwLockReadGuard<'_, Store> {
+    store.read().unwrap_or_else(|e| {
+        warn!("store lock poisoned by a panic, recover it");
+        store.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// Lock `store` for writing, see `read_lock`.
+fn write_lock(store: &RwLock<Store>) -> RwLockWriteGuard<'_, Store> {
+    store.write().unwrap_or_else(|e| {
+        warn!("store lock poisoned by a panic, recover it");
+        store.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// Marks a compaction running until dropped.
+struct CompactionGuard(Arc<AtomicBool>);
+
+impl CompactionGuard {
+    fn acquire(compacting: &Arc<AtomicBool>) -> Result<Self> {
+        if compacting.swap(true, Ordering::AcqRel) {
+            return Err(StoreError::CompactionInProgress);
+        }
+        Ok(Self(Arc::clone(compacting)))
+    }
+}
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Background thread syncing the store periodically.
+///
+/// The thread only holds a weak reference to the store, it's stopped
+/// and joined on `close()` or when the last handle is dropped.
+#[derive(Debug)]
+struct Flusher {
+    stop: Mutex<Option<Sender<()>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Flusher {
+    fn spawn(store: Weak<RwLock<Store>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            // a message or a hang up both stop the thread.
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let Some(store) = store.upgrade() else {
+                    break;
+                };
+                // skip this cycle rather than waiting for a long write,
+                // e.g. compaction, holding the lock.
+                let mut store = match store.try_write() {
+                    Ok(store) => store,
+                    Err(TryLockError::WouldBlock) => {
+                        debug!("store is busy, skip background sync");
+                        continue;
+                    }
+                    Err(TryLockError::Poisoned(e)) => {
+                        warn!("store lock poisoned by a panic, recover it");
+                        store.clear_poison();
+                        e.into_inner()
+                    }
+                };
+                if store.is_closed() {
+                    break;
+                }
+                if store.pending_bytes() > 0 {
+                    if let Err(e) = store.sync() {
+                        error!("background sync failed: {}", e);
+                    }
+                }
+            }
+        });
+
+        Self {
+            stop: Mutex::new(Some(stop)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    /// Stop the thread and wait for it to exit.
+    fn stop(&self) {
+        drop(self.stop.lock().unwrap().take());
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            if handle.join().is_err() {
+                error!("background sync thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
 /// Store handler for multiple threads.
+///
+/// Cloning a `BitCask` shares the same underlying store. Opening a path
+/// which is already opened in this process returns a shared handle as well,
+/// and the options passed to the second open are ignored.
 #[derive(Debug)]
 pub struct BitCask {
     inner: Arc<RwLock<Store>>,
+    flusher: Option<Arc<Flusher>>,
+    compacting: Arc<AtomicBool>,
 }
 
 impl BitCask {
@@ -65,54 +392,318 @@ impl BitCask {
     ) -> Result<Self> {
         let path = path.as_ref();
 
-        let disk_storage = RwLock::new(Store::open_with_options(path, opts)?);
+        // read-only handles are never shared with writers.
+        if opts.read_only {
+            let store = Store::open_with_options(path, opts)?;
+            let compacting = store.compaction_flag();
+            return Ok(Self {
+                inner: Arc::new(RwLock::new(store)),
+                flusher: None,
+                compacting,
+            });
+        }
+
+        create_dir_all(path, opts.mode)?;
+        let canonical_path = path.canonicalize()?;
+
+        let mut registry = registry().lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(registered) = registry.get(&canonical_path) {
+            if let Some(inner) = registered
+                .store
+                .upgrade()
+                .filter(|inner| !read_lock(inner).is_closed())
+            {
+                info!(
+                    "store {} already opened, share it",
+                    canonical_path.display()
+                );
+                let flusher = registered.flusher.as_ref().and_then(Weak::upgrade);
+                let compacting = read_lock(&inner).compaction_flag();
+                return Ok(Self {
+                    inner,
+                    flusher,
+                    compacting,
+                });
+            }
+        }
+
+        let sync_interval = opts.sync_interval;
+        let store = Store::open_with_options(path, opts)?;
+        let compacting = store.compaction_flag();
+        let inner = Arc::new(RwLock::new(store));
+        let flusher = (!sync_interval.is_zero())
+            .then(|| Arc::new(Flusher::spawn(Arc::downgrade(&inner), sync_interval)));
+
+        registry.retain(|_, v| v.store.strong_count() > 0);
+        registry.insert(
+            canonical_path,
+            Registered {
+                store: Arc::downgrade(&inner),
+                flusher: flusher.as_ref().map(Arc::downgrade),
+            },
+        );
+
         Ok(Self {
-            inner: Arc::new(disk_storage),
+            inner,
+            flusher,
+            compacting,
         })
     }
+
+    /// Subscribe to changes of keys starting with `prefix`.
+    ///
+    /// Events are sent after the change is applied, in the order
+    /// of changes. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self, prefix: impl Into<Vec<u8>>) -> Receiver<Event> {
+        let mut store = write_lock(&self.inner);
+        store.subscribe(prefix.into())
+    }
+
+    /// Copy value of the key to `w` without materializing it.
+    /// Return size of the value, `None` if key not found.
+    ///
+    /// The store is locked until the whole value is written,
+    /// a slow writer blocks others.
+    pub fn get_to<W: Write>(&mut self, key: &[u8], w: &mut W) -> Result<Option<u64>> {
+        let mut store = write_lock(&self.inner);
+        store.get_to(key, w)
+    }
+
+    /// Set key to a value of `size` bytes copied from `r`, without
+    /// materializing the value.
+    ///
+    /// The store is locked until the whole value is read,
+    /// a slow reader blocks others.
+    pub fn set_from_reader<R: Read>(&mut self, key: &[u8], size: u64, r: &mut R) -> Result<()> {
+        let mut store = write_lock(&self.inner);
+        store.set_from_reader(key, size, r)
+    }
+
+    /// Return value of the key, or if it's missing, compute it with `f`,
+    /// store and return it.
+    ///
+    /// The store is locked while `f` runs, so that concurrent callers
+    /// compute a missing value only once, a slow `f` blocks others.
+    pub fn get_or_insert_with<F>(&mut self, key: &[u8], f: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let mut store = write_lock(&self.inner);
+        if let Some(value) = store.get(key)? {
+            return Ok(value);
+        }
+
+        let value = f();
+        store.set(key, &value)?;
+        Ok(value)
+    }
+
+    /// Compact all data files into a single one, see
+    /// `DiskStorage::compact_to_single_file`.
+    ///
+    /// Return `StoreError::CompactionInProgress` if another compaction
+    /// runs, without waiting for it.
+    pub fn compact_to_single_file(&self) -> Result<CompactionReport> {
+        let _guard = CompactionGuard::acquire(&self.compacting)?;
+        let mut store = write_lock(&self.inner);
+        store.compact_to_single_file()
+    }
+
+    /// Compact only the given data files, see
+    /// `DiskStorage::compact_segments`.
+    ///
+    /// Return `StoreError::CompactionInProgress` if another compaction
+    /// runs, without waiting for it.
+    pub fn compact_segments(&self, file_ids: &[u64]) -> Result<CompactionReport> {
+        let _guard = CompactionGuard::acquire(&self.compacting)?;
+        let mut store = write_lock(&self.inner);
+        store.compact_segments(file_ids)
+    }
+
+    /// Compact data files, return `false` without waiting if another
+    /// compaction runs.
+    pub fn try_compact(&mut self) -> Result<bool> {
+        match self.compact() {
+            Err(StoreError::CompactionInProgress) => Ok(false),
+            res => res.map(|_| true),
+        }
+    }
+
+    /// Compact data files on a dedicated thread, the returned receiver
+    /// gets the result once it's done. Return
+    /// `StoreError::CompactionInProgress` right away if another
+    /// compaction runs.
+    ///
+    /// The store is still locked while the compaction runs.
+    pub fn spawn_compaction(&self) -> Result<Receiver<Result<CompactionReport>>> {
+        let guard = CompactionGuard::acquire(&self.compacting)?;
+        let inner = Arc::clone(&self.inner);
+        let (done, result) = mpsc::channel();
+        thread::spawn(move || {
+            let res = write_lock(&inner).compact();
+            // released before the result is sent, so that a compaction
+            // started once it's received isn't refused.
+            drop(guard);
+            if let Err(e) = &res {
+                error!("background compaction failed: {}", e);
+            }
+            // nobody may wait for the result.
+            let _ = done.send(res);
+        });
+        Ok(result)
+    }
+
+    /// Return `true` if a compaction runs or waits for the store lock.
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::Acquire)
+    }
+
+    /// Write missing hint files of immutable data files, see
+    /// `DiskStorage::recover`.
+    pub fn recover(&self) -> Result<u64> {
+        let mut store = write_lock(&self.inner);
+        store.recover()
+    }
+
+    /// Return statistics of rebuilding keydir when the store was opened.
+    pub fn recovery_stats(&self) -> RecoveryStats {
+        let store = read_lock(&self.inner);
+        store.recovery_stats().clone()
+    }
+
+    /// Check immutable data files for external modification, see
+    /// `DiskStorage::verify_segments`.
+    pub fn verify_segments(&self) -> Result<()> {
+        let store = read_lock(&self.inner);
+        store.verify_segments()
+    }
+
+    /// Return the manifest of the store.
+    pub fn manifest(&self) -> Manifest {
+        let store = read_lock(&self.inner);
+        store.manifest().clone()
+    }
 }
 
 impl Clone for BitCask {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            flusher: self.flusher.clone(),
+            compacting: Arc::clone(&self.compacting),
         }
     }
 }
 
 impl Storage for BitCask {
     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let mut store = self.inner.write().unwrap();
-        store.get(key)
+        let mut value = Vec::new();
+        Ok(self.get_into(key, &mut value)?.map(|_| value))
+    }
+
+    /// The value is read once the store is released where possible, so
+    /// that reading a large value doesn't hold off writers and compaction.
+    fn get_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        for _ in 0..MAX_UNLOCKED_READS {
+            let location = read_lock(&self.inner).value_location(key)?;
+            let Some(location) = location else {
+                break;
+            };
+            match location.read_into(key, buf) {
+                // compacted meanwhile, look the key up again.
+                Err(StoreError::SegmentMissing { .. }) => continue,
+                res => return res,
+            }
+        }
+
+        let mut store = write_lock(&self.inner);
+        store.get_into(key, buf)
     }
 
     fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
-        let mut store = self.inner.write().unwrap();
+        let mut store = write_lock(&self.inner);
         store.set(key, value)
     }
 
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let mut store = write_lock(&self.inner);
+        store.increment(key, delta)
+    }
+
+    fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64> {
+        let mut store = write_lock(&self.inner);
+        store.append(key, suffix)
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<bool> {
+        let mut store = write_lock(&self.inner);
+        store.rename(old, new)
+    }
+
+    fn set_new(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let mut store = write_lock(&self.inner);
+        store.set_new(key, value)
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let mut store = write_lock(&self.inner);
+        store.set_with_expiry(key, value, ttl)
+    }
+
+    fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        let mut store = write_lock(&self.inner);
+        store.expire(key, ttl)
+    }
+
+    fn persist(&mut self, key: &[u8]) -> Result<bool> {
+        let mut store = write_lock(&self.inner);
+        store.persist(key)
+    }
+
+    fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        let store = read_lock(&self.inner);
+        store.ttl(key)
+    }
+
     fn close(&mut self) -> Result<()> {
-        let mut store = self.inner.write().unwrap();
+        // stop the flusher first, it may be waiting for the lock.
+        if let Some(flusher) = &self.flusher {
+            flusher.stop();
+        }
+        let mut store = write_lock(&self.inner);
         store.close()
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let mut store = self.inner.write().unwrap();
+    /// Return `StoreError::CompactionInProgress` if another compaction
+    /// runs, without waiting for it.
+    fn compact(&mut self) -> Result<CompactionReport> {
+        let _guard = CompactionGuard::acquire(&self.compacting)?;
+        let mut store = write_lock(&self.inner);
         store.compact()
     }
 
     fn contains_key(&self, key: &[u8]) -> bool {
-        let store = self.inner.read().unwrap();
+        let store = read_lock(&self.inner);
         store.contains_key(key)
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        let mut store = self.inner.write().unwrap();
+    fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        let mut store = write_lock(&self.inner);
         store.delete(key)
     }
 
+    fn purge_expired(&mut self) -> Result<u64> {
+        let mut store = write_lock(&self.inner);
+        store.purge_expired()
+    }
+
     fn is_empty(&self) -> bool {
-        let store = self.inner.read().unwrap();
+        let store = read_lock(&self.inner);
         store.is_empty()
     }
 
@@ -120,24 +711,108 @@ impl Storage for BitCask {
     where
         F: FnMut(&[u8], &[u8]) -> Result<bool>,
     {
-        let mut store = self.inner.write().unwrap();
+        let mut store = write_lock(&self.inner);
         store.for_each(f)
     }
 
+    fn for_each_filtered<P, F>(&mut self, key_pred: P, f: &mut F) -> Result<()>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        let mut store = write_lock(&self.inner);
+        store.for_each_filtered(key_pred, f)
+    }
+
     fn keys(&self) -> Result<Vec<Vec<u8>>> {
-        let store = self.inner.read().unwrap();
+        let store = read_lock(&self.inner);
         store.keys()
     }
 
+    fn keys_into(&self, buf: &mut Vec<Vec<u8>>) -> Result<()> {
+        let store = read_lock(&self.inner);
+        store.keys_into(buf)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<Vec<u8>>> {
+        let store = read_lock(&self.inner);
+        store.keys_sorted()
+    }
+
     fn len(&self) -> u64 {
-        let store = self.inner.read().unwrap();
+        let store = read_lock(&self.inner);
         store.len()
     }
 
     fn sync(&mut self) -> Result<()> {
-        let mut store = self.inner.write().unwrap();
+        let mut store = write_lock(&self.inner);
         store.sync()
     }
+
+    fn last_sync_time(&self) -> Option<u64> {
+        let store = read_lock(&self.inner);
+        store.last_sync_time()
+    }
+
+    fn pending_bytes(&self) -> u64 {
+        let store = read_lock(&self.inner);
+        store.pending_bytes()
+    }
+
+    fn last_write_ts(&self) -> Option<u64> {
+        let store = read_lock(&self.inner);
+        store.last_write_ts()
+    }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        let store = read_lock(&self.inner);
+        store.first_key()
+    }
+
+    fn keys_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let store = read_lock(&self.inner);
+        store.keys_with_prefix(prefix, after, limit)
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        let store = read_lock(&self.inner);
+        store.last_key()
+    }
+
+    fn entries_metadata(&self) -> Result<Vec<EntryMetadata>> {
+        let store = read_lock(&self.inner);
+        store.entries_metadata()
+    }
+
+    fn entries_metadata_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<EntryMetadata>> {
+        let store = read_lock(&self.inner);
+        store.entries_metadata_with_prefix(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Stats {
+        let store = read_lock(&self.inner);
+        store.stats()
+    }
+
+    fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let store = read_lock(&self.inner);
+        store.segments()
+    }
+
+    fn metrics(&self) -> Arc<Metrics> {
+        let store = read_lock(&self.inner);
+        store.metrics()
+    }
 }
 
 impl Drop for BitCask {
@@ -145,3 +820,340 @@ impl Drop for BitCask {
         info!("bitcask dropped...");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_open_same_path_twice_shares_handle() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+
+        let mut db1 = BitCask::open(dir.path()).unwrap();
+        let mut db2 = BitCask::open(dir.path()).unwrap();
+        assert!(Arc::ptr_eq(&db1.inner, &db2.inner));
+
+        db1.set(b"hello", b"world").unwrap();
+        assert_eq!(db2.get(b"hello").unwrap(), Some(b"world".to_vec()));
+
+        drop(db1);
+        drop(db2);
+
+        // all handles dropped, the store can be opened again.
+        let mut db3 = BitCask::open(dir.path()).unwrap();
+        assert_eq!(db3.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = BitCask::open(dir.path()).unwrap();
+        db.set(b"hello", b"world").unwrap();
+
+        // a panic while the store is locked poisons the lock.
+        let inner = Arc::clone(&db.inner);
+        let res = thread::spawn(move || {
+            let _store = write_lock(&inner);
+            panic!("bug while holding the store lock");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(db.inner.is_poisoned());
+
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert!(!db.inner.is_poisoned());
+        db.set(b"foo", b"bar").unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::thread;
+
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let db = BitCask::open(dir.path()).unwrap();
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let mut db = db.clone();
+                let calls = Arc::clone(&calls);
+                thread::spawn(move || {
+                    db.get_or_insert_with(b"cached", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        format!("computed by {i}").into_bytes()
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        // computed once, every caller got the stored value.
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let mut db = db;
+        let stored = db.get(b"cached").unwrap().unwrap();
+        assert!(values.iter().all(|v| *v == stored));
+    }
+
+    #[test]
+    fn test_subscribe_overlapping_prefixes() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = BitCask::open(dir.path()).unwrap();
+
+        let users = db.subscribe("user:");
+        let all = db.subscribe("");
+
+        db.set(b"user:1", b"alice").unwrap();
+        db.set(b"order:1", b"book").unwrap();
+        db.delete(b"user:1").unwrap();
+        db.delete(b"user:2").unwrap();
+
+        let put = |k: &[u8], v: &[u8]| Event::Put {
+            key: k.to_vec(),
+            value: v.to_vec(),
+        };
+        let del = |k: &[u8]| Event::Delete { key: k.to_vec() };
+
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            vec![put(b"user:1", b"alice"), del(b"user:1")]
+        );
+        assert_eq!(
+            all.try_iter().collect::<Vec<_>>(),
+            vec![
+                put(b"user:1", b"alice"),
+                put(b"order:1", b"book"),
+                del(b"user:1")
+            ]
+        );
+
+        // dropped subscribers are cleaned up on next event.
+        drop(users);
+        db.set(b"user:3", b"carol").unwrap();
+        assert_eq!(all.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_background_sync() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = OpenOptions::new()
+            .sync(false)
+            .sync_interval(Duration::from_millis(20))
+            .open(dir.path())
+            .unwrap();
+
+        db.set(b"hello", b"world").unwrap();
+        assert!(db.pending_bytes() > 0);
+
+        // compaction holds the lock, the flusher skips cycles meanwhile.
+        db.compact().unwrap();
+        db.set(b"foo", b"bar").unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(db.pending_bytes(), 0);
+        assert!(db.last_sync_time().is_some());
+
+        let flusher = Arc::downgrade(db.flusher.as_ref().unwrap());
+        db.close().unwrap();
+        assert!(flusher.upgrade().unwrap().handle.lock().unwrap().is_none());
+
+        drop(db);
+        assert!(flusher.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_max_open_files() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let opts = OpenOptions::new().max_log_file_size(1).max_open_files(2);
+
+        {
+            let mut db = opts.open(dir.path()).unwrap();
+            for i in 0..10u8 {
+                db.set([i], [i]).unwrap();
+            }
+        }
+
+        let mut db = opts.open(dir.path()).unwrap();
+        for _ in 0..2 {
+            for i in 0..10u8 {
+                assert_eq!(db.get(&[i]).unwrap(), Some(vec![i]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_compaction() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = BitCask::open(dir.path()).unwrap();
+        db.set(b"hello", b"world").unwrap();
+
+        // the first compaction waits for the lock held here.
+        let lock = db.inner.write().unwrap();
+        let first = {
+            let mut db = db.clone();
+            thread::spawn(move || db.compact())
+        };
+        while !db.is_compacting() {
+            thread::yield_now();
+        }
+
+        // the second one returns without waiting.
+        assert!(matches!(
+            db.clone().compact(),
+            Err(StoreError::CompactionInProgress)
+        ));
+        drop(lock);
+
+        first.join().unwrap().unwrap();
+        assert!(!db.is_compacting());
+        assert_eq!(db.metrics().compactions.get(), 1);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_spawn_compaction() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = BitCask::open(dir.path()).unwrap();
+        db.set(b"hello", b"world").unwrap();
+
+        // the spawned compaction waits for the lock held here.
+        let lock = db.inner.write().unwrap();
+        let done = db.spawn_compaction().unwrap();
+        assert!(db.is_compacting());
+        assert!(matches!(
+            db.spawn_compaction(),
+            Err(StoreError::CompactionInProgress)
+        ));
+        assert!(!db.clone().try_compact().unwrap());
+        drop(lock);
+
+        done.recv().unwrap().unwrap();
+        assert!(db.try_compact().unwrap());
+        assert_eq!(db.metrics().compactions.get(), 2);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_get_outside_lock() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let value = vec![b'v'; 4 << 20];
+        let mut db = OpenOptions::new()
+            .max_value_size(value.len() as u64)
+            .open(dir.path())
+            .unwrap();
+        db.set(b"big", &value).unwrap();
+
+        // a location taken before compaction points to a removed file.
+        let location = db.inner.read().unwrap().value_location(b"big").unwrap();
+        db.compact().unwrap();
+        assert!(matches!(
+            location.unwrap().read_into(b"big", &mut Vec::new()),
+            Err(StoreError::SegmentMissing { .. })
+        ));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut db = db.clone();
+                let value = value.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(db.get(b"big").unwrap().as_ref(), Some(&value));
+                    }
+                })
+            })
+            .collect();
+        for i in 0..20u32 {
+            db.set(i.to_be_bytes(), b"garbage").unwrap();
+            db.compact().unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(db.metrics().hits.get(), 80);
+    }
+
+    #[test]
+    fn test_append_concurrent() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let db = BitCask::open(dir.path()).unwrap();
+
+        // reading the value and writing it back happen under one lock,
+        // so no chunk is lost.
+        let writers: Vec<_> = [b"a", b"b", b"c"]
+            .into_iter()
+            .map(|chunk| {
+                let mut db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        db.append(b"log", chunk).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut value = db.clone().get(b"log").unwrap().unwrap();
+        assert_eq!(value.len(), 150);
+        value.sort();
+        assert_eq!(value, [[b'a'; 50], [b'b'; 50], [b'c'; 50]].concat());
+    }
+
+    #[test]
+    fn test_open_with_keydir_kinds() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+
+        let mut db = OpenOptions::new()
+            .keydir(KeydirKind::Hash)
+            .open(dir.path())
+            .unwrap();
+        for key in ["c", "a", "d", "b"] {
+            db.set(key, key.repeat(2)).unwrap();
+        }
+        db.set("a", "new").unwrap();
+        db.delete(b"d").unwrap();
+        let expected = db.keys_sorted().unwrap();
+        drop(db);
+
+        for kind in [KeydirKind::BTree, KeydirKind::Hash] {
+            let mut db = OpenOptions::new().keydir(kind).open(dir.path()).unwrap();
+            assert_eq!(db.keys_sorted().unwrap(), expected, "{kind:?}");
+            assert_eq!(db.get(b"a").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.get(b"b").unwrap(), Some(b"bb".to_vec()));
+            assert_eq!(db.get(b"d").unwrap(), None);
+            if kind == KeydirKind::BTree {
+                // keys of a btree keydir come back in order.
+                assert_eq!(db.keys().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_last_write_ts() {
+        let dir = tempdir::TempDir::new("bitcask-test.db").unwrap();
+        let mut db = BitCask::open(dir.path()).unwrap();
+        assert_eq!(db.last_write_ts(), None);
+
+        db.set(b"a", b"1").unwrap();
+        let first = db.last_write_ts().unwrap();
+        assert!(first > 0);
+        db.get(b"a").unwrap();
+        assert_eq!(db.last_write_ts(), Some(first));
+
+        // timestamps are in seconds.
+        thread::sleep(Duration::from_millis(1100));
+        db.delete(b"a").unwrap();
+        let second = db.last_write_ts().unwrap();
+        assert!(second > first);
+        drop(db);
+
+        // recovered from the entries on open.
+        let db = BitCask::open(dir.path()).unwrap();
+        assert_eq!(db.last_write_ts(), Some(second));
+    }
+}
@@ -0,0 +1,95 @@
+//! Value cache implementation.
+//!
+//! A least-recently-used cache of values for hot keys, bounded by
+//! the total bytes of cached keys and values.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// LRU value cache, disabled when capacity is zero.
+#[derive(Debug, Default)]
+pub struct ValueCache {
+    /// maximum bytes of cached keys and values.
+    capacity: u64,
+
+    /// current bytes of cached keys and values.
+    size: u64,
+
+    /// logical clock used to order entries by recent use.
+    tick: u64,
+
+    /// mapping from a key to its value and last used tick.
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+
+    /// mapping from last used tick to key, oldest first.
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Return `true` unless the capacity is zero.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Return cached value of the key, mark it as recently used.
+    pub fn get(&mut self, key: &[u8]) -> Option<&[u8]> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            None => None,
+            Some((value, tick)) => {
+                let key = self.order.remove(tick).expect("cache order out of sync");
+                *tick = self.tick;
+                self.order.insert(self.tick, key);
+                Some(value.as_slice())
+            }
+        }
+    }
+
+    /// Cache value of the key, evict least recently used entries if full.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.remove(key);
+
+        let entry_size = (key.len() + value.len()) as u64;
+        if entry_size > self.capacity {
+            return;
+        }
+
+        while self.size + entry_size > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("cache order out of sync");
+            if let Some((value, _)) = self.entries.remove(&oldest) {
+                self.size -= (oldest.len() + value.len()) as u64;
+            }
+        }
+
+        self.tick += 1;
+        self.entries
+            .insert(key.to_vec(), (value.to_vec(), self.tick));
+        self.order.insert(self.tick, key.to_vec());
+        self.size += entry_size;
+    }
+
+    /// Remove cached value of the key.
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.size -= (key.len() + value.len()) as u64;
+        }
+    }
+
+    /// Remove all cached values.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+}
@@ -4,7 +4,6 @@ use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, StoreError>;
 
-#[allow(dead_code)]
 #[derive(Debug, Error)]
 pub enum StoreError {
     #[error(transparent)]
@@ -19,10 +18,24 @@ pub enum StoreError {
     #[error(transparent)]
     Pattern(#[from] glob::PatternError),
 
+    #[cfg(feature = "serde")]
+    #[error("serialization failed: {}", .0)]
+    Serde(String),
+
     /// Custom error definitions.
     #[error("invalid bytes, cannot descrialize entry")]
     DeserializeError,
 
+    #[error("entry at offset {offset} of {size} bytes exceeds data end {len}")]
+    EntryOutOfBounds { offset: u64, size: u64, len: u64 },
+
+    #[error("entry at offset {offset} exceeds the key or value size limit")]
+    EntryTooLarge {
+        offset: u64,
+        key_sz: u64,
+        value_sz: u64,
+    },
+
     #[error("crc check failed, data entry (key='{}', file_id={}, offset={}) was corrupted", String::from_utf8_lossy(.key), .file_id, .offset)]
     DataEntryCorrupted {
         file_id: u64,
@@ -30,20 +43,114 @@ pub enum StoreError {
         offset: u64,
     },
 
+    #[error("crc check failed, hint entry (file_id={file_id}, offset={offset}) was corrupted")]
+    HintEntryCorrupted { file_id: u64, offset: u64 },
+
+    #[error("hint entry (key='{}', file_id={}) points past the end of its data file", String::from_utf8_lossy(.key), .file_id)]
+    HintEntryOutOfBounds { file_id: u64, key: Vec<u8> },
+
+    #[error("hint entry at offset {offset} has unknown flags {flags:#04x}")]
+    UnknownHintFlags { offset: u64, flags: u8 },
+
+    #[error("hint file of segment {file_id} has no timestamps")]
+    HintWithoutTimestamps { file_id: u64 },
+
+    #[error("entry size {size} is invalid for a key of {key_sz} bytes")]
+    InvalidEntrySize { size: u64, key_sz: u64 },
+
+    #[error("data entry (key='{}', file_id={}, offset={}) not found", String::from_utf8_lossy(.key), .file_id, .offset)]
+    EntryNotFound {
+        key: Vec<u8>,
+        file_id: u64,
+        offset: u64,
+    },
+
+    #[error("file '{}' is not named after a segment", .0.display())]
+    InvalidFileName(std::path::PathBuf),
+
+    #[error("segment {file_id} is found twice")]
+    DuplicateSegment { file_id: u64 },
+
+    #[error("segment {file_id} is missing")]
+    SegmentMissing { file_id: u64 },
+
+    #[error("segment {file_id} was modified outside of the store")]
+    ExternalModification { file_id: u64 },
+
+    #[error("iteration failed at key '{}': {}", String::from_utf8_lossy(.key), .source)]
+    IterationFailed {
+        key: Vec<u8>,
+        source: Box<StoreError>,
+    },
+
     #[error("key '{}' not found", String::from_utf8_lossy(.0))]
     KeyNotFound(Vec<u8>),
 
+    #[error("key '{}' already exists", String::from_utf8_lossy(.0))]
+    KeyAlreadyExists(Vec<u8>),
+
+    #[error("value is not an integer")]
+    NotAnInteger,
+
+    #[error("increment or decrement would overflow")]
+    IntegerOverflow,
+
+    #[error("expire time is out of range")]
+    ExpiryOutOfRange,
+
     #[error("key is too large")]
     KeyIsTooLarge,
 
     #[error("value is too large")]
     ValueIsTooLarge,
 
+    #[error("key limit exceeded")]
+    KeyLimitExceeded,
+
+    #[error("disk limit exceeded")]
+    DiskLimitExceeded,
+
+    #[error("no space left on device")]
+    OutOfSpace,
+
     #[error("file '{}' is not writeable", .0.display())]
     FileNotWriteable(std::path::PathBuf),
 
-    #[error("db is already locked")]
-    AlreadyLocked,
+    #[error(
+        "db is already locked{}",
+        .pid.map(|pid| format!(" by process {pid}")).unwrap_or_default()
+    )]
+    AlreadyLocked { pid: Option<u32> },
+
+    #[error("operation was cancelled")]
+    Cancelled,
+
+    #[error("compaction is already in progress")]
+    CompactionInProgress,
+
+    #[error("store is closed")]
+    Closed,
+
+    #[error("store is opened read-only")]
+    ReadOnly,
+
+    #[error("store '{}' not found", .0.display())]
+    StoreNotFound(std::path::PathBuf),
+
+    #[error("incompatible store format version {found}, supported up to {supported}")]
+    IncompatibleVersion { found: u32, supported: u32 },
+
+    #[error("store has {found} shards, but opened with {expected}")]
+    ShardCountMismatch { found: usize, expected: usize },
+
+    #[error("invalid manifest: {}", .0)]
+    InvalidManifest(String),
+
+    #[error("invalid layout: {}", .0)]
+    InvalidLayout(String),
+
+    #[error("invalid option: {}", .0)]
+    InvalidOption(String),
 
     #[error("{}", .0)]
     Custom(String),
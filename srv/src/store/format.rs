@@ -2,12 +2,11 @@
 
 use std::{
     fmt::Display,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
 };
 
-use chrono::Utc;
-
-use super::error::Result;
+use super::error::{Result, StoreError};
+use crate::utils::time::now_secs;
 
 /// EntryIO trait.
 pub trait EntryIO {
@@ -26,44 +25,106 @@ pub trait EntryIO {
 
 pub const HEADER_SIZE: usize = 16;
 
+/// Size of the optional expiry field following the header.
+pub const EXPIRY_SIZE: usize = 8;
+
+/// Size of the optional timestamp field of hint entries.
+pub const TIMESTAMP_SIZE: usize = 8;
+
+/// Size of the optional u32 timestamp field of hint entries
+/// written by older versions.
+pub const TIMESTAMP32_SIZE: usize = 4;
+
+/// Entry flag: an expiry timestamp (u64, unix millis) follows the header.
+pub const FLAG_EXPIRY: u8 = 0x01;
+
+/// Hint entry flag: the data entry timestamp (u32) follows the expiry,
+/// written by older versions.
+pub const FLAG_TIMESTAMP32: u8 = 0x02;
+
+/// Data entry flag: value is a `BlobPointer` into a blob file.
+pub const FLAG_BLOB: u8 = 0x04;
+
+/// Hint entry flag: a crc (u32) follows the timestamp.
+pub const FLAG_CRC: u8 = 0x08;
+
+/// Hint entry flag: the data entry timestamp (u64) follows the expiry.
+pub const FLAG_TIMESTAMP: u8 = 0x10;
+
+/// Hint entry flag: the header holds the size of the whole data entry,
+/// older versions store its size less header and key.
+pub const FLAG_ENTRY_SIZE: u8 = 0x20;
+
+/// Hint entry flag: the data entry is a tombstone of the key.
+pub const FLAG_TOMBSTONE: u8 = 0x40;
+
+/// Flags hint entries may have, others are written by newer versions.
+const HINT_FLAGS: u8 =
+    FLAG_EXPIRY | FLAG_TIMESTAMP32 | FLAG_CRC | FLAG_TIMESTAMP | FLAG_ENTRY_SIZE | FLAG_TOMBSTONE;
+
+/// Size of the crc field of hint entries.
+pub const CRC_SIZE: usize = 4;
+
+/// Size of an encoded `BlobPointer`.
+pub const BLOB_POINTER_SIZE: usize = 24;
+
+/// Key size occupies the lower 24 bits of `key_sz` field,
+/// the upper 8 bits are entry flags.
+pub const KEY_SZ_MASK: u32 = 0x00ff_ffff;
+
 /// Entry Header Structure.
 ///
 /// # fields:
-/// - crc: u32
-/// - timestamp: u32
-/// - key_sz: u32
+/// - timestamp: u64
+/// - flags: u8 | key_sz: u24
 /// - value_sz: u32
 ///
+/// The timestamp used to be a u32 preceded by an always zero u32 crc,
+/// so entries written by older versions read the same.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct DataHeader([u8; HEADER_SIZE]);
 
 impl DataHeader {
-    pub fn new(crc: u32, timestamp: u32, key_sz: u32, value_sz: u32) -> Self {
+    pub fn new(timestamp: u64, key_sz: u32, value_sz: u32) -> Self {
         let mut buf = [0u8; HEADER_SIZE];
 
-        buf[0..4].copy_from_slice(&crc.to_be_bytes());
-        buf[4..8].copy_from_slice(&timestamp.to_be_bytes());
+        buf[0..8].copy_from_slice(&timestamp.to_be_bytes());
         buf[8..12].copy_from_slice(&key_sz.to_be_bytes());
         buf[12..16].copy_from_slice(&value_sz.to_be_bytes());
 
         Self(buf)
     }
 
-    pub fn crc(&self) -> u32 {
-        u32::from_be_bytes(self.0[0..4].try_into().unwrap())
-    }
-
-    pub fn timestamp(&self) -> u32 {
-        u32::from_be_bytes(self.0[4..8].try_into().unwrap())
+    pub fn timestamp(&self) -> u64 {
+        u64::from_be_bytes(self.0[0..8].try_into().unwrap())
     }
 
     pub fn key_sz(&self) -> u32 {
-        u32::from_be_bytes(self.0[8..12].try_into().unwrap())
+        u32::from_be_bytes(self.0[8..12].try_into().unwrap()) & KEY_SZ_MASK
     }
 
     pub fn value_sz(&self) -> u32 {
         u32::from_be_bytes(self.0[12..16].try_into().unwrap())
     }
+
+    pub fn flags(&self) -> u8 {
+        self.0[8]
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.0[8] = flags;
+        self
+    }
+
+    /// Size of the whole entry, header, expiry, key and value.
+    pub fn entry_size(&self) -> u64 {
+        let expiry_sz = if self.flags() & FLAG_EXPIRY != 0 {
+            EXPIRY_SIZE
+        } else {
+            0
+        };
+        (HEADER_SIZE + expiry_sz) as u64 + self.key_sz() as u64 + self.value_sz() as u64
+    }
 }
 
 impl AsRef<[u8]> for DataHeader {
@@ -96,6 +157,9 @@ pub struct DataEntry {
     /// value of disk entry.
     pub value: Vec<u8>,
 
+    /// expiry of disk entry, in unix milliseconds.
+    pub expire_at: Option<u64>,
+
     /// offset of disk entry.
     pub offset: Option<u64>,
 
@@ -104,19 +168,57 @@ pub struct DataEntry {
 }
 
 impl DataEntry {
-    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
-        let timestamp: u32 = Utc::now().timestamp().try_into().unwrap();
-        let crc = 0;
-        let (key_sz, value_sz) = (key.len() as u32, value.len() as u32);
-        let header = DataHeader::new(crc, timestamp, key_sz, value_sz);
-
-        Self {
+    /// Return `StoreError::KeyIsTooLarge` or `StoreError::ValueIsTooLarge`
+    /// if the size of `key` or `value` doesn't fit the header.
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Result<Self> {
+        let key_sz = u32::try_from(key.len())
+            .ok()
+            .filter(|&sz| sz <= KEY_SZ_MASK)
+            .ok_or(StoreError::KeyIsTooLarge)?;
+        let value_sz = u32::try_from(value.len()).map_err(|_| StoreError::ValueIsTooLarge)?;
+        let header = DataHeader::new(now_secs(), key_sz, value_sz);
+
+        Ok(Self {
             header,
             key,
             value,
+            expire_at: None,
             offset: None,
             file_id: None,
-        }
+        })
+    }
+
+    pub fn expire_at(mut self, expire_at: Option<u64>) -> Self {
+        let flags = match expire_at {
+            Some(_) => self.header.flags() | FLAG_EXPIRY,
+            None => self.header.flags() & !FLAG_EXPIRY,
+        };
+        self.header = self.header.with_flags(flags);
+        self.expire_at = expire_at;
+        self
+    }
+
+    /// Override the creation timestamp of the entry, in unix seconds.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        let flags = self.header.flags();
+        self.header = DataHeader::new(timestamp, self.header.key_sz(), self.header.value_sz())
+            .with_flags(flags);
+        self
+    }
+
+    /// Mark value of the entry as a `BlobPointer`.
+    pub fn blob(mut self, blob: bool) -> Self {
+        let flags = match blob {
+            true => self.header.flags() | FLAG_BLOB,
+            false => self.header.flags() & !FLAG_BLOB,
+        };
+        self.header = self.header.with_flags(flags);
+        self
+    }
+
+    /// Return `true` if value of the entry is a `BlobPointer`.
+    pub fn is_blob(&self) -> bool {
+        self.header.flags() & FLAG_BLOB != 0
     }
 
     pub fn offset(mut self, offset: u64) -> Self {
@@ -130,14 +232,15 @@ impl DataEntry {
     }
 
     pub fn size(&self) -> u64 {
-        (HEADER_SIZE + self.key.len() + self.value.len()) as u64
+        let expiry_sz = if self.expire_at.is_some() {
+            EXPIRY_SIZE
+        } else {
+            0
+        };
+        (HEADER_SIZE + expiry_sz + self.key.len() + self.value.len()) as u64
     }
 
-    // pub fn crc(&self) -> u32 {
-    //     self.header.crc()
-    // }
-
-    pub fn timestamp(&self) -> u32 {
+    pub fn timestamp(&self) -> u64 {
         self.header.timestamp()
     }
 
@@ -148,6 +251,113 @@ impl DataEntry {
     // pub fn value_sz(&self) -> usize {
     //     self.header.value_sz() as usize
     // }
+
+    /// Read header of the data entry at `offset`.
+    /// Return `None` if no entry at `offset`.
+    pub fn read_header<R>(r: &mut R, offset: u64) -> Result<Option<DataHeader>>
+    where
+        R: Read + Seek,
+    {
+        r.seek(SeekFrom::Start(offset))?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        if !read_header_bytes(r, &mut header)? || is_unwritten(&header) {
+            return Ok(None);
+        }
+
+        Ok(Some(DataHeader::from(header)))
+    }
+
+    /// Copy value of the data entry at `offset` to `w`, skipping the key,
+    /// without materializing the value.
+    /// Return header of the entry, or `None` if no entry at `offset`.
+    pub fn read_value_into<R, W>(r: &mut R, offset: u64, w: &mut W) -> Result<Option<DataHeader>>
+    where
+        R: Read + Seek,
+        W: Write + ?Sized,
+    {
+        let Some(header) = Self::read_header(r, offset)? else {
+            return Ok(None);
+        };
+
+        let mut skip = header.key_sz() as i64;
+        if header.flags() & FLAG_EXPIRY != 0 {
+            skip += EXPIRY_SIZE as i64;
+        }
+        r.seek(SeekFrom::Current(skip))?;
+
+        let value_sz = header.value_sz() as u64;
+        if io::copy(&mut r.by_ref().take(value_sz), w)? < value_sz {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(Some(header))
+    }
+
+    /// Write a data entry whose value of `value_sz` bytes is copied from
+    /// `r`, without materializing the value, stamped with `timestamp`.
+    /// Return offset and header of the entry.
+    ///
+    /// A failed write may leave a partial entry behind, it's up to
+    /// the caller to discard it.
+    pub fn write_from_reader<R, W>(
+        w: &mut W,
+        key: &[u8],
+        value_sz: u32,
+        timestamp: u64,
+        r: &mut R,
+    ) -> Result<(u64, DataHeader)>
+    where
+        R: Read,
+        W: Write + Seek + ?Sized,
+    {
+        let header = DataHeader::new(timestamp, key.len() as u32, value_sz);
+
+        let offset = w.stream_position()?;
+        w.write_all(header.as_ref())?;
+        w.write_all(key)?;
+        if io::copy(&mut r.by_ref().take(value_sz as u64), w)? < value_sz as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok((offset, header))
+    }
+}
+
+/// Location of a value stored in a blob file.
+///
+/// # fields:
+/// - file_id: u64
+/// - offset: u64, offset of the blob entry in the blob file
+/// - size: u64, size of the value
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file_id: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlobPointer {
+    pub fn encode(&self) -> [u8; BLOB_POINTER_SIZE] {
+        let mut buf = [0u8; BLOB_POINTER_SIZE];
+        buf[0..8].copy_from_slice(&self.file_id.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_be_bytes());
+        buf[16..24].copy_from_slice(&self.size.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != BLOB_POINTER_SIZE {
+            return Err(StoreError::DeserializeError);
+        }
+
+        Ok(Self {
+            file_id: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            size: u64::from_be_bytes(buf[16..24].try_into().unwrap()),
+        })
+    }
 }
 
 impl Display for DataEntry {
@@ -163,22 +373,67 @@ impl Display for DataEntry {
     }
 }
 
-impl EntryIO for DataEntry {
-    type Entry = Self;
+/// Largest key and value a data entry read back may have, sizes of
+/// a corrupted header must not allocate beyond them.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryLimits {
+    pub max_key_size: u64,
+    pub max_value_size: u64,
+}
 
-    fn read_from<R>(r: &mut R, offset: u64) -> Result<Option<Self::Entry>>
+impl Default for EntryLimits {
+    fn default() -> Self {
+        Self {
+            max_key_size: KEY_SZ_MASK as u64,
+            max_value_size: u32::MAX as u64,
+        }
+    }
+}
+
+impl DataEntry {
+    /// Read data entry at `offset`, rejecting keys and values
+    /// beyond `limits`.
+    /// Return `None` if no entry at `offset`.
+    pub fn read_within<R>(r: &mut R, offset: u64, limits: EntryLimits) -> Result<Option<Self>>
     where
         R: Read + Seek,
     {
         r.seek(SeekFrom::Start(offset))?;
 
         let mut buf = [0u8; HEADER_SIZE];
-        if r.read(&mut buf)? == 0 {
+        if !read_header_bytes(r, &mut buf)? || is_unwritten(&buf) {
             return Ok(None);
         }
 
         let header = DataHeader::from(buf);
 
+        // sizes of a corrupted header, or of a header read from the
+        // middle of an entry, must not allocate beyond the data.
+        let len = r.seek(SeekFrom::End(0))?;
+        if offset.saturating_add(header.entry_size()) > len {
+            return Err(StoreError::EntryOutOfBounds {
+                offset,
+                size: header.entry_size(),
+                len,
+            });
+        }
+        if header.key_sz() as u64 > limits.max_key_size
+            || header.value_sz() as u64 > limits.max_value_size
+        {
+            return Err(StoreError::EntryTooLarge {
+                offset,
+                key_sz: header.key_sz() as u64,
+                value_sz: header.value_sz() as u64,
+            });
+        }
+        r.seek(SeekFrom::Start(offset + HEADER_SIZE as u64))?;
+
+        let expire_at = if header.flags() & FLAG_EXPIRY != 0 {
+            Some(read_expiry(r)?)
+        } else {
+            None
+        };
+
         let mut key = vec![0u8; header.key_sz() as usize];
         r.read_exact(&mut key)?;
 
@@ -189,10 +444,22 @@ impl EntryIO for DataEntry {
             header,
             key,
             value,
+            expire_at,
             offset: None,
             file_id: None,
         }))
     }
+}
+
+impl EntryIO for DataEntry {
+    type Entry = Self;
+
+    fn read_from<R>(r: &mut R, offset: u64) -> Result<Option<Self::Entry>>
+    where
+        R: Read + Seek,
+    {
+        Self::read_within(r, offset, EntryLimits::default())
+    }
 
     fn write_to<W>(&self, w: &mut W) -> Result<u64>
     where
@@ -201,6 +468,9 @@ impl EntryIO for DataEntry {
         let offset = w.stream_position()?;
 
         w.write_all(self.header.as_ref())?;
+        if let Some(expire_at) = self.expire_at {
+            w.write_all(&expire_at.to_be_bytes())?;
+        }
         w.write_all(self.key.as_ref())?;
         w.write_all(self.value.as_ref())?;
 
@@ -212,19 +482,20 @@ impl EntryIO for DataEntry {
 ///
 /// # fields:
 /// - offset: u64
-/// - key_sz: u32
-/// - value_sz: u32
+/// - flags: u8 | key_sz: u24
+/// - size: u32, of the data entry with `FLAG_ENTRY_SIZE`, of the data
+///   entry less header and key otherwise
 ///
 #[derive(Debug)]
 pub struct HintHeader([u8; HEADER_SIZE]);
 
 impl HintHeader {
-    pub fn new(offset: u64, key_sz: u32, value_sz: u32) -> Self {
+    pub fn new(offset: u64, key_sz: u32, size: u32) -> Self {
         let mut buf = [0u8; HEADER_SIZE];
 
         buf[0..8].copy_from_slice(&offset.to_be_bytes());
         buf[8..12].copy_from_slice(&key_sz.to_be_bytes());
-        buf[12..16].copy_from_slice(&value_sz.to_be_bytes());
+        buf[12..16].copy_from_slice(&size.to_be_bytes());
 
         Self(buf)
     }
@@ -234,15 +505,25 @@ impl HintHeader {
     }
 
     pub fn key_sz(&self) -> usize {
-        u32::from_be_bytes(self.0[8..12].try_into().unwrap()) as usize
+        (u32::from_be_bytes(self.0[8..12].try_into().unwrap()) & KEY_SZ_MASK) as usize
     }
 
-    pub fn value_sz(&self) -> usize {
-        u32::from_be_bytes(self.0[12..16].try_into().unwrap()) as usize
+    pub fn flags(&self) -> u8 {
+        self.0[8]
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.0[8] = flags;
+        self
     }
 
+    /// Size of the data entry.
     pub fn size(&self) -> u64 {
-        HEADER_SIZE as u64 + self.key_sz() as u64 + self.value_sz() as u64
+        let size = u32::from_be_bytes(self.0[12..16].try_into().unwrap()) as u64;
+        if self.flags() & FLAG_ENTRY_SIZE != 0 {
+            return size;
+        }
+        HEADER_SIZE as u64 + self.key_sz() as u64 + size
     }
 }
 
@@ -266,14 +547,87 @@ pub struct HintEntry {
 
     /// key of disk entry.
     pub key: Vec<u8>,
+
+    /// expiry of disk entry, in unix milliseconds.
+    pub expire_at: Option<u64>,
+
+    /// timestamp of disk entry, hint files written
+    /// before timestamps were recorded give `0`.
+    pub timestamp: u64,
+
+    /// crc read from the hint file, hint files written
+    /// before crcs were recorded give `None`.
+    crc: Option<u32>,
 }
 
 impl HintEntry {
-    pub fn new(key: Vec<u8>, offset: u64, size: u64) -> Self {
-        let key_sz = key.len() as u32;
-        let value_sz = size as u32 - HEADER_SIZE as u32 - key_sz;
-        let header = HintHeader::new(offset, key_sz, value_sz);
-        Self { header, key }
+    /// Create hint entry of a data entry of `size` bytes at `offset`.
+    ///
+    /// Return `StoreError::InvalidEntrySize` if `size` can't hold the
+    /// header and the key, or doesn't fit the header.
+    pub fn new(key: Vec<u8>, offset: u64, size: u64) -> Result<Self> {
+        let key_sz = key.len() as u64;
+        let invalid = || StoreError::InvalidEntrySize { size, key_sz };
+        if size < HEADER_SIZE as u64 + key_sz || key_sz > KEY_SZ_MASK as u64 {
+            return Err(invalid());
+        }
+
+        // an entry with a value close to `u32::MAX` bytes is larger than
+        // that, record only its size past header and key then.
+        let header = match u32::try_from(size) {
+            Ok(size) => {
+                HintHeader::new(offset, key_sz as u32, size).with_flags(FLAG_CRC | FLAG_ENTRY_SIZE)
+            }
+            Err(_) => {
+                let rest =
+                    u32::try_from(size - HEADER_SIZE as u64 - key_sz).map_err(|_| invalid())?;
+                HintHeader::new(offset, key_sz as u32, rest).with_flags(FLAG_CRC)
+            }
+        };
+        Ok(Self {
+            header,
+            key,
+            expire_at: None,
+            timestamp: 0,
+            crc: None,
+        })
+    }
+
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        let flags = self.header.flags() & !FLAG_TIMESTAMP32 | FLAG_TIMESTAMP;
+        self.header = self.header.with_flags(flags);
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Mark the data entry as a tombstone of the key.
+    pub fn tombstone(mut self, tombstone: bool) -> Self {
+        let flags = match tombstone {
+            true => self.header.flags() | FLAG_TOMBSTONE,
+            false => self.header.flags() & !FLAG_TOMBSTONE,
+        };
+        self.header = self.header.with_flags(flags);
+        self
+    }
+
+    /// Return `true` if the data entry is a tombstone of the key.
+    pub fn is_tombstone(&self) -> bool {
+        self.header.flags() & FLAG_TOMBSTONE != 0
+    }
+
+    /// Return `true` if the entry records the data entry timestamp.
+    pub fn has_timestamp(&self) -> bool {
+        self.header.flags() & (FLAG_TIMESTAMP | FLAG_TIMESTAMP32) != 0
+    }
+
+    pub fn expire_at(mut self, expire_at: Option<u64>) -> Self {
+        let flags = match expire_at {
+            Some(_) => self.header.flags() | FLAG_EXPIRY,
+            None => self.header.flags() & !FLAG_EXPIRY,
+        };
+        self.header = self.header.with_flags(flags);
+        self.expire_at = expire_at;
+        self
     }
 
     pub fn offset(&self) -> u64 {
@@ -285,7 +639,49 @@ impl HintEntry {
     }
 
     pub fn selfsize(&self) -> u64 {
-        HEADER_SIZE as u64 + self.key.len() as u64
+        let expiry_sz = if self.expire_at.is_some() {
+            EXPIRY_SIZE
+        } else {
+            0
+        };
+        let timestamp_sz = if self.header.flags() & FLAG_TIMESTAMP != 0 {
+            TIMESTAMP_SIZE
+        } else if self.header.flags() & FLAG_TIMESTAMP32 != 0 {
+            TIMESTAMP32_SIZE
+        } else {
+            0
+        };
+        let crc_sz = if self.header.flags() & FLAG_CRC != 0 {
+            CRC_SIZE
+        } else {
+            0
+        };
+        (HEADER_SIZE + expiry_sz + timestamp_sz + crc_sz + self.key.len()) as u64
+    }
+
+    /// Crc over header, expiry, timestamp and key of the entry.
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(self.header.as_ref());
+        if let Some(expire_at) = self.expire_at {
+            hasher.update(&expire_at.to_be_bytes());
+        }
+        if self.header.flags() & FLAG_TIMESTAMP != 0 {
+            hasher.update(&self.timestamp.to_be_bytes());
+        } else if self.header.flags() & FLAG_TIMESTAMP32 != 0 {
+            hasher.update(&(self.timestamp as u32).to_be_bytes());
+        }
+        hasher.update(&self.key);
+        hasher.finalize()
+    }
+
+    /// Return `false` if the crc read from the hint file doesn't match,
+    /// entries without a crc are assumed valid.
+    pub fn is_valid(&self) -> bool {
+        match self.crc {
+            Some(crc) => crc == self.checksum(),
+            None => true,
+        }
     }
 
     // pub fn key_sz(&self) -> usize {
@@ -315,16 +711,54 @@ impl EntryIO for HintEntry {
         r.seek(SeekFrom::Start(offset))?;
 
         let mut buf = [0u8; HEADER_SIZE];
-        if r.read(&mut buf)? == 0 {
+        if !read_header_bytes(r, &mut buf)? {
             return Ok(None);
         }
 
         let header = HintHeader::from(buf);
+        if header.flags() & !HINT_FLAGS != 0 {
+            return Err(StoreError::UnknownHintFlags {
+                offset,
+                flags: header.flags(),
+            });
+        }
 
-        let mut key = vec![0u8; header.key_sz() as usize];
+        let expire_at = if header.flags() & FLAG_EXPIRY != 0 {
+            Some(read_expiry(r)?)
+        } else {
+            None
+        };
+
+        let timestamp = if header.flags() & FLAG_TIMESTAMP != 0 {
+            let mut buf = [0u8; TIMESTAMP_SIZE];
+            r.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf)
+        } else if header.flags() & FLAG_TIMESTAMP32 != 0 {
+            let mut buf = [0u8; TIMESTAMP32_SIZE];
+            r.read_exact(&mut buf)?;
+            u32::from_be_bytes(buf) as u64
+        } else {
+            0
+        };
+
+        let crc = if header.flags() & FLAG_CRC != 0 {
+            let mut buf = [0u8; CRC_SIZE];
+            r.read_exact(&mut buf)?;
+            Some(u32::from_be_bytes(buf))
+        } else {
+            None
+        };
+
+        let mut key = vec![0u8; header.key_sz()];
         r.read_exact(&mut key)?;
 
-        Ok(Some(Self::Entry { header, key }))
+        Ok(Some(Self::Entry {
+            header,
+            key,
+            expire_at,
+            timestamp,
+            crc,
+        }))
     }
 
     fn write_to<W>(&self, w: &mut W) -> Result<u64>
@@ -334,12 +768,56 @@ impl EntryIO for HintEntry {
         let offset = w.stream_position()?;
 
         w.write_all(self.header.as_ref())?;
+        if let Some(expire_at) = self.expire_at {
+            w.write_all(&expire_at.to_be_bytes())?;
+        }
+        if self.header.flags() & FLAG_TIMESTAMP != 0 {
+            w.write_all(&self.timestamp.to_be_bytes())?;
+        } else if self.header.flags() & FLAG_TIMESTAMP32 != 0 {
+            w.write_all(&(self.timestamp as u32).to_be_bytes())?;
+        }
+        if self.header.flags() & FLAG_CRC != 0 {
+            w.write_all(&self.checksum().to_be_bytes())?;
+        }
         w.write_all(self.key.as_ref())?;
 
         Ok(offset)
     }
 }
 
+/// Fill `buf` with a header, return `false` if `r` is at its end.
+///
+/// A header cut short, e.g. by a torn write, is `UnexpectedEof`.
+fn read_header_bytes<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    match filled {
+        0 => Ok(false),
+        n if n == buf.len() => Ok(true),
+        _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// Return `true` if `header` is unwritten space of a preallocated data
+/// file rather than an entry, entries are never stamped zero.
+fn is_unwritten(header: &[u8; HEADER_SIZE]) -> bool {
+    header.iter().all(|&b| b == 0)
+}
+
+fn read_expiry<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0u8; EXPIRY_SIZE];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -357,15 +835,16 @@ mod tests {
     fn random_header() -> DataHeader {
         let mut rng = rand::thread_rng();
 
-        DataHeader::new(rng.gen(), rng.gen(), rng.gen(), rng.gen())
+        DataHeader::new(rng.gen(), rng.gen(), rng.gen())
     }
 
     #[test]
     fn it_should_serialize_header() {
         let tests = [
-            DataHeader::new(10, 10, 10, 10),
-            DataHeader::new(0, 0, 0, 0),
-            DataHeader::new(10000, 10000, 10000, 10000),
+            DataHeader::new(10, 10, 10),
+            DataHeader::new(0, 0, 0),
+            DataHeader::new(10000, 10000, 10000),
+            DataHeader::new(u64::MAX, 10, 10),
         ];
 
         for test in tests {
@@ -382,7 +861,7 @@ mod tests {
 
     #[test]
     fn it_should_create_disk_entry() {
-        let entry = DataEntry::new(b"hello".to_vec(), b"world".to_vec());
+        let entry = DataEntry::new(b"hello".to_vec(), b"world".to_vec()).unwrap();
 
         assert_eq!(entry.header.key_sz(), 5);
         assert_eq!(entry.header.value_sz(), 5);
@@ -390,7 +869,7 @@ mod tests {
 
     #[test]
     fn test_entry_io() {
-        let entry = DataEntry::new(b"hello".to_vec(), b"world".to_vec());
+        let entry = DataEntry::new(b"hello".to_vec(), b"world".to_vec()).unwrap();
 
         let mut buf = Vec::new();
         let mut cursor = Cursor::new(&mut buf);
@@ -399,9 +878,387 @@ mod tests {
         assert_eq!(offset, 0);
 
         let entry1 = DataEntry::read_from(&mut cursor, offset).unwrap();
-        assert_eq!(entry1.is_some(), true);
+        assert!(entry1.is_some());
 
         let e = entry1.unwrap();
         assert_eq!(e.key, b"hello".to_vec());
     }
+
+    #[test]
+    fn test_entry_io_with_expiry() {
+        let entry = DataEntry::new(b"hello".to_vec(), b"world".to_vec())
+            .unwrap()
+            .expire_at(Some(42));
+        assert_eq!(entry.size(), (HEADER_SIZE + EXPIRY_SIZE + 10) as u64);
+
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+        entry.write_to(&mut cursor).unwrap();
+
+        let e = DataEntry::read_from(&mut cursor, 0).unwrap().unwrap();
+        assert_eq!(e.header.key_sz(), 5);
+        assert_eq!(e.expire_at, Some(42));
+        assert_eq!(e.value, b"world".to_vec());
+
+        let hint = HintEntry::new(e.key.clone(), 0, e.size())
+            .unwrap()
+            .expire_at(e.expire_at)
+            .timestamp(e.timestamp());
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+        hint.write_to(&mut cursor).unwrap();
+
+        let h = HintEntry::read_from(&mut cursor, 0).unwrap().unwrap();
+        assert_eq!(h.expire_at, Some(42));
+        assert_eq!(h.timestamp, e.timestamp());
+        assert_eq!(h.size(), e.size());
+        assert_eq!(
+            h.selfsize(),
+            (HEADER_SIZE + EXPIRY_SIZE + TIMESTAMP_SIZE + CRC_SIZE + 5) as u64
+        );
+        assert!(h.is_valid());
+
+        // flip a byte of the offset.
+        buf[3] ^= 0x01;
+        let h = HintEntry::read_from(&mut Cursor::new(&mut buf), 0)
+            .unwrap()
+            .unwrap();
+        assert!(!h.is_valid());
+    }
+
+    #[test]
+    fn test_far_future_timestamp() {
+        use crate::store::keydir::{BTreeMapKeydir, Keydir, KeydirEntry};
+
+        // 2200-01-01, beyond the range of a u32 timestamp.
+        let ts = 7_258_118_400u64;
+        let older = DataEntry::new(b"k".to_vec(), b"old".to_vec())
+            .unwrap()
+            .with_timestamp(ts);
+        let newer = DataEntry::new(b"k".to_vec(), b"new".to_vec())
+            .unwrap()
+            .with_timestamp(ts + 1);
+
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+        let mut keydir = BTreeMapKeydir::default();
+        for e in [newer, older] {
+            let offset = e.write_to(&mut cursor).unwrap();
+            let e = DataEntry::read_from(&mut cursor, offset).unwrap().unwrap();
+            keydir.put(
+                e.key.clone(),
+                KeydirEntry::new(0, offset, e.size(), e.timestamp()),
+            );
+        }
+
+        // newest wins regardless of the order entries are found.
+        assert_eq!(keydir.get(b"k").unwrap().timestamp, ts + 1);
+
+        let size = keydir.get(b"k").unwrap().size;
+        let hint = HintEntry::new(b"k".to_vec(), 0, size)
+            .unwrap()
+            .timestamp(ts + 1);
+        let mut buf = Vec::new();
+        hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let h = HintEntry::read_from(&mut Cursor::new(&mut buf), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.timestamp, ts + 1);
+        assert!(h.is_valid());
+
+        // hint entries of older versions carry a u32 timestamp.
+        let mut hint = HintEntry::new(b"k".to_vec(), 0, size).unwrap();
+        hint.header = HintHeader::new(0, 1, size as u32 - HEADER_SIZE as u32 - 1)
+            .with_flags(FLAG_TIMESTAMP32 | FLAG_CRC);
+        hint.timestamp = 42;
+        let mut buf = Vec::new();
+        hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let h = HintEntry::read_from(&mut Cursor::new(&mut buf), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.timestamp, 42);
+        assert_eq!(h.size(), size);
+        assert_eq!(
+            h.selfsize(),
+            (HEADER_SIZE + TIMESTAMP32_SIZE + CRC_SIZE + 1) as u64
+        );
+        assert!(h.is_valid());
+    }
+
+    #[test]
+    fn test_hint_entry_size() {
+        let round_trip = |hint: HintEntry| {
+            let mut buf = Vec::new();
+            hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+            HintEntry::read_from(&mut Cursor::new(&mut buf), 0)
+                .unwrap()
+                .unwrap()
+        };
+
+        // an empty value, and the largest size the header holds.
+        let min = HEADER_SIZE as u64 + 3;
+        let h = round_trip(HintEntry::new(b"key".to_vec(), 7, min).unwrap());
+        assert_eq!((h.offset(), h.size()), (7, min));
+        let max = u32::MAX as u64;
+        let h = round_trip(HintEntry::new(b"key".to_vec(), 0, max).unwrap());
+        assert_eq!(h.size(), max);
+        assert!(h.is_valid());
+
+        // larger entries record their size past header and key, up to
+        // the largest value size.
+        let largest = min + max;
+        for size in [max + 1, largest] {
+            let h = round_trip(HintEntry::new(b"key".to_vec(), 0, size).unwrap());
+            assert_eq!(h.size(), size);
+            assert!(h.is_valid());
+        }
+
+        for size in [0, min - 1, largest + 1] {
+            assert!(matches!(
+                HintEntry::new(b"key".to_vec(), 0, size),
+                Err(StoreError::InvalidEntrySize { key_sz: 3, .. })
+            ));
+        }
+        let key = vec![0u8; KEY_SZ_MASK as usize + 1];
+        assert!(HintEntry::new(key, 0, max).is_err());
+    }
+
+    #[test]
+    fn test_hint_entry_unknown_flags() {
+        let hint = HintEntry::new(b"key".to_vec(), 0, HEADER_SIZE as u64 + 3)
+            .unwrap()
+            .tombstone(true);
+        let mut buf = Vec::new();
+        hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let read = HintEntry::read_from(&mut Cursor::new(&buf), 0)
+            .unwrap()
+            .unwrap();
+        assert!(read.is_tombstone());
+
+        // a flag of a newer version isn't taken for an older entry.
+        buf[8] |= 0x80;
+        assert!(matches!(
+            HintEntry::read_from(&mut Cursor::new(&buf), 0),
+            Err(StoreError::UnknownHintFlags { offset: 0, flags }) if flags & 0x80 != 0
+        ));
+    }
+
+    #[test]
+    fn test_read_out_of_bounds() {
+        let entry = DataEntry::new(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let mut buf = Vec::new();
+        entry.write_to(&mut Cursor::new(&mut buf)).unwrap();
+
+        // a corrupted header claiming a huge value isn't allocated.
+        let mut corrupted = buf.clone();
+        corrupted[12..16].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            DataEntry::read_from(&mut Cursor::new(&corrupted), 0),
+            Err(StoreError::EntryOutOfBounds { offset: 0, len, .. }) if len == buf.len() as u64
+        ));
+
+        // a header read from the middle of an entry, here its value.
+        let mut value = DataHeader::new(1, KEY_SZ_MASK, u32::MAX).as_ref().to_vec();
+        value.extend_from_slice(b"tail");
+        let entry = DataEntry::new(b"key".to_vec(), value).unwrap();
+        let mut buf = Vec::new();
+        entry.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let value_offset = (HEADER_SIZE + 3) as u64;
+        assert!(matches!(
+            DataEntry::read_from(&mut Cursor::new(&buf), value_offset),
+            Err(StoreError::EntryOutOfBounds { offset, .. }) if offset == value_offset
+        ));
+
+        // an entry cut short.
+        assert!(matches!(
+            DataEntry::read_from(&mut Cursor::new(&buf[..buf.len() - 1]), 0),
+            Err(StoreError::EntryOutOfBounds { .. })
+        ));
+        assert_eq!(
+            DataEntry::read_from(&mut Cursor::new(&buf), 0)
+                .unwrap()
+                .unwrap()
+                .key,
+            b"key"
+        );
+
+        // a header within the data but beyond the size limits.
+        let limits = |max_key_size, max_value_size| EntryLimits {
+            max_key_size,
+            max_value_size,
+        };
+        let value_sz = buf.len() as u64 - HEADER_SIZE as u64 - 3;
+        for (max_key_size, max_value_size) in [(2, value_sz), (3, value_sz - 1)] {
+            assert!(matches!(
+                DataEntry::read_within(
+                    &mut Cursor::new(&buf),
+                    0,
+                    limits(max_key_size, max_value_size)
+                ),
+                Err(StoreError::EntryTooLarge {
+                    offset: 0,
+                    key_sz: 3,
+                    ..
+                })
+            ));
+        }
+        assert!(
+            DataEntry::read_within(&mut Cursor::new(&buf), 0, limits(3, value_sz))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_data_entry_size_limits() {
+        let key = vec![0u8; KEY_SZ_MASK as usize];
+        assert!(DataEntry::new(key, b"value".to_vec()).is_ok());
+        let key = vec![0u8; KEY_SZ_MASK as usize + 1];
+        assert!(matches!(
+            DataEntry::new(key, b"value".to_vec()),
+            Err(StoreError::KeyIsTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_blob_pointer() {
+        let ptr = BlobPointer {
+            file_id: 3,
+            offset: 1024,
+            size: 1 << 20,
+        };
+        assert_eq!(BlobPointer::decode(&ptr.encode()).unwrap(), ptr);
+        assert!(BlobPointer::decode(b"short").is_err());
+
+        let e = DataEntry::new(b"hello".to_vec(), ptr.encode().to_vec())
+            .unwrap()
+            .blob(true)
+            .expire_at(Some(42));
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+        e.write_to(&mut cursor).unwrap();
+
+        let e = DataEntry::read_from(&mut cursor, 0).unwrap().unwrap();
+        assert!(e.is_blob());
+        assert_eq!(e.expire_at, Some(42));
+        assert_eq!(BlobPointer::decode(&e.value).unwrap(), ptr);
+    }
+
+    /// Generated data entries: key, value, timestamp and expiry.
+    type DataEntries = Vec<(Vec<u8>, Vec<u8>, u64, Option<u64>)>;
+
+    /// Generated hint entries: key, offset, value size, timestamp and
+    /// expiry.
+    type HintEntries = Vec<(Vec<u8>, u64, u32, u64, Option<u64>)>;
+
+    /// Write `entries` one after the other, return the encoded bytes and
+    /// the offset of each entry.
+    fn encode<E: EntryIO>(entries: &[E]) -> (Vec<u8>, Vec<u64>) {
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+        let offsets = entries
+            .iter()
+            .map(|e| e.write_to(&mut cursor).unwrap())
+            .collect();
+        (buf, offsets)
+    }
+
+    fn data_entry_round_trip(entries: DataEntries) -> bool {
+        // entries are never stamped zero, see `is_unwritten`.
+        let entries: Vec<DataEntry> = entries
+            .into_iter()
+            .map(|(key, value, timestamp, expire_at)| {
+                DataEntry::new(key, value)
+                    .unwrap()
+                    .with_timestamp(timestamp.max(1))
+                    .expire_at(expire_at)
+            })
+            .collect();
+        let (buf, offsets) = encode(&entries);
+        assert_eq!(
+            buf.len() as u64,
+            entries.iter().map(DataEntry::size).sum::<u64>()
+        );
+
+        let mut cursor = Cursor::new(&buf);
+        for (entry, &offset) in entries.iter().zip(offsets.iter()) {
+            let read = DataEntry::read_from(&mut cursor, offset).unwrap().unwrap();
+            assert_eq!(&read, entry);
+            assert_eq!(offset + read.size(), cursor.position());
+        }
+        DataEntry::read_from(&mut cursor, buf.len() as u64)
+            .unwrap()
+            .is_none()
+    }
+
+    fn hint_entry_round_trip(entries: HintEntries) -> bool {
+        let entries: Vec<HintEntry> = entries
+            .into_iter()
+            .map(|(key, offset, value_sz, timestamp, expire_at)| {
+                // sizes are u32 in hint files.
+                let size =
+                    ((HEADER_SIZE + key.len()) as u64 + value_sz as u64).min(u32::MAX as u64);
+                HintEntry::new(key, offset, size)
+                    .unwrap()
+                    .expire_at(expire_at)
+                    .timestamp(timestamp)
+            })
+            .collect();
+        let (buf, offsets) = encode(&entries);
+        assert_eq!(
+            buf.len() as u64,
+            entries.iter().map(HintEntry::selfsize).sum::<u64>()
+        );
+
+        let mut cursor = Cursor::new(&buf);
+        for (entry, &offset) in entries.iter().zip(offsets.iter()) {
+            let read = HintEntry::read_from(&mut cursor, offset).unwrap().unwrap();
+            assert_eq!(read.key, entry.key);
+            assert_eq!(read.offset(), entry.offset());
+            assert_eq!(read.size(), entry.size());
+            assert_eq!(read.expire_at, entry.expire_at);
+            assert_eq!(read.timestamp, entry.timestamp);
+            assert!(read.is_valid());
+            assert_eq!(offset + read.selfsize(), cursor.position());
+        }
+        HintEntry::read_from(&mut cursor, buf.len() as u64)
+            .unwrap()
+            .is_none()
+    }
+
+    #[test]
+    fn test_entry_round_trip_quickcheck() {
+        quickcheck::QuickCheck::new()
+            .tests(500)
+            .quickcheck(data_entry_round_trip as fn(DataEntries) -> bool);
+        quickcheck::QuickCheck::new()
+            .tests(500)
+            .quickcheck(hint_entry_round_trip as fn(HintEntries) -> bool);
+    }
+
+    #[test]
+    fn test_entry_round_trip_edge_cases() {
+        let max_key = vec![0xffu8; KEY_SZ_MASK as usize];
+        let cases = vec![
+            (vec![], vec![], 1, None),
+            (vec![], b"value".to_vec(), u64::MAX, Some(0)),
+            (b"key".to_vec(), vec![], 1, Some(u64::MAX)),
+            (vec![0u8; 3], vec![0u8; 3], 1, None),
+            (max_key.clone(), vec![], 42, None),
+        ];
+        assert!(data_entry_round_trip(cases));
+
+        let cases = vec![
+            (vec![], 0, 0, 0, None),
+            (
+                b"key".to_vec(),
+                u64::MAX,
+                u32::MAX,
+                u64::MAX,
+                Some(u64::MAX),
+            ),
+            (max_key, 42, 0, 42, None),
+        ];
+        assert!(hint_entry_round_trip(cases));
+    }
 }
@@ -0,0 +1,489 @@
+//! File System Module.
+//!
+//! Data, hint, blob and manifest files of a store are accessed through
+//! `FileSystem`, so that stores can live on other backends than the
+//! local disk. The store directory and its lock are always local.
+//!
+//! Files which are not append-only logs, e.g. manifest and hint files,
+//! are written to a temporary file first and renamed into place once
+//! complete, so that a crash never leaves a partially written file.
+
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::error::Result;
+
+/// Handle of an opened file.
+pub trait FileHandle: Read + Write + Seek + Debug + Send + Sync {
+    /// Flush all pending writes to the underlying storage.
+    fn sync_all(&mut self) -> io::Result<()>;
+
+    /// Return current size of the file.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncate or extend the file to `size` bytes.
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl FileHandle for File {
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+/// Metadata of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// size of the file.
+    pub len: u64,
+
+    /// last modification time, `None` if not supported.
+    pub modified: Option<SystemTime>,
+}
+
+/// File system holding store files.
+pub trait FileSystem: Debug + Send + Sync {
+    /// Open file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Open file for appending, create it if not exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Open file for writing at the position of the handle, create it if
+    /// not exists. Unlike `create`, writes don't always append, so that
+    /// the file can be extended ahead of them with `set_len`.
+    fn create_positioned(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let _ = path;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Remove file.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename file, replace `to` if exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Return paths of files matching glob `pattern`.
+    fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>>;
+
+    /// Return metadata of file, `NotFound` error if not exists.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Return `true` if file exists.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+}
+
+/// File system of the local disk, backed by `std::fs`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdFileSystem {
+    /// permissions of created files, see `with_mode`.
+    mode: Option<u32>,
+}
+
+impl StdFileSystem {
+    /// Create files with permissions `mode` on unix, ignored elsewhere.
+    pub fn with_mode(mode: u32) -> Self {
+        Self { mode: Some(mode) }
+    }
+}
+
+impl FileSystem for StdFileSystem {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let mut opts = fs::OpenOptions::new();
+        opts.create(true).append(true);
+        set_create_mode(&mut opts, self.mode);
+        Ok(Box::new(opts.open(path)?))
+    }
+
+    fn create_positioned(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let mut opts = fs::OpenOptions::new();
+        opts.create(true).truncate(false).write(true);
+        set_create_mode(&mut opts, self.mode);
+        Ok(Box::new(opts.open(path)?))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for path in glob::glob(pattern)? {
+            paths.push(path?);
+        }
+        Ok(paths)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// Make files created with `opts` get permissions `mode` on unix,
+/// if given, instead of the default `0o666`. The process umask
+/// applies on top of it.
+pub fn set_create_mode(opts: &mut fs::OpenOptions, mode: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = (opts, mode);
+}
+
+/// Create directory `path` and its missing parents, with file
+/// permissions `mode` on unix if given. Directories get execute
+/// permission wherever `mode` grants read permission, so that files
+/// in them can be reached.
+pub fn create_dir_all(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode | (mode & 0o444) >> 2);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(path)
+}
+
+/// Return the temporary path a file is written to before renamed to `path`.
+///
+/// Files are opened for appending, leftovers of a previous failed
+/// write at that path are removed.
+pub fn prepare_tmp_path(fs: &dyn FileSystem, path: &Path) -> io::Result<PathBuf> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    match fs.remove(&tmp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(tmp_path),
+    }
+}
+
+/// Write `contents` to `path` atomically, replacing it if exists.
+///
+/// Readers see either the old content or the new one, never a mix.
+pub fn write_atomic(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = prepare_tmp_path(fs, path)?;
+
+    let mut f = fs.create(&tmp_path)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    drop(f);
+
+    fs.rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::collections::BTreeMap;
+    use std::io::SeekFrom;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Content = Arc<Mutex<Vec<u8>>>;
+
+    /// In-memory file system, creating files fails on demand.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct MemFileSystem {
+        files: Arc<Mutex<BTreeMap<PathBuf, Content>>>,
+        fail_create: Arc<AtomicBool>,
+        fail_rename: Arc<AtomicBool>,
+        space: Space,
+        reads: Arc<AtomicU64>,
+        writes: Arc<AtomicU64>,
+    }
+
+    /// Bytes left to write, `None` means unlimited.
+    type Space = Arc<Mutex<Option<u64>>>;
+
+    impl MemFileSystem {
+        /// Make `create` fail with an I/O error.
+        pub(crate) fn fail_create(&self, fail: bool) {
+            self.fail_create.store(fail, Ordering::SeqCst);
+        }
+
+        /// Make `rename` fail with an I/O error, like a crash before it.
+        pub(crate) fn fail_rename(&self, fail: bool) {
+            self.fail_rename.store(fail, Ordering::SeqCst);
+        }
+
+        /// Make writes fail with `StorageFull` once `bytes` more bytes
+        /// are written, `None` lifts the limit.
+        pub(crate) fn set_space(&self, bytes: Option<u64>) {
+            *self.space.lock().unwrap() = bytes;
+        }
+
+        /// Return number of reads from all files so far.
+        pub(crate) fn reads(&self) -> u64 {
+            self.reads.load(Ordering::SeqCst)
+        }
+
+        /// Return number of writes to all files so far.
+        pub(crate) fn writes(&self) -> u64 {
+            self.writes.load(Ordering::SeqCst)
+        }
+
+        /// Return paths of all files.
+        pub(crate) fn paths(&self) -> Vec<PathBuf> {
+            self.files.lock().unwrap().keys().cloned().collect()
+        }
+
+        fn content(&self, path: &Path) -> io::Result<Content> {
+            self.files
+                .lock()
+                .unwrap()
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+
+        fn create_file(&self, path: &Path, append: bool) -> io::Result<Box<dyn FileHandle>> {
+            if self.fail_create.load(Ordering::SeqCst) {
+                return Err(io::Error::other("injected error"));
+            }
+
+            let content = self
+                .files
+                .lock()
+                .unwrap()
+                .entry(path.to_path_buf())
+                .or_default()
+                .clone();
+            let pos = if append {
+                content.lock().unwrap().len() as u64
+            } else {
+                0
+            };
+            Ok(Box::new(MemFile {
+                content,
+                pos,
+                writeable: true,
+                append,
+                space: self.space.clone(),
+                reads: self.reads.clone(),
+                writes: self.writes.clone(),
+            }))
+        }
+    }
+
+    impl FileSystem for MemFileSystem {
+        fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+            Ok(Box::new(MemFile {
+                content: self.content(path)?,
+                pos: 0,
+                writeable: false,
+                append: false,
+                space: self.space.clone(),
+                reads: self.reads.clone(),
+                writes: self.writes.clone(),
+            }))
+        }
+
+        fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+            self.create_file(path, true)
+        }
+
+        fn create_positioned(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+            self.create_file(path, false)
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.files
+                .lock()
+                .unwrap()
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            if self.fail_rename.load(Ordering::SeqCst) {
+                return Err(io::Error::other("injected error"));
+            }
+
+            let mut files = self.files.lock().unwrap();
+            let content = files
+                .remove(from)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            files.insert(to.to_path_buf(), content);
+            Ok(())
+        }
+
+        fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
+            let pattern = glob::Pattern::new(pattern)?;
+            Ok(self
+                .paths()
+                .into_iter()
+                .filter(|path| pattern.matches_path(path))
+                .collect())
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            Ok(Metadata {
+                len: self.content(path)?.lock().unwrap().len() as u64,
+                modified: None,
+            })
+        }
+    }
+
+    /// In-memory file, writes always append if `append` is set,
+    /// otherwise they overwrite from the current position.
+    #[derive(Debug)]
+    struct MemFile {
+        content: Content,
+        pos: u64,
+        writeable: bool,
+        append: bool,
+        space: Space,
+        reads: Arc<AtomicU64>,
+        writes: Arc<AtomicU64>,
+    }
+
+    impl Read for MemFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let content = self.content.lock().unwrap();
+            let start = (self.pos as usize).min(content.len());
+            let n = buf.len().min(content.len() - start);
+            buf[..n].copy_from_slice(&content[start..start + n]);
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Write for MemFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.writeable {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let mut space = self.space.lock().unwrap();
+            let n = match space.as_mut() {
+                None => buf.len(),
+                Some(0) if !buf.is_empty() => {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull));
+                }
+                Some(left) => {
+                    let n = buf.len().min(*left as usize);
+                    *left -= n as u64;
+                    n
+                }
+            };
+            let mut content = self.content.lock().unwrap();
+            if self.append {
+                self.pos = content.len() as u64;
+            }
+            let start = self.pos as usize;
+            if content.len() < start + n {
+                content.resize(start + n, 0);
+            }
+            content[start..start + n].copy_from_slice(&buf[..n]);
+            self.pos += n as u64;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MemFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let len = self.content.lock().unwrap().len() as i64;
+            let pos = match pos {
+                SeekFrom::Start(n) => n as i64,
+                SeekFrom::End(n) => len + n,
+                SeekFrom::Current(n) => self.pos as i64 + n,
+            };
+            if pos < 0 {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            self.pos = pos as u64;
+            Ok(self.pos)
+        }
+    }
+
+    impl FileHandle for MemFile {
+        fn sync_all(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.content.lock().unwrap().len() as u64)
+        }
+
+        fn set_len(&mut self, size: u64) -> io::Result<()> {
+            if !self.writeable {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            self.content.lock().unwrap().resize(size as usize, 0);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MemFileSystem;
+    use super::*;
+
+    fn read(fs: &dyn FileSystem, path: &Path) -> Vec<u8> {
+        let mut content = Vec::new();
+        fs.open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let fs = MemFileSystem::default();
+        let path = Path::new("/db/MANIFEST");
+
+        write_atomic(&fs, path, b"old").unwrap();
+        assert_eq!(read(&fs, path), b"old");
+
+        // crash between write and rename, the old file is intact.
+        fs.fail_rename(true);
+        assert!(write_atomic(&fs, path, b"new but longer").is_err());
+        assert_eq!(read(&fs, path), b"old");
+        assert!(fs.exists(Path::new("/db/MANIFEST.tmp")));
+
+        // leftovers of the failed write are discarded.
+        fs.fail_rename(false);
+        write_atomic(&fs, path, b"new").unwrap();
+        assert_eq!(read(&fs, path), b"new");
+        assert_eq!(fs.paths(), vec![PathBuf::from("/db/MANIFEST")]);
+    }
+}
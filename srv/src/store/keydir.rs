@@ -3,12 +3,18 @@
 //! Keydir in an in-memory structure that maps all keys to their
 //! corresponding locations on the disk.
 
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::ops::Bound;
+use std::str::FromStr;
 // use std::hash::Hash;
 // use std::sync::{Arc, RwLock};
 
 use super::error::Result;
 use super::format::DataEntry;
+use super::StoreOptions;
 
 /// Keydir entry.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -22,19 +28,34 @@ pub struct KeydirEntry {
     /// size of the entry in bytes.
     pub size: u64,
 
-    /// timestamp of the record.
-    pub timestamp: u32,
+    /// timestamp of the record, always the one of its data entry,
+    /// compaction and hint files preserve it.
+    pub timestamp: u64,
+
+    /// expiry of the record, in unix milliseconds.
+    pub expire_at: Option<u64>,
 }
 
 impl KeydirEntry {
-    pub fn new(file_id: u64, offset: u64, size: u64, timestamp: u32) -> Self {
+    pub fn new(file_id: u64, offset: u64, size: u64, timestamp: u64) -> Self {
         Self {
             file_id,
             offset,
             size,
             timestamp,
+            expire_at: None,
         }
     }
+
+    pub fn expire_at(mut self, expire_at: Option<u64>) -> Self {
+        self.expire_at = expire_at;
+        self
+    }
+
+    /// Return `true` if the record is expired at `now` (unix milliseconds).
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire_at, Some(t) if t <= now)
+    }
 }
 
 impl From<&DataEntry> for KeydirEntry {
@@ -44,12 +65,24 @@ impl From<&DataEntry> for KeydirEntry {
             offset: v.offset.unwrap(),
             size: v.size(),
             timestamp: v.timestamp(),
+            expire_at: v.expire_at,
         }
     }
 }
 
 /// Keydir methods.
 pub trait Keydir: Default {
+    /// Create a keydir with room for at least `capacity` keys.
+    fn with_capacity(_capacity: usize) -> Self {
+        Self::default()
+    }
+
+    /// Create the keydir of a store opened with `opts`, with room for at
+    /// least `capacity` keys.
+    fn for_store(_opts: &StoreOptions, capacity: usize) -> Self {
+        Self::with_capacity(capacity)
+    }
+
     /// Returns a reference to corresponding entry.
     fn get(&self, key: &[u8]) -> Option<&KeydirEntry>;
 
@@ -60,7 +93,25 @@ pub trait Keydir: Default {
     fn remove(&mut self, key: &[u8]);
 
     /// List all keys in the keydir.
-    fn keys(&self) -> Vec<Vec<u8>>;
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.keys_iter().map(<[u8]>::to_vec).collect()
+    }
+
+    /// List all keys in the keydir in lexicographic order, unordered
+    /// keydirs sort them.
+    fn keys_sorted(&self) -> Vec<Vec<u8>> {
+        let mut keys = self.keys();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Iterate all keys in the keydir without cloning them, in
+    /// lexicographic order for ordered keydirs.
+    fn keys_iter(&self) -> impl Iterator<Item = &[u8]>;
+
+    /// Iterate all keys and their entries without mutable access, in
+    /// lexicographic order for ordered keydirs.
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &KeydirEntry)>;
 
     /// Iterate all keys in datastore and call function `f`
     /// for each entry.
@@ -68,8 +119,8 @@ pub trait Keydir: Default {
     /// If function `f` returns an `Err`, it stops iteration
     /// and propagates the `Err` to the caller.
     ///
-    /// You can continue iteration manually by return `Ok(true)`,
-    /// or stop iteration by returning `Ok(false)`.
+    /// You can stop iteration manually by returning `Ok(true)`,
+    /// or continue iteration by returning `Ok(false)`.
     fn for_each<F>(&mut self, f: &mut F) -> Result<()>
     where
         F: FnMut(&Vec<u8>, &mut KeydirEntry) -> Result<bool>;
@@ -77,20 +128,68 @@ pub trait Keydir: Default {
     /// length of the keys in the keydir
     fn len(&self) -> u64;
 
+    /// Return `true` if keydir contains no keys.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Return `true` if datastore contains the given key.
     fn contains_key(&self, key: &[u8]) -> bool;
+
+    /// Return the smallest key in lexicographic order.
+    fn first_key(&self) -> Option<Vec<u8>>;
+
+    /// Return the largest key in lexicographic order.
+    fn last_key(&self) -> Option<Vec<u8>>;
+
+    /// Return at most `limit` keys starting with `prefix` and greater
+    /// than `after`, in lexicographic order.
+    fn keys_with_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>>;
 }
 
-/// Keydir represented as a hashmap.
-#[derive(Debug, Default)]
-pub struct HashmapKeydir {
+/// Keydir represented as a hashmap, keys are hashed with `S`.
+#[derive(Default)]
+pub struct HashmapKeydir<S = RandomState> {
     /// mapping from a key to its keydir entry.
-    mapping: HashMap<Vec<u8>, KeydirEntry>,
+    mapping: HashMap<Vec<u8>, KeydirEntry, S>,
     // with rwlock
     // rwlock: Arc<RwLock<()>>,
 }
 
-impl Keydir for HashmapKeydir {
+/// Hasher of the store keydir, the Fx hash with the `fxhash` feature,
+/// see `FxHashmapKeydir` for the risk.
+#[cfg(feature = "fxhash")]
+pub type KeydirHasher = rustc_hash::FxBuildHasher;
+#[cfg(not(feature = "fxhash"))]
+pub type KeydirHasher = RandomState;
+
+/// Hashmap keydir using the fast, non-randomized Fx hash, keys must
+/// not be chosen by untrusted clients.
+#[cfg(feature = "fxhash")]
+pub type FxHashmapKeydir = HashmapKeydir<rustc_hash::FxBuildHasher>;
+
+impl<S> fmt::Debug for HashmapKeydir<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashmapKeydir")
+            .field("mapping", &self.mapping)
+            .finish()
+    }
+}
+
+impl<S> HashmapKeydir<S> {
+    /// Number of keys the keydir can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.mapping.capacity()
+    }
+}
+
+impl<S: BuildHasher + Default> Keydir for HashmapKeydir<S> {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            mapping: HashMap::with_capacity_and_hasher(capacity, S::default()),
+        }
+    }
+
     fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
         // let _read_lock = self.rwlock.read().unwrap();
         self.mapping.get(key)
@@ -113,9 +212,99 @@ impl Keydir for HashmapKeydir {
         self.mapping.remove(key);
     }
 
-    fn keys(&self) -> Vec<Vec<u8>> {
-        // let _read_lock = self.rwlock.read().unwrap();
-        self.mapping.keys().cloned().collect()
+    fn keys_iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.mapping.keys().map(Vec::as_slice)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &KeydirEntry)> {
+        self.mapping.iter().map(|(k, v)| (k.as_slice(), v))
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&Vec<u8>, &mut KeydirEntry) -> Result<bool>,
+    {
+        for (k, v) in self.mapping.iter_mut() {
+            if f(k, v)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.mapping.len() as u64
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.mapping.contains_key(key)
+    }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        // unordered, scan all keys.
+        self.mapping.keys().min().cloned()
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        self.mapping.keys().max().cloned()
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        // unordered, select and sort the smallest matching keys, only
+        // returned ones are cloned.
+        let mut keys: Vec<_> = self
+            .mapping
+            .keys()
+            .filter(|k| k.starts_with(prefix) && after.is_none_or(|a| k.as_slice() > a))
+            .collect();
+        if limit < keys.len() {
+            keys.select_nth_unstable(limit);
+            keys.truncate(limit);
+        }
+        keys.sort_unstable();
+        keys.into_iter().cloned().collect()
+    }
+}
+
+/// Keydir represented as a btreemap, keys are kept in lexicographic order.
+#[derive(Debug, Default)]
+pub struct BTreeMapKeydir {
+    /// mapping from a key to its keydir entry.
+    mapping: BTreeMap<Vec<u8>, KeydirEntry>,
+}
+
+impl Keydir for BTreeMapKeydir {
+    fn keys_sorted(&self) -> Vec<Vec<u8>> {
+        // already ordered.
+        self.keys()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
+        self.mapping.get(key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: KeydirEntry) -> &KeydirEntry {
+        self.mapping
+            .entry(key)
+            .and_modify(|e| {
+                if e.timestamp <= entry.timestamp {
+                    *e = entry.clone();
+                }
+            })
+            .or_insert(entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.mapping.remove(key);
+    }
+
+    fn keys_iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.mapping.keys().map(Vec::as_slice)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &KeydirEntry)> {
+        self.mapping.iter().map(|(k, v)| (k.as_slice(), v))
     }
 
     fn for_each<F>(&mut self, f: &mut F) -> Result<()>
@@ -138,6 +327,137 @@ impl Keydir for HashmapKeydir {
     fn contains_key(&self, key: &[u8]) -> bool {
         self.mapping.contains_key(key)
     }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        self.mapping.keys().next().cloned()
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        self.mapping.keys().next_back().cloned()
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        self.mapping
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Built-in keydir implementations a store can be opened with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeydirKind {
+    /// `HashmapKeydir`, fastest lookups.
+    #[default]
+    Hash,
+
+    /// `BTreeMapKeydir`, cheap ordered listing.
+    BTree,
+}
+
+impl FromStr for KeydirKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(KeydirKind::Hash),
+            "btree" => Ok(KeydirKind::BTree),
+            _ => Err(format!("invalid keydir `{s}`")),
+        }
+    }
+}
+
+/// Keydir of the kind selected by `StoreOptions::keydir`.
+#[derive(Debug)]
+pub enum AnyKeydir {
+    Hash(HashmapKeydir<KeydirHasher>),
+    BTree(BTreeMapKeydir),
+}
+
+impl Default for AnyKeydir {
+    fn default() -> Self {
+        AnyKeydir::Hash(HashmapKeydir::default())
+    }
+}
+
+/// Evaluate `$e` with `$k` bound to the keydir inside `$keydir`.
+macro_rules! dispatch {
+    ($keydir:expr, $k:ident => $e:expr) => {
+        match $keydir {
+            AnyKeydir::Hash($k) => $e,
+            AnyKeydir::BTree($k) => $e,
+        }
+    };
+}
+
+impl Keydir for AnyKeydir {
+    fn for_store(opts: &StoreOptions, capacity: usize) -> Self {
+        match opts.keydir {
+            KeydirKind::Hash => AnyKeydir::Hash(HashmapKeydir::with_capacity(capacity)),
+            KeydirKind::BTree => AnyKeydir::BTree(BTreeMapKeydir::with_capacity(capacity)),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
+        dispatch!(self, k => k.get(key))
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: KeydirEntry) -> &KeydirEntry {
+        dispatch!(self, k => k.put(key, entry))
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        dispatch!(self, k => k.remove(key))
+    }
+
+    fn keys_sorted(&self) -> Vec<Vec<u8>> {
+        dispatch!(self, k => k.keys_sorted())
+    }
+
+    fn keys_iter(&self) -> impl Iterator<Item = &[u8]> {
+        let keys: Box<dyn Iterator<Item = &[u8]>> = dispatch!(self, k => Box::new(k.keys_iter()));
+        keys
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &KeydirEntry)> {
+        let entries: Box<dyn Iterator<Item = (&[u8], &KeydirEntry)>> =
+            dispatch!(self, k => Box::new(k.iter()));
+        entries
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&Vec<u8>, &mut KeydirEntry) -> Result<bool>,
+    {
+        dispatch!(self, k => k.for_each(f))
+    }
+
+    fn len(&self) -> u64 {
+        dispatch!(self, k => k.len())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        dispatch!(self, k => k.contains_key(key))
+    }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        dispatch!(self, k => k.first_key())
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        dispatch!(self, k => k.last_key())
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        dispatch!(self, k => k.keys_with_prefix(prefix, after, limit))
+    }
 }
 
 #[cfg(test)]
@@ -146,9 +466,140 @@ mod tests {
 
     #[test]
     fn test_insert_if_newer_inserts_when_nonexistent() {
-        let mut k = HashmapKeydir::default();
+        let mut k = <HashmapKeydir>::default();
         let entry = KeydirEntry::new(0, 42, 0, 0);
         let e = k.put(b"foo".to_vec(), entry.clone());
         assert!(e == &entry, "Expected {:?}, got {:?}", &entry, e);
     }
+
+    #[test]
+    fn test_first_and_last_key() {
+        fn check<K: Keydir>() {
+            let mut k = K::default();
+            assert_eq!(k.first_key(), None);
+            assert_eq!(k.last_key(), None);
+
+            for key in [b"m".as_ref(), b"z", b"a", b"ab"] {
+                k.put(key.to_vec(), KeydirEntry::new(0, 0, 0, 0));
+            }
+            assert_eq!(k.first_key(), Some(b"a".to_vec()));
+            assert_eq!(k.last_key(), Some(b"z".to_vec()));
+        }
+
+        check::<HashmapKeydir>();
+        #[cfg(feature = "fxhash")]
+        check::<FxHashmapKeydir>();
+        check::<BTreeMapKeydir>();
+    }
+
+    #[test]
+    fn test_keys_iter() {
+        fn check<K: Keydir>() -> Vec<Vec<u8>> {
+            let mut k = K::default();
+            for i in 0..10000u32 {
+                k.put(i.to_be_bytes().to_vec(), KeydirEntry::new(0, 0, 0, 0));
+            }
+
+            // keys are borrowed, only the taken ones are cloned.
+            let keys: Vec<Vec<u8>> = k.keys_iter().take(5).map(<[u8]>::to_vec).collect();
+            assert_eq!(keys.len(), 5);
+            assert!(keys.iter().all(|key| k.contains_key(key)));
+            assert_eq!(k.keys_iter().count(), 10000);
+            keys
+        }
+
+        check::<HashmapKeydir>();
+        let keys = check::<BTreeMapKeydir>();
+        let expected: Vec<Vec<u8>> = (0..5u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_keys_sorted() {
+        fn check<K: Keydir>() {
+            let mut k = K::default();
+            let keys: [&[u8]; 7] = [b"ab", b"", b"\xff", b"a", b"a\x00", b"b", b"\x00"];
+            for key in keys {
+                k.put(key.to_vec(), KeydirEntry::new(0, 0, 0, 0));
+            }
+
+            // a key sorts before the keys it's a prefix of.
+            let expected: Vec<Vec<u8>> =
+                [b"".as_ref(), b"\x00", b"a", b"a\x00", b"ab", b"b", b"\xff"]
+                    .iter()
+                    .map(|k| k.to_vec())
+                    .collect();
+            assert_eq!(k.keys_sorted(), expected);
+        }
+
+        check::<HashmapKeydir>();
+        check::<BTreeMapKeydir>();
+    }
+
+    #[test]
+    fn test_keys_with_prefix() {
+        fn check<K: Keydir>() {
+            let mut k = K::default();
+            for key in [b"a".as_ref(), b"b", b"ba", b"bb", b"bc", b"c"] {
+                k.put(key.to_vec(), KeydirEntry::new(0, 0, 0, 0));
+            }
+
+            let keys = |prefix: &[u8], after: Option<&[u8]>, limit| {
+                k.keys_with_prefix(prefix, after, limit)
+            };
+            assert_eq!(
+                keys(b"b", None, 10),
+                vec![
+                    b"b".to_vec(),
+                    b"ba".to_vec(),
+                    b"bb".to_vec(),
+                    b"bc".to_vec()
+                ]
+            );
+            assert_eq!(keys(b"b", None, 2), vec![b"b".to_vec(), b"ba".to_vec()]);
+            assert_eq!(
+                keys(b"b", Some(b"ba"), 2),
+                vec![b"bb".to_vec(), b"bc".to_vec()]
+            );
+            assert_eq!(keys(b"b", Some(b"a"), 1), vec![b"b".to_vec()]);
+            assert!(keys(b"b", Some(b"bc"), 10).is_empty());
+            assert_eq!(keys(b"", Some(b"bc"), 10), vec![b"c".to_vec()]);
+        }
+
+        check::<HashmapKeydir>();
+        #[cfg(feature = "fxhash")]
+        check::<FxHashmapKeydir>();
+        check::<BTreeMapKeydir>();
+    }
+
+    /// Compare lookup throughput of the default and the Fx hasher, run
+    /// with `cargo test --release --features fxhash -- --ignored`.
+    #[cfg(feature = "fxhash")]
+    #[test]
+    #[ignore]
+    fn bench_hasher_lookups() {
+        use std::time::Instant;
+
+        fn lookups<K: Keydir>(keys: &[Vec<u8>]) -> f64 {
+            let mut k = K::with_capacity(keys.len());
+            for key in keys {
+                k.put(key.clone(), KeydirEntry::new(0, 0, 0, 0));
+            }
+
+            let start = Instant::now();
+            let mut found = 0;
+            for _ in 0..10 {
+                found += keys.iter().filter(|key| k.get(key).is_some()).count();
+            }
+            assert_eq!(found, keys.len() * 10);
+            found as f64 / start.elapsed().as_secs_f64()
+        }
+
+        let keys: Vec<Vec<u8>> = (0..1_000_000u64)
+            .map(|i| format!("user:{i:016}").into_bytes())
+            .collect();
+        let default = lookups::<HashmapKeydir>(&keys);
+        let fx = lookups::<FxHashmapKeydir>(&keys);
+        assert!(fx > default, "lookups/s: default {default:.0}, fx {fx:.0}");
+    }
 }
@@ -0,0 +1,270 @@
+//! Placement of segment files in a store directory.
+
+use std::path::{Path, PathBuf};
+
+use super::error::{Result, StoreError};
+use super::settings;
+
+/// Where data, hint and blob files of a store live, relative to the
+/// store directory, and the suffixes of their names. The lock file and
+/// the manifest always stay at the top of the store directory.
+///
+/// Defaults to all files directly in the store directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// subdirectory of data and blob files.
+    data_dir: PathBuf,
+
+    /// subdirectory of hint files.
+    hint_dir: PathBuf,
+
+    data_suffix: String,
+
+    hint_suffix: String,
+
+    blob_suffix: String,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::new(),
+            hint_dir: PathBuf::new(),
+            data_suffix: settings::DATA_FILE_SUFFIX.to_owned(),
+            hint_suffix: settings::HINT_FILE_SUFFIX.to_owned(),
+            blob_suffix: settings::BLOB_FILE_SUFFIX.to_owned(),
+        }
+    }
+}
+
+impl Layout {
+    /// Put data and blob files in `dir`, relative to the store directory.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = dir.into();
+        self
+    }
+
+    /// Put hint files in `dir`, relative to the store directory, e.g. to
+    /// leave them out of backups.
+    pub fn hint_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.hint_dir = dir.into();
+        self
+    }
+
+    /// Name data, hint and blob files `<file id><suffix>`.
+    pub fn suffixes(mut self, data: &str, hint: &str, blob: &str) -> Self {
+        self.data_suffix = data.to_owned();
+        self.hint_suffix = hint.to_owned();
+        self.blob_suffix = blob.to_owned();
+        self
+    }
+
+    /// Return `StoreError::InvalidLayout` if files of different kinds
+    /// could be mistaken for one another, or could escape the store
+    /// directory.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(StoreError::InvalidLayout(reason.to_owned()));
+
+        for dir in [&self.data_dir, &self.hint_dir] {
+            if dir.is_absolute() || dir.components().any(|c| c.as_os_str() == "..") {
+                return invalid("directories must be inside the store directory");
+            }
+        }
+        let suffixes = [&self.data_suffix, &self.hint_suffix, &self.blob_suffix];
+        // file ids are parsed up to the first dot.
+        if suffixes
+            .iter()
+            .any(|s| !s.starts_with('.') || s.contains(['/', '*']))
+        {
+            return invalid("suffixes must start with a dot");
+        }
+        // files are found by suffix, a suffix ending with another one
+        // would find files of the other kind too.
+        let clash = |a: &str, b: &str| a.ends_with(b) || b.ends_with(a);
+        if clash(&self.data_suffix, &self.blob_suffix)
+            || (self.data_dir == self.hint_dir
+                && (clash(&self.hint_suffix, &self.data_suffix)
+                    || clash(&self.hint_suffix, &self.blob_suffix)))
+        {
+            return invalid("files of different kinds must not share a suffix");
+        }
+        // nor files of a store with the default layout in the directory.
+        let default = Self::default();
+        let default_suffixes = [
+            &default.data_suffix,
+            &default.hint_suffix,
+            &default.blob_suffix,
+        ];
+        if suffixes
+            .iter()
+            .any(|s| default_suffixes.iter().any(|d| s != d && clash(s, d)))
+        {
+            return invalid("suffixes must not end with default suffixes or the other way round");
+        }
+        Ok(())
+    }
+
+    /// Return `true` for the flat layout of earlier versions.
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Return `true` if files are named as in the default layout.
+    pub(crate) fn has_default_suffixes(&self) -> bool {
+        let default = Self::default();
+        self.data_suffix == default.data_suffix
+            && self.hint_suffix == default.hint_suffix
+            && self.blob_suffix == default.blob_suffix
+    }
+
+    /// Return directories of data and hint files in store directory `dir`.
+    pub(crate) fn dirs(&self, dir: &Path) -> [PathBuf; 2] {
+        [dir.join(&self.data_dir), dir.join(&self.hint_dir)]
+    }
+
+    pub(crate) fn data_file_path(&self, dir: &Path, file_id: u64) -> PathBuf {
+        file_path(&dir.join(&self.data_dir), file_id, &self.data_suffix)
+    }
+
+    pub(crate) fn hint_file_path(&self, dir: &Path, file_id: u64) -> PathBuf {
+        file_path(&dir.join(&self.hint_dir), file_id, &self.hint_suffix)
+    }
+
+    pub(crate) fn blob_file_path(&self, dir: &Path, file_id: u64) -> PathBuf {
+        file_path(&dir.join(&self.data_dir), file_id, &self.blob_suffix)
+    }
+
+    /// Return file id of data file `path`, `None` unless it's named
+    /// exactly as `data_file_path` names it.
+    pub(crate) fn data_file_id(&self, path: &Path) -> Option<u64> {
+        file_id(path, &self.data_suffix)
+    }
+
+    pub(crate) fn hint_file_id(&self, path: &Path) -> Option<u64> {
+        file_id(path, &self.hint_suffix)
+    }
+
+    pub(crate) fn blob_file_id(&self, path: &Path) -> Option<u64> {
+        file_id(path, &self.blob_suffix)
+    }
+
+    /// Return glob patterns of data, hint and blob files in store
+    /// directory `dir`.
+    pub(crate) fn data_file_pattern(&self, dir: &Path) -> String {
+        pattern(&dir.join(&self.data_dir), &self.data_suffix)
+    }
+
+    pub(crate) fn hint_file_pattern(&self, dir: &Path) -> String {
+        pattern(&dir.join(&self.hint_dir), &self.hint_suffix)
+    }
+
+    pub(crate) fn blob_file_pattern(&self, dir: &Path) -> String {
+        pattern(&dir.join(&self.data_dir), &self.blob_suffix)
+    }
+}
+
+fn file_path(dir: &Path, file_id: u64, suffix: &str) -> PathBuf {
+    dir.join(format!("{:06}{}", file_id, suffix))
+}
+
+fn file_id(path: &Path, suffix: &str) -> Option<u64> {
+    let name = path.file_name()?.to_str()?.strip_suffix(suffix)?;
+    let file_id = name.parse().ok()?;
+    (format!("{:06}", file_id) == name).then_some(file_id)
+}
+
+fn pattern(dir: &Path, suffix: &str) -> String {
+    dir.join(format!("*{}", suffix)).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_paths() {
+        let dir = Path::new("/db");
+        let layout = Layout::default();
+        assert!(layout.is_default());
+        assert_eq!(
+            layout.data_file_path(dir, 1),
+            Path::new("/db/000001.tinkv.data")
+        );
+        assert_eq!(layout.hint_file_pattern(dir), "/db/*.tinkv.hint");
+
+        let layout = Layout::default()
+            .data_dir("data")
+            .hint_dir("hints")
+            .suffixes(".d", ".h", ".b");
+        assert!(layout.validate().is_ok());
+        assert_eq!(
+            layout.data_file_path(dir, 7),
+            Path::new("/db/data/000007.d")
+        );
+        assert_eq!(
+            layout.hint_file_path(dir, 7),
+            Path::new("/db/hints/000007.h")
+        );
+        assert_eq!(
+            layout.blob_file_path(dir, 7),
+            Path::new("/db/data/000007.b")
+        );
+        assert_eq!(layout.data_file_pattern(dir), "/db/data/*.d");
+
+        // only names as given by the layout have a file id.
+        assert_eq!(layout.data_file_id(Path::new("/db/data/000007.d")), Some(7));
+        assert_eq!(
+            layout.blob_file_id(Path::new("/db/data/1234567.b")),
+            Some(1234567)
+        );
+        for name in [
+            "000007.h",
+            "000007.old.d",
+            "7.d",
+            "0000007.d",
+            "+00007.d",
+            "x.d",
+        ] {
+            assert_eq!(layout.data_file_id(Path::new(name)), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_layout_validate() {
+        assert!(Layout::default().validate().is_ok());
+        assert!(Layout::default().data_dir("/abs").validate().is_err());
+        assert!(Layout::default().hint_dir("../out").validate().is_err());
+        assert!(Layout::default()
+            .suffixes("data", ".h", ".b")
+            .validate()
+            .is_err());
+        assert!(Layout::default()
+            .suffixes(".d", ".d", ".b")
+            .validate()
+            .is_err());
+        // hint files in their own directory may share the data suffix.
+        assert!(Layout::default()
+            .hint_dir("hints")
+            .suffixes(".d", ".d", ".b")
+            .validate()
+            .is_ok());
+        // blob files would be found as data files.
+        assert!(Layout::default()
+            .suffixes(".d", ".h", ".b.d")
+            .validate()
+            .is_err());
+        // files of a store with the default layout would be found.
+        assert!(Layout::default()
+            .suffixes(".data", ".h", ".b")
+            .validate()
+            .is_err());
+        assert!(Layout::default()
+            .suffixes(".d", ".v2.tinkv.hint", ".b")
+            .validate()
+            .is_err());
+        assert!(Layout::default()
+            .suffixes(".v2.data", ".v2.hint", ".v2.blob")
+            .validate()
+            .is_ok());
+    }
+}
@@ -1,10 +1,17 @@
 //! Lockfile implementation.
 
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
+use log::warn;
+
+use super::error::{Result, StoreError};
+use super::fs::{create_dir_all, set_create_mode};
+
 /// A simple lockfile for `DistStorage`.
+///
+/// The lockfile holds PID of the process owning the lock.
 #[derive(Debug)]
 pub struct Lockfile {
     handle: Option<File>,
@@ -12,17 +19,31 @@ pub struct Lockfile {
 }
 
 impl Lockfile {
-    /// Creates a lock at the provided `path`. Fails if lock is already exists.
-    pub fn lock(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+    /// Creates a lock at the provided `path`, with permissions `mode`
+    /// on unix if given. Fails with `StoreError::AlreadyLocked` if lock
+    /// is already exists.
+    pub fn lock(path: impl AsRef<Path>, mode: Option<u32>) -> Result<Self> {
         let path = path.as_ref();
 
         let dir_path = path.parent().expect("lock file must have a parent");
-        fs::create_dir_all(dir_path)?;
+        create_dir_all(dir_path, mode)?;
 
         let mut lockfile_opts = fs::OpenOptions::new();
         lockfile_opts.read(true).write(true).create_new(true);
+        set_create_mode(&mut lockfile_opts, mode);
 
-        let lockfile = lockfile_opts.open(path)?;
+        let mut lockfile = match lockfile_opts.open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let pid = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|s| s.trim().parse().ok());
+                return Err(StoreError::AlreadyLocked { pid });
+            }
+            Err(e) => return Err(e.into()),
+        };
+        lockfile.write_all(std::process::id().to_string().as_bytes())?;
+        lockfile.sync_all()?;
 
         Ok(Self {
             handle: Some(lockfile),
@@ -32,8 +53,18 @@ impl Lockfile {
 }
 
 impl Drop for Lockfile {
+    /// Remove the lock file, a lock file or directory removed by someone
+    /// else only logs a warning, dropping may happen while unwinding.
     fn drop(&mut self) {
         self.handle.take();
-        fs::remove_file(&self.path).expect("lock already dropped.");
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("lock file {} was already removed", self.path.display());
+            }
+            Err(e) => {
+                warn!("failed to remove lock file {}: {}", self.path.display(), e);
+            }
+        }
     }
 }
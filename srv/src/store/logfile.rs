@@ -1,13 +1,14 @@
 //! Data File Module.
 
-use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use log::{error, trace};
 
 use super::error::{Result, StoreError};
-use super::format::{DataEntry, EntryIO, HintEntry};
+use super::format::{DataEntry, DataHeader, EntryIO, EntryLimits, HintEntry};
+use super::fs::{FileHandle, FileSystem};
 
 use crate::utils::path::parse_file_id;
 
@@ -22,42 +23,151 @@ pub struct LogFile {
     /// Mark current data file can be writable or not.
     writeable: bool,
 
+    /// File system holding the file.
+    fs: Arc<dyn FileSystem>,
+
     /// File handle of data file for writing.
-    writer: Option<File>,
+    writer: Option<WriteBuffer>,
+
+    /// File handle of data file for reading, opened lazily.
+    reader: Option<Box<dyn FileHandle>>,
+
+    /// End of the written entries, the file being written may be
+    /// preallocated beyond it.
+    write_offset: u64,
+
+    /// Remove the file on drop if it's writeable and empty.
+    remove_empty_on_drop: bool,
 
-    /// File handle of data file for reading.
-    reader: File,
+    /// Shared with read-only twins of the file, it's never removed on
+    /// drop while one of them is alive.
+    refs: Arc<()>,
 }
 
 impl LogFile {
-    pub fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
+    pub fn new(fs: &Arc<dyn FileSystem>, path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
         let path = path.as_ref();
 
         // Data name must starts with valid file id.
-        let file_id = parse_file_id(path).expect("file id not found in file path");
+        let file_id =
+            parse_file_id(path).ok_or_else(|| StoreError::InvalidFileName(path.to_path_buf()))?;
 
         let writer = if writeable {
-            let f = fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(path)?;
-            Some(f)
+            Some(WriteBuffer::new(fs.create(path)?)?)
         } else {
             None
         };
-
-        let reader = fs::File::open(path)?;
+        let write_offset = match &writer {
+            Some(w) => w.size()?,
+            None => 0,
+        };
 
         Ok(Self {
             path: path.to_path_buf(),
             id: file_id,
             writeable,
+            fs: fs.clone(),
             writer,
-            reader,
+            reader: None,
+            write_offset,
+            remove_empty_on_drop: false,
+            refs: Arc::new(()),
+        })
+    }
+
+    /// Open file for writing and extend it to `len` bytes, so that disk
+    /// space is reserved ahead of writes. Entries are written from the
+    /// end of its current content on, and the unwritten space is cut
+    /// off once the file is sealed or dropped.
+    ///
+    /// Fall back to `LogFile::new` if `fs` can't preallocate files.
+    pub fn preallocated(
+        fs: &Arc<dyn FileSystem>,
+        path: impl AsRef<Path>,
+        len: u64,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let file_id =
+            parse_file_id(path).ok_or_else(|| StoreError::InvalidFileName(path.to_path_buf()))?;
+        let mut w = match fs.create_positioned(path) {
+            Ok(w) => w,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Self::new(fs, path, true),
+            Err(e) => return Err(e.into()),
+        };
+
+        let write_offset = w.size()?;
+        if write_offset < len {
+            w.set_len(len)?;
+        }
+        w.seek(SeekFrom::Start(write_offset))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            id: file_id,
+            writeable: true,
+            fs: fs.clone(),
+            writer: Some(WriteBuffer::new(w)?),
+            reader: None,
+            write_offset,
+            remove_empty_on_drop: false,
+            refs: Arc::new(()),
         })
     }
 
+    /// Return a read-only `LogFile` of the same file, the file isn't
+    /// removed on drop while the twin is alive.
+    pub fn read_only_twin(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            id: self.id,
+            writeable: false,
+            fs: self.fs.clone(),
+            writer: None,
+            reader: None,
+            write_offset: 0,
+            remove_empty_on_drop: false,
+            refs: self.refs.clone(),
+        }
+    }
+
+    /// Open the file for appending in place, keeping its reader, so that
+    /// a file loaded read-only becomes writeable without a second
+    /// `LogFile` of the same path.
+    pub fn make_writeable(&mut self) -> Result<()> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+
+        let w = self.fs.create(&self.path)?;
+        self.write_offset = w.size()?;
+        self.writer = Some(WriteBuffer::new(w)?);
+        self.writeable = true;
+        Ok(())
+    }
+
+    /// Return file handle for reading, open it if closed.
+    pub fn reader(&mut self) -> Result<&mut Box<dyn FileHandle>> {
+        if self.reader.is_none() {
+            trace!("open reader of log file {}", self.path.display());
+            self.reader = Some(self.fs.open(&self.path)?);
+        }
+        Ok(self.reader.as_mut().unwrap())
+    }
+
+    /// Return file handle for reading after flushing buffered writes,
+    /// so that it sees all of them.
+    pub fn flushed_reader(&mut self) -> Result<&mut Box<dyn FileHandle>> {
+        self.flush()?;
+        self.reader()
+    }
+
+    /// Close file handle for reading, it will be reopened on next read.
+    pub fn close_reader(&mut self) {
+        if self.reader.take().is_some() {
+            trace!("close reader of log file {}", self.path.display());
+        }
+    }
+
     /// Flush all pending writes to disk.
     pub fn sync(&mut self) -> Result<()> {
         self.flush()?;
@@ -67,6 +177,16 @@ impl LogFile {
         Ok(())
     }
 
+    /// Sync and close file handle for writing, the file is read-only
+    /// afterwards.
+    pub fn seal(&mut self) -> Result<()> {
+        self.trim()?;
+        self.sync()?;
+        self.writer = None;
+        self.writeable = false;
+        Ok(())
+    }
+
     /// Flush buf writer.
     fn flush(&mut self) -> Result<()> {
         if self.writeable {
@@ -75,22 +195,64 @@ impl LogFile {
         Ok(())
     }
 
-    /// file size.
+    /// Pass the tail of the entry written from `start` on to the file
+    /// if its head is there already, so that each entry is either
+    /// buffered or in the file as a whole, see `buffered_at`.
+    fn settle_entry(&mut self, start: u64) -> io::Result<()> {
+        match &mut self.writer {
+            Some(w) if start < w.base && !w.buf.is_empty() => w.flush_buf(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return buffered writes and their offset in the file if `offset`
+    /// is among them, so that they're read without flushing them.
+    fn buffered_at(&self, offset: u64) -> Option<(u64, &[u8])> {
+        let w = self.writer.as_ref()?;
+        let end = w.base + w.buf.len() as u64;
+        (offset >= w.base && offset < end).then_some((w.base, w.buf.as_slice()))
+    }
+
+    /// Cut off space preallocated beyond the written entries.
+    fn trim(&mut self) -> Result<()> {
+        if let Some(w) = &mut self.writer {
+            if w.size()? > self.write_offset {
+                w.set_len(self.write_offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// file size, for a file being written it's the end of the written
+    /// entries rather than its preallocated length.
     pub fn size(&self) -> Result<u64> {
-        Ok(self.reader.metadata()?.len())
+        let size = match (&self.writer, &self.reader) {
+            (Some(_), _) => self.write_offset,
+            (None, Some(f)) => f.size()?,
+            (None, None) => self.fs.metadata(&self.path)?.len,
+        };
+        Ok(size)
     }
 
     pub fn copy_bytes_from(&mut self, src: &mut LogFile, offset: u64, size: u64) -> Result<u64> {
-        let w = self.writer.as_mut().expect("data file is not writeable");
+        let w = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| StoreError::FileNotWriteable(self.path.clone()))?;
 
-        let r = &mut src.reader;
+        let r = src.flushed_reader()?;
         r.seek(SeekFrom::Start(offset))?;
 
         let mut r = r.take(size);
-        let w_offset = w.stream_position()?;
+        let w_offset = w.seek(SeekFrom::Start(self.write_offset))?;
 
         let num_types = io::copy(&mut r, w)?;
-        assert_eq!(num_types, size);
+        self.write_offset += num_types;
+        if num_types != size {
+            // the source was cut short, e.g. truncated behind our back.
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.settle_entry(w_offset)?;
 
         Ok(w_offset)
     }
@@ -98,6 +260,13 @@ impl LogFile {
 
 impl Drop for LogFile {
     fn drop(&mut self) {
+        if let Err(e) = self.trim() {
+            error!(
+                "failed to trim log file: {}, got error: {}",
+                self.path.display(),
+                e
+            );
+        }
         if let Err(e) = self.sync() {
             error!(
                 "failed to sync log file: {}, got error: {}",
@@ -106,12 +275,144 @@ impl Drop for LogFile {
             );
         }
 
-        // auto clean up if file size is zero.
-        if self.writeable && self.size().unwrap() == 0 {
-            trace!("log file `{}` is empty, remove it.", self.path.display());
+        // clean up if file size is zero, only if asked for, only the
+        // writer does it and only once no twin refers to the file. The
+        // file may be removed already, e.g. by compaction.
+        if !self.writeable || !self.remove_empty_on_drop || Arc::strong_count(&self.refs) > 1 {
+            return;
+        }
+        match self.size() {
+            Ok(0) => {
+                trace!("log file `{}` is empty, remove it.", self.path.display());
+                match self.fs.remove(self.path.as_path()) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => error!(
+                        "failed to remove empty log file: {}, got error: {}",
+                        self.path.display(),
+                        e
+                    ),
+                    _ => {}
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!(
+                "failed to get size of log file: {}, got error: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Handle of a file being written, holding up to `capacity` bytes of
+/// writes in memory so that small entries coalesce into fewer writes to
+/// the file. Seeking anywhere but to the end of the buffered writes, and
+/// reading, flush them first.
+#[derive(Debug)]
+struct WriteBuffer {
+    inner: Box<dyn FileHandle>,
+
+    /// Writes not passed to `inner` yet.
+    buf: Vec<u8>,
+
+    /// Zero writes through.
+    capacity: usize,
 
-            fs::remove_file(self.path.as_path()).unwrap();
+    /// Offset of the first buffered byte, i.e. position of `inner`.
+    base: u64,
+}
+
+impl WriteBuffer {
+    fn new(mut inner: Box<dyn FileHandle>) -> Result<Self> {
+        let base = inner.stream_position()?;
+        Ok(Self {
+            inner,
+            buf: Vec::new(),
+            capacity: 0,
+            base,
+        })
+    }
+
+    /// Pass buffered writes to `inner`, those written before a failure
+    /// are dropped from the buffer, the rest are retried on next flush.
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let res = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.buf[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.drain(..written);
+        self.base += written as u64;
+        res
+    }
+}
+
+impl Write for WriteBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.capacity {
+            self.flush_buf()?;
         }
+        if data.len() >= self.capacity {
+            let n = self.inner.write(data)?;
+            self.base += n as u64;
+            return Ok(n);
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl Read for WriteBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush_buf()?;
+        let n = self.inner.read(buf)?;
+        self.base += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for WriteBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.base + self.buf.len() as u64;
+        match pos {
+            SeekFrom::Start(offset) if offset == end => return Ok(end),
+            SeekFrom::Current(0) => return Ok(end),
+            _ => {}
+        }
+        self.flush_buf()?;
+        self.base = self.inner.seek(pos)?;
+        Ok(self.base)
+    }
+}
+
+impl FileHandle for WriteBuffer {
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.sync_all()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let end = self.base + self.buf.len() as u64;
+        Ok(self.inner.size()?.max(end))
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        // buffered writes beyond `size` are discarded, not written.
+        let keep = size.saturating_sub(self.base) as usize;
+        self.buf.truncate(keep);
+        self.flush_buf()?;
+        self.inner.set_len(size)
     }
 }
 
@@ -119,13 +420,37 @@ impl Drop for LogFile {
 #[derive(Debug)]
 pub struct DataFile {
     inner: LogFile,
+    limits: EntryLimits,
+
+    /// Number of entries, those loaded on open and written since.
+    entries: u64,
 }
 
 impl DataFile {
-    pub fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
-        let inner = LogFile::new(path, writeable)?;
+    pub fn new(fs: &Arc<dyn FileSystem>, path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
+        let inner = LogFile::new(fs, path, writeable)?;
+
+        Ok(Self {
+            inner,
+            limits: EntryLimits::default(),
+            entries: 0,
+        })
+    }
 
-        Ok(Self { inner })
+    /// Open data file for writing with `len` bytes preallocated,
+    /// see `LogFile::preallocated`.
+    pub fn preallocated(
+        fs: &Arc<dyn FileSystem>,
+        path: impl AsRef<Path>,
+        len: u64,
+    ) -> Result<Self> {
+        let inner = LogFile::preallocated(fs, path, len)?;
+
+        Ok(Self {
+            inner,
+            limits: EntryLimits::default(),
+            entries: 0,
+        })
     }
 
     pub fn path(&self) -> &Path {
@@ -140,16 +465,85 @@ impl DataFile {
         self.inner.size()
     }
 
-    pub fn iter(&mut self) -> DataEntryIter {
-        DataEntryIter {
-            reader: &mut self.inner.reader,
-            offset: 0,
-            file_id: self.inner.id,
+    pub fn iter(&mut self) -> Result<DataEntryIter<'_>> {
+        self.iter_from(0)
+    }
+
+    /// Iterate entries from `offset` on, which must be the offset of an entry.
+    pub fn iter_from(&mut self, offset: u64) -> Result<DataEntryIter<'_>> {
+        let file_id = self.inner.id;
+        Ok(DataEntryIter {
+            reader: self.inner.flushed_reader()?,
+            offset,
+            file_id,
+            limits: self.limits,
+        })
+    }
+
+    /// Sync and close file handle for writing, see `LogFile::seal`.
+    pub fn seal(&mut self) -> Result<()> {
+        self.inner.seal()
+    }
+
+    /// Open for appending, see `LogFile::make_writeable`.
+    pub fn make_writeable(&mut self) -> Result<()> {
+        self.inner.make_writeable()
+    }
+
+    /// Remove the data file on drop if it's writeable and still empty.
+    pub fn remove_empty_on_drop(mut self, remove: bool) -> Self {
+        self.inner.remove_empty_on_drop = remove;
+        self
+    }
+
+    /// Buffer up to `capacity` bytes of writes in memory, they're written
+    /// to the file once the buffer is full, on `sync` and on `seal`.
+    /// Zero writes through.
+    pub fn write_buffer(mut self, capacity: usize) -> Self {
+        if let Some(w) = &mut self.inner.writer {
+            w.capacity = capacity;
+        }
+        self
+    }
+
+    /// Reject entries read back with keys or values beyond `limits`.
+    pub fn entry_limits(mut self, limits: EntryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Return `true` if the entry at `offset` is only buffered in memory
+    /// yet, other handles of the file don't see it.
+    pub fn is_buffered(&self, offset: u64) -> bool {
+        self.inner.buffered_at(offset).is_some()
+    }
+
+    /// Return a read-only twin, see `LogFile::read_only_twin`.
+    pub fn read_only_twin(&self) -> Self {
+        Self {
+            inner: self.inner.read_only_twin(),
+            limits: self.limits,
+            entries: self.entries,
         }
     }
 
-    /// Save key-value pair to segement file.
-    pub fn write(&mut self, key: &[u8], value: &[u8]) -> Result<DataEntry> {
+    /// Open file handle for reading if closed.
+    pub fn open_reader(&mut self) -> Result<()> {
+        self.inner.reader().map(|_| ())
+    }
+
+    /// Close file handle for reading, it will be reopened on next read.
+    pub fn close_reader(&mut self) {
+        self.inner.close_reader()
+    }
+
+    /// Return `true` if file handle for reading is open.
+    pub fn is_reader_open(&self) -> bool {
+        self.inner.reader.is_some()
+    }
+
+    /// Save data entry to segment file.
+    pub fn write(&mut self, data_entry: DataEntry) -> Result<DataEntry> {
         let path = self.inner.path.as_path();
         let w = self
             .inner
@@ -158,13 +552,23 @@ impl DataFile {
             .ok_or_else(|| StoreError::FileNotWriteable(path.to_path_buf()))?;
 
         trace!(
-            "append {} to segement file {}",
-            String::from_utf8_lossy(key),
+            "append {} to segment file {}",
+            String::from_utf8_lossy(&data_entry.key),
             self.inner.path.display()
         );
 
-        let data_entry = DataEntry::new(key.to_vec(), value.to_vec());
-        let offset = data_entry.write_to(w)?;
+        // a failed write leaves a partial entry behind, discard it.
+        let start = w.seek(SeekFrom::Start(self.inner.write_offset))?;
+        let offset = match data_entry.write_to(w) {
+            Ok(offset) => offset,
+            Err(e) => {
+                truncate_to(w, path, start);
+                return Err(out_of_space_or(e));
+            }
+        };
+        self.inner.write_offset = w.stream_position()?;
+        self.inner.settle_entry(offset)?;
+        self.entries += 1;
 
         trace!(
             "successfully append {} to data file {}",
@@ -176,6 +580,7 @@ impl DataFile {
     }
 
     /// Read key value in data file.
+    /// Return `None` if `offset` is at or past the end of data file.
     pub fn read(&mut self, offset: u64) -> Result<Option<DataEntry>> {
         trace!(
             "read key value with offset {} in data file {}",
@@ -183,11 +588,17 @@ impl DataFile {
             self.inner.path.display()
         );
 
-        if self.inner.size()? < offset {
+        if offset >= self.inner.size()? {
             return Ok(None);
         }
 
-        match DataEntry::read_from(&mut self.inner.reader, offset)? {
+        let entry = match self.inner.buffered_at(offset) {
+            Some((base, buf)) => {
+                DataEntry::read_within(&mut Cursor::new(buf), offset - base, self.limits)?
+            }
+            None => DataEntry::read_within(self.inner.reader()?, offset, self.limits)?,
+        };
+        match entry {
             None => Ok(None),
             Some(entry) => {
                 trace!(
@@ -201,6 +612,92 @@ impl DataFile {
         }
     }
 
+    /// Read header of the entry at `offset` in data file.
+    /// Return `None` if no entry at `offset`.
+    pub fn read_header(&mut self, offset: u64) -> Result<Option<DataHeader>> {
+        if offset >= self.inner.size()? {
+            return Ok(None);
+        }
+
+        if let Some((base, buf)) = self.inner.buffered_at(offset) {
+            return DataEntry::read_header(&mut Cursor::new(buf), offset - base);
+        }
+        DataEntry::read_header(self.inner.reader()?, offset)
+    }
+
+    /// Return number of entries in data file, as loaded on open and
+    /// written since.
+    pub fn entry_count(&self) -> u64 {
+        self.entries
+    }
+
+    /// Record `count` entries loaded from the data file on open.
+    pub fn set_entry_count(&mut self, count: u64) {
+        self.entries = count;
+    }
+
+    /// Copy value in data file to `w`, without materializing it.
+    /// Return header of the entry, or `None` if no entry at `offset`.
+    pub fn read_value_into<W>(&mut self, offset: u64, w: &mut W) -> Result<Option<DataHeader>>
+    where
+        W: Write + ?Sized,
+    {
+        trace!(
+            "read value with offset {} in data file {}",
+            offset,
+            self.inner.path.display()
+        );
+
+        if offset >= self.inner.size()? {
+            return Ok(None);
+        }
+
+        if let Some((base, buf)) = self.inner.buffered_at(offset) {
+            return DataEntry::read_value_into(&mut Cursor::new(buf), offset - base, w);
+        }
+        DataEntry::read_value_into(self.inner.reader()?, offset, w)
+    }
+
+    /// Save data entry with value of `value_sz` bytes copied from `r`.
+    /// Return offset and header of the entry.
+    ///
+    /// A partially written entry is truncated if the write fails.
+    pub fn write_from_reader<R: Read>(
+        &mut self,
+        key: &[u8],
+        value_sz: u32,
+        timestamp: u64,
+        r: &mut R,
+    ) -> Result<(u64, DataHeader)> {
+        let path = self.inner.path.as_path();
+        let w = self
+            .inner
+            .writer
+            .as_mut()
+            .ok_or_else(|| StoreError::FileNotWriteable(path.to_path_buf()))?;
+
+        trace!(
+            "append {} bytes of {} to segment file {}",
+            value_sz,
+            String::from_utf8_lossy(key),
+            self.inner.path.display()
+        );
+
+        let start = w.seek(SeekFrom::Start(self.inner.write_offset))?;
+        match DataEntry::write_from_reader(w, key, value_sz, timestamp, r) {
+            Ok((offset, header)) => {
+                self.inner.write_offset = w.stream_position()?;
+                self.inner.settle_entry(offset)?;
+                self.entries += 1;
+                Ok((offset, header))
+            }
+            Err(e) => {
+                truncate_to(w, path, start);
+                Err(out_of_space_or(e))
+            }
+        }
+    }
+
     /// Flush all pending writes to disk.
     pub fn sync(&mut self) -> Result<()> {
         self.inner.sync()
@@ -209,31 +706,57 @@ impl DataFile {
     /// Copy `size` bytes from `src` data file.
     /// Return offset of the newly written entry.
     pub fn copy_bytes_from(&mut self, src: &mut DataFile, offset: u64, size: u64) -> Result<u64> {
-        self.inner.copy_bytes_from(&mut src.inner, offset, size)
+        let offset = self.inner.copy_bytes_from(&mut src.inner, offset, size)?;
+        self.entries += 1;
+        Ok(offset)
     }
 }
 
 pub struct DataEntryIter<'a> {
-    reader: &'a mut File,
+    reader: &'a mut Box<dyn FileHandle>,
     offset: u64,
     file_id: u64,
+    limits: EntryLimits,
 }
 
 impl<'a> Iterator for DataEntryIter<'a> {
-    type Item = DataEntry;
+    type Item = Result<DataEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match DataEntry::read_from(self.reader, self.offset).unwrap() {
-            None => None,
-            Some(entry) => {
+        match DataEntry::read_within(self.reader, self.offset, self.limits) {
+            Err(e) => Some(Err(e)),
+            Ok(None) => None,
+            Ok(Some(entry)) => {
                 let entry = entry.offset(self.offset).file_id(self.file_id);
                 self.offset += entry.size();
-                Some(entry)
+                Some(Ok(entry))
             }
         }
     }
 }
 
+/// Truncate file being written back to `size`, discarding a partial
+/// entry. A failure is only logged, the partial entry is then discarded
+/// when the file is scanned on next open.
+fn truncate_to(w: &mut dyn FileHandle, path: &Path, size: u64) {
+    let res = w.set_len(size).and_then(|()| w.seek(SeekFrom::End(0)));
+    if let Err(e) = res {
+        error!(
+            "failed to discard partial entry of {}, got error: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Return `StoreError::OutOfSpace` if `e` is caused by a full file system.
+fn out_of_space_or(e: StoreError) -> StoreError {
+    match e {
+        StoreError::Io(e) if e.kind() == io::ErrorKind::StorageFull => StoreError::OutOfSpace,
+        e => e,
+    }
+}
+
 /// HintFile
 #[derive(Debug)]
 pub struct HintFile {
@@ -243,8 +766,11 @@ pub struct HintFile {
 }
 
 impl HintFile {
-    pub fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
-        let inner = LogFile::new(path, writeable)?;
+    pub fn new(fs: &Arc<dyn FileSystem>, path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
+        let mut inner = LogFile::new(fs, path, writeable)?;
+        // hint files are written to a temporary path, an empty one is
+        // left over.
+        inner.remove_empty_on_drop = true;
 
         Ok(Self {
             inner,
@@ -260,24 +786,32 @@ impl HintFile {
         self.inner.id
     }
 
-    pub fn iter(&mut self) -> HintEntryIter {
-        HintEntryIter {
-            reader: &mut self.inner.reader,
+    /// Return number of entries written through this handle.
+    pub fn entries_written(&self) -> u64 {
+        self.entries_written
+    }
+
+    pub fn iter(&mut self) -> Result<HintEntryIter<'_>> {
+        let file_id = self.inner.id;
+        Ok(HintEntryIter {
+            reader: self.inner.flushed_reader()?,
             offset: 0,
-        }
+            file_id,
+        })
     }
 
-    pub fn write(&mut self, key: impl AsRef<[u8]>, offset: u64, size: u64) -> Result<u64> {
-        let entry = HintEntry::new(key.as_ref().to_vec(), offset, size);
+    pub fn write(&mut self, entry: HintEntry) -> Result<u64> {
         trace!("append {} to file {}", &entry, self.inner.path.display());
 
-        let w = &mut self
+        let path = self.inner.path.as_path();
+        let w = self
             .inner
             .writer
             .as_mut()
-            .expect("hint file is not writeable");
+            .ok_or_else(|| StoreError::FileNotWriteable(path.to_path_buf()))?;
 
         let offset = entry.write_to(w)?;
+        self.inner.write_offset = w.stream_position()?;
         self.entries_written += 1;
 
         self.inner.flush()?;
@@ -292,20 +826,120 @@ impl HintFile {
 }
 
 pub struct HintEntryIter<'a> {
-    reader: &'a mut File,
+    reader: &'a mut Box<dyn FileHandle>,
     offset: u64,
+    file_id: u64,
 }
 
 impl<'a> Iterator for HintEntryIter<'a> {
-    type Item = HintEntry;
+    type Item = Result<HintEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match HintEntry::read_from(self.reader, self.offset).unwrap() {
-            None => None,
-            Some(entry) => {
+        match HintEntry::read_from(self.reader, self.offset) {
+            Err(e) => Some(Err(e)),
+            Ok(None) => None,
+            Ok(Some(entry)) if !entry.is_valid() => Some(Err(StoreError::HintEntryCorrupted {
+                file_id: self.file_id,
+                offset: self.offset,
+            })),
+            Ok(Some(entry)) => {
                 self.offset += entry.selfsize();
-                Some(entry)
+                Some(Ok(entry))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::fs::mock::MemFileSystem;
+    use super::*;
+
+    #[test]
+    fn test_read_boundaries() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemFileSystem::default());
+        let mut df = DataFile::new(&fs, "/db/000001.tinkv.data", true).unwrap();
+        let entry = df
+            .write(DataEntry::new(b"key".to_vec(), b"value".to_vec()).unwrap())
+            .unwrap();
+        let size = df.size().unwrap();
+        assert_eq!(size, entry.size());
+
+        assert_eq!(df.read(0).unwrap().unwrap().value, b"value");
+        for offset in [size, size + 1, size * 100] {
+            assert!(df.read(offset).unwrap().is_none());
+            assert!(df.read_header(offset).unwrap().is_none());
+            assert!(df
+                .read_value_into(offset, &mut Vec::new())
+                .unwrap()
+                .is_none());
+        }
+
+        // the last byte of an entry doesn't hold a whole header.
+        assert!(matches!(
+            df.read(size - 1),
+            Err(StoreError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_write_read_only() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemFileSystem::default());
+        let mut df = DataFile::new(&fs, "/db/000001.tinkv.data", true).unwrap();
+        df.write(DataEntry::new(b"key".to_vec(), b"value".to_vec()).unwrap())
+            .unwrap();
+        let size = df.size().unwrap();
+        df.seal().unwrap();
+
+        // sealed files refuse writes instead of panicking.
+        assert!(matches!(
+            df.write(DataEntry::new(b"key".to_vec(), b"value".to_vec()).unwrap()),
+            Err(StoreError::FileNotWriteable(_))
+        ));
+        let mut src = df.read_only_twin();
+        assert!(matches!(
+            df.copy_bytes_from(&mut src, 0, size),
+            Err(StoreError::FileNotWriteable(_))
+        ));
+
+        // a source shorter than expected is an error too.
+        let mut dst = DataFile::new(&fs, "/db/000002.tinkv.data", true).unwrap();
+        assert!(matches!(
+            dst.copy_bytes_from(&mut src, 0, size + 1),
+            Err(StoreError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        let mut hint = HintFile::new(&fs, "/db/000001.tinkv.hint", false).unwrap();
+        assert!(matches!(
+            hint.write(HintEntry::new(b"key".to_vec(), 0, size).unwrap()),
+            Err(StoreError::FileNotWriteable(_))
+        ));
+    }
+
+    #[test]
+    fn test_remove_empty_on_drop() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemFileSystem::default());
+        let path = Path::new("/db/000001.tinkv.data");
+
+        // kept by default.
+        drop(DataFile::new(&fs, path, true).unwrap());
+        assert!(fs.exists(path));
+
+        // kept while a read-only twin refers to it.
+        let df = DataFile::new(&fs, path, true)
+            .unwrap()
+            .remove_empty_on_drop(true);
+        let twin = df.read_only_twin();
+        drop(df);
+        assert!(fs.exists(path));
+        drop(twin);
+        assert!(fs.exists(path));
+
+        let df = DataFile::new(&fs, path, true)
+            .unwrap()
+            .remove_empty_on_drop(true);
+        drop(df.read_only_twin());
+        drop(df);
+        assert!(!fs.exists(path));
+    }
+}
@@ -0,0 +1,129 @@
+//! Manifest implementation.
+//!
+//! Manifest is a small metadata file which marks a directory as a bitcask
+//! store and records the format version that wrote it.
+
+use std::io::Read;
+use std::path::Path;
+
+use chrono::Utc;
+use log::info;
+
+use super::error::{Result, StoreError};
+use super::fs::{self, FileSystem};
+use super::settings;
+
+/// Store manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// format version of the store.
+    pub version: u32,
+
+    /// unix timestamp of store creation.
+    pub created_at: i64,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            version: settings::FORMAT_VERSION,
+            created_at: Utc::now().timestamp(),
+        }
+    }
+
+    /// Load manifest from store directory `dir`, create a new one if
+    /// not found (new stores, or old stores written before manifest).
+    pub fn load_or_create(fs: &dyn FileSystem, dir: &Path) -> Result<Self> {
+        let path = dir.join(settings::MANIFEST_FILE_NAME);
+        let mut manifest = match Self::load(fs, dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Self::new();
+                info!("create manifest {}", path.display());
+                manifest.write(fs, dir)?;
+                return Ok(manifest);
+            }
+        };
+
+        // older formats are readable, mark the store with current version
+        // since new entries may use features older versions don't know.
+        if manifest.version < settings::FORMAT_VERSION {
+            info!(
+                "upgrade manifest {} from version {} to {}",
+                path.display(),
+                manifest.version,
+                settings::FORMAT_VERSION
+            );
+            manifest.version = settings::FORMAT_VERSION;
+            manifest.write(fs, dir)?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Load manifest from store directory `dir` without modifying it,
+    /// return `None` if not found.
+    pub fn load(fs: &dyn FileSystem, dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(settings::MANIFEST_FILE_NAME);
+        if !fs.exists(&path) {
+            return Ok(None);
+        }
+
+        let mut content = String::new();
+        fs.open(&path)?.read_to_string(&mut content)?;
+        let manifest = Self::decode(&content)?;
+        if manifest.version > settings::FORMAT_VERSION {
+            return Err(StoreError::IncompatibleVersion {
+                found: manifest.version,
+                supported: settings::FORMAT_VERSION,
+            });
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Write manifest to store directory `dir`, atomically replacing
+    /// the previous one.
+    pub fn write(&self, fs: &dyn FileSystem, dir: &Path) -> Result<()> {
+        let path = dir.join(settings::MANIFEST_FILE_NAME);
+        fs::write_atomic(fs, &path, self.encode().as_bytes())
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{}\nversion={}\ncreated_at={}\n",
+            settings::MANIFEST_MAGIC,
+            self.version,
+            self.created_at
+        )
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(settings::MANIFEST_MAGIC) {
+            return Err(StoreError::InvalidManifest("bad magic".to_owned()));
+        }
+
+        let mut version = None;
+        let mut created_at = None;
+        for line in lines {
+            match line.split_once('=') {
+                Some(("version", v)) => version = Some(v.parse()?),
+                Some(("created_at", v)) => created_at = Some(v.parse()?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            version: version
+                .ok_or_else(|| StoreError::InvalidManifest("version not found".to_owned()))?,
+            created_at: created_at.unwrap_or(0),
+        })
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
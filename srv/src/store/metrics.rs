@@ -0,0 +1,204 @@
+//! Metrics Module.
+//!
+//! Counters of operations and latency histograms of a store, updated
+//! with relaxed atomics so that reading them never takes a store lock.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Monotonic counter, reset only on demand.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Upper bounds of latency buckets, in microseconds, and their names.
+/// Latencies above the last bound fall into an overflow bucket.
+const LATENCY_BUCKETS: [(u64, &str); 4] = [
+    (100, "le_100us"),
+    (1_000, "le_1ms"),
+    (10_000, "le_10ms"),
+    (100_000, "le_100ms"),
+];
+
+/// Latency histogram with fixed buckets.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// one counter per bucket, the last one counts overflows.
+    buckets: [Counter; LATENCY_BUCKETS.len() + 1],
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros();
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|&(bound, _)| micros <= bound as u128)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].incr();
+    }
+
+    /// Return number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(Counter::get).sum()
+    }
+
+    fn report(&self, name: &str, report: &mut Vec<(String, u64)>) {
+        for (&(_, bucket), counter) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            report.push((format!("{name}_{bucket}"), counter.get()));
+        }
+        let overflow = &self.buckets[LATENCY_BUCKETS.len()];
+        report.push((format!("{name}_gt_100ms"), overflow.get()));
+    }
+
+    fn reset(&self) {
+        self.buckets.iter().for_each(Counter::reset);
+    }
+}
+
+/// Counters of store operations.
+///
+/// Values read back to serve `increment` and `append` count as gets,
+/// and their writes as sets.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// number of value lookups.
+    pub gets: Counter,
+
+    /// number of lookups which found the key.
+    pub hits: Counter,
+
+    /// number of lookups which didn't find the key.
+    pub misses: Counter,
+
+    /// number of values written.
+    pub sets: Counter,
+
+    /// number of delete requests.
+    pub deletes: Counter,
+
+    /// number of completed compactions.
+    pub compactions: Counter,
+
+    /// bytes of entries appended to data files.
+    pub bytes_written: Counter,
+
+    /// bytes of values returned by lookups.
+    pub bytes_read: Counter,
+
+    /// number of lookups served from the value cache.
+    pub cache_hits: Counter,
+
+    /// number of lookups of live keys missing in the value cache,
+    /// none while it's disabled.
+    pub cache_misses: Counter,
+
+    /// latency of lookups.
+    pub get_latency: Histogram,
+
+    /// latency of writes.
+    pub set_latency: Histogram,
+}
+
+impl Metrics {
+    /// Return `name` and value of all counters, histogram buckets are
+    /// reported as one counter each.
+    pub fn report(&self) -> Vec<(String, u64)> {
+        let mut report: Vec<(String, u64)> = [
+            ("gets", &self.gets),
+            ("hits", &self.hits),
+            ("misses", &self.misses),
+            ("sets", &self.sets),
+            ("deletes", &self.deletes),
+            ("compactions", &self.compactions),
+            ("bytes_written", &self.bytes_written),
+            ("bytes_read", &self.bytes_read),
+            ("cache_hits", &self.cache_hits),
+            ("cache_misses", &self.cache_misses),
+        ]
+        .iter()
+        .map(|(name, counter)| (name.to_string(), counter.get()))
+        .collect();
+
+        self.get_latency.report("get_latency", &mut report);
+        self.set_latency.report("set_latency", &mut report);
+        report
+    }
+
+    /// Account a lookup which took `latency` and found a value of
+    /// `size` bytes, if any.
+    pub fn record_get(&self, latency: Duration, size: Option<u64>) {
+        self.gets.incr();
+        match size {
+            None => self.misses.incr(),
+            Some(size) => {
+                self.hits.incr();
+                self.bytes_read.add(size);
+            }
+        }
+        self.get_latency.record(latency);
+    }
+
+    /// Reset all counters to zero.
+    pub fn reset(&self) {
+        for counter in [
+            &self.gets,
+            &self.hits,
+            &self.misses,
+            &self.sets,
+            &self.deletes,
+            &self.compactions,
+            &self.bytes_written,
+            &self.bytes_read,
+            &self.cache_hits,
+            &self.cache_misses,
+        ] {
+            counter.reset();
+        }
+        self.get_latency.reset();
+        self.set_latency.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let metrics = Metrics::default();
+        metrics.get_latency.record(Duration::from_micros(50));
+        metrics.get_latency.record(Duration::from_micros(100));
+        metrics.get_latency.record(Duration::from_millis(5));
+        metrics.get_latency.record(Duration::from_secs(1));
+        metrics.gets.add(4);
+        assert_eq!(metrics.get_latency.count(), 4);
+
+        let report = metrics.report();
+        let value = |name: &str| report.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(value("gets"), 4);
+        assert_eq!(value("get_latency_le_100us"), 2);
+        assert_eq!(value("get_latency_le_1ms"), 0);
+        assert_eq!(value("get_latency_le_10ms"), 1);
+        assert_eq!(value("get_latency_gt_100ms"), 1);
+
+        metrics.reset();
+        assert!(metrics.report().iter().all(|(_, v)| *v == 0));
+    }
+}
@@ -2,18 +2,36 @@
 
 pub mod arc;
 pub mod error;
+pub mod fs;
 pub mod keydir;
+pub mod layout;
+pub mod manifest;
+pub mod metrics;
+pub mod sharded;
 pub mod storage;
+#[cfg(feature = "serde")]
+pub mod typed;
+pub mod watch;
 
+mod cache;
 mod format;
 mod lockfile;
 mod logfile;
 mod settings;
 
-use keydir::HashmapKeydir;
-use storage::DiskStorage;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Copy, Clone)]
+use error::{Result, StoreError};
+use format::KEY_SZ_MASK;
+use fs::{FileSystem, StdFileSystem};
+use keydir::{AnyKeydir, KeydirKind};
+use layout::Layout;
+use metrics::Metrics;
+use storage::{DiskStorage, RecoveryCallback};
+
+#[derive(Debug, Clone)]
 pub struct StoreOptions {
     pub(crate) max_log_file_size: u64,
 
@@ -21,22 +39,164 @@ pub struct StoreOptions {
     // we should balance data reliability and writting performance.
     pub(crate) sync: bool,
 
+    // sync data periodically from a background thread, zero disables it.
+    pub(crate) sync_interval: Duration,
+
+    // largest key and value written, entries read back beyond them
+    // are rejected as corrupted.
     pub(crate) max_key_size: u64,
 
     pub(crate) max_value_size: u64,
+
+    // initial capacity of keydir, estimated from hint files if zero.
+    pub(crate) keydir_capacity: u64,
+
+    // maximum bytes of values cached in memory, zero disables the cache.
+    pub(crate) value_cache_bytes: u64,
+
+    // bytes of writes to the active data file buffered in memory, they're
+    // written to it once the buffer is full, on sync and on rotation.
+    // Zero writes through.
+    pub(crate) write_buffer_size: u64,
+
+    // maximum number of data files opened for reading, zero means unlimited.
+    pub(crate) max_open_files: u64,
+
+    // values larger than it are stored in blob files, zero disables blob files.
+    pub(crate) blob_threshold: u64,
+
+    // adding keys beyond it fails, zero means unlimited.
+    pub(crate) max_keys: u64,
+
+    // writes growing data and blob files beyond it fail, zero means
+    // unlimited. Deleted values are reclaimed only by compaction.
+    pub(crate) max_disk_bytes: u64,
+
+    // compact when number of data files exceeds it, zero means unlimited.
+    pub(crate) max_segment_files: u64,
+
+    // compaction keeps tombstones younger than it, even if no older
+    // data file holds the key any more.
+    pub(crate) tombstone_grace: Duration,
+
+    // sync after writing tombstones even if `sync` is off, so that
+    // deletes are durable once they return.
+    pub(crate) durable_deletes: bool,
+
+    // record size and modification time of immutable data files, and
+    // fail reads once they change behind the store's back.
+    pub(crate) detect_external_modification: bool,
+
+    // skip corrupted segments when rebuilding keydir instead of failing.
+    pub(crate) continue_on_corruption: bool,
+
+    // remove hint files without a data file when opening the store.
+    pub(crate) repair: bool,
+
+    // skip files matching segment names without a file id when opening
+    // the store, instead of failing.
+    pub(crate) ignore_foreign_files: bool,
+
+    // open the store without writing anything to it.
+    pub(crate) read_only: bool,
+
+    // open a read-only store even if a writer holds the lock.
+    pub(crate) ignore_lock: bool,
+
+    // called after each segment is loaded when rebuilding keydir.
+    pub(crate) recovery_progress: Option<RecoveryCallback>,
+
+    // once set, rebuilding keydir, compaction and verifying segments
+    // stop at the next consistent point with `StoreError::Cancelled`.
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+
+    // extend new active data files to `max_log_file_size` up front, the
+    // unwritten space is cut off once the file is sealed.
+    pub(crate) preallocate: bool,
+
+    // keydir implementation of `Store`, stores opened as `DiskStorage<K>`
+    // use `K` whatever it is.
+    pub(crate) keydir: KeydirKind,
+
+    // remove the active data file or blob file if it's still empty when
+    // it's dropped, e.g. on close, otherwise it's kept and reused.
+    pub(crate) remove_empty_files: bool,
+
+    // permissions of the store directory and lock file on unix, `None`
+    // uses the process defaults. Data files get the permissions of `fs`.
+    pub(crate) mode: Option<u32>,
+
+    // placement of data, hint and blob files in the store directory.
+    pub(crate) layout: Arc<Layout>,
+
+    // file system holding data files, the store directory and
+    // its lock are always on the local disk.
+    pub(crate) fs: Arc<dyn FileSystem>,
+
+    // counters of operations, shared by stores opened with clones
+    // of the options, e.g. shards of a sharded store.
+    pub(crate) metrics: Arc<Metrics>,
 }
 
 impl Default for StoreOptions {
     fn default() -> Self {
         Self {
             max_log_file_size: settings::DEFAULT_MAX_DATA_FILE_SIZE, // 100MB
-            sync: false, // SyncStrategy::Interval(100),    // 100s
+            sync: false,
+            sync_interval: Duration::ZERO,
             max_key_size: settings::DEFAULT_MAX_KEY_SIZE,
             max_value_size: settings::DEFAULT_MAX_VALUE_SIZE,
+            keydir_capacity: 0,
+            value_cache_bytes: 0,
+            write_buffer_size: 0,
+            max_open_files: settings::DEFAULT_MAX_OPEN_FILES,
+            blob_threshold: 0,
+            max_keys: 0,
+            max_disk_bytes: 0,
+            max_segment_files: 0,
+            tombstone_grace: Duration::ZERO,
+            durable_deletes: false,
+            detect_external_modification: false,
+            continue_on_corruption: false,
+            repair: false,
+            ignore_foreign_files: false,
+            read_only: false,
+            ignore_lock: false,
+            recovery_progress: None,
+            cancel: None,
+            preallocate: false,
+            keydir: KeydirKind::default(),
+            remove_empty_files: false,
+            mode: None,
+            layout: Arc::new(Layout::default()),
+            fs: Arc::new(StdFileSystem::default()),
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+}
+
+impl StoreOptions {
+    /// Return `StoreError::InvalidOption` if a limit exceeds what data
+    /// files can record, sizes of keys and values are stored in 24 and
+    /// 32 bits, or `StoreError::InvalidLayout` for an invalid layout.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.max_key_size > KEY_SZ_MASK as u64 {
+            return Err(StoreError::InvalidOption(format!(
+                "max_key_size {} exceeds {} bytes",
+                self.max_key_size, KEY_SZ_MASK
+            )));
+        }
+        if self.max_value_size > u32::MAX as u64 {
+            return Err(StoreError::InvalidOption(format!(
+                "max_value_size {} exceeds {} bytes",
+                self.max_value_size,
+                u32::MAX
+            )));
         }
+        self.layout.validate()
     }
 }
 
-pub type Store = DiskStorage<HashmapKeydir>;
+pub type Store = DiskStorage<AnyKeydir>;
 
 pub use arc::{BitCask, OpenOptions};
@@ -1,6 +1,11 @@
 pub const REMOVE_TOMESTONE: &[u8] = b"%TINKV_REMOVE_TOMESTOME%";
 pub const DATA_FILE_SUFFIX: &str = ".tinkv.data";
 pub const HINT_FILE_SUFFIX: &str = ".tinkv.hint";
+pub const BLOB_FILE_SUFFIX: &str = ".tinkv.blob";
 pub const DEFAULT_MAX_DATA_FILE_SIZE: u64 = 1024 * 1024 * 1024; // 1MB
 pub const DEFAULT_MAX_KEY_SIZE: u64 = 64;
 pub const DEFAULT_MAX_VALUE_SIZE: u64 = 65536;
+pub const DEFAULT_MAX_OPEN_FILES: u64 = 256;
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+pub const MANIFEST_MAGIC: &str = "BITCASK";
+pub const FORMAT_VERSION: u32 = 5;
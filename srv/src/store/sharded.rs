@@ -0,0 +1,472 @@
+//! Sharded Store.
+//!
+//! Keys are hashed to one of several independent stores, each living in
+//! its own subdirectory with its own lock, keydir and data files, so
+//! writes to different shards don't contend on the same lock.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+
+use super::arc::BitCask;
+use super::error::{Result, StoreError};
+use super::fs::create_dir_all;
+use super::metrics::Metrics;
+use super::storage::{CompactionReport, EntryMetadata, SegmentInfo, Stats, Storage};
+use super::StoreOptions;
+
+/// Sharded store handler for multiple threads.
+///
+/// Cloning a `ShardedBitCask` shares the same underlying shards.
+#[derive(Debug, Clone)]
+pub struct ShardedBitCask {
+    shards: Vec<BitCask>,
+}
+
+impl ShardedBitCask {
+    /// Open a sharded store of `num_shards` shards at `path`.
+    ///
+    /// The number of shards is fixed once the store is created, opening
+    /// it with a different number returns `StoreError::ShardCountMismatch`.
+    pub fn open(path: impl AsRef<Path>, num_shards: usize) -> Result<Self> {
+        Self::open_with_options(path, num_shards, StoreOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        num_shards: usize,
+        opts: StoreOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if num_shards == 0 {
+            return Err(StoreError::InvalidOption(
+                "number of shards must be positive".to_string(),
+            ));
+        }
+
+        create_dir_all(path, opts.mode)?;
+
+        let found = fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with("shard-"))
+            .count();
+        if found != 0 && found != num_shards {
+            return Err(StoreError::ShardCountMismatch {
+                found,
+                expected: num_shards,
+            });
+        }
+
+        info!("open {} shards at {}", num_shards, path.display());
+
+        let shards = (0..num_shards)
+            .map(|i| BitCask::open_with_options(shard_path(path, i), opts.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { shards })
+    }
+
+    /// Return number of shards.
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Return `true` if any shard is compacting.
+    pub fn is_compacting(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_compacting())
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        (fnv1a(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &[u8]) -> &BitCask {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut BitCask {
+        let i = self.shard_index(key);
+        &mut self.shards[i]
+    }
+}
+
+impl Storage for ShardedBitCask {
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.shard_mut(key).set(key, value)
+    }
+
+    fn set_new(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.shard_mut(key).set_new(key, value)
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = key.as_ref();
+        self.shard_mut(key).set_with_expiry(key, value, ttl)
+    }
+
+    fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        self.shard_mut(key).expire(key, ttl)
+    }
+
+    fn persist(&mut self, key: &[u8]) -> Result<bool> {
+        self.shard_mut(key).persist(key)
+    }
+
+    fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        self.shard(key).ttl(key)
+    }
+
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        self.shard_mut(key).increment(key, delta)
+    }
+
+    fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64> {
+        self.shard_mut(key).append(key, suffix)
+    }
+
+    /// Rename is atomic only if both keys live in the same shard,
+    /// otherwise the value is written to `new` before `old` is deleted.
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<bool> {
+        if self.shard_index(old) == self.shard_index(new) {
+            return self.shard_mut(old).rename(old, new);
+        }
+
+        let value = match self.shard_mut(old).get(old)? {
+            None => return Ok(false),
+            Some(value) => value,
+        };
+
+        match self.shard(old).ttl(old) {
+            None => self.shard_mut(new).set(new, value)?,
+            Some(ttl) => self.shard_mut(new).set_with_expiry(new, value, ttl)?,
+        }
+        self.shard_mut(old).delete(old)?;
+
+        Ok(true)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shard_mut(key).get(key)
+    }
+
+    fn get_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.shard_mut(key).get_into(key, buf)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        self.shard_mut(key).delete(key)
+    }
+
+    fn purge_expired(&mut self) -> Result<u64> {
+        let mut purged = 0;
+        for shard in self.shards.iter_mut() {
+            purged += shard.purge_expired()?;
+        }
+        Ok(purged)
+    }
+
+    /// Shards are locked one at a time, the keys of a shard are
+    /// appended while other shards keep serving writes.
+    fn keys_into(&self, buf: &mut Vec<Vec<u8>>) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.keys_into(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Each shard returns up to `limit` keys, the smallest `limit`
+    /// of them are returned.
+    fn keys_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.keys_with_prefix(prefix, after, limit)?);
+        }
+        keys.sort_unstable();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    fn entries_metadata(&self) -> Result<Vec<EntryMetadata>> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            entries.extend(shard.entries_metadata()?);
+        }
+        Ok(entries)
+    }
+
+    /// Each shard returns up to `limit` entries, those of the smallest
+    /// `limit` keys are returned.
+    fn entries_metadata_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<EntryMetadata>> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            entries.extend(shard.entries_metadata_with_prefix(prefix, after, limit)?);
+        }
+        entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.first_key())
+            .min()
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.last_key())
+            .max()
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport> {
+        let mut report = CompactionReport::default();
+        for shard in self.shards.iter_mut() {
+            let shard_report = shard.compact()?;
+            report.input_bytes += shard_report.input_bytes;
+            report.output_bytes += shard_report.output_bytes;
+            report.entries_copied += shard_report.entries_copied;
+            report.entries_dropped += shard_report.entries_dropped;
+            report.duration += shard_report.duration;
+            report.segments_removed += shard_report.segments_removed;
+            report.segments_created += shard_report.segments_created;
+        }
+        Ok(report)
+    }
+
+    fn len(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        self.for_each_filtered(|_| true, f)
+    }
+
+    fn for_each_filtered<P, F>(&mut self, key_pred: P, f: &mut F) -> Result<()>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        for shard in self.shards.iter_mut() {
+            let mut stopped = false;
+            shard.for_each_filtered(&key_pred, &mut |key, value| {
+                stopped = f(key, value)?;
+                Ok(stopped)
+            })?;
+
+            if stopped {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        for shard in self.shards.iter_mut() {
+            shard.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Return the oldest sync time of all shards, which bounds
+    /// durability of the whole store.
+    fn last_sync_time(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .map(|shard| shard.last_sync_time())
+            .min()
+            .flatten()
+    }
+
+    fn pending_bytes(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.pending_bytes()).sum()
+    }
+
+    fn last_write_ts(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.last_write_ts())
+            .max()
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            last_sync_time: self.last_sync_time(),
+            ..Stats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard_stats = shard.stats();
+            stats.keys += shard_stats.keys;
+            stats.segment_count += shard_stats.segment_count;
+            stats.pending_bytes += shard_stats.pending_bytes;
+            stats.total_bytes += shard_stats.total_bytes;
+            stats.reclaimable_bytes += shard_stats.reclaimable_bytes;
+            // shards are opened one after the other.
+            stats.recovery_duration += shard_stats.recovery_duration;
+            // shards are opened with the same options.
+            stats.durable_deletes = shard_stats.durable_deletes;
+        }
+        stats
+    }
+
+    /// Segments of all shards, ordered by shard, file ids repeat
+    /// across shards.
+    fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let mut segments = Vec::new();
+        for shard in self.shards.iter() {
+            segments.extend(shard.segments()?);
+        }
+        Ok(segments)
+    }
+
+    /// Shards are opened with the same options and share their metrics.
+    fn metrics(&self) -> Arc<Metrics> {
+        self.shards[0].metrics()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        for shard in self.shards.iter_mut() {
+            shard.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn shard_path(dir: &Path, index: usize) -> std::path::PathBuf {
+    dir.join(format!("shard-{:03}", index))
+}
+
+/// FNV-1a hash, stable across builds so keys stay in their shards.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_sharded_storage() {
+        let dir = tempdir::TempDir::new("sharded-test.db").unwrap();
+        assert!(matches!(
+            ShardedBitCask::open(dir.path(), 0),
+            Err(StoreError::InvalidOption(_))
+        ));
+
+        {
+            let mut db = ShardedBitCask::open(dir.path(), 4).unwrap();
+            for i in 0..100u32 {
+                db.set(i.to_be_bytes(), i.to_string()).unwrap();
+            }
+            assert_eq!(db.len(), 100);
+
+            // keys spread over all shards.
+            assert!(db.shards.iter().all(|shard| !shard.is_empty()));
+
+            assert!(db.rename(&0u32.to_be_bytes(), b"zero").unwrap());
+            db.delete(&1u32.to_be_bytes()).unwrap();
+        }
+
+        assert!(matches!(
+            ShardedBitCask::open(dir.path(), 2),
+            Err(StoreError::ShardCountMismatch {
+                found: 4,
+                expected: 2
+            })
+        ));
+
+        let mut db = ShardedBitCask::open(dir.path(), 4).unwrap();
+        assert_eq!(db.len(), 99);
+        assert_eq!(db.keys().unwrap().len(), 99);
+        assert_eq!(db.get(b"zero").unwrap(), Some(b"0".to_vec()));
+        assert_eq!(db.get(&1u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(&2u32.to_be_bytes()).unwrap(), Some(b"2".to_vec()));
+
+        let mut count = 0;
+        db.for_each(&mut |_, _| {
+            count += 1;
+            Ok(count == 10)
+        })
+        .unwrap();
+        assert_eq!(count, 10);
+    }
+
+    fn concurrent_writes(num_shards: usize) -> Duration {
+        const WRITERS: u32 = 4;
+        const WRITES: u32 = 2000;
+
+        let dir = tempdir::TempDir::new("sharded-bench.db").unwrap();
+        let opts = StoreOptions {
+            sync: true,
+            ..StoreOptions::default()
+        };
+        let db = ShardedBitCask::open_with_options(dir.path(), num_shards, opts).unwrap();
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let mut db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..WRITES {
+                        db.set(format!("{w}-{i}"), [0u8; 64]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    #[ignore = "benchmark, needs multiple cores"]
+    fn bench_sharded_concurrent_writes() {
+        let single = concurrent_writes(1);
+        let sharded = concurrent_writes(4);
+        assert!(
+            sharded < single,
+            "1 shard: {single:?}, 4 shards: {sharded:?}"
+        );
+    }
+}
@@ -1,38 +1,152 @@
 //! Store Module.
 
-use std::collections::BTreeMap;
-use std::fs;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use glob::glob;
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
 
+use super::cache::ValueCache;
 use super::error::{Result, StoreError};
-use super::format::DataEntry;
+use super::format::{
+    BlobPointer, DataEntry, EntryLimits, HintEntry, EXPIRY_SIZE, FLAG_BLOB, HEADER_SIZE,
+};
+use super::fs::{create_dir_all, prepare_tmp_path, FileHandle, FileSystem, Metadata};
 use super::keydir::{Keydir, KeydirEntry};
+use super::layout::Layout;
 
 use super::lockfile::Lockfile;
 use super::logfile::{DataFile, HintFile};
+use super::manifest::Manifest;
+use super::metrics::Metrics;
 use super::settings;
+use super::watch::{Event, Watchers};
 use super::StoreOptions;
 
+use crate::utils::time::{now_millis, now_secs};
+
 /// Store implementation methods.
 pub trait Storage {
     /// Set key and value to store.
     fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()>;
 
+    /// Set key and value to store only if the key does not exist yet.
+    /// Return `StoreError::KeyAlreadyExists` otherwise.
+    fn set_new(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Set key and value to store, the key expires after `ttl`.
+    fn set_with_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Set the key to expire after `ttl`.
+    /// Return `false` if the key does not exist.
+    fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool>;
+
+    /// Remove the expiry of the key.
+    /// Return `false` if the key does not exist or has no expiry.
+    fn persist(&mut self, key: &[u8]) -> Result<bool>;
+
+    /// Return remaining time to live of the key,
+    /// `None` if the key does not exist or has no expiry.
+    fn ttl(&self, key: &[u8]) -> Option<Duration>;
+
+    /// Add `delta` to the integer value of the key and return the new value.
+    /// A missing key is treated as `0`.
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64>;
+
+    /// Append `suffix` to the value of the key and return the new length.
+    /// A missing key is treated as an empty value.
+    fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64>;
+
+    /// Move the value of key `old` to key `new`, overwriting `new` if exists.
+    /// Return `false` if `old` does not exist.
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<bool>;
+
     /// Get value by key from the store.
     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Get value of the key into `buf`, reusing its allocation.
+    /// `buf` is cleared first, return number of bytes read,
+    /// `None` if key not found.
+    fn get_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>>;
+
     /// Delete key from the store.
-    fn delete(&mut self, key: &[u8]) -> Result<()>;
+    /// Return `true` if the key existed, expired keys don't.
+    fn delete(&mut self, key: &[u8]) -> Result<bool>;
+
+    /// Delete expired keys rather than waiting for them to be read,
+    /// return the number of keys deleted.
+    fn purge_expired(&mut self) -> Result<u64>;
 
     /// List all keys in the store.
-    fn keys(&self) -> Result<Vec<Vec<u8>>>;
+    fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::with_capacity(self.len() as usize);
+        self.keys_into(&mut keys)?;
+        Ok(keys)
+    }
+
+    /// Append all keys in the store to `buf`, so that callers listing
+    /// keys repeatedly can reuse the same buffer.
+    ///
+    /// Listing a large store holds its lock while every key is cloned,
+    /// prefer `keys_with_prefix` to list it page by page.
+    fn keys_into(&self, buf: &mut Vec<Vec<u8>>) -> Result<()>;
+
+    /// List all keys in the store in lexicographic order, whatever the
+    /// keydir, so listings of two stores can be compared.
+    fn keys_sorted(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = self.keys()?;
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Return the smallest key in lexicographic order.
+    ///
+    /// Cheap for ordered keydirs, others scan all keys.
+    fn first_key(&self) -> Option<Vec<u8>>;
+
+    /// Return the largest key in lexicographic order.
+    ///
+    /// Cheap for ordered keydirs, others scan all keys.
+    fn last_key(&self) -> Option<Vec<u8>>;
+
+    /// Return at most `limit` keys starting with `prefix` and greater
+    /// than `after` in lexicographic order, for paginated listing.
+    ///
+    /// Cheap for ordered keydirs, others scan all keys.
+    fn keys_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>>;
+
+    /// Return key, size and timestamp of all keys, read from keydir
+    /// without reading values.
+    fn entries_metadata(&self) -> Result<Vec<EntryMetadata>>;
+
+    /// Return metadata of at most `limit` keys starting with `prefix`
+    /// and greater than `after` in lexicographic order, see
+    /// `keys_with_prefix`.
+    fn entries_metadata_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<EntryMetadata>>;
 
     /// Compact data files in the store.
     /// Clear stale entries from data files and reclaim disk space.
-    fn compact(&mut self) -> Result<()>;
+    fn compact(&mut self) -> Result<CompactionReport>;
 
     /// Return total number of keys in datastore.
     fn len(&self) -> u64;
@@ -47,21 +161,284 @@ pub trait Storage {
     /// for each entry.
     ///
     /// If function `f` return an `Err`, it stops iteration
-    /// and propagates the `Err` to the caller.
+    /// and propagates the `Err` to the caller, wrapped in
+    /// `StoreError::IterationFailed` with the key being processed.
+    ///
+    /// You can stop iteration manually by returning `Ok(true)`,
+    /// or continue iteration by returning `Ok(false)`.
     ///
-    /// You can continue iteration manually by returning `Ok(true)`,
-    /// or stop iteration by returning `Ok(false)`.
+    /// A keydir entry without a readable data entry is reported
+    /// as `StoreError::EntryNotFound`.
     fn for_each<F>(&mut self, f: &mut F) -> Result<()>
     where
         F: FnMut(&[u8], &[u8]) -> Result<bool>;
 
+    /// Like `for_each`, but call function `f` only for keys matching
+    /// `key_pred`. Keys are matched against the keydir, so values of
+    /// other keys are never read from disk.
+    fn for_each_filtered<P, F>(&mut self, key_pred: P, f: &mut F) -> Result<()>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8]) -> Result<bool>;
+
     /// Force flushing any pending writes to the datastore.
     fn sync(&mut self) -> Result<()>;
 
+    /// Return unix timestamp in milliseconds of last successful sync,
+    /// `None` if never synced since open.
+    fn last_sync_time(&self) -> Option<u64>;
+
+    /// Return number of bytes written since last successful sync.
+    fn pending_bytes(&self) -> u64;
+
+    /// Return unix timestamp in seconds of the most recent write, also
+    /// of writes before open, `None` if the store is empty.
+    fn last_write_ts(&self) -> Option<u64>;
+
+    /// Return point-in-time statistics of the datastore.
+    fn stats(&self) -> Stats;
+
+    /// Return layout of data files, ordered by file id, for debugging.
+    ///
+    /// Live keys are counted from keydir on each call.
+    fn segments(&self) -> Result<Vec<SegmentInfo>>;
+
+    /// Return counters of operations since open or last reset.
+    fn metrics(&self) -> Arc<Metrics>;
+
+    /// Reset counters of operations to zero.
+    fn stats_reset(&self) {
+        self.metrics().reset();
+    }
+
     /// Close a datastore, flush all pending writes to the datastore.
     fn close(&mut self) -> Result<()>;
 }
 
+/// Point-in-time statistics of a store.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// number of keys.
+    pub keys: u64,
+
+    /// number of data files, including the active one.
+    pub segment_count: u64,
+
+    /// unix timestamp in milliseconds of last successful sync.
+    pub last_sync_time: Option<u64>,
+
+    /// bytes written since last successful sync.
+    pub pending_bytes: u64,
+
+    /// size of all data files.
+    pub total_bytes: u64,
+
+    /// bytes of data files not holding the latest entry of a key,
+    /// which compaction would free.
+    pub reclaimable_bytes: u64,
+
+    /// time spent building keydir when the store was opened.
+    pub recovery_duration: Duration,
+
+    /// deletes are synced even if other writes aren't.
+    pub durable_deletes: bool,
+}
+
+/// Metadata of the latest entry of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub key: Vec<u8>,
+
+    /// size of the entry in its data file, header and key included.
+    pub size: u64,
+
+    /// unix timestamp in seconds of the entry.
+    pub timestamp: u64,
+}
+
+impl EntryMetadata {
+    fn new(key: &[u8], entry: &KeydirEntry) -> Self {
+        Self {
+            key: key.to_vec(),
+            size: entry.size,
+            timestamp: entry.timestamp,
+        }
+    }
+}
+
+/// Location of the value of a key, to read it without holding the
+/// store, see `DiskStorage::value_location`.
+#[derive(Debug, Clone)]
+pub struct ValueLocation {
+    fs: Arc<dyn FileSystem>,
+    dir: PathBuf,
+    layout: Arc<Layout>,
+    file_id: u64,
+    offset: u64,
+    metrics: Arc<Metrics>,
+}
+
+impl ValueLocation {
+    /// Read value of `key` into `buf`, cleared first, through readers
+    /// opened for this call only.
+    ///
+    /// Return `StoreError::SegmentMissing` if a file was removed since
+    /// the location was taken, e.g. by compaction, the key has to be
+    /// looked up again.
+    pub fn read_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        let start = Instant::now();
+
+        buf.clear();
+        let path = self.layout.data_file_path(&self.dir, self.file_id);
+        let mut r = self.open(&path, self.file_id)?;
+        let Some(header) = DataEntry::read_value_into(&mut r, self.offset, buf)? else {
+            self.metrics.record_get(start.elapsed(), None);
+            return Ok(None);
+        };
+
+        if header.flags() & FLAG_BLOB != 0 {
+            let ptr = BlobPointer::decode(buf)?;
+            buf.clear();
+            let path = self.layout.blob_file_path(&self.dir, ptr.file_id);
+            let mut r = self.open(&path, ptr.file_id)?;
+            if DataEntry::read_value_into(&mut r, ptr.offset, buf)?.is_none() {
+                return Err(StoreError::EntryNotFound {
+                    key: key.to_vec(),
+                    file_id: ptr.file_id,
+                    offset: ptr.offset,
+                });
+            }
+        }
+
+        self.metrics
+            .record_get(start.elapsed(), Some(buf.len() as u64));
+        Ok(Some(buf.len()))
+    }
+
+    fn open(&self, path: &Path, file_id: u64) -> Result<Box<dyn FileHandle>> {
+        self.fs.open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => StoreError::SegmentMissing { file_id },
+            _ => e.into(),
+        })
+    }
+}
+
+/// Layout of a data file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub file_id: u64,
+
+    pub path: PathBuf,
+
+    /// size of the data file.
+    pub size_bytes: u64,
+
+    /// number of keys whose latest value lives in the data file,
+    /// including expired keys not evicted yet.
+    pub live_keys: u64,
+
+    /// size of the entries of `live_keys`.
+    pub live_bytes: u64,
+
+    /// the data file is appended to.
+    pub is_active: bool,
+
+    /// the data file has a hint file.
+    pub has_hint: bool,
+}
+
+impl SegmentInfo {
+    /// Return size of the stale entries and tombstones of the data
+    /// file, which compaction would free.
+    pub fn stale_bytes(&self) -> u64 {
+        self.size_bytes.saturating_sub(self.live_bytes)
+    }
+}
+
+/// Progress of rebuilding keydir when opening a store.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    /// number of segments to load.
+    pub files_total: u64,
+
+    /// number of segments loaded or skipped so far.
+    pub files_processed: u64,
+
+    /// number of corrupted segments skipped so far.
+    pub files_skipped: u64,
+
+    /// number of segments loaded from hint files so far.
+    pub files_from_hints: u64,
+
+    /// number of keys in keydir so far.
+    pub keys_loaded: u64,
+}
+
+/// Statistics of rebuilding keydir when the store was opened.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// number of entries loaded from each segment, by file id.
+    pub entries_per_file: BTreeMap<u64, u64>,
+
+    /// number of segments loaded from hint files.
+    pub files_from_hints: u64,
+
+    /// number of segments loaded by scanning data files.
+    pub files_scanned: u64,
+
+    /// number of corrupted segments skipped.
+    pub files_skipped: u64,
+
+    /// number of hint files without a data file, ignored.
+    pub orphaned_hints: u64,
+
+    /// number of tombstones seen in data and hint files.
+    pub tombstones: u64,
+
+    /// number of keys in keydir once loaded.
+    pub keys: u64,
+
+    /// time spent rebuilding keydir.
+    pub duration: Duration,
+}
+
+/// Outcome of a compaction, to tell how much it rewrote for how much
+/// it reclaimed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// size of the data files compacted.
+    pub input_bytes: u64,
+
+    /// size of the data files written by compaction, tombstones kept
+    /// in the active data file included.
+    pub output_bytes: u64,
+
+    /// number of live entries and tombstones copied.
+    pub entries_copied: u64,
+
+    /// number of stale entries and tombstones left behind.
+    pub entries_dropped: u64,
+
+    /// time spent compacting.
+    pub duration: Duration,
+
+    /// number of data files removed.
+    pub segments_removed: u64,
+
+    /// number of data files created.
+    pub segments_created: u64,
+}
+
+/// Callback invoked after each segment is loaded.
+#[derive(Clone)]
+pub struct RecoveryCallback(pub Arc<dyn Fn(&RecoveryProgress) + Send + Sync>);
+
+impl Debug for RecoveryCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecoveryCallback")
+    }
+}
+
 /// Disk storage.
 #[derive(Debug)]
 pub struct DiskStorage<K>
@@ -71,20 +448,69 @@ where
     /// directory for database.
     path: PathBuf,
 
-    /// lock for database directory.
-    _lock: Lockfile,
+    /// lock for database directory, `None` if read-only store ignores it.
+    _lock: Option<Lockfile>,
+
+    /// manifest of the store.
+    manifest: Manifest,
 
     /// holds a bunch of data files.
     data_files: BTreeMap<u64, DataFile>,
 
-    /// only active data files is writeable.
-    active_data_file: Option<DataFile>,
+    /// data files with open readers.
+    open_readers: OpenReaders,
+
+    /// blob files holding large values, keyed by blob file id.
+    blob_files: BTreeMap<u64, DataFile>,
+
+    /// blob file for writing large values, created on demand.
+    active_blob_file: Option<DataFile>,
+
+    /// id of the active data file, the only writeable one in `data_files`.
+    active_data_file: Option<u64>,
 
     /// keydir maintains key value index for fast query.
     keydir: K,
 
+    /// tombstones of deleted keys, kept until compaction drops them.
+    tombstones: BTreeMap<Vec<u8>, KeydirEntry>,
+
     /// store options.
     opts: StoreOptions,
+
+    /// subscribers of key changes.
+    watchers: Watchers,
+
+    /// cache of hot values.
+    cache: ValueCache,
+
+    /// unix timestamp in milliseconds of last successful sync.
+    last_sync_time: Option<u64>,
+
+    /// bytes written since last successful sync.
+    pending_bytes: u64,
+
+    /// statistics of rebuilding keydir on open.
+    recovery_stats: RecoveryStats,
+
+    /// total bytes of data and blob files, bounded by `max_disk_bytes`.
+    disk_bytes: u64,
+
+    /// largest timestamp of entries seen since open, new entries never
+    /// get a smaller one so that they win over older ones in keydir
+    /// even if the clock goes backwards.
+    last_timestamp: u64,
+
+    /// metadata of immutable data files when they were sealed, only
+    /// recorded if `detect_external_modification` is set.
+    fingerprints: BTreeMap<u64, Metadata>,
+
+    /// set while a compaction runs, shared with handles which check it
+    /// without taking the store lock.
+    compacting: Arc<AtomicBool>,
+
+    /// store is closed, all further operations fail.
+    closed: bool,
 }
 
 impl<K> DiskStorage<K>
@@ -102,475 +528,4940 @@ where
         let path = path.as_ref();
 
         info!("open store path: {}", path.display());
+        opts.validate()?;
+
+        if opts.read_only {
+            if !path.is_dir() {
+                return Err(StoreError::StoreNotFound(path.to_path_buf()));
+            }
+        } else {
+            create_dir_all(path, opts.mode)?;
+            for dir in opts.layout.dirs(path) {
+                create_dir_all(&dir, opts.mode)?;
+            }
+        }
 
-        fs::create_dir_all(path)?;
+        // the same directory may be reached through different paths,
+        // lock and refer to it by its canonical path.
+        let path = path.canonicalize()?;
+        let path = path.as_path();
 
-        let lock = Lockfile::lock(path.join("LOCK")).or(Err(StoreError::AlreadyLocked))?;
+        let (lock, manifest) = if opts.read_only {
+            // a read-only store never writes, a writer holding the lock
+            // can be ignored if the caller accepts a possibly stale view.
+            let lock = if opts.ignore_lock {
+                None
+            } else {
+                Some(Lockfile::lock(path.join("LOCK"), opts.mode)?)
+            };
+            (lock, Manifest::load(&*opts.fs, path)?.unwrap_or_default())
+        } else {
+            let lock = Lockfile::lock(path.join("LOCK"), opts.mode)?;
+            (Some(lock), Manifest::load_or_create(&*opts.fs, path)?)
+        };
 
         let mut store = Self {
             path: path.to_path_buf(),
             _lock: lock,
+            manifest,
             data_files: BTreeMap::new(),
+            open_readers: OpenReaders::new(opts.max_open_files),
+            blob_files: BTreeMap::new(),
+            active_blob_file: None,
             active_data_file: None,
             keydir: K::default(),
+            tombstones: BTreeMap::new(),
+            watchers: Watchers::default(),
+            cache: ValueCache::new(opts.value_cache_bytes),
             opts,
+            last_sync_time: None,
+            pending_bytes: 0,
+            recovery_stats: RecoveryStats::default(),
+            disk_bytes: 0,
+            last_timestamp: 0,
+            fingerprints: BTreeMap::new(),
+            compacting: Arc::new(AtomicBool::new(false)),
+            closed: false,
         };
 
+        store.migrate_layout()?;
         store.open_data_files()?;
         store.build_keydir()?;
-        store.new_active_data_file(None)?;
+        if !store.opts.read_only {
+            store.open_active_data_file()?;
+        }
+        store.disk_bytes = store.segment_bytes()?;
 
         Ok(store)
     }
 
-    /// Open data files (they are immutable).
-    fn open_data_files(&mut self) -> Result<()> {
-        let pattern = format!("{}/*{}", self.path.display(), settings::DATA_FILE_SUFFIX);
-        trace!("read data files with pattern: {}", &pattern);
-        for path in glob(&pattern)? {
-            let df = DataFile::new(path?.as_path(), false)?;
+    /// Move files left in the store directory by the default layout into
+    /// the configured one. A read-only store can't move them, it keeps
+    /// reading them where they are.
+    ///
+    /// Only directories are migrated, files with other suffixes than the
+    /// configured ones belong to another store sharing the directory.
+    fn migrate_layout(&mut self) -> Result<()> {
+        let legacy = Layout::default();
+        if self.opts.layout.is_default() || !self.opts.layout.has_default_suffixes() {
+            return Ok(());
+        }
 
-            self.data_files.insert(df.file_id(), df);
+        let layout = self.opts.layout.clone();
+        let mut moves = Vec::new();
+        for (pattern, file_id_of, path_of) in [
+            (
+                legacy.data_file_pattern(&self.path),
+                Layout::data_file_id as fn(&Layout, &Path) -> Option<u64>,
+                Layout::data_file_path as fn(&Layout, &Path, u64) -> PathBuf,
+            ),
+            (
+                legacy.hint_file_pattern(&self.path),
+                Layout::hint_file_id,
+                Layout::hint_file_path,
+            ),
+            (
+                legacy.blob_file_pattern(&self.path),
+                Layout::blob_file_id,
+                Layout::blob_file_path,
+            ),
+        ] {
+            for from in self.opts.fs.glob(&pattern)? {
+                // files not named after a segment stay where they are.
+                let Some(file_id) = file_id_of(&legacy, &from) else {
+                    continue;
+                };
+                let to = path_of(&layout, &self.path, file_id);
+                if to != from {
+                    moves.push((from, to));
+                }
+            }
+        }
+        if moves.is_empty() {
+            return Ok(());
+        }
+        if self.opts.read_only {
+            info!("store files are in the default layout, reading them in place");
+            self.opts.layout = Arc::new(legacy);
+            return Ok(());
         }
-        trace!("got {} immutable data files", &self.data_files.len());
 
+        for (from, to) in moves {
+            info!("move {} to {}", from.display(), to.display());
+            self.opts.fs.rename(&from, &to)?;
+        }
         Ok(())
     }
 
-    fn build_keydir(&mut self) -> Result<()> {
-        let mut file_ids: Vec<u64> = self.data_files.keys().cloned().collect();
-        file_ids.sort();
-
-        for file_id in file_ids {
-            let hint_file_path = segment_hint_file_path(&self.path, file_id);
-            if hint_file_path.exists() {
-                self.build_keydir_from_hint_file(&hint_file_path)?;
-            } else {
-                self.build_keydir_from_data_file(file_id)?;
-            }
-        }
+    /// Return `true` if the store is closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
 
-        info!("build keydir done, got {} keys.", self.keydir.len());
+    /// Return the flag set while a compaction runs. The store doesn't
+    /// set it itself, compaction needs `&mut self` so it never races
+    /// with another one, handles sharing the store do.
+    pub fn compaction_flag(&self) -> Arc<AtomicBool> {
+        self.compacting.clone()
+    }
 
+    fn ensure_open(&self) -> Result<()> {
+        if self.closed {
+            return Err(StoreError::Closed);
+        }
         Ok(())
     }
 
-    fn build_keydir_from_hint_file(&mut self, path: &Path) -> Result<()> {
-        trace!("build keydir from hint file {}", path.display());
-        let mut hint_file = HintFile::new(path, false)?;
-        let hind_file_id = hint_file.file_id();
-
-        for entry in hint_file.iter() {
-            let keydir_entry = KeydirEntry::new(hind_file_id, entry.offset(), entry.size(), 0);
-            let _old = self.keydir.put(entry.key, keydir_entry);
-            // todo!()
+    /// Return `StoreError::Cancelled` if the cancellation token is set.
+    fn check_cancelled(&self) -> Result<()> {
+        if is_cancelled(&self.opts.cancel) {
+            return Err(StoreError::Cancelled);
         }
-
         Ok(())
     }
 
-    fn build_keydir_from_data_file(&mut self, file_id: u64) -> Result<()> {
-        let df = self.data_files.get_mut(&file_id).unwrap();
-        info!("build keydir from data file {}", df.path().display());
+    /// Write hint file of the data file from its entries, so that
+    /// next open loads the keydir without scanning the data file.
+    ///
+    /// Return `true` if hint file is written.
+    fn write_hint_file(&mut self, file_id: u64) -> Result<bool> {
+        let df = self.open_readers.get(&mut self.data_files, file_id)?;
+        if df.size()? == 0 {
+            return Ok(false);
+        }
 
-        for entry in df.iter() {
-            if entry.value == settings::REMOVE_TOMESTONE {
-                trace!("{} is a remove tomestone", &entry);
+        let mut hint_entries = Vec::new();
+        for entry in df.iter()? {
+            let entry = entry?;
+            hint_entries.push(
+                HintEntry::new(entry.key.clone(), entry.offset.unwrap(), entry.size())?
+                    .expire_at(entry.expire_at)
+                    .timestamp(entry.timestamp())
+                    .tombstone(entry.value == settings::REMOVE_TOMESTONE),
+            );
+        }
 
-                self.keydir.remove(&entry.key);
-            } else {
-                let keydir_entry = KeydirEntry::from(&entry);
-                let _old = self.keydir.put(entry.key, keydir_entry);
-                // todo!()
+        // write to a temporary file first, a partial hint file
+        // would hide entries of the data file.
+        let hint_file_path = self.opts.layout.hint_file_path(&self.path, file_id);
+        let tmp_path = prepare_tmp_path(&*self.opts.fs, &hint_file_path)?;
+        {
+            let mut hint_file = HintFile::new(&self.opts.fs, &tmp_path, true)?;
+            for hint_entry in hint_entries {
+                hint_file.write(hint_entry)?;
             }
+            hint_file.sync()?;
         }
+        self.opts.fs.rename(&tmp_path, &hint_file_path)?;
 
-        Ok(())
+        info!("write hint file {}", hint_file_path.display());
+        Ok(true)
     }
 
-    fn new_active_data_file(&mut self, file_id: Option<u64>) -> Result<()> {
-        // default next file id should be `max_file_id` + 1
-        let next_file_id: u64 =
-            file_id.unwrap_or_else(|| self.data_files.keys().max().unwrap_or(&0) + 1);
+    /// Write missing hint files of immutable data files, e.g. those
+    /// rotated before an ungraceful shutdown, so that next open loads
+    /// keydir from them instead of scanning data files.
+    ///
+    /// Data files with a valid hint file are skipped, return number of
+    /// hint files written.
+    pub fn recover(&mut self) -> Result<u64> {
+        self.ensure_open()?;
 
-        // build data file path.
-        let p = segment_data_file_path(&self.path, next_file_id);
-        debug!("new data file at: {}", &p.display());
-        self.active_data_file = Some(DataFile::new(p.as_path(), true)?);
+        if self.opts.read_only {
+            return Err(StoreError::ReadOnly);
+        }
 
-        // prepare a read-only data file with the same path.
-        let df = DataFile::new(p.as_path(), false)?;
-        self.data_files.insert(df.file_id(), df);
+        let file_ids: Vec<u64> = self
+            .data_files
+            .keys()
+            .copied()
+            .filter(|&id| Some(id) != self.active_data_file)
+            .collect();
 
-        Ok(())
-    }
+        let mut written = 0;
+        for file_id in file_ids {
+            let hint_file_path = self.opts.layout.hint_file_path(&self.path, file_id);
+            if self.opts.fs.exists(&hint_file_path) {
+                let mut hint_file = HintFile::new(&self.opts.fs, &hint_file_path, false)?;
+                match hint_file.iter()?.collect::<Result<Vec<_>>>() {
+                    Ok(_) => continue,
+                    Err(e) => warn!(
+                        "rewrite hint file {}, got error: {}",
+                        hint_file_path.display(),
+                        e
+                    ),
+                }
+            }
 
-    fn next_file_id(&self) -> u64 {
-        self.active_data_file
-            .as_ref()
-            .expect("active data file not found")
-            .file_id()
-            + 1
-    }
+            if self.write_hint_file(file_id)? {
+                written += 1;
+            }
+        }
 
-    fn write(&mut self, key: &[u8], value: &[u8]) -> Result<DataEntry> {
-        let mut df = self
-            .active_data_file
-            .as_mut()
-            .expect("active data file not found");
+        info!("recover done, {} hint files written", written);
+        Ok(written)
+    }
 
-        // check file size, rotate to another one if nessessary.
-        if df.size()? > self.opts.max_log_file_size {
-            info!(
-                "size of active data file `{}` exceeds maximum size of {} bytes, switch to another one",
-                df.path().display(),
-                self.opts.max_log_file_size
-            );
+    /// Copy value of the key to `w` without materializing it, values
+    /// are read from data files in chunks.
+    /// Return size of the value, `None` if key not found.
+    pub fn get_to<W: Write>(&mut self, key: &[u8], w: &mut W) -> Result<Option<u64>> {
+        self.ensure_open()?;
 
-            // sync data to disk.
-            let _ = df.sync();
+        let start = Instant::now();
+        let size = self
+            .copy_value_to(key, w)
+            .map_err(|e| self.external_modification_or(e))?;
+        self.record_get(start, size);
+        Ok(size)
+    }
 
-            // create a new active data file.
-            self.new_active_data_file(None)?;
+    fn copy_value_to<W: Write>(&mut self, key: &[u8], w: &mut W) -> Result<Option<u64>> {
+        let Some(keydir_entry) = self.live_keydir_entry(key) else {
+            return Ok(None);
+        };
 
-            // get new active data file for writting.
-            df = self
-                .active_data_file
-                .as_mut()
-                .expect("active data file not found");
+        if let Some(value) = self.cache.get(key) {
+            self.opts.metrics.cache_hits.incr();
+            w.write_all(value)?;
+            return Ok(Some(value.len() as u64));
         }
-
-        let entry = df.write(key, value)?;
-        if self.opts.sync {
-            // make sure data entry is persisted in storage.
-            df.sync()?;
+        if self.cache.is_enabled() {
+            self.opts.metrics.cache_misses.incr();
         }
 
-        Ok(entry)
-    }
-}
-
-impl<K> Storage for DiskStorage<K>
-where
-    K: Keydir + Default,
-{
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        match self.keydir.get(key) {
-            None => Ok(None),
-            Some(keydir_entry) => {
-                trace!(
-                    "found key `{}` in keydir, got value {:?}",
-                    String::from_utf8_lossy(key),
-                    &keydir_entry,
-                );
+        let df = self.data_file_of(key, keydir_entry.file_id)?;
+        let Some(header) = df.read_header(keydir_entry.offset)? else {
+            return Ok(None);
+        };
 
-                let df = self
-                    .data_files
-                    .get_mut(&keydir_entry.file_id)
-                    .unwrap_or_else(|| {
-                        panic!("data file {} not found", &keydir_entry.file_id);
-                    });
-
-                match df.read(keydir_entry.offset)? {
-                    None => Ok(None),
-                    Some(e) => Ok(e.value.into()),
-                }
-            }
+        if header.flags() & FLAG_BLOB == 0 {
+            df.read_value_into(keydir_entry.offset, w)?;
+            return Ok(Some(header.value_sz() as u64));
         }
+
+        let mut ptr = Vec::new();
+        df.read_value_into(keydir_entry.offset, &mut ptr)?;
+        let ptr = BlobPointer::decode(&ptr)?;
+        read_blob(&mut self.blob_files, key, &ptr, w)?;
+        Ok(Some(ptr.size))
     }
 
-    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
-        let (key, value) = (key.as_ref(), value.as_ref());
+    /// Set key to a value of `size` bytes copied from `r`, without
+    /// materializing the value. Values streamed in are not cached.
+    ///
+    /// Nothing is stored if `r` fails or ends before `size` bytes.
+    pub fn set_from_reader<R: Read>(&mut self, key: &[u8], size: u64, r: &mut R) -> Result<()> {
+        self.ensure_writeable()?;
 
         if key.len() as u64 > self.opts.max_key_size {
             return Err(StoreError::KeyIsTooLarge);
         }
 
-        if value.len() as u64 > self.opts.max_value_size {
+        if size > self.opts.max_value_size || size > u32::MAX as u64 {
             return Err(StoreError::ValueIsTooLarge);
         }
 
-        // save data to data file.
-        let data_entry = self.write(key, value)?;
+        self.check_limits(key, (HEADER_SIZE + key.len()) as u64 + size)?;
 
-        // update keydir, the in-memory index.
-        let keydir_entry = KeydirEntry::from(&data_entry);
-        let _old = self.keydir.put(data_entry.key, keydir_entry);
+        let start = Instant::now();
 
-        Ok(())
-    }
+        let keydir_entry = if self.opts.blob_threshold > 0 && size > self.opts.blob_threshold {
+            let timestamp = self.next_timestamp();
+            let bf = self.active_blob_file()?;
+            let (offset, _) = bf.write_from_reader(key, size as u32, timestamp, r)?;
+            bf.sync()?;
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        if !self.keydir.contains_key(key) {
-            trace!(
-                "remove key `{}`, but it not found in datastore",
-                String::from_utf8_lossy(key)
-            );
+            let ptr = BlobPointer {
+                file_id: bf.file_id(),
+                offset,
+                size,
+            };
+            let blob_size = (HEADER_SIZE + key.len()) as u64 + size;
+            self.opts.metrics.bytes_written.add(blob_size);
+            self.disk_bytes += blob_size;
+            let entry = DataEntry::new(key.to_vec(), ptr.encode().to_vec())?.blob(true);
+            KeydirEntry::from(&self.write(entry)?)
         } else {
-            trace!(
-                "remove key `{}` from datastore",
-                String::from_utf8_lossy(key)
-            );
+            self.rotate_active_data_file()?;
 
-            // write tomestone, will be removed on compaction.
-            let _entry = self.write(key, settings::REMOVE_TOMESTONE)?;
+            let timestamp = self.next_timestamp();
+            let df = self.active_data_file();
+            let (offset, header) = df.write_from_reader(key, size as u32, timestamp, r)?;
+            let entry_size = (HEADER_SIZE + key.len()) as u64 + size;
+            let keydir_entry =
+                KeydirEntry::new(df.file_id(), offset, entry_size, header.timestamp());
+            self.written(entry_size)?;
+            keydir_entry
+        };
+
+        self.tombstones.remove(key);
+        let _old = self.keydir.put(key.to_vec(), keydir_entry);
+        self.cache.remove(key);
+        self.record_set(start);
 
-            // remove key from in-memory index.
-            self.keydir.remove(key);
+        // read the value back only if someone is watching.
+        if !self.watchers.is_empty() {
+            if let Some(value) = self.get(key)? {
+                self.watchers.notify(|| Event::Put {
+                    key: key.to_vec(),
+                    value,
+                });
+            }
         }
 
         Ok(())
     }
 
-    fn keys(&self) -> Result<Vec<Vec<u8>>> {
-        Ok(self.keydir.keys())
-    }
+    /// Write a tombstone of the key and remove it from keydir.
+    fn write_tombstone(&mut self, key: &[u8]) -> Result<()> {
+        // write tomestone, will be removed on compaction.
+        let tomestone = DataEntry::new(key.to_vec(), settings::REMOVE_TOMESTONE.to_vec())?;
+        let entry = self.write(tomestone)?;
+        if self.opts.durable_deletes && !self.opts.sync {
+            self.sync()?;
+        }
+        self.tombstones
+            .insert(key.to_vec(), KeydirEntry::from(&entry));
 
-    fn len(&self) -> u64 {
-        self.keydir.len()
+        // remove key from in-memory index.
+        self.keydir.remove(key);
+        self.cache.remove(key);
+        Ok(())
     }
 
-    fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
+    /// Return keydir entry of the key, `None` if not found or expired.
+    fn live_keydir_entry(&mut self, key: &[u8]) -> Option<KeydirEntry> {
+        match self.keydir.get(key) {
+            None => None,
+            Some(keydir_entry) if keydir_entry.is_expired(now_millis()) => {
+                trace!("key `{}` is expired", String::from_utf8_lossy(key));
+
+                // expire lazily, the record on disk is dropped on compaction.
+                self.keydir.remove(key);
+                self.cache.remove(key);
+                None
+            }
+            Some(keydir_entry) => Some(keydir_entry.clone()),
+        }
+    }
+
+    /// Return data file `file_id` holding the key for reading.
+    ///
+    /// An immutable data file whose size changed since it was sealed
+    /// returns `StoreError::ExternalModification`.
+    fn data_file_of(&mut self, key: &[u8], file_id: u64) -> Result<&mut DataFile> {
+        match self.open_readers.get(&mut self.data_files, file_id) {
+            Ok(df) => match self.fingerprints.get(&file_id) {
+                Some(fingerprint) if df.size()? != fingerprint.len => {
+                    Err(StoreError::ExternalModification { file_id })
+                }
+                _ => Ok(df),
+            },
+            Err(StoreError::SegmentMissing { file_id })
+                if self.fingerprints.contains_key(&file_id) =>
+            {
+                Err(StoreError::ExternalModification { file_id })
+            }
+            Err(e @ StoreError::SegmentMissing { .. }) => {
+                // drop the dangling entry, later reads see a miss.
+                warn!("{}, remove key `{}`", e, String::from_utf8_lossy(key));
+                self.keydir.remove(key);
+                self.cache.remove(key);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Iterate all keys without cloning them, the iterator borrows
+    /// the keydir so callers can filter and take only what they need.
+    pub fn keys_iter(&self) -> Result<impl Iterator<Item = &[u8]>> {
+        self.ensure_open()?;
+
+        Ok(self.keydir.keys_iter())
+    }
+
+    /// Return location of the value of `key`, to read it once the store
+    /// is released so that reading a large value doesn't hold off
+    /// writers and compaction.
+    ///
+    /// Return `None` if the value has to be read with `get_into`
+    /// instead: the key isn't live, values are cached, data files are
+    /// checked for external modification or the value is still in the
+    /// write buffer.
+    pub fn value_location(&self, key: &[u8]) -> Result<Option<ValueLocation>> {
+        self.ensure_open()?;
+
+        if self.opts.value_cache_bytes > 0 || self.opts.detect_external_modification {
+            return Ok(None);
+        }
+
+        // entries still in the write buffer are only seen through the
+        // store's own handle.
+        let location = self
+            .keydir
+            .get(key)
+            .filter(|entry| !entry.is_expired(now_millis()))
+            .filter(|entry| {
+                !self
+                    .data_files
+                    .get(&entry.file_id)
+                    .is_some_and(|df| df.is_buffered(entry.offset))
+            })
+            .map(|entry| ValueLocation {
+                fs: self.opts.fs.clone(),
+                dir: self.path.clone(),
+                layout: self.opts.layout.clone(),
+                file_id: entry.file_id,
+                offset: entry.offset,
+                metrics: self.opts.metrics.clone(),
+            });
+        Ok(location)
+    }
+
+    /// Return sealed data files holding stale entries, the most
+    /// fragmented first, i.e. those freeing the most per byte copied
+    /// when compacted.
+    pub fn compaction_candidates(&self) -> Result<Vec<SegmentInfo>> {
+        let mut segments: Vec<SegmentInfo> = self
+            .segments()?
+            .into_iter()
+            .filter(|s| !s.is_active && s.stale_bytes() > 0)
+            .collect();
+        // compare stale ratios without rounding.
+        segments.sort_by(|a, b| {
+            let a_ratio = a.stale_bytes() as u128 * b.size_bytes as u128;
+            let b_ratio = b.stale_bytes() as u128 * a.size_bytes as u128;
+            b_ratio
+                .cmp(&a_ratio)
+                .then(b.stale_bytes().cmp(&a.stale_bytes()))
+        });
+        Ok(segments)
+    }
+
+    /// Return statistics of rebuilding keydir when the store was opened.
+    pub fn recovery_stats(&self) -> &RecoveryStats {
+        &self.recovery_stats
+    }
+
+    /// Return all records of `key` in data files, live or stale, ordered
+    /// by file id and offset, as `(file_id, offset, timestamp,
+    /// is_tombstone)`.
+    ///
+    /// Scans every data file, meant for troubleshooting recovery.
+    pub fn dump_key(&mut self, key: &[u8]) -> Result<Vec<(u64, u64, u64, bool)>> {
+        self.ensure_open()?;
+
+        let file_ids: Vec<u64> = self.data_files.keys().copied().collect();
+        let mut records = Vec::new();
+        for file_id in file_ids {
+            let df = self.open_readers.get(&mut self.data_files, file_id)?;
+            for entry in df.iter()? {
+                let entry = entry?;
+                if entry.key == key {
+                    let is_tombstone = entry.value == settings::REMOVE_TOMESTONE;
+                    records.push((
+                        file_id,
+                        entry.offset.unwrap_or_default(),
+                        entry.timestamp(),
+                        is_tombstone,
+                    ));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Compact all data files into a single one, regardless of
+    /// `max_log_file_size`, e.g. for an archival snapshot easy to copy.
+    ///
+    /// The resulting data file may exceed the size limit, it's never
+    /// appended to and is compacted as usual.
+    pub fn compact_to_single_file(&mut self) -> Result<CompactionReport> {
+        self.compact_all(u64::MAX)
+    }
+
+    /// Compact only the given data files, e.g. the most fragmented ones
+    /// from `compaction_candidates`. Their live entries are copied into
+    /// new data files and they're removed, other data files and blob
+    /// files are left untouched.
+    ///
+    /// Return `StoreError::SegmentMissing` if a data file isn't
+    /// registered.
+    pub fn compact_segments(&mut self, file_ids: &[u64]) -> Result<CompactionReport> {
+        self.ensure_open()?;
+
+        if self.opts.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+        if let Some(&file_id) = file_ids.iter().find(|id| !self.data_files.contains_key(id)) {
+            return Err(StoreError::SegmentMissing { file_id });
+        }
+        if file_ids.is_empty() {
+            return Ok(CompactionReport::default());
+        }
+
+        // keydir entries are about to change, drop all cached values.
+        self.cache.clear();
+
+        self.check_cancelled()?;
+        let start = Instant::now();
+        let mut report = self.compact_files(file_ids, self.opts.max_log_file_size)?;
+        report.duration = start.elapsed();
+
+        info!("compaction of segments {:?} done: {:?}", file_ids, report);
+        self.opts.metrics.compactions.incr();
+        Ok(report)
+    }
+
+    /// Compact all data files, switching to a new compaction data file
+    /// once one exceeds `max_file_size`.
+    fn compact_all(&mut self, max_file_size: u64) -> Result<CompactionReport> {
+        self.ensure_open()?;
+
+        if self.opts.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+
+        // keydir entries are about to change, drop all cached values.
+        self.cache.clear();
+
+        // rewritten blobs get new pointers, compact them first
+        // so the pointers are compacted along with other entries.
+        self.compact_blob_files()?;
+
+        self.check_cancelled()?;
+        let start = Instant::now();
+        let file_ids: Vec<u64> = self.data_files.keys().copied().collect();
+        let mut report = self.compact_files(&file_ids, max_file_size)?;
+        report.duration = start.elapsed();
+
+        info!("compaction done: {:?}", report);
+        self.opts.metrics.compactions.incr();
+        Ok(report)
+    }
+
+    /// Return the manifest of the store.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Subscribe to changes of keys starting with `prefix`.
+    pub fn subscribe(&mut self, prefix: Vec<u8>) -> Receiver<Event> {
+        self.watchers.subscribe(prefix)
+    }
+
+    /// Check immutable data files against their metadata recorded when
+    /// they were sealed, return `StoreError::ExternalModification` if
+    /// any of them was removed, truncated, appended to or rewritten.
+    pub fn verify_segments(&self) -> Result<()> {
+        for (&file_id, fingerprint) in self.fingerprints.iter() {
+            self.check_cancelled()?;
+            let path = self.opts.layout.data_file_path(&self.path, file_id);
+            match self.opts.fs.metadata(&path) {
+                Ok(metadata) if metadata == *fingerprint => {}
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {
+                    warn!("data file {} was modified externally", path.display());
+                    return Err(StoreError::ExternalModification { file_id });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace error `e` of a failed read by `StoreError::ExternalModification`
+    /// if a data file was modified behind the store's back.
+    fn external_modification_or(&self, e: StoreError) -> StoreError {
+        if matches!(e, StoreError::ExternalModification { .. }) {
+            return e;
+        }
+        match self.verify_segments() {
+            Err(modified @ StoreError::ExternalModification { .. }) => modified,
+            _ => e,
+        }
+    }
+
+    /// Record metadata of data file `file_id` once it's immutable.
+    fn record_fingerprint(&mut self, file_id: u64) -> Result<()> {
+        if self.opts.detect_external_modification {
+            let path = self.opts.layout.data_file_path(&self.path, file_id);
+            self.fingerprints
+                .insert(file_id, self.opts.fs.metadata(&path)?);
+        }
+        Ok(())
+    }
+
+    /// Open data files (they are immutable).
+    fn open_data_files(&mut self) -> Result<()> {
+        let pattern = self.opts.layout.data_file_pattern(&self.path);
+        trace!("read data files with pattern: {}", &pattern);
+        for path in self.opts.fs.glob(&pattern)? {
+            let file_id = self.opts.layout.data_file_id(&path);
+            let Some(file_id) = self.segment_file_id(&path, file_id)? else {
+                continue;
+            };
+            if self.data_files.contains_key(&file_id) {
+                return Err(StoreError::DuplicateSegment { file_id });
+            }
+            let df = DataFile::new(&self.opts.fs, &path, false)?.entry_limits(self.entry_limits());
+
+            self.data_files.insert(file_id, df);
+            self.record_fingerprint(file_id)?;
+        }
+        trace!("got {} immutable data files", &self.data_files.len());
+
+        let pattern = self.opts.layout.blob_file_pattern(&self.path);
+        for path in self.opts.fs.glob(&pattern)? {
+            let file_id = self.opts.layout.blob_file_id(&path);
+            let Some(file_id) = self.segment_file_id(&path, file_id)? else {
+                continue;
+            };
+            if self.blob_files.contains_key(&file_id) {
+                return Err(StoreError::DuplicateSegment { file_id });
+            }
+            let bf = DataFile::new(&self.opts.fs, &path, false)?.entry_limits(self.entry_limits());
+
+            self.blob_files.insert(file_id, bf);
+        }
+        trace!("got {} blob files", &self.blob_files.len());
+
+        Ok(())
+    }
+
+    /// Return `file_id`, the id of segment file `path` if it's named after
+    /// a segment. Other files, e.g. editor backups, are skipped if
+    /// `ignore_foreign_files`, `StoreError::InvalidFileName` otherwise.
+    fn segment_file_id(&self, path: &Path, file_id: Option<u64>) -> Result<Option<u64>> {
+        match file_id {
+            Some(file_id) => Ok(Some(file_id)),
+            None if self.opts.ignore_foreign_files => {
+                warn!("ignore foreign file {}", path.display());
+                Ok(None)
+            }
+            None => Err(StoreError::InvalidFileName(path.to_path_buf())),
+        }
+    }
+
+    /// Estimate number of keys from hint files, each hint entry
+    /// takes at least `HEADER_SIZE` bytes.
+    fn estimate_keydir_capacity(&self) -> Result<u64> {
+        let mut capacity = 0;
+        for &file_id in self.data_files.keys() {
+            let hint_file_path = self.opts.layout.hint_file_path(&self.path, file_id);
+            if let Ok(metadata) = self.opts.fs.metadata(&hint_file_path) {
+                capacity += metadata.len / HEADER_SIZE as u64;
+            }
+        }
+        Ok(capacity)
+    }
+
+    /// Warn about hint files without a data file, left behind by a crash
+    /// while compaction removes segments, and remove them if `repair`.
+    /// They're never loaded, keydir is built from data files.
+    fn check_orphaned_hint_files(&mut self, stats: &mut RecoveryStats) -> Result<()> {
+        let pattern = self.opts.layout.hint_file_pattern(&self.path);
+        let orphaned: Vec<PathBuf> = self
+            .opts
+            .fs
+            .glob(&pattern)?
+            .into_iter()
+            .filter(|path| {
+                self.opts
+                    .layout
+                    .hint_file_id(path)
+                    .is_some_and(|id| !self.data_files.contains_key(&id))
+            })
+            .collect();
+        if orphaned.is_empty() {
+            return Ok(());
+        }
+
+        let paths: Vec<_> = orphaned.iter().map(|p| p.display().to_string()).collect();
+        warn!("ignore hint files without data file: {}", paths.join(", "));
+        stats.orphaned_hints = orphaned.len() as u64;
+
+        if self.opts.repair && !self.opts.read_only {
+            for path in orphaned {
+                info!("remove orphaned hint file {}", path.display());
+                self.opts.fs.remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn build_keydir(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let mut stats = RecoveryStats::default();
+        self.check_orphaned_hint_files(&mut stats)?;
+        let mut file_ids: Vec<u64> = self.data_files.keys().cloned().collect();
+        file_ids.sort();
+
+        // reserve keydir up front to avoid rehashing while loading.
+        let capacity = match self.opts.keydir_capacity {
+            0 => self.estimate_keydir_capacity()?,
+            n => n,
+        };
+        trace!("build keydir with capacity {}", capacity);
+        self.keydir = K::for_store(&self.opts, capacity as usize);
+
+        let mut progress = RecoveryProgress {
+            files_total: file_ids.len() as u64,
+            ..RecoveryProgress::default()
+        };
+        for file_id in file_ids {
+            self.check_cancelled()?;
+            let file_start = Instant::now();
+            let hint_file_path = self.opts.layout.hint_file_path(&self.path, file_id);
+            let res = if self.opts.fs.exists(&hint_file_path) {
+                self.build_keydir_from_hint_file(&hint_file_path, &mut stats)
+                    .map(|()| stats.files_from_hints += 1)
+                    .or_else(|e| {
+                        warn!(
+                            "discard hint file {}, got error: {}, scan data file instead",
+                            hint_file_path.display(),
+                            e
+                        );
+                        stats.entries_per_file.remove(&file_id);
+                        self.build_keydir_from_data_file(file_id, 0, &mut stats)
+                    })
+            } else {
+                self.build_keydir_from_data_file(file_id, 0, &mut stats)
+            };
+
+            match res {
+                Ok(()) => {}
+                Err(e) if self.opts.continue_on_corruption => {
+                    error!("skip corrupted segment {}, got error: {}", file_id, e);
+                    stats.files_skipped += 1;
+                }
+                Err(e) => return Err(e),
+            }
+            // compaction tells entries dropped from the count.
+            let entries = stats.entries_per_file.get(&file_id).copied().unwrap_or(0);
+            if let Some(df) = self.data_files.get_mut(&file_id) {
+                df.set_entry_count(entries);
+            }
+            progress.files_skipped = stats.files_skipped;
+            progress.files_from_hints = stats.files_from_hints;
+
+            progress.files_processed += 1;
+            progress.keys_loaded = self.keydir.len();
+            info!(
+                "loaded segment {} ({}/{}): {} entries in {:?}, {:?} elapsed",
+                file_id,
+                progress.files_processed,
+                progress.files_total,
+                entries,
+                file_start.elapsed(),
+                start.elapsed()
+            );
+            if let Some(f) = &self.opts.recovery_progress {
+                f.0(&progress);
+            }
+        }
+
+        stats.keys = self.keydir.len();
+        stats.duration = start.elapsed();
+        info!(
+            "build keydir done, got {} keys in {:?}: {} segments from hint files, {} scanned, {} skipped, {} tombstones",
+            stats.keys,
+            stats.duration,
+            stats.files_from_hints,
+            stats.files_scanned,
+            stats.files_skipped,
+            stats.tombstones
+        );
+        self.recovery_stats = stats;
+
+        Ok(())
+    }
+
+    fn build_keydir_from_hint_file(
+        &mut self,
+        path: &Path,
+        stats: &mut RecoveryStats,
+    ) -> Result<()> {
+        trace!("build keydir from hint file {}", path.display());
+        let mut hint_file = HintFile::new(&self.opts.fs, path, false)?;
+        let hind_file_id = hint_file.file_id();
+
+        // read all entries before touching keydir, so that a corrupted
+        // hint file is discarded as a whole.
+        let entries = hint_file.iter()?.collect::<Result<Vec<_>>>()?;
+
+        // keydir timestamps must be the ones of data entries, which hint
+        // files of older versions don't record.
+        if entries.iter().any(|e| !e.has_timestamp()) {
+            return Err(StoreError::HintWithoutTimestamps {
+                file_id: hind_file_id,
+            });
+        }
+        *stats.entries_per_file.entry(hind_file_id).or_default() += entries.len() as u64;
+
+        // entries must lie within their data file.
+        let data_file_size = self
+            .open_readers
+            .get(&mut self.data_files, hind_file_id)?
+            .size()?;
+        if let Some(entry) = entries
+            .iter()
+            .find(|e| e.offset().saturating_add(e.size()) > data_file_size)
+        {
+            return Err(StoreError::HintEntryOutOfBounds {
+                file_id: hind_file_id,
+                key: entry.key.clone(),
+            });
+        }
+        let covered = entries
+            .iter()
+            .map(|e| e.offset() + e.size())
+            .max()
+            .unwrap_or_default();
+
+        let now = now_millis();
+        for entry in entries {
+            self.last_timestamp = self.last_timestamp.max(entry.timestamp);
+            let keydir_entry =
+                KeydirEntry::new(hind_file_id, entry.offset(), entry.size(), entry.timestamp)
+                    .expire_at(entry.expire_at);
+            if entry.is_tombstone() {
+                stats.tombstones += 1;
+                self.keydir.remove(&entry.key);
+                self.tombstones.insert(entry.key, keydir_entry);
+                continue;
+            }
+            if keydir_entry.is_expired(now) {
+                self.keydir.remove(&entry.key);
+                continue;
+            }
+            self.tombstones.remove(&entry.key);
+            let _old = self.keydir.put(entry.key, keydir_entry);
+            // todo!()
+        }
+
+        // entries appended after the hint file was written, e.g. by a
+        // crash before it's rewritten, are only in the data file.
+        if covered < data_file_size {
+            debug!(
+                "hint file {} covers {} of {} bytes, scan the rest of data file",
+                path.display(),
+                covered,
+                data_file_size
+            );
+            self.build_keydir_from_data_file(hind_file_id, covered, stats)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load entries of data file `file_id` from offset `from` on, a scan
+    /// from a later offset completes a hint file which covers only the
+    /// beginning of the data file.
+    fn build_keydir_from_data_file(
+        &mut self,
+        file_id: u64,
+        from: u64,
+        stats: &mut RecoveryStats,
+    ) -> Result<()> {
+        // only the newest data file was being written to when the store
+        // went down, a short entry elsewhere is corruption.
+        let newest = self.data_files.keys().next_back() == Some(&file_id);
+
+        let df = self.open_readers.get(&mut self.data_files, file_id)?;
+        info!("build keydir from data file {}", df.path().display());
+
+        let now = now_millis();
+        let mut end = from;
+        let mut torn = false;
+        for entry in df.iter_from(from)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                // an entry cut short by a failed write or a crash.
+                Err(e) if newest && is_partial_entry(&e) => {
+                    warn!(
+                        "discard partial entry at offset {} of data file {}",
+                        end,
+                        df.path().display()
+                    );
+                    torn = true;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            end += entry.size();
+            *stats.entries_per_file.entry(file_id).or_default() += 1;
+            self.last_timestamp = self.last_timestamp.max(entry.timestamp());
+            if entry.value == settings::REMOVE_TOMESTONE {
+                trace!("{} is a remove tomestone", &entry);
+                stats.tombstones += 1;
+
+                self.keydir.remove(&entry.key);
+                self.tombstones
+                    .insert(entry.key.clone(), KeydirEntry::from(&entry));
+            } else if matches!(entry.expire_at, Some(t) if t <= now) {
+                trace!("{} is expired", &entry);
+
+                self.keydir.remove(&entry.key);
+            } else {
+                let keydir_entry = KeydirEntry::from(&entry);
+                self.tombstones.remove(&entry.key);
+                let _old = self.keydir.put(entry.key, keydir_entry);
+                // todo!()
+            }
+        }
+        if from == 0 {
+            stats.files_scanned += 1;
+        }
+
+        // space preallocated beyond the entries is left behind by a crash.
+        if newest && !torn && end < df.size()? {
+            warn!(
+                "discard unwritten space from offset {} of data file {}",
+                end,
+                df.path().display()
+            );
+            torn = true;
+        }
+
+        if torn && !self.opts.read_only {
+            let path = self.opts.layout.data_file_path(&self.path, file_id);
+            self.opts.fs.create(&path)?.set_len(end)?;
+            self.data_files.get_mut(&file_id).unwrap().close_reader();
+            self.record_fingerprint(file_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reuse the newest data file as the active one if it's below
+    /// `max_log_file_size`, so that reopening the store doesn't leave a
+    /// small segment behind every time, otherwise start a new one.
+    fn open_active_data_file(&mut self) -> Result<()> {
+        let file_id = match self.data_files.iter().next_back() {
+            Some((&file_id, df)) if df.size()? < self.opts.max_log_file_size => file_id,
+            _ => return self.new_active_data_file(None),
+        };
+
+        // the hint file would miss entries appended from now on, it's
+        // written again on close.
+        let hint_file_path = self.opts.layout.hint_file_path(&self.path, file_id);
+        if self.opts.fs.exists(&hint_file_path) {
+            self.opts.fs.remove(&hint_file_path)?;
+        }
+
+        // the segment registered on open becomes writeable, it isn't
+        // opened a second time.
+        let mut df = self
+            .data_files
+            .remove(&file_id)
+            .unwrap()
+            .remove_empty_on_drop(self.opts.remove_empty_files);
+        debug!("reuse data file at: {}", df.path().display());
+        df.make_writeable()?;
+        let df = df.write_buffer(self.opts.write_buffer_size as usize);
+        self.data_files.insert(file_id, df);
+        self.fingerprints.remove(&file_id);
+        self.active_data_file = Some(file_id);
+
+        Ok(())
+    }
+
+    fn new_active_data_file(&mut self, file_id: Option<u64>) -> Result<()> {
+        // default next file id should be `max_file_id` + 1
+        let next_file_id: u64 =
+            file_id.unwrap_or_else(|| self.data_files.keys().max().unwrap_or(&0) + 1);
+
+        // seal the previous one, it's removed on drop if empty and
+        // `remove_empty_files`.
+        if let Some(file_id) = self.active_data_file.take() {
+            if let Some(mut df) = self.data_files.remove(&file_id) {
+                if df.size()? > 0 || !self.opts.remove_empty_files {
+                    df.seal()?;
+                    self.data_files.insert(file_id, df);
+                    self.record_fingerprint(file_id)?;
+                }
+            }
+            self.open_readers.retain(&self.data_files);
+        }
+
+        // build data file path.
+        let p = self.opts.layout.data_file_path(&self.path, next_file_id);
+        debug!("new data file at: {}", &p.display());
+        let df = if self.opts.preallocate {
+            DataFile::preallocated(&self.opts.fs, p.as_path(), self.opts.max_log_file_size)?
+        } else {
+            DataFile::new(&self.opts.fs, p.as_path(), true)?
+        };
+        let df = df
+            .remove_empty_on_drop(self.opts.remove_empty_files)
+            .write_buffer(self.opts.write_buffer_size as usize)
+            .entry_limits(self.entry_limits());
+        self.active_data_file = Some(df.file_id());
+        self.data_files.insert(df.file_id(), df);
+        self.disk_bytes = self.segment_bytes()?;
+
+        Ok(())
+    }
+
+    /// Return total bytes of data and blob files.
+    fn segment_bytes(&self) -> Result<u64> {
+        let mut bytes = 0;
+        for f in self.data_files.values().chain(self.blob_files.values()) {
+            bytes += f.size()?;
+        }
+        Ok(bytes)
+    }
+
+    /// Return error if writing `size` bytes for `key` would exceed
+    /// `max_keys` or `max_disk_bytes`, overwriting a key never counts
+    /// as a new key, expired keys don't count either.
+    fn check_limits(&mut self, key: &[u8], size: u64) -> Result<()> {
+        let max_keys = self.opts.max_keys;
+        if max_keys > 0 && self.keydir.len() >= max_keys && !self.contains_key(key) {
+            // expire lazily, the records on disk are dropped on compaction.
+            let now = now_millis();
+            let expired: Vec<Vec<u8>> = self
+                .keydir
+                .iter()
+                .filter(|(_, entry)| entry.is_expired(now))
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            for key in expired.iter() {
+                self.keydir.remove(key);
+                self.cache.remove(key);
+            }
+            if self.keydir.len() >= max_keys {
+                return Err(StoreError::KeyLimitExceeded);
+            }
+        }
+
+        let max_disk_bytes = self.opts.max_disk_bytes;
+        if max_disk_bytes > 0 && self.disk_bytes.saturating_add(size) > max_disk_bytes {
+            return Err(StoreError::DiskLimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Return the active data file.
+    fn active_data_file(&mut self) -> &mut DataFile {
+        let file_id = self.active_data_file.expect("active data file not found");
+        self.data_files
+            .get_mut(&file_id)
+            .expect("active data file not found")
+    }
+
+    /// Save key value to data file, and update keydir.
+    fn put(&mut self, key: &[u8], value: &[u8], expire_at: Option<u64>) -> Result<()> {
+        self.ensure_open()?;
+
+        if key.len() as u64 > self.opts.max_key_size {
+            return Err(StoreError::KeyIsTooLarge);
+        }
+
+        if value.len() as u64 > self.opts.max_value_size {
+            return Err(StoreError::ValueIsTooLarge);
+        }
+
+        self.check_limits(
+            key,
+            (HEADER_SIZE + EXPIRY_SIZE + key.len() + value.len()) as u64,
+        )?;
+
+        let start = Instant::now();
+
+        // save data to data file, large values go to a blob file first
+        // and the data entry points to it.
+        let data_entry =
+            if self.opts.blob_threshold > 0 && value.len() as u64 > self.opts.blob_threshold {
+                let ptr = self.write_blob(key, value, expire_at)?;
+                DataEntry::new(key.to_vec(), ptr.encode().to_vec())?.blob(true)
+            } else {
+                DataEntry::new(key.to_vec(), value.to_vec())?
+            };
+        let data_entry = self.write(data_entry.expire_at(expire_at))?;
+
+        // update keydir, the in-memory index.
+        let keydir_entry = KeydirEntry::from(&data_entry);
+        self.tombstones.remove(key);
+        let _old = self.keydir.put(data_entry.key, keydir_entry);
+        self.cache.put(key, value);
+        self.record_set(start);
+
+        self.watchers.notify(|| Event::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+
+        Ok(())
+    }
+
+    /// Save value to active blob file, return pointer to it.
+    ///
+    /// The blob is synced before returning, so a data entry pointing
+    /// to it never survives a crash without the blob.
+    fn write_blob(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expire_at: Option<u64>,
+    ) -> Result<BlobPointer> {
+        if self.opts.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+
+        let bf = self.active_blob_file()?;
+        let entry = DataEntry::new(key.to_vec(), value.to_vec())?.expire_at(expire_at);
+        let entry = bf.write(entry)?;
+        bf.sync()?;
+
+        let ptr = BlobPointer {
+            file_id: bf.file_id(),
+            offset: entry.offset.expect("offset of blob entry"),
+            size: value.len() as u64,
+        };
+        self.opts.metrics.bytes_written.add(entry.size());
+        self.disk_bytes += entry.size();
+        Ok(ptr)
+    }
+
+    /// Return the active blob file, switch to a new one if it's full.
+    fn active_blob_file(&mut self) -> Result<&mut DataFile> {
+        let rotate = match &self.active_blob_file {
+            None => true,
+            Some(bf) => bf.size()? > self.opts.max_log_file_size,
+        };
+        if rotate {
+            let next_file_id = self.blob_files.keys().max().unwrap_or(&0) + 1;
+            let p = self.opts.layout.blob_file_path(&self.path, next_file_id);
+            debug!("new blob file at: {}", &p.display());
+            let bf = DataFile::new(&self.opts.fs, &p, true)?
+                .remove_empty_on_drop(self.opts.remove_empty_files)
+                .entry_limits(self.entry_limits());
+            self.blob_files.insert(next_file_id, bf.read_only_twin());
+            self.active_blob_file = Some(bf);
+        }
+
+        Ok(self
+            .active_blob_file
+            .as_mut()
+            .expect("active blob file not found"))
+    }
+
+    /// Remove blob files without live values, and rewrite blob files
+    /// which are less than half live into the active blob file.
+    fn compact_blob_files(&mut self) -> Result<()> {
+        let active_blob_file_id = self.active_blob_file.as_ref().map(|bf| bf.file_id());
+        let blob_file_ids: Vec<u64> = self
+            .blob_files
+            .keys()
+            .copied()
+            .filter(|&id| Some(id) != active_blob_file_id)
+            .collect();
+
+        for blob_file_id in blob_file_ids {
+            self.check_cancelled()?;
+            let bf = self.blob_files.get_mut(&blob_file_id).unwrap();
+            let total_bytes = bf.size()?;
+            let blobs = bf
+                .iter()?
+                .map(|e| {
+                    e.map(|e| {
+                        let size = e.size();
+                        (e.key, e.offset.unwrap(), size)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // a blob is live if its key still points to it.
+            let mut live = Vec::new();
+            let mut live_bytes = 0;
+            for (key, offset, size) in blobs {
+                if self.blob_pointer_of(&key)?.map(|p| (p.file_id, p.offset))
+                    == Some((blob_file_id, offset))
+                {
+                    live.push(key);
+                    live_bytes += size;
+                }
+            }
+
+            if live_bytes * 2 >= total_bytes && live_bytes > 0 {
+                continue;
+            }
+
+            info!(
+                "compact blob file {} with {} of {} bytes live",
+                blob_file_id, live_bytes, total_bytes
+            );
+
+            for key in live {
+                let mut value = Vec::new();
+                let (expire_at, timestamp) = match self.keydir.get(&key) {
+                    Some(e) => (e.expire_at, e.timestamp),
+                    None => (None, 0),
+                };
+                let ptr = self.blob_pointer_of(&key)?.expect("live blob pointer");
+                read_blob(&mut self.blob_files, &key, &ptr, &mut value)?;
+
+                let ptr = self.write_blob(&key, &value, expire_at)?;
+                let entry = DataEntry::new(key, ptr.encode().to_vec())?
+                    .blob(true)
+                    .expire_at(expire_at)
+                    .with_timestamp(timestamp);
+                let entry = self.write_entry(entry)?;
+                let keydir_entry = KeydirEntry::from(&entry);
+                self.keydir.put(entry.key, keydir_entry);
+            }
+
+            // the pointers to the moved blobs must be durable before
+            // the old blob file is gone.
+            self.sync()?;
+            if let Some(bf) = self.blob_files.remove(&blob_file_id) {
+                info!("remove stale blob file {}", bf.path().display());
+                self.opts.fs.remove(bf.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return blob pointer of the key, `None` if the key is missing
+    /// or its value is not in a blob file.
+    fn blob_pointer_of(&mut self, key: &[u8]) -> Result<Option<BlobPointer>> {
+        let keydir_entry = match self.keydir.get(key) {
+            None => return Ok(None),
+            Some(e) => e.clone(),
+        };
+
+        let df = self
+            .open_readers
+            .get(&mut self.data_files, keydir_entry.file_id)?;
+
+        match df.read(keydir_entry.offset)? {
+            Some(entry) if entry.is_blob() => Ok(Some(BlobPointer::decode(&entry.value)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the key and value sizes entries read back may have.
+    fn entry_limits(&self) -> EntryLimits {
+        EntryLimits {
+            max_key_size: self.opts.max_key_size,
+            max_value_size: self.opts.max_value_size,
+        }
+    }
+
+    /// Return an id above all data files, compaction data files may
+    /// follow the active one.
+    fn next_file_id(&self) -> u64 {
+        let active_file_id = self.active_data_file.expect("active data file not found");
+        self.data_files
+            .keys()
+            .max()
+            .map_or(active_file_id, |&id| id.max(active_file_id))
+            + 1
+    }
+
+    fn write(&mut self, entry: DataEntry) -> Result<DataEntry> {
+        let timestamp = self.next_timestamp();
+        self.write_entry(entry.with_timestamp(timestamp))
+    }
+
+    /// Append `entry` to the active data file keeping its timestamp,
+    /// e.g. an entry moved by compaction.
+    fn write_entry(&mut self, entry: DataEntry) -> Result<DataEntry> {
+        self.ensure_writeable()?;
+        self.rotate_active_data_file()?;
+
+        // get active data file for writting.
+        let df = self.active_data_file();
+
+        let entry = df.write(entry)?;
+        self.written(entry.size())?;
+
+        Ok(entry)
+    }
+
+    /// Return timestamp of a new entry, the wall clock unless it's behind
+    /// an entry seen before, then the timestamp of that entry.
+    fn next_timestamp(&mut self) -> u64 {
+        self.last_timestamp = self.last_timestamp.max(now_secs());
+        self.last_timestamp
+    }
+
+    /// Return error if the store is closed or read-only.
+    fn ensure_writeable(&self) -> Result<()> {
+        self.ensure_open()?;
+
+        if self.opts.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Read value of the key into `buf`, cleared first.
+    fn read_value_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        let Some(keydir_entry) = self.live_keydir_entry(key) else {
+            return Ok(None);
+        };
+
+        buf.clear();
+        if let Some(value) = self.cache.get(key) {
+            trace!("found key `{}` in cache", String::from_utf8_lossy(key));
+            self.opts.metrics.cache_hits.incr();
+            buf.extend_from_slice(value);
+            return Ok(Some(value.len()));
+        }
+        if self.cache.is_enabled() {
+            self.opts.metrics.cache_misses.incr();
+        }
+
+        trace!(
+            "found key `{}` in keydir, got value {:?}",
+            String::from_utf8_lossy(key),
+            &keydir_entry,
+        );
+
+        let df = self.data_file_of(key, keydir_entry.file_id)?;
+        match df.read_value_into(keydir_entry.offset, buf)? {
+            None => Ok(None),
+            Some(header) => {
+                if header.flags() & FLAG_BLOB != 0 {
+                    let ptr = BlobPointer::decode(buf)?;
+                    buf.clear();
+                    read_blob(&mut self.blob_files, key, &ptr, buf)?;
+                }
+
+                self.cache.put(key, buf);
+                Ok(Some(buf.len()))
+            }
+        }
+    }
+
+    /// Account a lookup which started at `start` and found a value of
+    /// `size` bytes, if any.
+    fn record_get(&self, start: Instant, size: Option<u64>) {
+        self.opts.metrics.record_get(start.elapsed(), size);
+    }
+
+    /// Account a write which started at `start`.
+    fn record_set(&self, start: Instant) {
+        self.opts.metrics.sets.incr();
+        self.opts.metrics.set_latency.record(start.elapsed());
+    }
+
+    /// Account `size` bytes written to the active data file,
+    /// and sync them if configured.
+    fn written(&mut self, size: u64) -> Result<()> {
+        self.pending_bytes += size;
+        self.disk_bytes += size;
+        self.opts.metrics.bytes_written.add(size);
+
+        if self.opts.sync {
+            // make sure data entry is persisted in storage.
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Switch to a new active data file if the current one is full.
+    fn rotate_active_data_file(&mut self) -> Result<()> {
+        let max_log_file_size = self.opts.max_log_file_size;
+        let df = self.active_data_file();
+
+        // check file size, rotate to another one if nessessary.
+        if df.size()? > max_log_file_size {
+            info!(
+                "size of active data file `{}` exceeds maximum size of {} bytes, switch to another one",
+                df.path().display(),
+                max_log_file_size
+            );
+
+            // sync data to disk.
+            let _ = self.sync();
+
+            // create a new active data file.
+            self.new_active_data_file(None)?;
+
+            // bound number of segments, compaction copies entries without
+            // going through `write`, so it never gets here recursively.
+            let max_segment_files = self.opts.max_segment_files;
+            if max_segment_files > 0 && self.data_files.len() as u64 > max_segment_files {
+                info!(
+                    "number of data files exceeds {}, compact them",
+                    max_segment_files
+                );
+                self.compact()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy live entries and retained tombstones out of the given data
+    /// files, then remove them. Data files not given are left untouched.
+    ///
+    /// If cancelled, entries copied so far are kept in the compaction
+    /// data files and the given ones are left in place, still holding
+    /// the rest.
+    ///
+    /// Return the report of the compaction, but its duration.
+    fn compact_files(&mut self, file_ids: &[u64], max_file_size: u64) -> Result<CompactionReport> {
+        let mut report = CompactionReport::default();
+        let mut entries_in = 0;
+        for &file_id in file_ids {
+            let df = match self.open_readers.get(&mut self.data_files, file_id) {
+                Ok(df) => df,
+                // nothing to count, nothing to copy either.
+                Err(StoreError::SegmentMissing { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            report.input_bytes += df.size()?;
+            entries_in += df.entry_count();
+        }
+
+        // an expired entry or a tombstone may shadow an older entry of the
+        // key in any data file left in place, file ids don't tell which
+        // ones are older once compaction ran before.
+        let has_survivors = self.data_files.keys().any(|id| !file_ids.contains(id));
+
+        // compaction data files take the next ids, a new active data file
+        // follows them once they're written, so that entries written
+        // afterwards are loaded after the compacted ones.
+        let mut compaction_data_file_id = self.next_file_id();
+
+        // create a new data file for compaction.
+        let data_file_path = self
+            .opts
+            .layout
+            .data_file_path(&self.path, compaction_data_file_id);
+        let limits = self.entry_limits();
+        let mut compaction_df = DataFile::new(&self.opts.fs, &data_file_path, true)?
+            .remove_empty_on_drop(true)
+            .entry_limits(limits);
+
+        // register read-only compaction data file.
+        self.data_files
+            .insert(compaction_df.file_id(), compaction_df.read_only_twin());
+
+        // create a new hint file to store compaction file index, it's
+        // renamed into place once the compaction data file is complete.
+        let mut hint_file_path = self
+            .opts
+            .layout
+            .hint_file_path(&self.path, compaction_data_file_id);
+        let mut hint_tmp_path = prepare_tmp_path(&*self.opts.fs, &hint_file_path)?;
+        let mut hint_file = HintFile::new(&self.opts.fs, &hint_tmp_path, true)?;
+
+        let now = now_millis();
+        let mut expired = Vec::new();
+
+        // copy all the data entries into compaction data file.
+        let cancel = self.opts.cancel.clone();
+        let mut cancelled = false;
+        let mut wrapper = |key: &Vec<u8>, keydir_entry: &mut KeydirEntry| -> Result<bool> {
+            if !file_ids.contains(&keydir_entry.file_id) {
+                return Ok(false);
+            }
+            if is_cancelled(&cancel) {
+                cancelled = true;
+                return Ok(true);
+            }
+            if keydir_entry.is_expired(now) && !has_survivors {
+                expired.push(key.clone());
+                return Ok(false);
+            }
+
+            if compaction_df.size()? > max_file_size {
+                hint_file.sync()?;
+                self.opts.fs.rename(&hint_tmp_path, &hint_file_path)?;
+
+                compaction_data_file_id += 1;
+                // switch to a new data file for compaction, the full one
+                // replaces its read-only registration.
+                let data_file_path = self
+                    .opts
+                    .layout
+                    .data_file_path(&self.path, compaction_data_file_id);
+                let mut full_df = std::mem::replace(
+                    &mut compaction_df,
+                    DataFile::new(&self.opts.fs, &data_file_path, true)?
+                        .remove_empty_on_drop(true)
+                        .entry_limits(limits),
+                );
+                full_df.seal()?;
+                report.segments_created += 1;
+                report.output_bytes += full_df.size()?;
+                if self.opts.detect_external_modification {
+                    let metadata = self.opts.fs.metadata(full_df.path())?;
+                    self.fingerprints.insert(full_df.file_id(), metadata);
+                }
+                self.data_files.insert(full_df.file_id(), full_df);
+
+                self.data_files
+                    .insert(compaction_df.file_id(), compaction_df.read_only_twin());
+
+                hint_file_path = self
+                    .opts
+                    .layout
+                    .hint_file_path(&self.path, compaction_data_file_id);
+                hint_tmp_path = prepare_tmp_path(&*self.opts.fs, &hint_file_path)?;
+                hint_file = HintFile::new(&self.opts.fs, &hint_tmp_path, true)?;
+            }
+
+            let df = self
+                .open_readers
+                .get(&mut self.data_files, keydir_entry.file_id)?;
+
+            let offset =
+                compaction_df.copy_bytes_from(df, keydir_entry.offset, keydir_entry.size)?;
+
+            let hint_entry = HintEntry::new(key.clone(), offset, keydir_entry.size)?
+                .expire_at(keydir_entry.expire_at)
+                .timestamp(keydir_entry.timestamp);
+            hint_file.write(hint_entry)?;
+
+            keydir_entry.file_id = compaction_df.file_id();
+            keydir_entry.offset = offset;
+            report.entries_copied += 1;
+
+            Ok(false)
+        };
+
+        if let Err(e) = self.keydir.for_each(&mut wrapper) {
+            self.new_active_data_file(None)?;
+            return Err(e);
+        }
+
+        // expired keys are gone with the data files being compacted.
+        for key in expired {
+            self.keydir.remove(&key);
+            self.cache.remove(&key);
+        }
+
+        // an empty compaction data file is removed on drop, once its
+        // read-only twin is unregistered, before the active data file
+        // may take its id.
+        if compaction_df.size()? == 0 {
+            self.data_files.remove(&compaction_df.file_id());
+            drop(compaction_df);
+        } else {
+            compaction_df.seal()?;
+            report.segments_created += 1;
+            report.output_bytes += compaction_df.size()?;
+            let compaction_file_id = compaction_df.file_id();
+            self.data_files.insert(compaction_file_id, compaction_df);
+            self.record_fingerprint(compaction_file_id)?;
+        }
+        hint_file.sync()?;
+        // empty hint files are removed on drop.
+        if hint_file.entries_written() > 0 {
+            self.opts.fs.rename(&hint_tmp_path, &hint_file_path)?;
+        }
+        self.new_active_data_file(None)?;
+
+        if cancelled {
+            info!("compaction cancelled, keep data files being compacted");
+            self.disk_bytes = self.segment_bytes()?;
+            return Err(StoreError::Cancelled);
+        }
+
+        self.retain_tombstones(file_ids, has_survivors, &mut report)?;
+        report.entries_dropped = entries_in.saturating_sub(report.entries_copied);
+
+        // remove stale segments.
+        for df in self.data_files.values() {
+            if file_ids.contains(&df.file_id()) {
+                report.segments_removed += 1;
+                if self.opts.fs.exists(df.path()) {
+                    info!("remove stale log file {}", df.path().display());
+                    self.opts.fs.remove(df.path())?;
+                }
+
+                let hint_file_path = self.opts.layout.hint_file_path(&self.path, df.file_id());
+                if self.opts.fs.exists(&hint_file_path) {
+                    info!("remove stale log hint file {}", hint_file_path.display());
+                    self.opts.fs.remove(&hint_file_path)?;
+                }
+            }
+        }
+
+        self.data_files.retain(|k, _| !file_ids.contains(k));
+        self.fingerprints.retain(|k, _| !file_ids.contains(k));
+        self.open_readers.retain(&self.data_files);
+        self.disk_bytes = self.segment_bytes()?;
+
+        Ok(report)
+    }
+
+    /// Copy tombstones out of data files about to be compacted into the
+    /// active data file, as long as they're still needed.
+    ///
+    /// A tombstone shadows values of the key in other data files, so it's
+    /// kept while any data file survives compaction, `has_survivors`.
+    /// Which data files hold the key is unknown, any one counts. Tombstones
+    /// newer than `tombstone_grace` are kept anyway.
+    ///
+    /// Tombstones go to the active data file rather than the compacted
+    /// ones, which only hold live values. They're counted in
+    /// `report` as copied.
+    fn retain_tombstones(
+        &mut self,
+        file_ids: &[u64],
+        has_survivors: bool,
+        report: &mut CompactionReport,
+    ) -> Result<()> {
+        let now = now_millis() / 1000;
+        let grace = self.opts.tombstone_grace.as_secs();
+
+        // take the active data file out, tombstones are copied into it
+        // from other data files.
+        let active_file_id = self.active_data_file.expect("active data file not found");
+        let mut df = self
+            .data_files
+            .remove(&active_file_id)
+            .expect("active data file not found");
+
+        let mut dropped = Vec::new();
+        for (key, tombstone) in self.tombstones.iter_mut() {
+            if !file_ids.contains(&tombstone.file_id) {
+                continue;
+            }
+
+            if !has_survivors && tombstone.timestamp.saturating_add(grace) <= now {
+                dropped.push(key.clone());
+                continue;
+            }
+
+            trace!("retain tombstone of key `{}`", String::from_utf8_lossy(key));
+            let copied = self
+                .open_readers
+                .get(&mut self.data_files, tombstone.file_id)
+                .and_then(|src| df.copy_bytes_from(src, tombstone.offset, tombstone.size));
+            match copied {
+                Ok(offset) => {
+                    tombstone.offset = offset;
+                    tombstone.file_id = active_file_id;
+                    report.entries_copied += 1;
+                    report.output_bytes += tombstone.size;
+                }
+                Err(e) => {
+                    self.data_files.insert(active_file_id, df);
+                    return Err(e);
+                }
+            }
+        }
+        let synced = df.sync();
+        self.data_files.insert(active_file_id, df);
+        synced?;
+
+        for key in dropped {
+            self.tombstones.remove(&key);
+        }
+
+        Ok(())
+    }
+}
+
+impl<K> Storage for DiskStorage<K>
+where
+    K: Keydir + Default,
+{
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(self.get_into(key, &mut value)?.map(|_| value))
+    }
+
+    fn get_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.ensure_open()?;
+
+        let start = Instant::now();
+        let size = self
+            .read_value_into(key, buf)
+            .map_err(|e| self.external_modification_or(e))?;
+        self.record_get(start, size.map(|n| n as u64));
+        Ok(size)
+    }
+
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.put(key.as_ref(), value.as_ref(), None)
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expire_at = expire_at_after(ttl)?;
+        self.put(key.as_ref(), value.as_ref(), Some(expire_at))
+    }
+
+    fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        match self.get(key)? {
+            None => Ok(false),
+            Some(value) => {
+                let expire_at = expire_at_after(ttl)?;
+                self.put(key, &value, Some(expire_at))?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn persist(&mut self, key: &[u8]) -> Result<bool> {
+        self.ensure_open()?;
+
+        if self.ttl(key).is_none() {
+            return Ok(false);
+        }
+
+        match self.get(key)? {
+            None => Ok(false),
+            Some(value) => {
+                self.put(key, &value, None)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        let now = now_millis();
+        match self.keydir.get(key) {
+            Some(e) if !e.is_expired(now) => e
+                .expire_at
+                .map(|expire_at| Duration::from_millis(expire_at - now)),
+            _ => None,
+        }
+    }
+
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let current = match self.get(key)? {
+            None => 0,
+            Some(value) => std::str::from_utf8(&value)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or(StoreError::NotAnInteger)?,
+        };
+
+        let value = current
+            .checked_add(delta)
+            .ok_or(StoreError::IntegerOverflow)?;
+
+        // keep the expiry of the key, if any.
+        let expire_at = self.keydir.get(key).and_then(|e| e.expire_at);
+        self.put(key, value.to_string().as_bytes(), expire_at)?;
+
+        Ok(value)
+    }
+
+    fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64> {
+        let mut value = self.get(key)?.unwrap_or_default();
+
+        if (value.len() + suffix.len()) as u64 > self.opts.max_value_size {
+            return Err(StoreError::ValueIsTooLarge);
+        }
+        value.extend_from_slice(suffix);
+
+        // keep the expiry of the key, if any.
+        let expire_at = self.keydir.get(key).and_then(|e| e.expire_at);
+        self.put(key, &value, expire_at)?;
+
+        Ok(value.len() as u64)
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<bool> {
+        self.ensure_open()?;
+
+        if old == new {
+            return Ok(true);
+        }
+
+        let value = match self.get(old)? {
+            None => return Ok(false),
+            Some(value) => value,
+        };
+
+        // keep the expiry of the key, if any.
+        let expire_at = self.keydir.get(old).and_then(|e| e.expire_at);
+        self.put(new, &value, expire_at)?;
+        self.delete(old)?;
+
+        Ok(true)
+    }
+
+    fn set_new(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.ensure_open()?;
+
+        let key = key.as_ref();
+
+        if self.contains_key(key) {
+            return Err(StoreError::KeyAlreadyExists(key.to_vec()));
+        }
+
+        self.set(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        self.ensure_open()?;
+
+        self.opts.metrics.deletes.incr();
+
+        let found = self.contains_key(key);
+
+        if !self.keydir.contains_key(key) {
+            trace!(
+                "remove key `{}`, but it not found in datastore",
+                String::from_utf8_lossy(key)
+            );
+        } else {
+            trace!(
+                "remove key `{}` from datastore",
+                String::from_utf8_lossy(key)
+            );
+
+            self.write_tombstone(key)?;
+
+            self.watchers.notify(|| Event::Delete { key: key.to_vec() });
+        }
+
+        Ok(found)
+    }
+
+    /// Expired keys are found with the clock of `get`, they get
+    /// tombstones as deleted keys do.
+    fn purge_expired(&mut self) -> Result<u64> {
+        self.ensure_writeable()?;
+
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = self
+            .keydir
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        for key in expired.iter() {
+            self.write_tombstone(key)?;
+        }
+
+        debug!("purged {} expired keys", expired.len());
+        Ok(expired.len() as u64)
+    }
+
+    fn keys_into(&self, buf: &mut Vec<Vec<u8>>) -> Result<()> {
+        self.ensure_open()?;
+
+        buf.reserve(self.keydir.len() as usize);
+        buf.extend(self.keydir.keys_iter().map(<[u8]>::to_vec));
+        Ok(())
+    }
+
+    /// Sorted by keydir, ordered keydirs don't sort again.
+    fn keys_sorted(&self) -> Result<Vec<Vec<u8>>> {
+        self.ensure_open()?;
+
+        Ok(self.keydir.keys_sorted())
+    }
+
+    fn first_key(&self) -> Option<Vec<u8>> {
+        self.keydir.first_key()
+    }
+
+    fn keys_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.ensure_open()?;
+
+        Ok(self.keydir.keys_with_prefix(prefix, after, limit))
+    }
+
+    fn last_key(&self) -> Option<Vec<u8>> {
+        self.keydir.last_key()
+    }
+
+    fn entries_metadata(&self) -> Result<Vec<EntryMetadata>> {
+        self.ensure_open()?;
+
+        Ok(self
+            .keydir
+            .iter()
+            .map(|(key, entry)| EntryMetadata::new(key, entry))
+            .collect())
+    }
+
+    fn entries_metadata_with_prefix(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<EntryMetadata>> {
+        self.ensure_open()?;
+
+        let keys = self.keydir.keys_with_prefix(prefix, after, limit);
+        Ok(keys
+            .iter()
+            .filter_map(|key| Some(EntryMetadata::new(key, self.keydir.get(key)?)))
+            .collect())
+    }
+
+    fn len(&self) -> u64 {
+        self.keydir.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        match self.keydir.get(key) {
+            None => false,
+            Some(e) => !e.is_expired(now_millis()),
+        }
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        self.for_each_filtered(|_| true, f)
+    }
+
+    fn for_each_filtered<P, F>(&mut self, key_pred: P, f: &mut F) -> Result<()>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        self.ensure_open()?;
+
+        let mut dangling = None;
+        let mut wrapper = |key: &Vec<u8>, keydir_entry: &mut KeydirEntry| -> Result<bool> {
+            if !key_pred(key) {
+                return Ok(false);
+            }
+
+            let not_found = || StoreError::EntryNotFound {
+                key: key.clone(),
+                file_id: keydir_entry.file_id,
+                offset: keydir_entry.offset,
+            };
+
+            let df = self
+                .open_readers
+                .get(&mut self.data_files, keydir_entry.file_id)
+                .inspect_err(|_| dangling = Some(key.clone()))?;
+
+            match df.read(keydir_entry.offset)? {
+                None => Err(not_found()),
+                Some(mut entry) => {
+                    if entry.is_blob() {
+                        let ptr = BlobPointer::decode(&entry.value)?;
+                        entry.value.clear();
+                        read_blob(&mut self.blob_files, key, &ptr, &mut entry.value)?;
+                    }
+
+                    f(&entry.key, &entry.value).map_err(|e| StoreError::IterationFailed {
+                        key: key.clone(),
+                        source: Box::new(e),
+                    })
+                }
+            }
+        };
+
+        let res = self.keydir.for_each(&mut wrapper);
+
+        if let (Err(e @ StoreError::SegmentMissing { .. }), Some(key)) = (&res, dangling) {
+            warn!("{}, remove key `{}`", e, String::from_utf8_lossy(&key));
+            self.keydir.remove(&key);
+            self.cache.remove(&key);
+        }
+
+        res
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.ensure_open()?;
+
+        if self.active_data_file.is_some() {
+            self.active_data_file().sync()?;
+        }
+        self.pending_bytes = 0;
+        self.last_sync_time = Some(now_millis());
+        Ok(())
+    }
+
+    fn last_sync_time(&self) -> Option<u64> {
+        self.last_sync_time
+    }
+
+    fn pending_bytes(&self) -> u64 {
+        self.pending_bytes
+    }
+
+    fn last_write_ts(&self) -> Option<u64> {
+        Some(self.last_timestamp).filter(|&ts| ts > 0)
+    }
+
+    fn stats(&self) -> Stats {
+        // a data file whose size can't be read counts as empty.
+        let total_bytes: u64 = self
+            .data_files
+            .values()
+            .map(|df| df.size().unwrap_or_default())
+            .sum();
+        let live_bytes: u64 = self.keydir.iter().map(|(_, entry)| entry.size).sum();
+        Stats {
+            keys: self.keydir.len(),
+            segment_count: self.data_files.len() as u64,
+            last_sync_time: self.last_sync_time,
+            pending_bytes: self.pending_bytes,
+            total_bytes,
+            reclaimable_bytes: total_bytes.saturating_sub(live_bytes),
+            recovery_duration: self.recovery_stats.duration,
+            durable_deletes: self.opts.durable_deletes,
+        }
+    }
+
+    fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        self.ensure_open()?;
+
+        // number and size of live entries by file id.
+        let mut live: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        for (_, entry) in self.keydir.iter() {
+            let (keys, bytes) = live.entry(entry.file_id).or_default();
+            *keys += 1;
+            *bytes += entry.size;
+        }
+
+        let mut segments = Vec::with_capacity(self.data_files.len());
+        for (&file_id, df) in self.data_files.iter() {
+            let (live_keys, live_bytes) = live.get(&file_id).copied().unwrap_or_default();
+            segments.push(SegmentInfo {
+                file_id,
+                path: df.path().to_path_buf(),
+                size_bytes: df.size()?,
+                live_keys,
+                live_bytes,
+                is_active: self.active_data_file == Some(file_id),
+                has_hint: self
+                    .opts
+                    .fs
+                    .exists(&self.opts.layout.hint_file_path(&self.path, file_id)),
+            });
+        }
+        Ok(segments)
+    }
+
+    fn metrics(&self) -> Arc<Metrics> {
+        self.opts.metrics.clone()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.sync()?;
+
+        if let Some(file_id) = self.active_data_file {
+            self.write_hint_file(file_id)?;
+        }
+
+        // release file handles and the lock.
+        self.active_data_file = None;
+        self.active_blob_file = None;
+        self.data_files.clear();
+        self.blob_files.clear();
+        self.open_readers.retain(&self.data_files);
+        self.keydir = K::default();
+        self.tombstones.clear();
+        self.cache.clear();
+        self._lock = None;
+        self.closed = true;
+
+        info!("store {} closed", self.path.display());
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport> {
+        self.compact_all(self.opts.max_log_file_size)
+    }
+}
+
+impl<K> Drop for DiskStorage<K>
+where
+    K: Keydir + Default,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!(
+                "failed to close store {}, got error: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Return `true` if the cancellation token `cancel` is set.
+fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+    cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+/// Return unix timestamp in milliseconds `ttl` from now,
+/// `StoreError::ExpiryOutOfRange` if it overflows.
+fn expire_at_after(ttl: Duration) -> Result<u64> {
+    u64::try_from(ttl.as_millis())
+        .ok()
+        .and_then(|ttl| now_millis().checked_add(ttl))
+        .ok_or(StoreError::ExpiryOutOfRange)
+}
+
+/// Data files with open readers, in least recently used order.
+#[derive(Debug)]
+struct OpenReaders {
+    /// maximum number of open readers, zero means unlimited.
+    max_open_files: u64,
+
+    /// file ids of data files with open readers, most recently used last.
+    lru: VecDeque<u64>,
+}
+
+impl OpenReaders {
+    fn new(max_open_files: u64) -> Self {
+        Self {
+            max_open_files,
+            lru: VecDeque::new(),
+        }
+    }
+
+    /// Return data file `file_id` for reading, and close readers of the
+    /// least recently used data files exceeding `max_open_files`.
+    ///
+    /// Return `StoreError::SegmentMissing` if the data file is not
+    /// registered or removed from disk.
+    fn get<'a>(
+        &mut self,
+        data_files: &'a mut BTreeMap<u64, DataFile>,
+        file_id: u64,
+    ) -> Result<&'a mut DataFile> {
+        if !data_files.contains_key(&file_id) {
+            return Err(StoreError::SegmentMissing { file_id });
+        }
+
+        if self.lru.back() != Some(&file_id) {
+            self.lru.retain(|&id| id != file_id);
+            self.lru.push_back(file_id);
+        }
+
+        while self.max_open_files > 0 && self.lru.len() as u64 > self.max_open_files {
+            let id = self.lru.pop_front().unwrap();
+            if let Some(df) = data_files.get_mut(&id) {
+                df.close_reader();
+            }
+        }
+
+        let df = data_files.get_mut(&file_id).unwrap();
+        if !df.is_reader_open() {
+            df.open_reader().map_err(|e| match e {
+                StoreError::Io(e) if e.kind() == io::ErrorKind::NotFound => {
+                    StoreError::SegmentMissing { file_id }
+                }
+                e => e,
+            })?;
+        }
+        Ok(df)
+    }
+
+    /// Forget data files which are no longer registered.
+    fn retain(&mut self, data_files: &BTreeMap<u64, DataFile>) {
+        self.lru.retain(|id| data_files.contains_key(id));
+    }
+}
+
+/// Read value pointed by `ptr` into `buf`.
+fn read_blob<W: Write + ?Sized>(
+    blob_files: &mut BTreeMap<u64, DataFile>,
+    key: &[u8],
+    ptr: &BlobPointer,
+    w: &mut W,
+) -> Result<()> {
+    let not_found = || StoreError::EntryNotFound {
+        key: key.to_vec(),
+        file_id: ptr.file_id,
+        offset: ptr.offset,
+    };
+
+    let bf = blob_files.get_mut(&ptr.file_id).ok_or_else(not_found)?;
+    bf.read_value_into(ptr.offset, w)?.ok_or_else(not_found)?;
+    Ok(())
+}
+
+/// Return `true` if reading an entry failed because it's cut short.
+fn is_partial_entry(e: &StoreError) -> bool {
+    match e {
+        StoreError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        StoreError::EntryOutOfBounds { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir;
+
+    use super::*;
+
+    use super::super::format::{CRC_SIZE, KEY_SZ_MASK, TIMESTAMP_SIZE};
+    use super::super::fs::mock::MemFileSystem;
+    use super::super::fs::{FileHandle, FileSystem, StdFileSystem};
+    use super::super::keydir::{BTreeMapKeydir, HashmapKeydir};
+    use super::super::OpenOptions;
+
+    fn segment_data_file_path(dir: &Path, segment_id: u64) -> PathBuf {
+        segment_file_path(dir, segment_id, settings::DATA_FILE_SUFFIX)
+    }
+
+    fn segment_hint_file_path(dir: &Path, segment_id: u64) -> PathBuf {
+        segment_file_path(dir, segment_id, settings::HINT_FILE_SUFFIX)
+    }
+
+    fn segment_file_path(dir: &Path, segment_id: u64, suffix: &str) -> PathBuf {
+        let mut p = dir.to_path_buf();
+        p.push(format!("{:06}{}", segment_id, suffix));
+        p
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_to_owned, clippy::bool_assert_comparison)]
+    fn disk_storage_should_get_put() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        assert_eq!(db.len(), 0);
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, None);
+
+        db.set(b"hello".to_vec(), b"world".to_vec()).unwrap();
+
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.contains_key(b"hello"), true);
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, Some(b"world".to_vec()));
+
+        db.set(b"hello".to_vec(), b"underworld".to_vec()).unwrap();
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, Some(b"underworld".to_vec()));
+
+        assert!(db.delete(b"hello").unwrap());
+
+        let res = db.get(b"hello").unwrap();
+        assert_eq!(res, None);
+        assert!(!db.delete(b"hello").unwrap());
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_to_owned)]
+    fn disk_storage_should_persist() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"persistence".to_vec(), b"check".to_vec()).unwrap();
+            db.set(b"removed".to_vec(), b"entry".to_vec()).unwrap();
+            db.delete(b"removed").unwrap();
+        }
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            let res = db.get(b"persistence").unwrap();
+            assert_eq!(res, Some(b"check".to_vec()));
+
+            let res = db.get(b"removed").unwrap();
+            assert_eq!(res, None);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_to_owned, clippy::bool_assert_comparison)]
+    fn disk_storage_should_retate_logs() {
+        const VERSION: u8 = 10;
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let open_opts = OpenOptions::new().max_log_file_size(50);
+
+        {
+            let mut db = open_opts.open(dir.path()).unwrap();
+
+            for i in 0..=VERSION {
+                db.set(b"version".to_vec(), vec![i]).unwrap();
+            }
+        }
+
+        fn segment_data_file_path(dir: &Path, segment_id: u64) -> PathBuf {
+            segment_file_path(dir, segment_id, settings::DATA_FILE_SUFFIX)
+        }
+
+        let logfile = segment_data_file_path(dir.path(), 1);
+        assert_eq!(logfile.exists(), true);
+
+        assert!(logfile.exists(), "log file has not been rotated");
+
+        {
+            let mut db = open_opts.open(dir.path()).unwrap();
+
+            let res = db.get(b"version").unwrap();
+            assert_eq!(res, Some(vec![VERSION]));
+        }
+    }
+
+    #[test]
+    fn test_invalid_file_name() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"foo", b"bar").unwrap();
+        }
+
+        let junk = dir
+            .path()
+            .join(format!("junk{}", settings::DATA_FILE_SUFFIX));
+        fs::write(&junk, b"junk").unwrap();
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open(dir.path()),
+            Err(StoreError::InvalidFileName(path)) if path.ends_with(junk.file_name().unwrap())
+        ));
+    }
+
+    #[test]
+    fn test_ignore_foreign_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 50,
+            blob_threshold: 16,
+            ..StoreOptions::default()
+        };
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..6u8 {
+                db.set([i], [i; 8]).unwrap();
+            }
+            db.set(b"blob", [7; 32]).unwrap();
+        }
+
+        for name in [
+            format!("junk{}", settings::DATA_FILE_SUFFIX),
+            format!(".#000001{}", settings::DATA_FILE_SUFFIX),
+            format!("000001 (copy){}", settings::DATA_FILE_SUFFIX),
+            format!("backup{}", settings::BLOB_FILE_SUFFIX),
+            // would be taken for segment 1.
+            format!("000001.old{}", settings::DATA_FILE_SUFFIX),
+            format!("0000001{}", settings::DATA_FILE_SUFFIX),
+        ] {
+            fs::write(dir.path().join(name), b"junk").unwrap();
+        }
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_with_options(dir.path(), opts.clone()),
+            Err(StoreError::InvalidFileName(_))
+        ));
+
+        let opts = StoreOptions {
+            ignore_foreign_files: true,
+            ..opts
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.len(), 7);
+        assert_eq!(db.get(&[5]).unwrap(), Some(vec![5; 8]));
+        assert_eq!(db.get(b"blob").unwrap(), Some(vec![7; 32]));
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_lock_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let _db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        let db2: Result<DiskStorage<HashmapKeydir>> = DiskStorage::open(dir.path());
+        assert_eq!(db2.is_err(), true);
+    }
+
+    #[test]
+    fn test_lock_file_removed() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        db.set(b"hello", b"world").unwrap();
+
+        // dropping the store only warns.
+        fs::remove_file(dir.path().join("LOCK")).unwrap();
+        drop(db);
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        // so does removing the whole directory.
+        fs::remove_dir_all(dir.path()).unwrap();
+        drop(db);
+    }
+
+    #[test]
+    fn test_manifest_created_for_old_store() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"hello", b"world").unwrap();
+        }
+
+        // simulate a store written before manifest existed.
+        let manifest_path = dir.path().join(settings::MANIFEST_FILE_NAME);
+        fs::remove_file(&manifest_path).unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert!(manifest_path.exists());
+        assert_eq!(db.manifest().version, settings::FORMAT_VERSION);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_manifest_with_higher_version() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        let manifest = Manifest {
+            version: settings::FORMAT_VERSION + 1,
+            created_at: 0,
+        };
+        manifest
+            .write(&StdFileSystem::default(), dir.path())
+            .unwrap();
+
+        let db: Result<DiskStorage<HashmapKeydir>> = DiskStorage::open(dir.path());
+        assert!(matches!(
+            db,
+            Err(StoreError::IncompatibleVersion { found, .. }) if found == settings::FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_set_new() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db = OpenOptions::new().open(dir.path()).unwrap();
+
+        db.set_new(b"event-1", b"created").unwrap();
+
+        let res = db.set_new(b"event-1", b"overwritten");
+        assert!(matches!(res, Err(StoreError::KeyAlreadyExists(_))));
+        assert_eq!(db.get(b"event-1").unwrap(), Some(b"created".to_vec()));
+
+        // an expired key is absent.
+        db.set_with_expiry(b"event-2", b"created", Duration::from_millis(50))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        db.set_new(b"event-2", b"again").unwrap();
+        assert_eq!(db.get(b"event-2").unwrap(), Some(b"again".to_vec()));
+    }
+
+    #[test]
+    fn test_expiry_survives_reopen() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db = OpenOptions::new().open(dir.path()).unwrap();
+            db.set(b"key", b"old").unwrap();
+            db.set_with_expiry(b"key", b"new", Duration::from_millis(200))
+                .unwrap();
+            db.set_with_expiry(b"long", b"value", Duration::from_secs(100))
+                .unwrap();
+            db.compact().unwrap();
+        }
+
+        {
+            let mut db = OpenOptions::new().open(dir.path()).unwrap();
+            assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+            assert!(db.ttl(b"long").unwrap() > Duration::from_secs(90));
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+
+        // expired entry hides older versions of the key.
+        let mut db = OpenOptions::new().open(dir.path()).unwrap();
+        assert!(!db.contains_key(b"key"));
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            for i in 0..5u8 {
+                db.set_with_expiry([i], [i], Duration::from_millis(100))
+                    .unwrap();
+            }
+            db.set_with_expiry(b"long", b"value", Duration::from_secs(100))
+                .unwrap();
+            db.set(b"forever", b"value").unwrap();
+            assert_eq!(db.purge_expired().unwrap(), 0);
+
+            std::thread::sleep(Duration::from_millis(150));
+            // still counted until read or purged.
+            assert_eq!(db.len(), 7);
+            assert_eq!(db.purge_expired().unwrap(), 5);
+            assert_eq!(db.len(), 2);
+            assert_eq!(db.purge_expired().unwrap(), 0);
+            assert_eq!(db.tombstones.len(), 5);
+        }
+
+        // tombstones keep them deleted across restarts and compaction.
+        for _ in 0..2 {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            assert_eq!(db.len(), 2);
+            assert_eq!(db.get(&[0]).unwrap(), None);
+            assert_eq!(db.get(b"long").unwrap(), Some(b"value".to_vec()));
+            db.compact().unwrap();
+            assert_eq!(db.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_compaction_drops_expired_keys() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"key", b"old").unwrap();
+            db.set_with_expiry(b"key", b"new", Duration::from_millis(100))
+                .unwrap();
+            db.set_with_expiry(b"short", b"value", Duration::from_millis(100))
+                .unwrap();
+            db.set(b"forever", b"value").unwrap();
+            std::thread::sleep(Duration::from_millis(150));
+
+            let report = db.compact().unwrap();
+            assert_eq!(report.entries_copied, 1);
+            assert_eq!(report.entries_dropped, 3);
+            assert_eq!(db.len(), 1);
+        }
+
+        // the old value of `key` is gone as well.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.keys().unwrap(), vec![b"forever".to_vec()]);
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compaction_preserves_timestamps() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        let timestamps = {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            for i in 0..10u8 {
+                db.set([i], [i]).unwrap();
+            }
+            db.set([0], b"overwritten").unwrap();
+
+            let timestamps: Vec<u64> = (0..10u8)
+                .map(|i| db.keydir.get(&[i]).unwrap().timestamp)
+                .collect();
+            assert!(timestamps.iter().all(|&ts| ts > 0));
+
+            db.compact().unwrap();
+            for i in 0..10u8 {
+                assert_eq!(
+                    db.keydir.get(&[i]).unwrap().timestamp,
+                    timestamps[i as usize]
+                );
+            }
+            assert_timestamps_match(&mut db);
+
+            timestamps
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(
+                db.keydir.get(&[i]).unwrap().timestamp,
+                timestamps[i as usize]
+            );
+        }
+        assert_timestamps_match(&mut db);
+        assert_eq!(db.get(&[0]).unwrap(), Some(b"overwritten".to_vec()));
+    }
+
+    #[test]
+    fn test_keydir_reserved_on_open() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            for i in 0..1000u32 {
+                db.set(i.to_be_bytes(), b"value").unwrap();
+            }
+            // write hint files.
+            db.compact().unwrap();
+        }
+
+        // keydir is allocated once from hint files, never rehashed.
+        // The newest segment is reused as the active one unless it's
+        // full, which would drop its hint file.
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        let estimated = db.estimate_keydir_capacity().unwrap();
+        assert!(estimated >= 1000);
+        assert_eq!(
+            db.keydir.capacity(),
+            <HashmapKeydir>::with_capacity(estimated as usize).capacity()
+        );
+
+        // configured capacity takes precedence.
+        drop(db);
+        let opts = StoreOptions {
+            keydir_capacity: 5000,
+            ..StoreOptions::default()
+        };
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert!(db.keydir.capacity() >= 5000);
+    }
+
+    #[test]
+    fn test_increment() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        assert_eq!(db.increment(b"counter", 1).unwrap(), 1);
+        assert_eq!(db.increment(b"counter", 10).unwrap(), 11);
+        assert_eq!(db.increment(b"counter", -20).unwrap(), -9);
+        assert_eq!(db.get(b"counter").unwrap(), Some(b"-9".to_vec()));
+
+        db.set(b"text", b"hello").unwrap();
+        assert!(matches!(
+            db.increment(b"text", 1),
+            Err(StoreError::NotAnInteger)
+        ));
+
+        db.set(b"max", i64::MAX.to_string()).unwrap();
+        assert!(matches!(
+            db.increment(b"max", 1),
+            Err(StoreError::IntegerOverflow)
+        ));
+        assert_eq!(
+            db.get(b"max").unwrap(),
+            Some(i64::MAX.to_string().into_bytes())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_increment() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let db = OpenOptions::new().open(dir.path()).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mut db = db.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        db.increment(b"counter", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut db = db;
+        assert_eq!(db.get(b"counter").unwrap(), Some(b"400".to_vec()));
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_value_size: 16,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        assert_eq!(db.append(b"log", b"hello").unwrap(), 5);
+        assert_eq!(db.append(b"log", b" world").unwrap(), 11);
+        assert_eq!(db.get(b"log").unwrap(), Some(b"hello world".to_vec()));
+
+        assert!(matches!(
+            db.append(b"log", b" too long"),
+            Err(StoreError::ValueIsTooLarge)
+        ));
+        assert_eq!(db.get(b"log").unwrap(), Some(b"hello world".to_vec()));
+
+        db.delete(b"log").unwrap();
+        assert_eq!(db.append(b"log", b"again").unwrap(), 5);
+        assert_eq!(db.get(b"log").unwrap(), Some(b"again".to_vec()));
+    }
+
+    #[test]
+    fn test_for_each_errors() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        db.set(b"hello", b"world").unwrap();
+
+        let res = db.for_each(&mut |_, _| Err(StoreError::Custom("oops".to_owned())));
+        match res {
+            Err(StoreError::IterationFailed { key, source }) => {
+                assert_eq!(key, b"hello".to_vec());
+                assert!(matches!(*source, StoreError::Custom(_)));
+            }
+            _ => panic!("expected iteration error, got {:?}", res),
+        }
+
+        // a keydir entry pointing past the end of data file.
+        let file_id = db.keydir.get(b"hello").unwrap().file_id;
+        db.keydir.put(
+            b"dangling".to_vec(),
+            KeydirEntry::new(file_id, 1 << 20, 0, 0),
+        );
+
+        let res = db.for_each(&mut |_, _| Ok(false));
+        assert!(matches!(res, Err(StoreError::EntryNotFound { .. })));
+    }
+
+    #[test]
+    fn test_for_each_filtered() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mem = MemFileSystem::default();
+        let opts = StoreOptions {
+            fs: Arc::new(mem.clone()),
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for i in 0..10 {
+            db.set(format!("a{i}"), "value").unwrap();
+            db.set(format!("b{i}"), "value").unwrap();
+        }
+
+        let mut keys = Vec::new();
+        db.for_each_filtered(|key| key.starts_with(b"a"), &mut |key, value| {
+            assert_eq!(value, b"value");
+            keys.push(key.to_vec());
+            Ok(false)
+        })
+        .unwrap();
+        keys.sort();
+        let expected: Vec<_> = (0..10).map(|i| format!("a{i}").into_bytes()).collect();
+        assert_eq!(keys, expected);
+
+        // values of keys not matching are never read.
+        let reads = mem.reads();
+        db.for_each_filtered(|key| key.starts_with(b"c"), &mut |_, _| {
+            panic!("no key matches")
+        })
+        .unwrap();
+        assert_eq!(mem.reads(), reads);
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            value_cache_bytes: 1024,
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(b"hot", b"value").unwrap();
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        let hits_and_misses = |db: &DiskStorage<HashmapKeydir>| {
+            let metrics = &db.opts.metrics;
+            (metrics.cache_hits.get(), metrics.cache_misses.get())
+        };
+
+        // first read hits disk, second read is served from cache.
+        assert_eq!(db.get(b"hot").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(hits_and_misses(&db), (0, 1));
+        assert_eq!(db.get(b"hot").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(hits_and_misses(&db), (1, 1));
+
+        // cache is updated on set, invalidated on delete.
+        db.set(b"hot", b"new value").unwrap();
+        assert_eq!(db.get(b"hot").unwrap(), Some(b"new value".to_vec()));
+        assert_eq!(hits_and_misses(&db), (2, 1));
+
+        db.delete(b"hot").unwrap();
+        assert_eq!(db.get(b"hot").unwrap(), None);
+    }
+
+    #[test]
+    fn test_value_cache_evicts_least_recently_used() {
+        let mut cache = ValueCache::new(8);
+        cache.put(b"a", b"111");
+        cache.put(b"b", b"222");
+        assert!(cache.get(b"a").is_some());
+
+        // evicts `b`, the least recently used one.
+        cache.put(b"c", b"333");
+        assert!(cache.get(b"b").is_none());
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"c").is_some());
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"old", b"value").unwrap();
+            db.set(b"new", b"overwritten").unwrap();
+
+            assert!(db.rename(b"old", b"new").unwrap());
+            assert_eq!(db.get(b"old").unwrap(), None);
+            assert_eq!(db.get(b"new").unwrap(), Some(b"value".to_vec()));
+
+            assert!(!db.rename(b"old", b"other").unwrap());
+            assert!(db.rename(b"new", b"new").unwrap());
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.get(b"old").unwrap(), None);
+        assert_eq!(db.get(b"new").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_pending_bytes_and_last_sync_time() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        assert_eq!(db.pending_bytes(), 0);
+        assert_eq!(db.last_sync_time(), None);
+
+        db.set(b"hello", b"world").unwrap();
+        assert!(db.pending_bytes() > 0);
+
+        db.sync().unwrap();
+        assert_eq!(db.pending_bytes(), 0);
+        let synced_at = db.last_sync_time().unwrap();
+        assert!(synced_at > 0);
+
+        std::thread::sleep(Duration::from_millis(5));
+        db.set(b"hello", b"again").unwrap();
+        db.sync().unwrap();
+        assert!(db.last_sync_time().unwrap() > synced_at);
+    }
+
+    #[test]
+    fn test_durable_deletes() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            durable_deletes: true,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert!(db.stats().durable_deletes);
+
+        // other writes aren't synced.
+        db.set(b"hello", b"world").unwrap();
+        assert_eq!(db.last_sync_time(), None);
+        assert!(db.pending_bytes() > 0);
+
+        db.delete(b"hello").unwrap();
+        assert!(db.last_sync_time().is_some());
+        assert_eq!(db.pending_bytes(), 0);
+        db.set(b"foo", b"bar").unwrap();
+
+        // the retained tombstone still follows the value it deletes.
+        db.compact_files(&[2], u64::MAX).unwrap();
+        let records = db.dump_key(b"hello").unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].3 && records[1].3);
+        assert!(records[0].0 < records[1].0);
+        drop(db);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), None);
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_max_open_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            max_open_files: 4,
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..20u8 {
+                db.set([i], [i]).unwrap();
+            }
+            assert!(db.data_files.len() >= 20);
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        let open_readers = |db: &DiskStorage<HashmapKeydir>| {
+            db.data_files
+                .values()
+                .filter(|df| df.is_reader_open())
+                .count()
+        };
+        assert!(open_readers(&db) <= 4);
+
+        for i in 0..20u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i]));
+            assert!(open_readers(&db) <= 4);
+        }
+
+        let mut count = 0;
+        db.for_each(&mut |_, _| {
+            count += 1;
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(count, 20);
+
+        db.compact().unwrap();
+        assert!(open_readers(&db) <= 4);
+        for i in 0..20u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn test_get_into() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        db.set(b"short", b"abc").unwrap();
+        db.set(b"long", vec![7u8; 100]).unwrap();
+        db.set_with_expiry(b"expiry", b"soon", Duration::from_secs(60))
+            .unwrap();
+
+        let mut buf = Vec::with_capacity(128);
+        let ptr = buf.as_ptr();
+
+        for _ in 0..3 {
+            assert_eq!(db.get_into(b"long", &mut buf).unwrap(), Some(100));
+            assert_eq!(buf, vec![7u8; 100]);
+
+            assert_eq!(db.get_into(b"short", &mut buf).unwrap(), Some(3));
+            assert_eq!(buf, b"abc");
+
+            assert_eq!(db.get_into(b"expiry", &mut buf).unwrap(), Some(4));
+            assert_eq!(buf, b"soon");
+
+            assert_eq!(db.get_into(b"missing", &mut buf).unwrap(), None);
+        }
+
+        // capacity suffices, allocation is reused.
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.capacity(), 128);
+    }
+
+    #[test]
+    fn test_recovery_progress_and_corruption() {
+        use std::sync::Mutex;
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(b"a", b"1").unwrap();
+            db.set(b"b", b"2").unwrap();
+            db.set(b"c", b"3").unwrap();
+        }
+
+        // each key lives in its own segment, corrupt the one holding `b`.
+        fs::write(segment_data_file_path(dir.path(), 2), [0xff; 32]).unwrap();
+
+        assert!(DiskStorage::<HashmapKeydir>::open_with_options(dir.path(), opts.clone()).is_err());
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let opts = StoreOptions {
+            continue_on_corruption: true,
+            recovery_progress: Some(RecoveryCallback(Arc::new({
+                let reports = Arc::clone(&reports);
+                move |p: &RecoveryProgress| reports.lock().unwrap().push(p.clone())
+            }))),
+            ..opts
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(
+            reports.last(),
+            Some(&RecoveryProgress {
+                files_total: 3,
+                files_processed: 3,
+                files_skipped: 1,
+                files_from_hints: 1,
+                keys_loaded: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_key_and_disk_limits() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_keys: 2,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        db.set(b"a", b"1").unwrap();
+        db.set(b"b", b"2").unwrap();
+        assert!(matches!(
+            db.set(b"c", b"3"),
+            Err(StoreError::KeyLimitExceeded)
+        ));
+        db.set(b"a", b"overwritten").unwrap();
+        db.delete(b"b").unwrap();
+        db.set(b"c", b"3").unwrap();
+
+        // expired keys don't count.
+        db.set_with_expiry(b"a", b"1", Duration::from_millis(50))
+            .unwrap();
+        db.set_with_expiry(b"c", b"3", Duration::from_millis(50))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        db.set(b"d", b"4").unwrap();
+        db.set(b"e", b"5").unwrap();
+        assert!(matches!(
+            db.set(b"f", b"6"),
+            Err(StoreError::KeyLimitExceeded)
+        ));
+        drop(db);
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_disk_bytes: 256,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        let mut writes = 0;
+        loop {
+            match db.set(b"key", [0u8; 32]) {
+                Ok(()) => writes += 1,
+                Err(StoreError::DiskLimitExceeded) => break,
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+        assert!(writes > 1);
+        assert!(db.disk_bytes <= 256);
+
+        // overwritten values are reclaimed by compaction.
+        db.compact().unwrap();
+        assert!(db.disk_bytes < 128);
+        db.set(b"key", [1u8; 32]).unwrap();
+        drop(db);
+
+        // usage is accounted on open as well.
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert!(db.disk_bytes > 0);
+        assert_eq!(db.disk_bytes, db.segment_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_clock_going_backwards() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let future = now_secs() + 3600;
+
+        // written while the clock was an hour ahead.
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.last_timestamp = future;
+            db.set(b"a", b"old").unwrap();
+            db.set(b"b", b"old").unwrap();
+            assert_eq!(db.keydir.get(b"a").unwrap().timestamp, future);
+        }
+
+        // the clock is back to normal, overwrites still win.
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            assert_eq!(db.last_timestamp, future);
+            db.set(b"a", b"new").unwrap();
+            assert_eq!(db.keydir.get(b"a").unwrap().timestamp, future);
+
+            let mut r = b"new".as_slice();
+            db.set_from_reader(b"b", 3, &mut r).unwrap();
+            assert_eq!(db.keydir.get(b"b").unwrap().timestamp, future);
+        }
+
+        // both from hint files and data files.
+        for _ in 0..2 {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            assert_eq!(db.get(b"a").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.get(b"b").unwrap(), Some(b"new".to_vec()));
+            db.compact().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_set_from_reader_large_value() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let value: Vec<u8> = (0..8 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let opts = || StoreOptions {
+            max_value_size: value.len() as u64,
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts()).unwrap();
+            let mut r = io::Cursor::new(&value);
+            db.set_from_reader(b"large", value.len() as u64, &mut r)
+                .unwrap();
+            assert_eq!(r.position(), value.len() as u64);
+
+            let mut streamed = Vec::new();
+            assert_eq!(
+                db.get_to(b"large", &mut streamed).unwrap(),
+                Some(value.len() as u64)
+            );
+            assert!(streamed == value);
+
+            // a reader ending early stores nothing.
+            let mut r = io::Cursor::new(&value[..10]);
+            assert!(db.set_from_reader(b"short", 11, &mut r).is_err());
+            assert_eq!(db.get(b"short").unwrap(), None);
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts()).unwrap();
+        assert!(db.get(b"large").unwrap().unwrap() == value);
+    }
+
+    #[test]
+    fn test_recovery_stats() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        // closing writes a hint file of the active segment,
+        // tombstones included.
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"a", b"1").unwrap();
+            db.set(b"b", b"2").unwrap();
+            db.set(b"c", [b'3'; 64]).unwrap();
+        }
+        {
+            // a full segment isn't reused as the active one.
+            let path = segment_data_file_path(dir.path(), 1);
+            let opts = StoreOptions {
+                max_log_file_size: fs::metadata(path).unwrap().len(),
+                ..StoreOptions::default()
+            };
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts).unwrap();
+            db.delete(b"a").unwrap();
+            db.set(b"d", b"4").unwrap();
+        }
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        let stats = db.recovery_stats();
+        assert_eq!(stats.entries_per_file, BTreeMap::from([(1, 3), (2, 2)]));
+        assert_eq!(stats.files_from_hints, 2);
+        assert_eq!(stats.files_scanned, 0);
+        assert_eq!(stats.files_skipped, 0);
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(stats.keys, 3);
+        assert!(!stats.duration.is_zero());
+        assert_eq!(db.stats().recovery_duration, stats.duration);
+    }
+
+    #[test]
+    fn test_recover() {
+        use std::sync::Mutex;
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            recovery_progress: Some(RecoveryCallback(Arc::new({
+                let reports = Arc::clone(&reports);
+                move |p: &RecoveryProgress| reports.lock().unwrap().push(p.clone())
+            }))),
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..5u8 {
+                db.set([i], [i]).unwrap();
+            }
+            // an ungraceful shutdown writes no hint file.
+            std::mem::forget(db);
+        }
+        fs::remove_file(dir.path().join("LOCK")).unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert_eq!(reports.lock().unwrap().last().unwrap().files_from_hints, 0);
+
+        // the active data file is not recovered.
+        assert_eq!(db.recover().unwrap(), 5);
+        for file_id in 1..=5 {
+            assert!(segment_hint_file_path(dir.path(), file_id).exists());
+        }
+        assert_eq!(db.recover().unwrap(), 0);
+        drop(db);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(reports.lock().unwrap().last().unwrap().files_from_hints, 5);
+        for i in 0..5u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn test_max_segment_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            max_segment_files: 3,
+            ..StoreOptions::default()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        let data_files_in_dir = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .path()
+                        .to_string_lossy()
+                        .ends_with(settings::DATA_FILE_SUFFIX)
+                })
+                .count()
+        };
+
+        for i in 0..50u8 {
+            db.set([i % 2], [i]).unwrap();
+
+            // one slack for the segment being rotated in.
+            assert!(db.stats().segment_count <= 3 + 1);
+            assert!(data_files_in_dir() <= 3 + 1);
+        }
+
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![48]));
+        assert_eq!(db.get(&[1]).unwrap(), Some(vec![49]));
+    }
+
+    #[test]
+    fn test_compact_to_single_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 64,
+            remove_empty_files: true,
+            ..StoreOptions::default()
+        };
+        let files_with_suffix = |suffix: &str| {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.to_string_lossy().ends_with(suffix))
+                .count()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..100u8 {
+                db.set([i], [i; 16]).unwrap();
+            }
+            assert!(db.stats().segment_count > 10);
+
+            db.compact_to_single_file().unwrap();
+            db.close().unwrap();
+        }
+        assert_eq!(files_with_suffix(settings::DATA_FILE_SUFFIX), 1);
+        assert_eq!(files_with_suffix(settings::HINT_FILE_SUFFIX), 1);
+
+        // the oversized data file is loaded from its hint file.
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.recovery_stats().files_from_hints, 1);
+        assert_eq!(db.len(), 100);
+        for i in 0..100u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 16]));
+        }
+    }
+
+    #[test]
+    fn test_compaction_report() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 64,
+            ..StoreOptions::default()
+        };
+        let data_files = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap())
+                .filter(|e| {
+                    e.file_name()
+                        .to_string_lossy()
+                        .ends_with(settings::DATA_FILE_SUFFIX)
+                })
+                .map(|e| e.metadata().unwrap().len())
+                .collect::<Vec<_>>()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        // 20 values, 10 overwrites and 5 tombstones, 15 keys left.
+        for i in 0..20u8 {
+            db.set([i], [i; 16]).unwrap();
+        }
+        // entries written before are counted on open.
+        drop(db);
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for i in 0..10u8 {
+            db.set([i], [i; 8]).unwrap();
+        }
+        for i in 15..20u8 {
+            db.delete(&[i]).unwrap();
+        }
+        let before = data_files();
+
+        let report = db.compact().unwrap();
+        let after = data_files();
+
+        assert_eq!(report.input_bytes, before.iter().sum::<u64>());
+        assert_eq!(report.output_bytes, after.iter().sum::<u64>());
+        assert_eq!(report.entries_copied, 15);
+        assert_eq!(report.entries_dropped, 20);
+        assert_eq!(report.segments_removed, before.len() as u64);
+        // all but the new, empty, active data file.
+        assert_eq!(report.segments_created, after.len() as u64 - 1);
+        assert!(report.output_bytes < report.input_bytes);
+
+        // nothing stale left.
+        let report = db.compact().unwrap();
+        assert_eq!(report.entries_dropped, 0);
+        assert_eq!(report.output_bytes, report.input_bytes);
+
+        // compaction data files of both runs didn't clash.
+        drop(db);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 15);
+        assert_eq!(db.get(&[0]).unwrap(), Some(vec![0; 8]));
+        assert_eq!(db.get(&[14]).unwrap(), Some(vec![14; 16]));
+    }
+
+    #[test]
+    fn test_dump_key() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+
+        for value in [b"1", b"2", b"3"] {
+            db.set(b"key", value).unwrap();
+            db.set(b"other", value).unwrap();
+        }
+        let records = db.dump_key(b"key").unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records
+            .windows(2)
+            .all(|w| (w[0].0, w[0].1) < (w[1].0, w[1].1)));
+        assert!(records.windows(2).all(|w| w[0].2 <= w[1].2));
+        assert!(records.iter().all(|r| !r.3));
+        // the live record is the last one.
+        let live = db.keydir.get(b"key").unwrap();
+        assert_eq!((records[2].0, records[2].1), (live.file_id, live.offset));
+
+        db.delete(b"key").unwrap();
+        let records = db.dump_key(b"key").unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(records[3].3);
+        assert!(db.dump_key(b"missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_first_and_last_key() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        {
+            let mut db: DiskStorage<BTreeMapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            assert_eq!(db.first_key(), None);
+            assert_eq!(db.last_key(), None);
+
+            for key in ["banana", "cherry", "apple", "date"] {
+                db.set(key, key).unwrap();
+            }
+            db.delete(b"date").unwrap();
+
+            assert_eq!(db.first_key(), Some(b"apple".to_vec()));
+            assert_eq!(db.last_key(), Some(b"cherry".to_vec()));
+        }
+
+        let db: DiskStorage<BTreeMapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.first_key(), Some(b"apple".to_vec()));
+        assert_eq!(db.last_key(), Some(b"cherry".to_vec()));
+    }
+
+    #[test]
+    fn test_keys_pagination() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<BTreeMapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        for i in 0..10u8 {
+            db.set([i], [i]).unwrap();
+        }
+
+        // the buffer is appended to.
+        let mut buf = vec![b"x".to_vec()];
+        db.keys_into(&mut buf).unwrap();
+        assert_eq!(buf.len(), 11);
+        assert_eq!(buf[1..], db.keys().unwrap()[..]);
+
+        let page = |db: &DiskStorage<BTreeMapKeydir>, after: Option<u8>, limit| {
+            let after = after.map(|a| [a]);
+            db.keys_with_prefix(b"", after.as_ref().map(|a| a.as_slice()), limit)
+                .unwrap()
+        };
+        assert!(page(&db, None, 0).is_empty());
+        assert_eq!(page(&db, None, 10).len(), 10);
+        assert_eq!(page(&db, Some(6), 3), vec![vec![7], vec![8], vec![9]]);
+        assert!(page(&db, Some(9), 3).is_empty());
+
+        // keys written behind the cursor don't shift later pages.
+        let mut keys = page(&db, None, 4);
+        db.set([0, 0], b"").unwrap();
+        db.delete(&[5]).unwrap();
+        while let Some(after) = keys.last().map(|k| k[0]) {
+            let next = page(&db, Some(after), 4);
+            if next.is_empty() {
+                break;
+            }
+            keys.extend(next);
+        }
+        let expected: Vec<Vec<u8>> = [0, 1, 2, 3, 4, 6, 7, 8, 9].map(|i| vec![i]).into();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_blob_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            blob_threshold: 100,
+            ..StoreOptions::default()
+        };
+        let blob_file = segment_file_path(dir.path(), 1, settings::BLOB_FILE_SUFFIX);
+        let big = |i: u8| vec![i; 1000];
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..4u8 {
+                db.set([b'b', i], big(i)).unwrap();
+            }
+            for round in 0..10u8 {
+                for i in 0..4u8 {
+                    db.set([b's', i], [round]).unwrap();
+                }
+            }
+            let blob_file_size = fs::metadata(&blob_file).unwrap().len();
+            assert!(blob_file_size > 4000);
+
+            // all blobs are live, compaction leaves the blob file alone.
+            db.compact().unwrap();
+            assert_eq!(fs::metadata(&blob_file).unwrap().len(), blob_file_size);
+            assert_eq!(db.get(&[b'b', 2]).unwrap(), Some(big(2)));
+            assert_eq!(db.get(&[b's', 2]).unwrap(), Some(vec![9]));
+
+            let mut values = 0;
+            db.for_each(&mut |key, value| {
+                if key[0] == b'b' {
+                    assert_eq!(value, big(key[1]));
+                }
+                values += 1;
+                Ok(false)
+            })
+            .unwrap();
+            assert_eq!(values, 8);
+        }
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.get(&[b'b', 0]).unwrap(), Some(big(0)));
+
+            // a quarter of the blob file stays live, it is rewritten.
+            for i in 1..4u8 {
+                db.delete(&[b'b', i]).unwrap();
+            }
+            db.compact().unwrap();
+            assert!(!blob_file.exists());
+            assert_eq!(db.get(&[b'b', 0]).unwrap(), Some(big(0)));
+            assert_eq!(db.get(&[b'b', 1]).unwrap(), None);
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.get(&[b'b', 0]).unwrap(), Some(big(0)));
+        assert_eq!(db.get(&[b's', 3]).unwrap(), Some(vec![9]));
+    }
+
+    #[test]
+    fn test_open_same_dir_via_different_paths() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let _db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        // relative path of the same directory.
+        let cwd = std::env::current_dir().unwrap();
+        let relative: PathBuf = cwd
+            .components()
+            .skip(1)
+            .map(|_| "..")
+            .collect::<PathBuf>()
+            .join(dir.path().strip_prefix("/").unwrap());
+        assert!(relative.is_relative());
+
+        match DiskStorage::<HashmapKeydir>::open(&relative) {
+            Err(StoreError::AlreadyLocked { pid }) => assert_eq!(pid, Some(std::process::id())),
+            r => panic!("expect AlreadyLocked, got {:?}", r.map(|_| ())),
+        }
+
+        let linked = tempdir::TempDir::new("disk-storage-link").unwrap();
+        let link = linked.path().join("db");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open(&link),
+            Err(StoreError::AlreadyLocked { .. })
+        ));
+    }
+
+    #[test]
+    fn test_no_empty_segment_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let data_files = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.to_string_lossy().ends_with(settings::DATA_FILE_SUFFIX))
+                .collect::<Vec<_>>()
+        };
+
+        // an empty active data file is kept and reused by default.
+        for _ in 0..3 {
+            let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            drop(db);
+        }
+        assert_eq!(data_files(), vec![segment_data_file_path(dir.path(), 1)]);
+        assert_eq!(fs::metadata(&data_files()[0]).unwrap().len(), 0);
+
+        // open and drop without writes.
+        let opts = StoreOptions {
+            remove_empty_files: true,
+            ..StoreOptions::default()
+        };
+        for _ in 0..3 {
+            let db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            drop(db);
+        }
+        assert!(data_files().is_empty());
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        db.set(b"hello", b"world").unwrap();
+        db.delete(b"hello").unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.stats().segment_count, 1);
+        drop(db);
+        assert!(data_files().is_empty());
+    }
+
+    #[test]
+    fn test_close() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        db.set(b"hello", b"world").unwrap();
+        let file_id = db.active_data_file.unwrap();
+
+        db.close().unwrap();
+        assert!(db.is_closed());
+        assert!(segment_hint_file_path(dir.path(), file_id).exists());
+        assert!(!dir.path().join("LOCK").exists());
+
+        assert!(matches!(
+            db.set(b"hello", b"again"),
+            Err(StoreError::Closed)
+        ));
+        assert!(matches!(db.get(b"hello"), Err(StoreError::Closed)));
+        assert!(matches!(db.delete(b"hello"), Err(StoreError::Closed)));
+
+        // closing again is a no-op.
+        db.close().unwrap();
+
+        // lock is released, the store reopens from the hint file.
+        let mut db2: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db2.get(b"hello").unwrap(), Some(b"world".to_vec()));
+
+        drop(db);
+        assert!(dir.path().join("LOCK").exists());
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for key in [b"a", b"b", b"c", b"a"] {
+            db.set(key, key).unwrap();
+        }
+
+        // each write rotates, the first value of `a` is stale.
+        let layout = |db: &DiskStorage<HashmapKeydir>| -> Vec<(u64, u64, bool)> {
+            db.segments()
+                .unwrap()
+                .iter()
+                .map(|s| (s.file_id, s.live_keys, s.is_active))
+                .collect()
+        };
+        assert_eq!(
+            layout(&db),
+            vec![(1, 0, false), (2, 1, false), (3, 1, false), (4, 1, true)]
+        );
+        let segments = db.segments().unwrap();
+        assert!(segments.iter().all(|s| s.size_bytes > 0));
+        assert_eq!(segments[0].path, segment_data_file_path(&db.path, 1));
+
+        // compaction moves live keys to new data files with hint files,
+        // and a new empty active one.
+        db.compact().unwrap();
+        let segments = db.segments().unwrap();
+        assert!(segments.iter().all(|s| s.file_id > 4));
+        assert_eq!(segments.iter().map(|s| s.live_keys).sum::<u64>(), 3);
+        let active: Vec<_> = segments.iter().filter(|s| s.is_active).collect();
+        assert_eq!(active.len(), 1);
+        assert_eq!((active[0].live_keys, active[0].size_bytes), (0, 0));
+        assert!(segments.iter().all(|s| s.is_active || s.has_hint));
+    }
+
+    #[test]
+    fn test_compaction_candidates() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 100,
+            ..StoreOptions::default()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for i in 0..10u8 {
+            db.set([i], [i; 8]).unwrap();
+        }
+        // overwrite most keys of the first data file, a little of the second.
+        for i in [0u8, 1, 2, 5] {
+            db.set([i], [i; 8]).unwrap();
+        }
+
+        let segments = db.segments().unwrap();
+        let first = &segments[0];
+        let entries = db.data_files[&first.file_id].entry_count();
+        assert!(first.live_keys < entries);
+        assert!(first.stale_bytes() > 0);
+        assert_eq!(
+            segments.iter().map(|s| s.live_bytes).sum::<u64>(),
+            db.keydir.iter().map(|(_, e)| e.size).sum::<u64>()
+        );
+
+        let candidates = db.compaction_candidates().unwrap();
+        assert_eq!(candidates[0].file_id, first.file_id);
+        assert!(candidates.iter().all(|s| !s.is_active));
+        assert!(candidates
+            .windows(2)
+            .all(|w| w[0].stale_bytes() * w[1].size_bytes >= w[1].stale_bytes() * w[0].size_bytes));
+
+        db.compact().unwrap();
+        assert!(db.compaction_candidates().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_hint_file_tombstones() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 50,
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(b"k", [0; 8]).unwrap();
+            for i in 0..6u8 {
+                db.set([i], [i; 8]).unwrap();
+            }
+            db.delete(b"k").unwrap();
+            let tombstone_file_id = db.tombstones[&b"k".to_vec()].file_id;
+            for i in 10..16u8 {
+                db.set([i], [i; 8]).unwrap();
+            }
+            assert_ne!(db.active_data_file, Some(tombstone_file_id));
+
+            // the data file holding the value survives, the tombstone
+            // is kept.
+            db.compact_segments(&[tombstone_file_id]).unwrap();
+            assert!(db.data_files.contains_key(&1));
+            assert!(db.tombstones.contains_key(b"k".as_slice()));
+
+            // hint files of all data files, tombstones included.
+            db.recover().unwrap();
+        }
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        let stats = db.recovery_stats();
+        assert_eq!(stats.files_scanned, 0);
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(db.get(b"k").unwrap(), None);
+        assert_eq!(db.len(), 12);
+    }
+
+    #[test]
+    fn test_compact_segments() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 50,
+            ..StoreOptions::default()
+        };
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        for i in 0..15u8 {
+            db.set([i], [i; 8]).unwrap();
+        }
+        let sealed: Vec<u64> = db
+            .segments()
+            .unwrap()
+            .iter()
+            .filter(|s| !s.is_active)
+            .map(|s| s.file_id)
+            .collect();
+        assert_eq!(db.data_files.len(), 5);
+
+        // overwrite keys of the first two data files.
+        let (first, second) = (sealed[0], sealed[1]);
+        let keys_of = |db: &DiskStorage<HashmapKeydir>, file_id: u64| -> Vec<Vec<u8>> {
+            let mut keys: Vec<Vec<u8>> = db
+                .keydir
+                .iter()
+                .filter(|(_, e)| e.file_id == file_id)
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            keys.sort();
+            keys
+        };
+        let mut fragmented = keys_of(&db, first)[..2].to_vec();
+        fragmented.push(keys_of(&db, second)[0].clone());
+        for key in fragmented.iter() {
+            db.set(key, b"new").unwrap();
+        }
+        let candidates: Vec<u64> = db
+            .compaction_candidates()
+            .unwrap()
+            .iter()
+            .map(|s| s.file_id)
+            .collect();
+        assert_eq!(candidates.len(), 2);
+
+        let others: Vec<u64> = db
+            .data_files
+            .keys()
+            .copied()
+            .filter(|id| !candidates.contains(id))
+            .collect();
+        let report = db.compact_segments(&candidates).unwrap();
+        assert_eq!(report.segments_removed, 2);
+        assert_eq!(report.entries_dropped, 3);
+
+        // only the compacted data files are gone.
+        for id in candidates.iter() {
+            assert!(!db.data_files.contains_key(id));
+            assert!(!segment_data_file_path(&db.path, *id).exists());
+        }
+        for id in others.iter() {
+            assert!(db.data_files.contains_key(id));
+        }
+        assert!(db.compaction_candidates().unwrap().is_empty());
+
+        let check = |db: &mut DiskStorage<HashmapKeydir>| {
+            assert_eq!(db.len(), 15);
+            for i in 0..15u8 {
+                let expected = if fragmented.contains(&vec![i]) {
+                    b"new".to_vec()
+                } else {
+                    vec![i; 8]
+                };
+                assert_eq!(db.get(&[i]).unwrap(), Some(expected));
+            }
+        };
+        check(&mut db);
+
+        assert!(matches!(
+            db.compact_segments(&[candidates[0]]),
+            Err(StoreError::SegmentMissing { .. })
+        ));
+
+        drop(db);
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        check(&mut db);
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        // number of writes to the file system for 100 small entries.
+        let writes_of = |write_buffer_size: u64| {
+            let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+            let mem = MemFileSystem::default();
+            let opts = StoreOptions {
+                write_buffer_size,
+                fs: Arc::new(mem.clone()),
+                ..StoreOptions::default()
+            };
+            let data_file = segment_data_file_path(&dir.path().canonicalize().unwrap(), 1);
+
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            let writes = mem.writes();
+            for i in 0..100 {
+                db.set(format!("key-{i}"), format!("value-{i}")).unwrap();
+            }
+            let writes = mem.writes() - writes;
+
+            // unflushed entries are read from the buffer.
+            let reads = mem.reads();
+            for i in 0..100 {
+                assert_eq!(
+                    db.get(format!("key-{i}").as_bytes()).unwrap(),
+                    Some(format!("value-{i}").into_bytes())
+                );
+            }
+            if write_buffer_size > 0 {
+                assert_eq!(mem.reads(), reads);
+                assert!(
+                    mem.metadata(&data_file).unwrap().len < db.active_data_file().size().unwrap()
+                );
+                assert!(db.value_location(b"key-99").unwrap().is_none());
+            }
+
+            db.sync().unwrap();
+            assert_eq!(
+                mem.metadata(&data_file).unwrap().len,
+                db.active_data_file().size().unwrap()
+            );
+            db.delete(b"key-0").unwrap();
+            drop(db);
+
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts).unwrap();
+            assert_eq!(db.len(), 99);
+            assert_eq!(db.get(b"key-99").unwrap(), Some(b"value-99".to_vec()));
+            writes
+        };
+
+        let unbuffered = writes_of(0);
+        let buffered = writes_of(4096);
+        assert!(
+            buffered * 10 < unbuffered,
+            "{buffered} writes with a buffer, {unbuffered} without"
+        );
+
+        // the buffer fills up in the middle of an entry, which is read
+        // back whole.
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            write_buffer_size: 64,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        db.set(b"abc", b"12345").unwrap();
+        db.set(b"def", [7; 30]).unwrap();
+        assert_eq!(db.get(b"def").unwrap(), Some(vec![7; 30]));
+        let mut value = Vec::new();
+        let location = db.value_location(b"def").unwrap().unwrap();
+        location.read_into(b"def", &mut value).unwrap();
+        assert_eq!(value, [7; 30]);
+
+        let mut r = [8; 30].as_slice();
+        db.set_from_reader(b"ghi", 30, &mut r).unwrap();
+        assert_eq!(db.get(b"ghi").unwrap(), Some(vec![8; 30]));
+    }
+
+    #[test]
+    fn test_size_limits_fit_format() {
+        let with_limits = |max_key_size: u64, max_value_size: u64| StoreOptions {
+            max_key_size,
+            max_value_size,
+            ..StoreOptions::default()
+        };
+
+        // sizes are recorded in 24 bits for keys and 32 bits for values.
+        let max_key_size = KEY_SZ_MASK as u64;
+        let max_value_size = u32::MAX as u64;
+        assert!(with_limits(max_key_size, max_value_size).validate().is_ok());
+        for opts in [
+            with_limits(max_key_size + 1, max_value_size),
+            with_limits(max_key_size, max_value_size + 1),
+            with_limits(max_key_size, 5 << 30),
+        ] {
+            assert!(matches!(opts.validate(), Err(StoreError::InvalidOption(_))));
+        }
+
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_with_options(
+                dir.path(),
+                with_limits(64, max_value_size + 1)
+            ),
+            Err(StoreError::InvalidOption(_))
+        ));
+    }
+
+    #[test]
+    fn test_operation_counters() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+
+        db.set(b"a", b"1").unwrap();
+        db.set(b"b", b"22").unwrap();
+        db.set(b"a", b"333").unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"333".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"22".to_vec()));
+        assert_eq!(db.get(b"c").unwrap(), None);
+        db.delete(b"b").unwrap();
+        db.compact().unwrap();
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.sets.get(), 3);
+        assert_eq!(metrics.gets.get(), 3);
+        assert_eq!(metrics.hits.get(), 2);
+        assert_eq!(metrics.misses.get(), 1);
+        assert_eq!(metrics.bytes_read.get(), 5);
+        assert_eq!(metrics.deletes.get(), 1);
+        assert_eq!(metrics.compactions.get(), 1);
+        // three values and a tombstone, compaction copies don't count.
+        let entry_size = |key: &[u8], value: &[u8]| (HEADER_SIZE + key.len() + value.len()) as u64;
+        assert_eq!(
+            metrics.bytes_written.get(),
+            entry_size(b"a", b"1")
+                + entry_size(b"b", b"22")
+                + entry_size(b"a", b"333")
+                + entry_size(b"b", settings::REMOVE_TOMESTONE)
+        );
+
+        db.stats_reset();
+        assert!(metrics.report().iter().all(|(_, v)| *v == 0));
+    }
+
+    #[test]
+    fn test_layout_suffixes() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let v2 = StoreOptions {
+            layout: Arc::new(Layout::default().suffixes(".v2.data", ".v2.hint", ".v2.blob")),
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"v1", b"1").unwrap();
+        }
+        // not a data file of either store.
+        fs::write(dir.path().join("000009.other.data"), b"garbage").unwrap();
+
+        // files of the other version are left alone.
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), v2.clone()).unwrap();
+            assert!(db.is_empty());
+            db.set(b"v2", b"2").unwrap();
+        }
+        assert!(segment_data_file_path(dir.path(), 1).exists());
+        assert!(segment_hint_file_path(dir.path(), 1).exists());
+        assert!(dir.path().join("000001.v2.data").exists());
+        assert!(dir.path().join("000001.v2.hint").exists());
+
+        let db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.keys().unwrap(), vec![b"v1".to_vec()]);
+        drop(db);
+        let db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), v2).unwrap();
+        assert_eq!(db.keys().unwrap(), vec![b"v2".to_vec()]);
+    }
+
+    #[test]
+    fn test_layout() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let count = |sub: &str, suffix: &str| {
+            fs::read_dir(dir.path().join(sub))
+                .map(|entries| {
+                    entries
+                        .map(|e| e.unwrap().path())
+                        .filter(|p| p.to_string_lossy().ends_with(suffix))
+                        .count()
+                })
+                .unwrap_or(0)
+        };
+        let legacy = StoreOptions {
+            max_log_file_size: 50,
+            ..StoreOptions::default()
+        };
+        let opts = StoreOptions {
+            layout: Arc::new(Layout::default().data_dir("data").hint_dir("hints")),
+            ..legacy.clone()
+        };
 
-    fn contains_key(&self, key: &[u8]) -> bool {
-        self.keydir.contains_key(key)
-    }
+        // written with the default layout.
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), legacy.clone()).unwrap();
+            for i in 0..10u8 {
+                db.set([i], [i; 8]).unwrap();
+            }
+            db.compact().unwrap();
+        }
+        assert!(count("", settings::DATA_FILE_SUFFIX) > 0);
+        assert!(count("", settings::HINT_FILE_SUFFIX) > 0);
 
-    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
-    where
-        F: FnMut(&[u8], &[u8]) -> Result<bool>,
-    {
-        let mut wrapper = |_key: &Vec<u8>, keydir_entry: &mut KeydirEntry| -> Result<bool> {
-            let df = self.data_files.get_mut(&keydir_entry.file_id).unwrap();
-            let data_entry = df.read(keydir_entry.offset)?;
-            match data_entry {
-                None => Ok(false),
-                Some(entry) => f(&entry.key, &entry.value),
+        // a read-only store reads files where they are.
+        {
+            let read_only = StoreOptions {
+                read_only: true,
+                ..opts.clone()
+            };
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), read_only).unwrap();
+            assert_eq!(db.get(&[3]).unwrap(), Some(vec![3; 8]));
+            assert_eq!(count("data", settings::DATA_FILE_SUFFIX), 0);
+        }
+
+        // files are moved into the new layout on open.
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            assert_eq!(count("", settings::DATA_FILE_SUFFIX), 0);
+            assert_eq!(count("", settings::HINT_FILE_SUFFIX), 0);
+            assert!(count("data", settings::DATA_FILE_SUFFIX) > 0);
+            assert!(count("hints", settings::HINT_FILE_SUFFIX) > 0);
+            for i in 0..10u8 {
+                assert_eq!(db.get(&[i]).unwrap(), Some(vec![i; 8]));
             }
-        };
 
-        self.keydir.for_each(&mut wrapper)
-    }
+            // new data files, compaction and hint files follow it too.
+            for i in 0..10u8 {
+                db.set([i], [i + 1; 8]).unwrap();
+            }
+            db.compact().unwrap();
+            assert_eq!(count("", settings::DATA_FILE_SUFFIX), 0);
+            assert_eq!(count("", settings::HINT_FILE_SUFFIX), 0);
+        }
 
-    fn sync(&mut self) -> Result<()> {
-        if self.active_data_file.is_some() {
-            self.active_data_file.as_mut().unwrap().sync()?;
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(db.get(&[i]).unwrap(), Some(vec![i + 1; 8]));
         }
-        Ok(())
-    }
 
-    fn close(&mut self) -> Result<()> {
-        self.sync()?;
-        Ok(())
+        let invalid = StoreOptions {
+            layout: Arc::new(Layout::default().data_dir("../elsewhere")),
+            ..StoreOptions::default()
+        };
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_with_options(dir.path(), invalid),
+            Err(StoreError::InvalidLayout(_))
+        ));
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let next_file_id = self.next_file_id();
+    #[test]
+    fn test_external_modification() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            detect_external_modification: true,
+            ..StoreOptions::default()
+        };
 
-        // switch to another active data file
-        self.new_active_data_file(Some(next_file_id + 1))?;
-        let mut compaction_data_file_id = next_file_id + 2;
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            db.set(key, key).unwrap();
+        }
+        assert_eq!(db.get(b"a").unwrap(), Some(b"a".to_vec()));
+        db.verify_segments().unwrap();
 
-        // create a new data file for compaction.
-        let data_file_path = segment_data_file_path(&self.path, compaction_data_file_id);
-        let mut compaction_df = DataFile::new(&data_file_path, true)?;
+        // truncate the first segment behind the store's back.
+        let path = segment_data_file_path(dir.path(), 1);
+        let size = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
 
-        // register read-only compaction data file.
-        self.data_files.insert(
-            compaction_df.file_id(),
-            DataFile::new(&data_file_path, false)?,
-        );
+        assert!(matches!(
+            db.get(b"a"),
+            Err(StoreError::ExternalModification { file_id: 1 })
+        ));
+        assert!(matches!(
+            db.verify_segments(),
+            Err(StoreError::ExternalModification { file_id: 1 })
+        ));
 
-        // create a new hint file to store compaction file index.
-        let hint_file_path = segment_hint_file_path(&self.path, compaction_data_file_id);
-        let mut hint_file = HintFile::new(&hint_file_path, true)?;
+        // a removed segment is reported too, its keys are kept.
+        fs::remove_file(segment_data_file_path(dir.path(), 2)).unwrap();
+        db.data_files.get_mut(&2).unwrap().close_reader();
+        assert!(matches!(
+            db.get(b"b"),
+            Err(StoreError::ExternalModification { file_id: 2 })
+        ));
+        assert!(db.contains_key(b"b"));
 
-        // copy all the data entries into compaction data file.
-        let mut wrapper = |key: &Vec<u8>, keydir_entry: &mut KeydirEntry| -> Result<bool> {
-            if compaction_df.size()? > self.opts.max_log_file_size {
-                compaction_df.sync()?;
-                hint_file.sync()?;
+        // the active segment isn't tracked.
+        assert_eq!(db.get(b"c").unwrap(), Some(b"c".to_vec()));
+    }
 
-                compaction_data_file_id += 1;
-                // switch to a new data file for compaction
-                let data_file_path = segment_data_file_path(&self.path, compaction_data_file_id);
-                compaction_df = DataFile::new(&data_file_path, true)?;
+    #[test]
+    fn test_segment_missing() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            max_open_files: 1,
+            ..StoreOptions::default()
+        };
 
-                self.data_files.insert(
-                    compaction_df.file_id(),
-                    DataFile::new(&data_file_path, false)?,
-                );
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            db.set(key, key).unwrap();
+        }
+        assert_eq!(db.get(b"c").unwrap(), Some(b"c".to_vec()));
 
-                let hint_file_path = segment_hint_file_path(&self.path, compaction_data_file_id);
-                hint_file = HintFile::new(&hint_file_path, true)?;
-            }
+        // `a` lives in the first segment, its reader is closed.
+        fs::remove_file(segment_data_file_path(dir.path(), 1)).unwrap();
 
-            let df = self
-                .data_files
-                .get_mut(&keydir_entry.file_id)
-                .expect("cannot find data file");
+        assert!(matches!(
+            db.get(b"a"),
+            Err(StoreError::SegmentMissing { file_id: 1 })
+        ));
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"b".to_vec()));
 
-            let offset =
-                compaction_df.copy_bytes_from(df, keydir_entry.offset, keydir_entry.size)?;
+        // same for a segment unregistered behind the store's back.
+        db.data_files.remove(&2);
+        let res = db.for_each(&mut |_, _| Ok(false));
+        assert!(matches!(
+            res,
+            Err(StoreError::SegmentMissing { file_id: 2 })
+        ));
+        assert_eq!(db.get(b"b").unwrap(), None);
 
-            keydir_entry.file_id = compaction_df.file_id();
-            keydir_entry.offset = offset;
+        let mut keys = Vec::new();
+        db.for_each(&mut |key, _| {
+            keys.push(key.to_vec());
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(keys, vec![b"c".to_vec()]);
+    }
 
-            hint_file.write(key, keydir_entry.offset, keydir_entry.size)?;
+    #[test]
+    fn test_corrupted_hint_file_falls_back_to_data_file() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
 
-            Ok(false)
+        let file_id = {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"hello", b"world").unwrap();
+            db.set(b"foo", b"bar").unwrap();
+            db.active_data_file.unwrap()
         };
 
-        self.keydir.for_each(&mut wrapper)?;
+        // flip a byte of the offset of the second hint entry.
+        let hint_file_path = segment_hint_file_path(dir.path(), file_id);
+        let mut bytes = fs::read(&hint_file_path).unwrap();
+        let second = HEADER_SIZE + TIMESTAMP_SIZE + CRC_SIZE + b"hello".len();
+        bytes[second + 7] ^= 0x10;
+        fs::write(&hint_file_path, bytes).unwrap();
 
-        compaction_df.sync()?;
-        hint_file.sync()?;
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
 
-        // remove stale segments.
-        for df in self.data_files.values() {
-            if df.file_id() <= next_file_id {
-                if df.path().exists() {
-                    info!("remove stale log file {}", df.path().display());
-                    fs::remove_file(df.path())?;
-                }
+    #[test]
+    fn test_tombstone_retention() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
 
-                let hint_file_path = segment_hint_file_path(&self.path, df.file_id());
-                if hint_file_path.exists() {
-                    info!("remove stale log hint file {}", hint_file_path.display());
-                    fs::remove_file(&hint_file_path)?;
-                }
-            }
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(b"hello", b"world").unwrap();
+            db.delete(b"hello").unwrap();
+            db.set(b"foo", b"bar").unwrap();
+
+            // the tombstone lives in segment 2, segment 1 still
+            // holds the value and survives partial compaction.
+            db.compact_files(&[2], u64::MAX).unwrap();
+            assert!(!segment_data_file_path(dir.path(), 2).exists());
+            assert!(segment_data_file_path(dir.path(), 1).exists());
+            assert!(db.tombstones.contains_key(b"hello".as_slice()));
         }
 
-        self.data_files.retain(|&k, _| k > next_file_id);
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), None);
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
 
-        Ok(())
+        // no older segment survives full compaction, the tombstone is dropped.
+        db.compact().unwrap();
+        assert!(db.tombstones.is_empty());
+        drop(db);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.get(b"hello").unwrap(), None);
+        assert_eq!(db.len(), 1);
+
+        // unless it's younger than the grace period.
+        db.opts.tombstone_grace = Duration::from_secs(3600);
+        db.delete(b"foo").unwrap();
+        db.compact().unwrap();
+        assert!(db.tombstones.contains_key(b"foo".as_slice()));
+        drop(db);
+
+        // the retained tombstone is persisted, and dropped by compaction
+        // once the grace period is over.
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.recovery_stats().tombstones, 1);
+        assert!(db.tombstones.contains_key(b"foo".as_slice()));
+        assert_eq!(db.get(b"foo").unwrap(), None);
+        db.compact().unwrap();
+        assert!(db.tombstones.is_empty());
     }
-}
 
-impl<K> Drop for DiskStorage<K>
-where
-    K: Keydir + Default,
-{
-    fn drop(&mut self) {
-        // ignore sync errors.
-        trace!("sync all pending writes to disk.");
-        let _r = self.sync();
+    #[test]
+    fn test_tombstone_after_compaction() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        {
+            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+            db.set(b"k", b"v1").unwrap();
+            db.compact().unwrap();
+            db.delete(b"k").unwrap();
+        }
+
+        // the compacted value is loaded before its tombstone.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.get(b"k").unwrap(), None);
+
+        // the data file of the value survives, so does the tombstone.
+        let file_id = db.tombstones.get(b"k".as_slice()).unwrap().file_id;
+        db.compact_segments(&[file_id]).unwrap();
+        assert!(db.tombstones.contains_key(b"k".as_slice()));
+        drop(db);
+
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.get(b"k").unwrap(), None);
     }
-}
 
-fn segment_data_file_path(dir: &Path, segment_id: u64) -> PathBuf {
-    segment_file_path(dir, segment_id, settings::DATA_FILE_SUFFIX)
-}
+    #[test]
+    fn test_out_of_space() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mem = MemFileSystem::default();
+        let opts = StoreOptions {
+            fs: Arc::new(mem.clone()),
+            ..StoreOptions::default()
+        };
+        let data_file = segment_data_file_path(&dir.path().canonicalize().unwrap(), 1);
+        let data_file_len = || mem.metadata(&data_file).unwrap().len;
 
-fn segment_hint_file_path(dir: &Path, segment_id: u64) -> PathBuf {
-    segment_file_path(dir, segment_id, settings::HINT_FILE_SUFFIX)
-}
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(b"foo", b"bar").unwrap();
+            let len = data_file_len();
 
-fn segment_file_path(dir: &Path, segment_id: u64, suffix: &str) -> PathBuf {
-    let mut p = dir.to_path_buf();
-    p.push(format!("{:06}{}", segment_id, suffix));
-    p
-}
+            // the disk fills up in the middle of the entry.
+            mem.set_space(Some(20));
+            assert!(matches!(
+                db.set(b"foo", [0u8; 100]),
+                Err(StoreError::OutOfSpace)
+            ));
+            assert_eq!(data_file_len(), len);
+            assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
 
-#[cfg(test)]
-mod tests {
-    use tempdir;
+            // writes succeed again once space is freed.
+            mem.set_space(None);
+            db.set(b"baz", b"qux").unwrap();
+        }
 
-    use super::*;
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(db.get(b"baz").unwrap(), Some(b"qux".to_vec()));
+        drop(db);
 
-    use super::super::keydir::HashmapKeydir;
-    use super::super::OpenOptions;
+        // a partial entry left by a crash, before any hint file is
+        // written, is discarded on open.
+        let len = data_file_len();
+        mem.remove(&segment_hint_file_path(data_file.parent().unwrap(), 1))
+            .unwrap();
+        mem.create(&data_file)
+            .unwrap()
+            .write_all(&[1u8; 10])
+            .unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(data_file_len(), len);
+        assert_eq!(db.get(b"baz").unwrap(), Some(b"qux".to_vec()));
+    }
 
     #[test]
-    fn disk_storage_should_get_put() {
+    fn test_mock_file_system() {
         let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
-        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
-
-        assert_eq!(db.len(), 0);
+        let mem = MemFileSystem::default();
+        let opts = StoreOptions {
+            max_log_file_size: 64,
+            fs: Arc::new(mem.clone()),
+            ..StoreOptions::default()
+        };
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, None);
+        let len = {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..20 {
+                db.set(format!("key-{i}"), format!("value-{i}")).unwrap();
+            }
+            db.delete(b"key-0").unwrap();
+            db.compact().unwrap();
+            assert_eq!(db.get(b"key-1").unwrap(), Some(b"value-1".to_vec()));
 
-        db.set(b"hello".to_vec(), b"world".to_vec()).unwrap();
+            // rotation fails to create a new data file.
+            mem.fail_create(true);
+            let res = (0..20).try_for_each(|i| db.set(format!("tmp-{i}"), "x"));
+            assert!(matches!(res, Err(StoreError::Io(_))));
+            mem.fail_create(false);
+            db.len()
+        };
 
-        assert_eq!(db.len(), 1);
-        assert_eq!(db.contains_key(b"hello"), true);
+        // the lock is released, nothing is left on the local disk.
+        let local: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(local.is_empty(), "{:?}", local);
+        assert!(mem.paths().len() > 2);
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, Some(b"world".to_vec()));
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"key-0").unwrap(), None);
+        assert_eq!(db.get(b"key-19").unwrap(), Some(b"value-19".to_vec()));
+        assert_eq!(db.len(), len);
+    }
 
-        db.set(b"hello".to_vec(), b"underworld".to_vec()).unwrap();
+    #[test]
+    #[cfg(unix)]
+    fn test_create_mode() {
+        use std::os::unix::fs::PermissionsExt;
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, Some(b"underworld".to_vec()));
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let path = dir.path().join("db");
+        let opts = StoreOptions {
+            mode: Some(0o600),
+            fs: Arc::new(StdFileSystem::with_mode(0o600)),
+            ..StoreOptions::default()
+        };
 
-        db.delete(b"hello").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(&path, opts).unwrap();
+        db.set(b"key", b"value").unwrap();
 
-        let res = db.get(b"hello").unwrap();
-        assert_eq!(res, None);
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&segment_data_file_path(&path, 1)), 0o600);
+        assert_eq!(mode(&path.join("LOCK")), 0o600);
+        assert_eq!(mode(&path), 0o700);
     }
 
     #[test]
-    fn disk_storage_should_persist() {
+    fn test_reuse_active_data_file() {
         let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let data_files = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .ends_with(settings::DATA_FILE_SUFFIX)
+                })
+                .count()
+        };
 
-        {
+        for i in 0..10u32 {
             let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
-            db.set(b"persistence".to_vec(), b"check".to_vec()).unwrap();
-            db.set(b"removed".to_vec(), b"entry".to_vec()).unwrap();
-            db.delete(b"removed").unwrap();
+            db.set(i.to_be_bytes(), b"value").unwrap();
         }
+        assert_eq!(data_files(), 1);
 
-        {
-            let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
-            let res = db.get(b"persistence").unwrap();
-            assert_eq!(res, Some(b"check".to_vec()));
+        // entries appended to the reused segment survive without the
+        // hint file written on close.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert!(!segment_hint_file_path(dir.path(), 1).exists());
+        db.set(b"key", b"value").unwrap();
+        drop(db);
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(db.len(), 11);
+        assert_eq!(
+            db.get(&9u32.to_be_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
 
-            let res = db.get(b"removed").unwrap();
-            assert_eq!(res, None);
+        // a full segment isn't reused.
+        drop(db);
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        db.set(b"key", b"other").unwrap();
+        drop(db);
+        assert_eq!(data_files(), 2);
+    }
+
+    #[test]
+    fn test_open_registers_segments_once() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let on_disk = || {
+            let pattern = format!("{}/*{}", dir.path().display(), settings::DATA_FILE_SUFFIX);
+            glob::glob(&pattern).unwrap().count()
+        };
+        let check = |db: &DiskStorage<HashmapKeydir>| {
+            assert_eq!(db.data_files.len(), on_disk());
+            assert_eq!(db.active_data_file, db.data_files.keys().max().copied());
+        };
+
+        // a new store holds only its active segment.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        check(&db);
+        db.set(b"hello", b"world").unwrap();
+        drop(db);
+
+        // the newest segment is reused.
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        check(&db);
+        assert_eq!(db.data_files.len(), 1);
+        db.set(b"foo", b"bar").unwrap();
+        drop(db);
+
+        // full segments are rotated, a new active one is added once.
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            ..StoreOptions::default()
+        };
+        for i in 0..3u32 {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            check(&db);
+            db.set(i.to_be_bytes(), b"value").unwrap();
         }
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        check(&db);
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.get(b"foo").unwrap(), Some(b"bar".to_vec()));
     }
 
     #[test]
-    fn disk_storage_should_retate_logs() {
-        const VERSION: u8 = 10;
+    fn test_preallocate() {
         let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
-        let open_opts = OpenOptions::new().max_log_file_size(50);
+        let opts = StoreOptions {
+            max_log_file_size: 1000,
+            preallocate: true,
+            ..StoreOptions::default()
+        };
+        let file_len = |id| {
+            fs::metadata(segment_data_file_path(dir.path(), id))
+                .unwrap()
+                .len()
+        };
 
-        {
-            let mut db = open_opts.open(dir.path()).unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        db.set(0u32.to_be_bytes(), [b'v'; 100]).unwrap();
+        assert_eq!(file_len(1), 1000);
+        assert_eq!(db.active_data_file().size().unwrap(), 120);
 
-            for i in 0..=VERSION {
-                db.set(b"version".to_vec(), vec![i]).unwrap();
+        // rotation happens at the size of the written entries, the
+        // sealed segment is cut to it.
+        for i in 1..12u32 {
+            db.set(i.to_be_bytes(), [b'v'; 100]).unwrap();
+        }
+        assert_eq!(file_len(1), 9 * 120);
+        assert_eq!(file_len(2), 1000);
+        drop(db);
+        assert_eq!(file_len(2), 3 * 120);
+
+        // unwritten space left behind by a crash, before the hint file
+        // is written, is discarded on open.
+        fs::remove_file(segment_hint_file_path(dir.path(), 2)).unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(segment_data_file_path(dir.path(), 2))
+            .unwrap()
+            .set_len(1000)
+            .unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(file_len(2), 3 * 120);
+        assert_eq!(db.len(), 12);
+        for i in 0..12u32 {
+            assert_eq!(db.get(&i.to_be_bytes()).unwrap(), Some(vec![b'v'; 100]));
+        }
+    }
+
+    #[test]
+    fn test_cancel_compaction() {
+        use std::sync::atomic::AtomicU64;
+
+        /// Local file system setting `cancel` once `creates` more files
+        /// are created.
+        #[derive(Debug)]
+        struct CancelOnCreate {
+            creates: AtomicU64,
+            cancel: Arc<AtomicBool>,
+        }
+
+        impl FileSystem for CancelOnCreate {
+            fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+                StdFileSystem::default().open(path)
+            }
+
+            fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+                if self.creates.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    self.cancel.store(true, Ordering::SeqCst);
+                }
+                StdFileSystem::default().create(path)
+            }
+
+            fn remove(&self, path: &Path) -> io::Result<()> {
+                StdFileSystem::default().remove(path)
+            }
+
+            fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+                StdFileSystem::default().rename(from, to)
+            }
+
+            fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
+                StdFileSystem::default().glob(pattern)
+            }
+
+            fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+                StdFileSystem::default().metadata(path)
             }
         }
 
-        fn segment_data_file_path(dir: &Path, segment_id: u64) -> PathBuf {
-            segment_file_path(dir, segment_id, settings::DATA_FILE_SUFFIX)
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let fs = Arc::new(CancelOnCreate {
+            creates: AtomicU64::new(u64::MAX),
+            cancel: cancel.clone(),
+        });
+        let opts = StoreOptions {
+            max_log_file_size: 1,
+            cancel: Some(cancel.clone()),
+            fs: fs.clone(),
+            ..StoreOptions::default()
+        };
+
+        {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            for i in 0..100u32 {
+                db.set(i.to_be_bytes(), i.to_string()).unwrap();
+            }
         }
 
-        let logfile = segment_data_file_path(dir.path(), 1);
-        assert_eq!(logfile.exists(), true);
+        // each compaction data file holds one entry, along with its
+        // hint file, cancel after about a quarter of them.
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        fs.creates.store(50, Ordering::SeqCst);
+        assert!(matches!(db.compact(), Err(StoreError::Cancelled)));
+        assert_eq!(db.opts.metrics.compactions.get(), 0);
 
-        assert!(logfile.exists(), "log file has not been rotated");
+        let check = |db: &mut DiskStorage<HashmapKeydir>| {
+            assert_eq!(db.len(), 100);
+            for i in 0..100u32 {
+                assert_eq!(
+                    db.get(&i.to_be_bytes()).unwrap(),
+                    Some(i.to_string().into_bytes())
+                );
+            }
+        };
+        check(&mut db);
+        assert!(db.data_files.len() > 100);
+
+        // cancellation stops opening as well.
+        drop(db);
+        assert!(matches!(
+            DiskStorage::<HashmapKeydir>::open_with_options(dir.path(), opts.clone()),
+            Err(StoreError::Cancelled)
+        ));
+
+        cancel.store(false, Ordering::SeqCst);
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        check(&mut db);
+        db.compact().unwrap();
+        check(&mut db);
+        // one compaction data file per key, and the active one.
+        assert_eq!(db.data_files.len(), 101);
+    }
+
+    #[test]
+    fn test_entries_metadata() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let mut db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        let start = now_secs();
+        db.set(b"a", b"1").unwrap();
+        db.set(b"bb", b"22").unwrap();
+        db.set(b"ccc", vec![b'3'; 100]).unwrap();
 
+        let mut entries = db.entries_metadata().unwrap();
+        entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let sizes: Vec<_> = entries.iter().map(|e| (e.key.as_slice(), e.size)).collect();
+        let header = HEADER_SIZE as u64;
+        assert_eq!(
+            sizes,
+            vec![
+                (&b"a"[..], header + 2),
+                (&b"bb"[..], header + 4),
+                (&b"ccc"[..], header + 103)
+            ]
+        );
+        assert!(entries
+            .iter()
+            .all(|e| (start..=now_secs()).contains(&e.timestamp)));
+
+        let page = db.entries_metadata_with_prefix(b"", Some(b"a"), 1).unwrap();
+        assert_eq!(page, entries[1..2]);
+    }
+
+    #[test]
+    fn test_hint_file_bounds() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let key = vec![b'k'; KEY_SZ_MASK as usize];
+        let opts = StoreOptions {
+            max_key_size: key.len() as u64,
+            ..StoreOptions::default()
+        };
+
+        // keys of the largest size the format holds.
         {
-            let mut db = open_opts.open(dir.path()).unwrap();
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            db.set(&key, b"value").unwrap();
+            db.set(b"small", b"value").unwrap();
+        }
+        let size = {
+            let mut db: DiskStorage<HashmapKeydir> =
+                DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.recovery_stats().files_from_hints, 1);
+            assert_eq!(db.get(&key).unwrap(), Some(b"value".to_vec()));
+            db.keydir.get(&key).unwrap().size
+        };
+        assert_eq!(size, (HEADER_SIZE + key.len() + 5) as u64);
 
-            let res = db.get(b"version").unwrap();
-            assert_eq!(res, Some(vec![VERSION]));
+        // the hint file points past the end of the truncated data
+        // file, the data file is scanned instead.
+        let path = segment_data_file_path(dir.path(), 1);
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(size).unwrap();
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert_eq!(db.recovery_stats().files_from_hints, 0);
+        assert_eq!(db.recovery_stats().files_scanned, 1);
+        assert_eq!(db.get(&key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"small").unwrap(), None);
+    }
+
+    #[test]
+    fn test_mismatched_hint_files() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let open = |repair| -> DiskStorage<HashmapKeydir> {
+            let opts = StoreOptions {
+                repair,
+                ..StoreOptions::default()
+            };
+            DiskStorage::open_with_options(dir.path(), opts).unwrap()
+        };
+        let hint_file = segment_hint_file_path(dir.path(), 1);
+
+        {
+            let mut db = open(false);
+            db.set(b"a", b"1").unwrap();
+            db.set(b"b", b"2").unwrap();
+        }
+        let partial_hint = fs::read(&hint_file).unwrap();
+        {
+            let mut db = open(false);
+            db.set(b"c", b"3").unwrap();
+            db.delete(b"a").unwrap();
+        }
+
+        // a hint file covering the beginning of its data file only.
+        fs::write(&hint_file, partial_hint).unwrap();
+        // a hint file whose data file was removed.
+        let orphaned = segment_hint_file_path(dir.path(), 7);
+        fs::copy(&hint_file, &orphaned).unwrap();
+
+        let mut db = open(false);
+        assert_eq!(db.recovery_stats().orphaned_hints, 1);
+        assert_eq!(db.recovery_stats().files_from_hints, 1);
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.len(), 2);
+        drop(db);
+        assert!(orphaned.exists());
+
+        let db = open(true);
+        assert_eq!(db.recovery_stats().orphaned_hints, 1);
+        assert!(!orphaned.exists());
+    }
+
+    /// Assert that timestamps in keydir are the ones of data entries.
+    fn assert_timestamps_match<K: Keydir + Default>(db: &mut DiskStorage<K>) {
+        let entries: Vec<_> = db
+            .keydir
+            .iter()
+            .map(|(key, entry)| (key.to_vec(), entry.clone()))
+            .collect();
+        for (key, entry) in entries {
+            let header = db
+                .data_files
+                .get_mut(&entry.file_id)
+                .unwrap()
+                .read_header(entry.offset)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                header.timestamp(),
+                entry.timestamp,
+                "key {}",
+                String::from_utf8_lossy(&key)
+            );
         }
     }
 
     #[test]
-    fn test_lock_file() {
+    fn test_blob_compaction_syncs_pointers() {
         let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
-        let _db: DiskStorage<HashmapKeydir> = DiskStorage::open(dir.path()).unwrap();
+        let opts = StoreOptions {
+            blob_threshold: 8,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        db.set(b"big", [b'x'; 32]).unwrap();
+        for _ in 0..3 {
+            db.set(b"junk", [b'y'; 32]).unwrap();
+        }
+        db.delete(b"junk").unwrap();
+        // only blob files no longer written to are compacted.
+        db.active_blob_file = None;
+        assert_eq!(db.last_sync_time(), None);
 
-        let db2: Result<DiskStorage<HashmapKeydir>> = DiskStorage::open(dir.path());
-        assert_eq!(db2.is_err(), true);
+        db.compact_blob_files().unwrap();
+        assert_eq!(db.blob_files.len(), 1);
+        assert_eq!(db.pending_bytes(), 0);
+        assert!(db.last_sync_time().is_some());
+        assert_eq!(db.get(b"big").unwrap(), Some(vec![b'x'; 32]));
+    }
+
+    #[test]
+    fn test_blob_compaction_preserves_timestamps() {
+        let dir = tempdir::TempDir::new("disk-storage-test.db").unwrap();
+        let opts = StoreOptions {
+            blob_threshold: 8,
+            ..StoreOptions::default()
+        };
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        db.set(b"small", b"value").unwrap();
+        db.set(b"big", [b'x'; 32]).unwrap();
+        for _ in 0..3 {
+            db.set(b"junk", [b'y'; 32]).unwrap();
+        }
+        db.delete(b"junk").unwrap();
+        let timestamps = |db: &DiskStorage<HashmapKeydir>| {
+            [&b"small"[..], b"big"].map(|key| db.keydir.get(key).unwrap().timestamp)
+        };
+        let before = timestamps(&db);
+
+        // the blob file is mostly garbage, its live blob is moved too.
+        std::thread::sleep(Duration::from_millis(1100));
+        db.compact().unwrap();
+        assert_eq!(db.blob_files.len(), 1);
+        assert_eq!(timestamps(&db), before);
+        assert_timestamps_match(&mut db);
+        drop(db);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts.clone()).unwrap();
+        assert!(db.recovery_stats().files_from_hints > 0);
+        assert_eq!(timestamps(&db), before);
+        assert_timestamps_match(&mut db);
+
+        // a hint file without timestamps, as written by older versions,
+        // is replaced by a scan of its data file.
+        let file_id = db.keydir.get(b"small").unwrap().file_id;
+        let hint_entries: Vec<_> = db
+            .keydir
+            .iter()
+            .filter(|(_, e)| e.file_id == file_id)
+            .map(|(key, e)| HintEntry::new(key.to_vec(), e.offset, e.size).unwrap())
+            .collect();
+        drop(db);
+        let hint_file_path = segment_hint_file_path(dir.path(), file_id);
+        fs::remove_file(&hint_file_path).unwrap();
+        let mut hint_file = HintFile::new(&opts.fs, &hint_file_path, true).unwrap();
+        for hint_entry in hint_entries {
+            hint_file.write(hint_entry).unwrap();
+        }
+        drop(hint_file);
+
+        let mut db: DiskStorage<HashmapKeydir> =
+            DiskStorage::open_with_options(dir.path(), opts).unwrap();
+        assert!(db.recovery_stats().files_scanned > 0);
+        assert_eq!(timestamps(&db), before);
+        assert_timestamps_match(&mut db);
     }
 }
@@ -0,0 +1,155 @@
+//! Typed key/value helpers.
+//!
+//! Values are serialized with `bincode` or `json` before being handed
+//! to the byte-oriented `Storage` methods, so any `Serialize` type can
+//! be stored without manual encoding. Failures to serialize or
+//! deserialize return `StoreError::Serde`.
+
+use std::fmt::Display;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::arc::BitCask;
+use super::error::{Result, StoreError};
+use super::storage::Storage;
+
+impl BitCask {
+    /// Serialize key and value with bincode, and set them to store.
+    pub fn set_typed<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let key = bincode::serialize(key).map_err(serde_error)?;
+        self.set_bincode(key, value)
+    }
+
+    /// Get value of the key serialized with bincode and deserialize it,
+    /// `None` if key not found.
+    pub fn get_typed<K, V>(&mut self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        let key = bincode::serialize(key).map_err(serde_error)?;
+        self.get_bincode(&key)
+    }
+
+    /// Set key to value serialized with bincode.
+    pub fn set_bincode<V>(&mut self, key: impl AsRef<[u8]>, value: &V) -> Result<()>
+    where
+        V: Serialize + ?Sized,
+    {
+        let value = bincode::serialize(value).map_err(serde_error)?;
+        self.set(key, value)
+    }
+
+    /// Get value of the key deserialized with bincode, `None` if key
+    /// not found.
+    ///
+    /// A value not deserializable as `V`, e.g. one stored as another
+    /// type, returns `StoreError::Serde`.
+    pub fn get_bincode<V: DeserializeOwned>(&mut self, key: &[u8]) -> Result<Option<V>> {
+        match self.get(key)? {
+            None => Ok(None),
+            Some(value) => bincode::deserialize(&value).map(Some).map_err(serde_error),
+        }
+    }
+
+    /// Set key to value serialized as json.
+    pub fn set_json<V>(&mut self, key: impl AsRef<[u8]>, value: &V) -> Result<()>
+    where
+        V: Serialize + ?Sized,
+    {
+        let value = serde_json::to_vec(value).map_err(serde_error)?;
+        self.set(key, value)
+    }
+
+    /// Get value of the key deserialized from json, `None` if key
+    /// not found.
+    pub fn get_json<V: DeserializeOwned>(&mut self, key: &[u8]) -> Result<Option<V>> {
+        match self.get(key)? {
+            None => Ok(None),
+            Some(value) => serde_json::from_slice(&value)
+                .map(Some)
+                .map_err(serde_error),
+        }
+    }
+}
+
+fn serde_error(e: impl Display) -> StoreError {
+    StoreError::Serde(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::store::OpenOptions;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+        tags: Vec<String>,
+    }
+
+    fn alice() -> User {
+        User {
+            name: "alice".to_owned(),
+            age: 42,
+            tags: vec!["admin".to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_typed_round_trip() {
+        let dir = tempdir::TempDir::new("typed-test.db").unwrap();
+        let mut db = OpenOptions::new().open(dir.path()).unwrap();
+
+        db.set_typed(&1u64, &alice()).unwrap();
+
+        assert_eq!(db.get_typed::<_, User>(&1u64).unwrap(), Some(alice()));
+        assert_eq!(db.get_typed::<_, User>(&2u64).unwrap(), None);
+
+        // keys of different types don't collide.
+        db.set_typed("1", &true).unwrap();
+        assert_eq!(db.get_typed::<_, bool>("1").unwrap(), Some(true));
+        assert!(matches!(
+            db.get_typed::<_, User>("1"),
+            Err(StoreError::Serde(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_and_bincode() {
+        let dir = tempdir::TempDir::new("typed-test.db").unwrap();
+        let mut db = OpenOptions::new().open(dir.path()).unwrap();
+
+        db.set_json("user:json", &alice()).unwrap();
+        db.set_bincode("user:bincode", &alice()).unwrap();
+
+        // keys stay raw bytes, json values are readable as is.
+        let raw = db.get(b"user:json").unwrap().unwrap();
+        assert!(raw.starts_with(br#"{"name":"alice""#));
+
+        assert_eq!(db.get_json::<User>(b"user:json").unwrap(), Some(alice()));
+        assert_eq!(
+            db.get_bincode::<User>(b"user:bincode").unwrap(),
+            Some(alice())
+        );
+        assert_eq!(db.get_json::<User>(b"missing").unwrap(), None);
+
+        // mismatched types and encodings fail to deserialize.
+        assert!(matches!(
+            db.get_json::<Vec<u32>>(b"user:json"),
+            Err(StoreError::Serde(_))
+        ));
+        assert!(matches!(
+            db.get_json::<User>(b"user:bincode"),
+            Err(StoreError::Serde(_))
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+//! Watch implementation.
+//!
+//! Watchers subscribe to a key prefix and receive change events
+//! through a channel, so slow listeners never block writers.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Key change event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// key was set to value.
+    Put { key: Vec<u8>, value: Vec<u8> },
+
+    /// key was deleted.
+    Delete { key: Vec<u8> },
+}
+
+impl Event {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Event::Put { key, .. } => key,
+            Event::Delete { key } => key,
+        }
+    }
+}
+
+/// Subscribers of key change events.
+#[derive(Debug, Default)]
+pub struct Watchers {
+    subscribers: Vec<(Vec<u8>, Sender<Event>)>,
+}
+
+impl Watchers {
+    /// Subscribe to changes of keys starting with `prefix`.
+    pub fn subscribe(&mut self, prefix: Vec<u8>) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push((prefix, sender));
+        receiver
+    }
+
+    /// Return `true` if nobody subscribes.
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Send `event` to all subscribers watching its key.
+    ///
+    /// Subscribers whose receiver has been dropped are removed.
+    pub fn notify(&mut self, event: impl FnOnce() -> Event) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let event = event();
+        self.subscribers.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -0,0 +1,216 @@
+//! Chunked transfer framing.
+//!
+//! A large payload is sent as a series of `$<len>\n<len bytes>` chunks,
+//! terminated by an empty `$0\n` chunk, so neither side has to hold the
+//! whole payload in memory.
+
+use std::io::{self, BufRead, Read, Write};
+
+/// Maximum size of a chunk written by `ChunkedWriter`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writer framing written bytes into chunks of at most `CHUNK_SIZE`.
+///
+/// `finish` must be called to write the terminating chunk.
+#[derive(Debug)]
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Write pending bytes and the terminating chunk, return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.inner.write_all(b"$0\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(self.inner, "${}", self.buf.len())?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+/// Reader of chunked payload, ends at the terminating chunk.
+#[derive(Debug)]
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+
+    /// bytes left in the current chunk.
+    remaining: u64,
+
+    /// the terminating chunk is read.
+    done: bool,
+
+    /// expected size of the payload, if known.
+    expected: Option<u64>,
+
+    /// bytes of the payload read so far.
+    total: u64,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+            expected: None,
+            total: 0,
+        }
+    }
+
+    /// Read a payload of exactly `len` bytes, a longer payload fails with
+    /// `InvalidData` as soon as it's detected, before the last expected
+    /// byte is returned.
+    pub fn with_len(inner: R, len: u64) -> Self {
+        Self {
+            expected: Some(len),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Read the rest of the payload and discard it, so that the inner
+    /// reader is positioned after the terminating chunk.
+    pub fn drain(&mut self) -> io::Result<u64> {
+        io::copy(self, &mut io::sink())
+    }
+
+    fn read_chunk_header(&mut self) -> io::Result<()> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+
+        let len = line
+            .trim_end()
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk header"))?;
+
+        self.remaining = len;
+        self.done = len == 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.read_chunk_header()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= n as u64;
+        self.total += n as u64;
+
+        if let Some(expected) = self.expected {
+            let too_long = || io::Error::new(io::ErrorKind::InvalidData, "payload is too long");
+            if self.total > expected {
+                return Err(too_long());
+            }
+            // the payload must end right after the expected size.
+            if self.total == expected {
+                if self.remaining == 0 {
+                    self.read_chunk_header()?;
+                }
+                if !self.done {
+                    return Err(too_long());
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let mut w = ChunkedWriter::new(Vec::new());
+        w.write_all(&payload).unwrap();
+        let mut framed = w.finish().unwrap();
+        assert!(framed.starts_with(format!("${}\n", CHUNK_SIZE).as_bytes()));
+        assert!(framed.ends_with(b"$0\n"));
+        framed.extend_from_slice(b"next");
+
+        let mut r = ChunkedReader::new(framed.as_slice());
+        let mut read = Vec::new();
+        r.read_to_end(&mut read).unwrap();
+        assert_eq!(read, payload);
+
+        // the inner reader stops right after the payload.
+        let mut rest = String::new();
+        r.inner.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "next");
+
+        let mut r = ChunkedReader::new(b"$3\nab".as_slice());
+        assert_eq!(
+            r.read_to_end(&mut Vec::new()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut r = ChunkedReader::with_len(b"$2\nab$1\nc$0\n".as_slice(), 3);
+        let mut read = Vec::new();
+        r.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"abc");
+
+        // the extra byte is detected before the expected ones are returned.
+        let mut r = ChunkedReader::with_len(b"$2\nab$2\ncd$0\n".as_slice(), 3);
+        let mut buf = [0u8; 3];
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert_eq!(
+            r.read(&mut buf[2..]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut r = ChunkedReader::new(b"3\nabc".as_slice());
+        assert_eq!(
+            r.read_to_end(&mut Vec::new()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -1,4 +1,8 @@
 //! utils module.
+pub mod chunked;
+pub mod encoding;
 pub mod path;
 pub mod server;
 pub mod threadpool;
+pub mod time;
+pub mod tokenizer;
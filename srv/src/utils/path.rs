@@ -2,7 +2,6 @@
 
 use std::path::Path;
 
-#[allow(dead_code)]
 pub fn parse_file_id(path: &Path) -> Option<u64> {
     path.file_name()?
         .to_str()?
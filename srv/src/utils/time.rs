@@ -0,0 +1,14 @@
+//! time utils
+
+use chrono::Utc;
+
+/// Return current unix timestamp in milliseconds.
+pub fn now_millis() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+/// Return current unix timestamp in seconds, `0` if the clock is
+/// before the epoch.
+pub fn now_secs() -> u64 {
+    Utc::now().timestamp().max(0) as u64
+}
@@ -0,0 +1,98 @@
+//! Command line tokenizer.
+
+use thiserror::Error;
+
+/// Tokenize error.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TokenizeError {
+    #[error("unbalanced quotes in request")]
+    UnbalancedQuotes,
+
+    #[error("closing quote must be followed by a space")]
+    InvalidQuote,
+}
+
+/// Split a command line into tokens.
+///
+/// Tokens are separated by any amount of whitespace, a token wrapped in
+/// double quotes may contain whitespace, and `\"` or `\\` inside quotes
+/// escape a quote or a backslash.
+pub fn tokenize(line: &str) -> Result<Vec<String>, TokenizeError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let Some(c) = chars.next() else {
+            return Ok(tokens);
+        };
+
+        let mut token = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    None => return Err(TokenizeError::UnbalancedQuotes),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => token.push(c),
+                        Some(c) => {
+                            token.push('\\');
+                            token.push(c);
+                        }
+                        None => return Err(TokenizeError::UnbalancedQuotes),
+                    },
+                    Some(c) => token.push(c),
+                }
+            }
+
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err(TokenizeError::InvalidQuote);
+            }
+        } else {
+            token.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+
+        tokens.push(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tests = [
+            ("", vec![]),
+            ("  \r\n", vec![]),
+            ("get foo", vec!["get", "foo"]),
+            ("set  foo   bar\r", vec!["set", "foo", "bar"]),
+            ("set key \"two words\"", vec!["set", "key", "two words"]),
+            ("set key \"\"", vec!["set", "key", ""]),
+            (
+                r#"set key "say \"hi\" \\ ok""#,
+                vec!["set", "key", r#"say "hi" \ ok"#],
+            ),
+        ];
+
+        for (line, expected) in tests {
+            assert_eq!(tokenize(line).unwrap(), expected, "line: {line:?}");
+        }
+    }
+
+    #[test]
+    fn test_tokenize_malformed_quotes() {
+        assert_eq!(
+            tokenize("set key \"unterminated"),
+            Err(TokenizeError::UnbalancedQuotes)
+        );
+        assert_eq!(
+            tokenize("set key \"quoted\"tail"),
+            Err(TokenizeError::InvalidQuote)
+        );
+    }
+}